@@ -11,7 +11,9 @@ use flate2::Compression;
 use ini::Ini;
 use linked_hash_map::LinkedHashMap;
 use regex::Regex;
+use std::cell::RefCell;
 use std::collections::hash_map::HashMap;
+use std::convert::TryFrom;
 use std::io;
 use std::io::Read;
 use std::io::Write;
@@ -27,12 +29,123 @@ pub trait GitObject {
     /// whatever it takes to convert it into a meaningful representation.  What exactly that means depend on each subclass.
     fn serialize(&self) -> Result<Vec<u8>, WyagError>;
     fn deserialize(&mut self, data: Vec<u8>) -> Result<(), WyagError>;
-    fn fmt(&self) -> &[u8];
+    fn fmt(&self) -> ObjectType;
     fn repo(&self) -> Option<&GitRepository> {
         panic!("Not yet implemented")
     }
 }
 
+/// The kinds of object wyag knows how to store, as named in an object's header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ObjectType {
+    Blob,
+    Tree,
+    Commit,
+    Tag,
+}
+
+impl ObjectType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ObjectType::Blob => "blob",
+            ObjectType::Tree => "tree",
+            ObjectType::Commit => "commit",
+            ObjectType::Tag => "tag",
+        }
+    }
+
+    fn as_bytes(&self) -> &'static [u8] {
+        self.as_str().as_bytes()
+    }
+
+    /// Returns `None` for anything that isn't one of the four known types.
+    fn from_bytes(b: &[u8]) -> Option<ObjectType> {
+        match b {
+            b"blob" => Some(ObjectType::Blob),
+            b"tree" => Some(ObjectType::Tree),
+            b"commit" => Some(ObjectType::Commit),
+            b"tag" => Some(ObjectType::Tag),
+            _ => None,
+        }
+    }
+}
+
+/// A full-length object name, normalized to lowercase so that names differing only in case compare equal.
+///
+/// Abbreviated names are rejected rather than guessed at; resolve those with `object_find` first.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ObjectId(String);
+
+impl ObjectId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl TryFrom<&str> for ObjectId {
+    type Error = WyagError;
+
+    fn try_from(s: &str) -> Result<ObjectId, WyagError> {
+        if (s.len() != 40 && s.len() != 64) || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(WyagError::new(
+                format!("'{}' is not a full object name", s).as_ref(),
+            ));
+        }
+        Ok(ObjectId(s.to_ascii_lowercase()))
+    }
+}
+
+impl TryFrom<String> for ObjectId {
+    type Error = WyagError;
+
+    fn try_from(s: String) -> Result<ObjectId, WyagError> {
+        ObjectId::try_from(s.as_str())
+    }
+}
+
+impl From<ObjectId> for String {
+    fn from(id: ObjectId) -> String {
+        id.0
+    }
+}
+
+impl fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod object_id_tests {
+    use super::*;
+
+    #[test]
+    fn names_differing_in_case_are_the_same_id() {
+        let lower = ObjectId::try_from("4b825dc642cb6eb9a060e54bf8d69288fbee4904").unwrap();
+        let upper =
+            ObjectId::try_from("4B825DC642CB6EB9A060E54BF8D69288FBEE4904".to_owned()).unwrap();
+        assert_eq!(lower, upper);
+        assert_eq!(
+            String::from(upper),
+            "4b825dc642cb6eb9a060e54bf8d69288fbee4904"
+        );
+
+        let mut seen: std::collections::HashSet<ObjectId> = std::collections::HashSet::new();
+        seen.insert(lower);
+        assert!(
+            seen.contains(&ObjectId::try_from("4B825dc642cb6eb9a060e54bf8d69288fbee4904").unwrap())
+        );
+    }
+
+    #[test]
+    fn abbreviated_or_non_hex_names_are_rejected() {
+        assert!(ObjectId::try_from("4b825dc").is_err());
+        assert!(ObjectId::try_from("HEAD").is_err());
+        assert!(ObjectId::try_from("zb825dc642cb6eb9a060e54bf8d69288fbee4904").is_err());
+        assert!(ObjectId::try_from("a".repeat(64)).is_ok());
+    }
+}
+
 enum GObj<'a> {
     Tag(GitTag<'a>),
     Commit(GitCommit<'a>),
@@ -42,22 +155,26 @@ enum GObj<'a> {
 
 /// Git Object Concrete Types
 struct GitTag<'a> {
-    repo: Option<&'a GitRepository<'a>>,
+    repo: Option<&'a GitRepository>,
     kvlm: LinkedHashMap<String, Vec<String>>,
     _data: Vec<u8>,
 }
 struct GitCommit<'a> {
-    repo: Option<&'a GitRepository<'a>>,
+    repo: Option<&'a GitRepository>,
     kvlm: LinkedHashMap<String, Vec<String>>,
     _data: Vec<u8>,
+    /// The bytes a commit that isn't UTF-8 was decoded from, and the kvlm they decoded to.
+    /// Serializing an unchanged kvlm writes those bytes back, since re-encoding the decoded text wouldn't.
+    raw: Option<Vec<u8>>,
+    raw_kvlm: LinkedHashMap<String, Vec<String>>,
 }
 
 struct GitBlob<'a> {
-    repo: Option<&'a GitRepository<'a>>,
+    repo: Option<&'a GitRepository>,
     blob_data: Vec<u8>,
 }
 struct GitTree<'a> {
-    repo: Option<&'a GitRepository<'a>>,
+    repo: Option<&'a GitRepository>,
     items: Vec<GitTreeLeaf>,
 }
 
@@ -84,13 +201,13 @@ impl<'a> GitObject for GitTag<'a> {
         Ok(())
     }
 
-    fn fmt(&self) -> &[u8] {
-        b"tag"
+    fn fmt(&self) -> ObjectType {
+        ObjectType::Tag
     }
 
-    // fn repo(&self) -> &GitRepository {
-    //     panic!("Not yet implemented");
-    // }
+    fn repo(&self) -> Option<&GitRepository> {
+        self.repo
+    }
 }
 
 impl<'a> GitCommit<'a> {
@@ -99,25 +216,119 @@ impl<'a> GitCommit<'a> {
             repo: repo,
             kvlm: LinkedHashMap::default(),
             _data: bytes.to_vec(),
+            raw: None,
+            raw_kvlm: LinkedHashMap::default(),
+        }
+    }
+
+    /// The SHA of the tree this commit snapshots. Errors, rather than panicking, on a commit without one.
+    fn tree_sha(&self) -> Result<String, WyagError> {
+        match self.kvlm.get("tree") {
+            Some(t) if t.len() > 0 => Ok(t[0].to_owned()),
+            _ => Err(WyagError::new("Malformed commit: it has no tree")),
+        }
+    }
+
+    /// The SHAs of this commit's parents, in order. Empty for a root commit.
+    fn parents(&self) -> Vec<String> {
+        match self.kvlm.get("parent") {
+            Some(p) => p.clone(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Whether this commit joins two or more lines of history.
+    fn is_merge_commit(&self) -> bool {
+        self.parents().len() > 1
+    }
+
+    /// The ASCII-armored signature from the `gpgsig` header, if the commit was signed.
+    fn gpgsig(&self) -> Option<&str> {
+        match self.kvlm.get("gpgsig") {
+            Some(v) if v.len() > 0 => Some(v[0].as_ref()),
+            _ => None,
+        }
+    }
+
+    /// The charset the `encoding` header says the message is in, if there is one. Without it, the message is UTF-8.
+    fn encoding(&self) -> Option<&str> {
+        match self.kvlm.get("encoding") {
+            Some(v) if v.len() > 0 => Some(v[0].as_ref()),
+            _ => None,
+        }
+    }
+
+    /// The full commit message, everything after the headers. Empty if the commit has none.
+    ///
+    /// A commit that isn't UTF-8 has its headers and message decoded from its `encoding` if wyag knows it
+    /// (only ISO-8859-1 so far), and otherwise has its undecodable bytes replaced.
+    fn message(&self) -> &str {
+        match self.kvlm.get("") {
+            Some(m) if m.len() > 0 => m[0].as_ref(),
+            _ => "",
+        }
+    }
+
+    /// The first line of the message.
+    fn subject(&self) -> &str {
+        self.message().lines().next().unwrap_or("")
+    }
+
+    /// The message after its subject line and the blank lines separating them. Empty for a one-line message.
+    fn body(&self) -> &str {
+        let message = self.message();
+        match message.find('\n') {
+            Some(i) => message[i + 1..].trim_start_matches(|c| c == '\n' || c == '\r'),
+            None => "",
         }
     }
 }
 
 impl<'a> GitObject for GitCommit<'a> {
     fn serialize(&self) -> Result<Vec<u8>, WyagError> {
-        let x = kvlm_serialize(&self.kvlm).into_bytes();
-        Ok(x)
+        match &self.raw {
+            Some(raw) if self.raw_kvlm == self.kvlm => Ok(raw.clone()),
+            _ => match self.encoding() {
+                // The decoded text is written back as UTF-8, so an `encoding` naming anything else would be a lie
+                Some(e) if !e.eq_ignore_ascii_case("UTF-8") => {
+                    let mut kvlm = self.kvlm.clone();
+                    kvlm.remove("encoding");
+                    Ok(kvlm_serialize(&kvlm).into_bytes())
+                }
+                _ => Ok(kvlm_serialize(&self.kvlm).into_bytes()),
+            },
+        }
     }
 
     fn deserialize(&mut self, data: Vec<u8>) -> Result<(), WyagError> {
         let mut hm: LinkedHashMap<String, Vec<String>> = LinkedHashMap::new();
-        kvlm_parse(data, 0, &mut hm);
+        self.raw = None;
+        if str::from_utf8(&data).is_ok() {
+            kvlm_parse(data, 0, &mut hm);
+        } else {
+            // Header names are ASCII, so a lossy first pass is enough to find the encoding to decode it all by
+            let mut probe: LinkedHashMap<String, Vec<String>> = LinkedHashMap::new();
+            kvlm_parse(
+                String::from_utf8_lossy(&data).into_owned().into_bytes(),
+                0,
+                &mut probe,
+            );
+            let encoding = probe.get("encoding").and_then(|e| e.first()).cloned();
+            let decoded = commit_decode(encoding.as_ref().map(|e| e.as_ref()), &data);
+            kvlm_parse(decoded.into_bytes(), 0, &mut hm);
+            self.raw_kvlm = hm.clone();
+            self.raw = Some(data);
+        }
         self.kvlm = hm;
         Ok(())
     }
 
-    fn fmt(&self) -> &[u8] {
-        b"commit"
+    fn fmt(&self) -> ObjectType {
+        ObjectType::Commit
+    }
+
+    fn repo(&self) -> Option<&GitRepository> {
+        self.repo
     }
 }
 
@@ -140,8 +351,12 @@ impl<'a> GitObject for GitBlob<'a> {
         Ok(())
     }
 
-    fn fmt(&self) -> &[u8] {
-        b"blob"
+    fn fmt(&self) -> ObjectType {
+        ObjectType::Blob
+    }
+
+    fn repo(&self) -> Option<&GitRepository> {
+        self.repo
     }
 }
 
@@ -165,25 +380,44 @@ impl<'a> GitObject for GitTree<'a> {
         Ok(())
     }
 
-    fn fmt(&self) -> &[u8] {
-        b"tree"
+    fn fmt(&self) -> ObjectType {
+        ObjectType::Tree
+    }
+
+    fn repo(&self) -> Option<&GitRepository> {
+        self.repo
     }
 }
 
 /// Git Repository object
-pub struct GitRepository<'a> {
-    worktree: &'a str,
+pub struct GitRepository {
+    worktree: String,
     gitdir: PathBuf,
     conf: Ini,
+    /// `sha -> (type, size)` for object headers already read, see object_read_header
+    header_cache: RefCell<HashMap<String, (String, usize)>>,
+    /// Set by `open_readonly`: anything that would write to the repository errors instead
+    read_only: bool,
 }
 
-impl<'a> GitRepository<'a> {
-    pub fn new(path: &'a str, force: bool) -> Result<GitRepository, WyagError> {
+impl GitRepository {
+    pub fn new(path: &str, force: bool) -> Result<GitRepository, WyagError> {
         // Set up the gitdir
-        let git_path = Path::new(path).join(".git");
+        let git_path = gitdir_resolve(Path::new(path))?;
+        GitRepository::open(path, git_path, force)
+    }
+
+    /// Opens the repository whose worktree is `path` and whose gitdir is `git_path`, which needn't be `path/.git`.
+    fn open(path: &str, git_path: PathBuf, force: bool) -> Result<GitRepository, WyagError> {
         if !(force || git_path.is_dir()) {
-            let serr = "Not a git path";
-            return Err(WyagError::new(serr));
+            return Err(WyagError::new(
+                format!(
+                    "Not a git path: {} is not a git directory (opening the repository at {})",
+                    git_path.display(),
+                    path
+                )
+                .as_ref(),
+            ));
         }
 
         // Read configuration file in .git/config
@@ -220,20 +454,50 @@ impl<'a> GitRepository<'a> {
         }
 
         let gr = GitRepository {
-            worktree: path,
-            gitdir: git_path.to_path_buf(),
+            worktree: path.to_owned(),
+            gitdir: git_path,
             conf: conf,
+            header_cache: RefCell::new(HashMap::new()),
+            read_only: false,
         };
 
+        // Only the setting is validated here; scanning the store for a half-done migration is left to fsck
+        object_format_validate(&gr)?;
+
+        Ok(gr)
+    }
+
+    /// Opens the repository at `path` for reading only.
+    ///
+    /// The config is loaded if there is one, but not validated, so repositories with an unsupported
+    /// `repositoryformatversion` can still have their objects read. Writing objects or refs errors.
+    pub fn open_readonly(path: &str) -> Result<GitRepository, WyagError> {
+        let git_path = gitdir_resolve(Path::new(path))?;
+        if !git_path.is_dir() {
+            return Err(WyagError::new(
+                format!(
+                    "Not a git path: {} is not a git directory",
+                    git_path.display()
+                )
+                .as_ref(),
+            ));
+        }
+        let mut gr = GitRepository::open(path, git_path, true)?;
+        gr.read_only = true;
         Ok(gr)
     }
 
+    /// Forgets every cached object header, e.g. after objects were rewritten behind wyag's back.
+    pub fn header_cache_clear(&self) {
+        self.header_cache.borrow_mut().clear();
+    }
+
     /// Creates a new repository at `path`
     pub fn repo_create(path: &str) -> Result<GitRepository, WyagError> {
         let repo = GitRepository::new(path, true)?;
 
         // check that repo path is either non-existant, or is an empty dir
-        let p: PathBuf = PathBuf::from(repo.worktree);
+        let p: PathBuf = PathBuf::from(&repo.worktree);
 
         if p.exists() {
             if p.is_file() {
@@ -247,7 +511,7 @@ impl<'a> GitRepository<'a> {
                     "Cannot create new repository, supplied path is not empty.",
                 ));
             }
-            if let Err(m) = std::fs::create_dir_all(repo.worktree) {
+            if let Err(m) = std::fs::create_dir_all(&repo.worktree) {
                 return Err(WyagError::new_with_error(
                     "failed to create work directory for supplied repository",
                     Box::new(m),
@@ -344,6 +608,73 @@ impl<'a> GitRepository<'a> {
     }
 }
 
+/// Whether `core.bare` marks this repository as having no worktree.
+fn is_bare(repo: &GitRepository) -> bool {
+    match repo.conf.section(Some("core".to_owned())) {
+        Some(core) => match core.get("bare") {
+            Some(b) => b.trim().eq_ignore_ascii_case("true"),
+            None => false,
+        },
+        None => false,
+    }
+}
+
+/// Errors out for bare repositories, for commands that read or write the worktree.
+fn require_worktree(repo: &GitRepository) -> Result<(), WyagError> {
+    if is_bare(repo) {
+        return Err(WyagError::new("this operation must be run in a work tree"));
+    }
+    Ok(())
+}
+
+/// Errors out for repositories opened with `GitRepository::open_readonly`, for anything that writes.
+fn require_writable(repo: &GitRepository) -> Result<(), WyagError> {
+    if repo.read_only {
+        return Err(WyagError::new(
+            "this repository was opened read-only and cannot be written to",
+        ));
+    }
+    Ok(())
+}
+
+/// Works out the gitdir for the worktree at `worktree`.
+///
+/// Normally this is just `worktree/.git`, but linked worktrees and submodules have a `.git`
+/// *file* instead, containing a `gitdir: <path>` line pointing at the real git directory.
+fn gitdir_resolve(worktree: &Path) -> Result<PathBuf, WyagError> {
+    let dot_git = worktree.join(".git");
+    if !dot_git.is_file() {
+        return Ok(dot_git);
+    }
+
+    let contents = match std::fs::read_to_string(&dot_git) {
+        Ok(s) => s,
+        Err(m) => {
+            return Err(WyagError::new_with_error(
+                "Failed to read .git file",
+                Box::new(m),
+            ));
+        }
+    };
+    match contents.lines().next() {
+        Some(line) if line.starts_with("gitdir: ") => {
+            let pointer = PathBuf::from(line["gitdir: ".len()..].trim());
+            if pointer.is_absolute() {
+                return Ok(pointer);
+            }
+            // A relative pointer is relative to the directory holding the .git file, not to the cwd
+            let joined = worktree.join(&pointer);
+            match joined.canonicalize() {
+                Ok(p) => Ok(p),
+                Err(_) => Ok(joined),
+            }
+        }
+        _ => Err(WyagError::new(
+            "Invalid .git file, expected it to start with 'gitdir: <path>'",
+        )),
+    }
+}
+
 // EndRegion: GitRepository
 
 // Region: RepoPaths
@@ -351,6 +682,9 @@ impl<'a> GitRepository<'a> {
 /// Looks for a repository, starting at `path` and recursing back until `/`.
 /// To identify something as a repo, checks for the presence of a .git directory.
 ///
+/// If `GIT_DIR` is set, no search happens: that directory is the gitdir, and the worktree is
+/// `GIT_WORK_TREE` if that is set too, or `path` otherwise.
+///
 /// # examples
 /// repo_find("./", false)  
 ///
@@ -358,7 +692,7 @@ impl<'a> GitRepository<'a> {
 ///
 /// repo_find("./", true)
 ///
-///     Err => ("Failed to find a repository") // if no repo is found, but finding one was required
+///     Err => ("Failed to find a repository: not a git repository ...") // if no repo is found, but finding one was required
 ///
 /// repo_find("./" [true/false])
 ///
@@ -368,6 +702,32 @@ impl<'a> GitRepository<'a> {
 ///
 ///     Err("Failed to read directory") // if some error was encountered
 fn repo_find(path: &str, required: bool) -> Result<Option<GitRepository>, WyagError> {
+    Ok(repo_discover(path, required)?.map(|found| found.repo))
+}
+
+/// A repository found by `repo_discover`, along with where it was found.
+struct FoundRepository {
+    repo: GitRepository,
+    /// The directory holding `.git`, or the worktree given by `GIT_WORK_TREE` / `path` when `GIT_DIR` is set
+    path: PathBuf,
+}
+
+/// Same search as `repo_find`, but also reports the directory the repository was discovered at.
+///
+/// When `required` and nothing is found, the error names the directory the search started from.
+fn repo_discover(path: &str, required: bool) -> Result<Option<FoundRepository>, WyagError> {
+    if let Some(git_dir) = std::env::var_os("GIT_DIR") {
+        let worktree = match std::env::var("GIT_WORK_TREE") {
+            Ok(w) => w,
+            Err(_) => path.to_owned(),
+        };
+        let gr = GitRepository::open(&worktree, PathBuf::from(git_dir), false)?;
+        return Ok(Some(FoundRepository {
+            repo: gr,
+            path: PathBuf::from(worktree),
+        }));
+    }
+
     let p = PathBuf::from(path);
     let real = match p.canonicalize() {
         Ok(p) => p,
@@ -379,19 +739,38 @@ fn repo_find(path: &str, required: bool) -> Result<Option<GitRepository>, WyagEr
         }
     };
 
-    if p.join(".git").is_dir() {
+    if p.join(".git").exists() {
         let gr = GitRepository::new(path, false)?;
-        return Ok(Some(gr));
+        return Ok(Some(FoundRepository {
+            repo: gr,
+            path: real,
+        }));
     }
 
     // # If we haven't returned, recurse in parent
-    while let Some(p) = real.parent() {
-        if p.join(".git").is_dir() {
-            let gr = GitRepository::new(path, false)?;
-            return Ok(Some(gr));
+    for p in real.ancestors().skip(1) {
+        if p.join(".git").exists() {
+            let worktree = match p.to_str() {
+                Some(s) => s,
+                None => return Err(WyagError::new("Repository path is not valid unicode")),
+            };
+            let gr = GitRepository::new(worktree, false)?;
+            return Ok(Some(FoundRepository {
+                repo: gr,
+                path: p.to_path_buf(),
+            }));
         }
     }
 
+    if required {
+        return Err(WyagError::new(
+            format!(
+                "Failed to find a repository: not a git repository (or any of the parent directories): {}",
+                real.display()
+            )
+            .as_ref(),
+        ));
+    }
     return Ok(None);
 }
 
@@ -422,7 +801,11 @@ fn repo_dir_path(root: &PathBuf, mk_dir: bool, paths: Vec<&str>) -> Result<PathB
             return Ok(p);
         } else {
             return Err(WyagError::new(
-                "Path already existed as a file. Cannot overwrite file with directory.",
+                format!(
+                    "Path {} already existed as a file. Cannot overwrite file with directory.",
+                    p.display()
+                )
+                .as_ref(),
             ));
         }
     }
@@ -430,14 +813,20 @@ fn repo_dir_path(root: &PathBuf, mk_dir: bool, paths: Vec<&str>) -> Result<PathB
     if mk_dir {
         if let Err(m) = std::fs::create_dir_all(&p) {
             return Err(WyagError::new_with_error(
-                "Couldn't create necessary directories",
+                format!("Couldn't create necessary directories for {}", p.display()).as_ref(),
                 Box::new(m),
             ));
         }
         return Ok(p);
     }
 
-    return Err(WyagError::new_with_error("Failed to create directories", Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create directory. Didn't exist, but was not given the mk_dir option to create subdirectories"))));
+    Err(WyagError::new(
+        format!(
+            "Directory {} does not exist, and was not asked to be created (mk_dir was off)",
+            p.display()
+        )
+        .as_ref(),
+    ))
 }
 
 /// Same as repo_path, but create dirname(*path) if absent.  For
@@ -470,6 +859,57 @@ fn repo_file_path(root: &PathBuf, mk_dir: bool, paths: Vec<&str>) -> Result<Path
     }
 }
 
+/// Turns a user-supplied `path` (relative to the current directory, or absolute) into a path relative to the worktree root.
+///
+/// The path itself needn't exist, but its parent directory must. Errors if it falls outside the worktree.
+fn worktree_relative(repo: &GitRepository, path: &str) -> Result<PathBuf, WyagError> {
+    let root = match Path::new(&repo.worktree).canonicalize() {
+        Ok(p) => p,
+        Err(m) => {
+            return Err(WyagError::new_with_error(
+                "Failed to create canonical path from the worktree",
+                Box::new(m),
+            ));
+        }
+    };
+
+    let p = Path::new(path);
+    let full = match p.canonicalize() {
+        Ok(c) => c,
+        Err(_) => {
+            // Not on disk (e.g. already deleted), so only its parent can be canonicalized
+            let name = match p.file_name() {
+                Some(n) => n,
+                None => {
+                    return Err(WyagError::new(
+                        format!("Failed to resolve path {}", path).as_ref(),
+                    ));
+                }
+            };
+            let parent = match p.parent() {
+                Some(d) if d.as_os_str().len() > 0 => d,
+                _ => Path::new("."),
+            };
+            match parent.canonicalize() {
+                Ok(c) => c.join(name),
+                Err(m) => {
+                    return Err(WyagError::new_with_error(
+                        format!("Failed to resolve path {}", path).as_ref(),
+                        Box::new(m),
+                    ));
+                }
+            }
+        }
+    };
+
+    match full.strip_prefix(&root) {
+        Ok(rel) => Ok(rel.to_path_buf()),
+        Err(_) => Err(WyagError::new(
+            format!("{} is outside repository at {}", path, repo.worktree).as_ref(),
+        )),
+    }
+}
+
 // EndRegion: RepoPaths
 
 /// Region: GitIndex
@@ -509,10 +949,14 @@ struct GitIndexEntry {
 
     flag_extended: bool,
 
-    flag_stage: bool,
+    /// Merge stage: 0 normally, 1 to 3 for the base, ours and theirs sides of a conflict
+    flag_stage: u8,
 
     /// Length of the name if < 0xFFF (yes, three Fs), -1 otherwise
     flag_name_length: bool,
+
+    /// Path of the file, relative to the worktree root
+    path: String,
 }
 
 impl GitIndexEntry {
@@ -530,892 +974,936 @@ impl GitIndexEntry {
             obj: "".to_owned(),
             flag_assume_valid: false,
             flag_extended: false,
-            flag_stage: false,
+            flag_stage: 0,
             flag_name_length: false,
+            path: "".to_owned(),
         }
     }
-}
-
-/// EndRegion: GitIndex
 
-// Region: Reading/Writing Objects
-
-/// Read object object_id from Git repository repo.  Return a
-/// GitObject whose exact type depends on the object.
-/// 4.3
-fn object_read<'a>(repo: &'a GitRepository, sha: &str) -> Result<GObj<'a>, WyagError> {
-    // grab the object in question from the filesystem
-    let path = repo_file_gr(&repo, false, vec!["objects", &sha[..2], &sha[2..]])?;
+    /// The full mode, as written in trees and by `ls-files -s`: object type and permissions together.
+    fn mode_octal(&self) -> u32 {
+        let kind = match self.mode.as_ref() {
+            "b1010" => 0o120000,
+            "b1110" => 0o160000,
+            _ => 0o100000,
+        };
+        kind | self.mode_perms
+    }
+}
 
-    // read the raw bytes of the file.
-    let raw = match std::fs::read(path) {
-        Ok(bv) => bv,
-        Err(m) => {
-            return Err(WyagError::new_with_error(
-                format!(
-                    "Failed to read git object file {}. This error happened before deflating.",
-                    sha
-                )
-                .as_ref(),
-                Box::new(m),
+/// Prints every entry of the index with all of its fields, for diagnosing index problems.
+pub fn cmd_debug_index() -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            return Err(WyagError::new(
+                "No repository was found, cannot use wyag-debug-index",
             ));
         }
     };
+    for line in debug_index(&repo)? {
+        println!("{}", line);
+    }
+    Ok(())
+}
 
-    // decode the zlib enconded data
-    let decoded = match decode_reader(raw) {
-        Ok(s) => s,
+/// One `<mode> <sha> <stage>\t<path>` line per entry, each followed by its stat data like `ls-files --debug` shows it.
+fn debug_index(repo: &GitRepository) -> Result<Vec<String>, WyagError> {
+    let mut lines: Vec<String> = Vec::new();
+    for e in index_read(repo)? {
+        lines.push(format!(
+            "{:06o} {} {}\t{}",
+            e.mode_octal(),
+            e.obj,
+            e.flag_stage,
+            e.path
+        ));
+        lines.push(format!("  ctime: {}:{}", e.ctime.0, e.ctime.1));
+        lines.push(format!("  mtime: {}:{}", e.mtime.0, e.mtime.1));
+        lines.push(format!("  dev: {}\tino: {}", e.dev, e.ino));
+        lines.push(format!("  uid: {}\tgid: {}", e.uid, e.gid));
+        lines.push(format!(
+            "  size: {}\tflags: assume-valid={} extended={}",
+            e.size, e.flag_assume_valid, e.flag_extended
+        ));
+    }
+    Ok(lines)
+}
+
+/// Reads the entries of `.git/index`, in the order they are stored. A repository without an index has none.
+///
+/// Versions 2 and 3 are understood; version 4's prefix-compressed paths are not. Extensions are skipped.
+fn index_read(repo: &GitRepository) -> Result<Vec<GitIndexEntry>, WyagError> {
+    let raw = match std::fs::read(repo_path_gr(repo, vec!["index"])) {
+        Ok(r) => r,
+        Err(ref m) if m.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
         Err(m) => {
             return Err(WyagError::new_with_error(
-                format!("Failed to decode ZLIB encoded byte array: {0}", sha).as_ref(),
+                "Failed to read the index",
                 Box::new(m),
             ));
         }
     };
-
-    // read the object type
-    let xIdx = match decoded.iter().position(|&r| r == b' ') {
-        Some(i) => i,
-        None => return Err(WyagError::new(
-            format!("Failed decode git object type {}- no space delimeter was found. Is this file corrupted?", sha).as_ref(),
-        )),
-    };
-
-    // read and validate object size
-    let yIdx = match decoded.iter().position(|&r| r == b'\x00') {
-        Some(i) => i,
-        None => return Err(WyagError::new(
-            format!("Failed decode git object type {} - no null delimeter was found. Is this file corrupted?", sha).as_ref(),
-        )),
+    let truncated = || WyagError::new("Malformed index: it ends in the middle of an entry");
+    let u32_at = |off: usize| -> Result<u32, WyagError> {
+        match raw.get(off..off + 4) {
+            Some(b) => Ok(u32::from(b[0]) << 24
+                | u32::from(b[1]) << 16
+                | u32::from(b[2]) << 8
+                | u32::from(b[3])),
+            None => Err(truncated()),
+        }
     };
 
-    let size = str::from_utf8(&decoded[xIdx..yIdx]).unwrap(); // todo wyag error here
-    let size: usize = size.parse().unwrap(); // todo wyag error here
-    if size != decoded.len() - (yIdx - 1) {
+    if raw.get(..4) != Some(&b"DIRC"[..]) {
+        return Err(WyagError::new("Malformed index: missing DIRC signature"));
+    }
+    let version = u32_at(4)?;
+    if version != 2 && version != 3 {
         return Err(WyagError::new(
-            format!("Malformed object {}, bad length.", sha).as_ref(),
+            format!("Index version {} is not supported", version).as_ref(),
         ));
     }
-
-    let dfmt = &decoded[..xIdx];
-
-    let mut c: GObj;
-    match dfmt {
-        b"commit" => c = GObj::Commit(GitCommit::new(Some(repo), &decoded[yIdx + 1..])),
-        b"tree" => c = GObj::Tree(GitTree::new(Some(repo), &decoded[yIdx + 1..])),
-        b"tag" => c = GObj::Tag(GitTag::new(Some(repo), &decoded[yIdx + 1..])),
-        b"blob" => c = GObj::Blob(GitBlob::new(Some(repo), &decoded[yIdx + 1..])),
-        _ => {
-            return Err(WyagError::new(
-                format!("Unknown type {} for object {}", "", sha).as_ref(), // todo fromat for dfmt
-            ));
+    let count = u32_at(8)?;
+
+    let mut entries: Vec<GitIndexEntry> = Vec::new();
+    let mut off = 12;
+    for _ in 0..count {
+        let start = off;
+        let mode = u32_at(off + 24)?;
+        let sha = match raw.get(off + 40..off + 60) {
+            Some(b) => bytes_to_hex(b),
+            None => return Err(truncated()),
+        };
+        let flags = (u32_at(off + 58)? & 0xFFFF) as u16;
+        off += 62;
+        let extended = flags & 0x4000 != 0;
+        if extended {
+            off += 2;
         }
-    };
-
-    Ok(c)
+        let name_len = match raw.get(off..).and_then(|r| r.iter().position(|&b| b == 0)) {
+            Some(n) => n,
+            None => return Err(truncated()),
+        };
+        let path = String::from_utf8_lossy(&raw[off..off + name_len]).into_owned();
+        off += name_len;
+        // Entries are NUL-padded to a multiple of eight bytes, with at least one NUL
+        off = start + ((off - start) / 8 + 1) * 8;
+
+        entries.push(GitIndexEntry {
+            ctime: (u32_at(start)? as usize, u32_at(start + 4)? as usize),
+            mtime: (u32_at(start + 8)? as usize, u32_at(start + 12)? as usize),
+            dev: u32_at(start + 16)?.to_string(),
+            ino: u32_at(start + 20)?.to_string(),
+            mode: format!("b{:04b}", mode >> 12),
+            mode_perms: mode & 0o777,
+            uid: u32_at(start + 28)?.to_string(),
+            gid: u32_at(start + 32)?.to_string(),
+            size: u32_at(start + 36)? as usize,
+            obj: sha,
+            flag_assume_valid: flags & 0x8000 != 0,
+            flag_extended: extended,
+            flag_stage: ((flags >> 12) & 0x3) as u8,
+            flag_name_length: flags & 0xFFF == 0xFFF,
+            path: path,
+        });
+    }
+    Ok(entries)
 }
 
-fn decode_reader(bytes: Vec<u8>) -> std::io::Result<Vec<u8>> {
-    let mut z = ZlibDecoder::new(&bytes[..]);
-    let mut byteBuf: Vec<u8> = Vec::new();
-    z.read_exact(&mut byteBuf)?;
-    Ok(byteBuf)
+#[cfg(test)]
+mod index_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn debug_index_shows_each_entrys_sha_and_mode() {
+        let path = scratch_dir("wyag_debug_index");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        assert_eq!(debug_index(&gr).unwrap(), Vec::<String>::new());
+
+        let blob = write_loose_object(&gr, b"blob", b"hello\n");
+        let index = index_bytes(&[(0o100644, &blob, "a.txt"), (0o100755, &blob, "bin/run.sh")]);
+        std::fs::write(repo_path_gr(&gr, vec!["index"]), index).unwrap();
+
+        let lines = debug_index(&gr).unwrap();
+        assert_eq!(lines[0], format!("100644 {} 0\ta.txt", blob));
+        assert_eq!(lines[1], "  ctime: 1600000000:5");
+        assert_eq!(lines[3], "  dev: 2049\tino: 1234");
+        assert_eq!(lines[6], format!("100755 {} 0\tbin/run.sh", blob));
+        assert_eq!(lines.len(), 12);
+
+        std::fs::write(repo_path_gr(&gr, vec!["index"]), b"DIRC\x00\x00\x00\x02").unwrap();
+        assert!(debug_index(&gr).is_err());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
 }
 
-/// Writes the GitObject to its appropriate location in the repo
-/// 4.4
-fn object_write(obj: &GitObject, actually_write: bool) -> Result<String, WyagError> {
-    // serialize the data
-    let data = obj.serialize()?;
-
-    // Add header
-    let mut result: Vec<u8> = Vec::new();
-    result.extend(obj.fmt());
-    result.extend(vec![b' ']);
-    let us = data.len().to_string().into_bytes();
-    result.extend(us);
-    result.extend(vec![b'\x00']);
-    result.extend(data);
-
-    // compute hash
-    let mut sha = crypto::sha1::Sha1::new();
-    sha.input(&result);
-    let outStr = sha.result_str();
-
-    if actually_write {
-        // compute path
-        let path = repo_file_gr(
-            obj.repo().unwrap(),
-            true,
-            vec!["objects", &outStr[..2], &outStr[2..]],
-        )?;
+/// EndRegion: GitIndex
 
-        let mut e = ZlibEncoder::new(Vec::new(), Compression::default());
-        match e.write_all(&result) {
-            Ok(_) => (),
-            Err(m) => {
-                return Err(WyagError::new_with_error(
-                    "failed to zlib compress object",
-                    Box::new(m),
-                ));
-            }
-        };
+// Region: Reading/Writing Objects
 
-        let compressed_bytes = match e.finish() {
-            Ok(bytes) => bytes,
-            Err(m) => {
-                return Err(WyagError::new_with_error(
-                    "Failed to finish zlib compressing object",
-                    Box::new(m),
-                ));
-            }
-        };
+/// Read object object_id from Git repository repo.  Return a
+/// GitObject whose exact type depends on the object.
+/// 4.3
+fn object_read<'a>(repo: &'a GitRepository, sha: &str) -> Result<GObj<'a>, WyagError> {
+    let path = object_path(repo, sha);
+    let decoded = object_inflate(&path, sha, big_file_threshold(repo)?)?;
 
-        let compressed_byte_str = "TODO FIXME";
-        // TODO get a string from the compressed bytes
-        match std::fs::write(path, compressed_byte_str) {
-            Ok(_) => (),
-            Err(m) => {
-                return Err(WyagError::new_with_error(
-                    "Failed to write GitObject to file. See inner error for more information.",
-                    Box::new(m),
-                ));
-            }
-        };
-    }
+    let mut c: GObj<'a> = match parse_object(&decoded) {
+        Ok(o) => o,
+        Err(m) => {
+            return Err(WyagError::new_with_error(
+                format!("Failed to parse object {}", sha).as_ref(),
+                Box::new(m),
+            ));
+        }
+    };
+    match &mut c {
+        GObj::Tag(o) => o.repo = Some(repo),
+        GObj::Commit(o) => o.repo = Some(repo),
+        GObj::Blob(o) => o.repo = Some(repo),
+        GObj::Tree(o) => o.repo = Some(repo),
+    };
 
-    Ok(outStr)
+    Ok(c)
 }
 
-fn object_find<'a>(
-    repo: &GitRepository,
-    name: &'a str,
-    fmt: Option<&str>,
-    follow: bool,
-) -> Result<Option<String>, WyagError> {
-    let rvec: Vec<String> = object_resolve(repo, name)?;
-    if rvec.len() == 0 {
-        let errStr = format!("No such reference: {}", &name);
-        return Err(WyagError::new(errStr.as_ref()));
-    }
-    if rvec.len() > 1 {
-        let errStr = format!(
-            "Ambiguous reference {0}: Candidates are:\n - {1}.",
-            &name,
-            rvec.join("\n - ")
-        );
-        return Err(WyagError::new(errStr.as_ref()));
-    }
-
-    let mut sha = rvec[0].to_owned();
-    if let None = fmt {
-        return Ok(Some(sha));
+/// Parses an already inflated object, `<type> <size>\0<payload>`, into the matching GObj.
+///
+/// The result isn't tied to a repository; object_read attaches one after reading from disk.
+fn parse_object(raw: &[u8]) -> Result<GObj<'static>, WyagError> {
+    let (dfmt, payload) = object_split(raw)?;
+    match ObjectType::from_bytes(&dfmt) {
+        Some(otype) => parse_payload(otype, payload.to_vec()),
+        None => Err(WyagError::new(
+            format!("Unknown object type {}", String::from_utf8_lossy(&dfmt)).as_ref(),
+        )),
     }
+}
 
-    loop {
-        let mut o = object_read(repo, sha.as_ref())?;
-        let fmtmatcher = match &o {
-            GObj::Blob(b) => String::from_utf8(b.fmt().to_vec()).unwrap(),
-            GObj::Commit(c) => String::from_utf8(c.fmt().to_vec()).unwrap(),
-            GObj::Tag(t) => String::from_utf8(t.fmt().to_vec()).unwrap(),
-            GObj::Tree(tr) => String::from_utf8(tr.fmt().to_vec()).unwrap(),
-        };
-        let fmtmatcher: &str = fmtmatcher.as_ref();
-        if fmtmatcher == fmt.unwrap() {
-            return Ok(Some(sha));
+/// Deserializes the payload of an object already known to be of type `otype`.
+fn parse_payload(otype: ObjectType, payload: Vec<u8>) -> Result<GObj<'static>, WyagError> {
+    let c: GObj;
+    match otype {
+        ObjectType::Commit => {
+            let mut o = GitCommit::new(None, &payload);
+            o.deserialize(payload)?;
+            c = GObj::Commit(o)
         }
-        if !follow {
-            return Ok(None);
+        ObjectType::Tree => {
+            let mut o = GitTree::new(None, &payload);
+            o.deserialize(payload)?;
+            c = GObj::Tree(o)
         }
-        /* follow tags */
-        match &o {
-            GObj::Tag(t) => sha = t.kvlm["object"][0].to_owned(),
-            GObj::Commit(c) => {
-                if fmtmatcher == "tree" {
-                    sha = c.kvlm["tree"][0].to_owned();
-                }
-            }
-            _ => return Ok(None),
+        ObjectType::Tag => {
+            let mut o = GitTag::new(None, &payload);
+            o.deserialize(payload)?;
+            c = GObj::Tag(o)
         }
-    }
+        ObjectType::Blob => c = GObj::Blob(GitBlob::new(None, &payload)),
+    };
+
+    Ok(c)
 }
 
-/// Resolve name to an object hash in repo.
-///
-/// This function is aware of:
+/// Reads object `sha` and returns its type and payload, without deserializing it into a GObj.
 ///
-/// - the HEAD literal
-/// - short and long hashes
-/// - tags
-/// - branches
-/// - remote branches
-fn object_resolve(repo: &GitRepository, name: &str) -> Result<Vec<String>, WyagError> {
-    let mut candidates: Vec<String> = Vec::new();
-    let hash_re = Regex::new(r"[0-9A-Fa-f]{1,16}$").unwrap();
-
-    /* Empty string? abort */
-    if name.trim().len() == 0 {
-        return Ok(candidates);
-    }
-
-    /* HEAD is nonambiguous */
-    if name == "HEAD" {
-        candidates.push(ref_resolve(repo, "HEAD")?);
-        return Ok(candidates);
-    }
-
-    if hash_re.is_match(name) {
-        let nlen = name.len();
-        let nlower = name.to_lowercase();
-        if nlen == 40 {
-            /* this is a complete hash */
-            candidates.push(nlower);
-            return Ok(candidates);
-        } else if nlen >= 4 {
-            /// This is a small hash 4 seems to be the minimal length
-            /// for git to consider something a short hash.
-            /// This limit is documented in man git-rev-parse
-            let prefix = nlower[0..2].as_ref();
-            let path = repo_dir_gr(repo, false, vec!["objects", prefix])?;
-            let rem: &str = nlower[2..].as_ref();
-            let mut i = std::fs::read_dir(path).expect("Failed to read path.");
-            while let Some(item) = i.next() {
-                match item {
-                    Ok(fd) => {
-                        let ff = fd.file_name();
-                        let fname: &str = ff.to_str().unwrap(); //.to_owned();
-                        if fname.starts_with(&rem) {
-                            let mut s = String::default();
-                            s.push_str(prefix);
-                            s.push_str(fname);
-                            candidates.push(s);
-                        }
-                    }
-                    Err(m) => {
-                        return Err(WyagError::new_with_error(
-                            "Failed to read item in directory",
-                            Box::new(m),
-                        ));
-                    }
-                };
-            }
-        }
+/// Unlike object_read_raw, types wyag doesn't know about are an error.
+fn read_raw(repo: &GitRepository, sha: &str) -> Result<(ObjectType, Vec<u8>), WyagError> {
+    let (dfmt, payload) = object_read_raw(repo, sha)?;
+    match ObjectType::from_bytes(&dfmt) {
+        Some(otype) => Ok((otype, payload)),
+        None => Err(WyagError::new(
+            format!(
+                "Unknown object type {} for object {}",
+                String::from_utf8_lossy(&dfmt),
+                sha
+            )
+            .as_ref(),
+        )),
     }
-
-    Ok(candidates)
 }
 
-pub fn cmd_rev_parse(name: &str, gtype: Option<&str>) -> Result<(), WyagError> {
-    let repo = match repo_find(".", false)? {
-        Some(gr) => gr,
-        None => {
-            println!("No repository was found, cannot use rev_parse");
-            return Ok(());
-        }
-    };
-
-    match object_find(&repo, name, gtype, true)? {
-        Some(s) => println!("{}", s),
-        None => println!(""),
-    };
+/// Reads and validates the header of object `sha`, returning its type string and payload
+/// without caring whether the type is one wyag knows about.
+fn object_read_raw(repo: &GitRepository, sha: &str) -> Result<(Vec<u8>, Vec<u8>), WyagError> {
+    // grab the object in question from the filesystem
+    let path = object_path(repo, sha);
 
-    Ok(())
-}
+    // read and inflate the file
+    let decoded = object_inflate(&path, sha, big_file_threshold(repo)?)?;
 
-pub fn cmd_cat_file(gtype: &str, obj: &str) -> Result<(), WyagError> {
-    let repo = repo_find(".", false)?;
-    cat_file(repo, gtype, obj)
+    match object_split(&decoded) {
+        Ok((dfmt, payload)) => Ok((dfmt.to_vec(), payload.to_vec())),
+        Err(m) => Err(WyagError::new_with_error(
+            format!("Failed to parse object {}", sha).as_ref(),
+            Box::new(m),
+        )),
+    }
 }
 
-fn cat_file<'a>(repo: Option<GitRepository<'_>>, gtype: &str, obj: &str) -> Result<(), WyagError> {
-    let repo = match repo {
-        Some(gr) => gr,
+/// Splits an inflated object into its type and payload, checking the size in its header.
+fn object_split(decoded: &[u8]) -> Result<(&[u8], &[u8]), WyagError> {
+    // the header ends at the first null
+    let yIdx = match decoded.iter().position(|&r| r == b'\x00') {
+        Some(i) => i,
         None => {
-            println!("No git repository was found, cannot cat-file");
-            return Ok(());
+            return Err(WyagError::new(
+                "Failed to decode git object - no null delimeter was found. Is this file corrupted?",
+            ));
         }
     };
-    let of = match object_find(&repo, obj, Some(gtype), true)? {
-        Some(s) => s,
+
+    // read the object type
+    let xIdx = match decoded[..yIdx].iter().position(|&r| r == b' ') {
+        Some(i) => i,
         None => {
-            println!("no object found for the type: {}", gtype);
-            return Ok(());
-        }
-    };
-    let o: Box<dyn GitObject> = match object_read(&repo, of.as_ref())? {
-        GObj::Blob(x) => Box::new(x),
-        GObj::Commit(y) => Box::new(y),
-        GObj::Tag(z) => Box::new(z),
-        GObj::Tree(a) => Box::new(a),
-        _ => return Err(WyagError::new("??")),
-    };
-    let s = (*o).serialize()?.to_vec();
-    let st = match String::from_utf8(s) {
-        Ok(s) => s,
-        Err(m) => {
-            return Err(WyagError::new_with_error(
-                "Failed to cat file, contained invalid characters",
-                Box::new(m),
+            return Err(WyagError::new(
+                "Failed to decode git object type - no space delimeter was found. Is this file corrupted?",
             ));
         }
     };
-    println!("{}", st);
-    Ok(())
+
+    // read and validate object size
+    let size: usize = match str::from_utf8(&decoded[xIdx + 1..yIdx]).map(|s| s.parse()) {
+        Ok(Ok(s)) => s,
+        _ => return Err(WyagError::new("Malformed object, size is not a number.")),
+    };
+    if size != decoded.len() - (yIdx + 1) {
+        return Err(WyagError::new("Malformed object, bad length."));
+    }
+
+    Ok((&decoded[..xIdx], &decoded[yIdx + 1..]))
 }
 
-pub fn cmd_hash_object(actually_write: bool, gtype: &str, path: &str) -> Result<(), WyagError> {
-    let mut grOpt: Option<GitRepository> = None;
-    if actually_write {
-        let repo = GitRepository::new(".", false)?;
-        grOpt = Some(repo);
+/// Reads just the `<type> <size>` header of object `sha`, without inflating the rest of it.
+///
+/// Results are kept in the repository's header cache, which is consulted before touching disk.
+fn object_read_header(repo: &GitRepository, sha: &str) -> Result<(String, usize), WyagError> {
+    if let Some(h) = repo.header_cache.borrow().get(sha) {
+        return Ok(h.clone());
     }
 
-    let mut fd = match std::fs::File::open(path) {
+    let path = object_path(repo, sha);
+    let fd = match std::fs::File::open(&path) {
         Ok(f) => f,
         Err(m) => {
             return Err(WyagError::new_with_error(
-                "Failed to open file at specified path for hash-object",
+                format!(
+                    "Failed to open git object file {}. This error happened before deflating.",
+                    sha
+                )
+                .as_ref(),
                 Box::new(m),
             ));
         }
     };
 
-    let sha1 = hash_object(&mut fd, gtype, grOpt)?;
-    println!("{}", sha1);
-    Ok(())
-}
-
-fn hash_object<'a>(
-    fd: &mut std::fs::File,
-    gitType: &str,
-    repo: Option<GitRepository<'_>>,
-) -> Result<String, WyagError> {
-    let mut bytes: Vec<u8> = Vec::new();
-    match fd.read_to_end(&mut bytes) {
-        Ok(_) => (),
-        Err(m) => {
-            return Err(WyagError::new_with_error(
-                "Failed to perform hash-object",
-                Box::new(m),
-            ));
+    // The header is short, so stop decoding as soon as its null terminator shows up
+    let z = flate2::bufread::ZlibDecoder::new(io::BufReader::new(fd));
+    let mut header: Vec<u8> = Vec::new();
+    for b in z.bytes().take(64) {
+        match b {
+            Ok(b'\x00') => break,
+            Ok(c) => header.push(c),
+            Err(m) => {
+                return Err(WyagError::new_with_error(
+                    format!("Failed to decode ZLIB encoded object file: {0}", sha).as_ref(),
+                    Box::new(m),
+                ));
+            }
         }
-    };
-    let bytes = bytes.as_slice();
+    }
 
-    let mut c: Box<GitObject>;
-    match gitType {
-        "commit" => c = Box::new(GitCommit::new(repo.as_ref(), bytes)),
-        "tree" => c = Box::new(GitTree::new(repo.as_ref(), bytes)),
-        "tag" => c = Box::new(GitTag::new(repo.as_ref(), bytes)),
-        "blob" => c = Box::new(GitBlob::new(repo.as_ref(), bytes)),
+    let xIdx = match header.iter().position(|&r| r == b' ') {
+        Some(i) => i,
+        None => return Err(WyagError::new(
+            format!("Failed decode git object type {}- no space delimeter was found. Is this file corrupted?", sha).as_ref(),
+        )),
+    };
+    let size: usize = match str::from_utf8(&header[xIdx + 1..]).map(|s| s.parse()) {
+        Ok(Ok(s)) => s,
         _ => {
             return Err(WyagError::new(
-                format!("Unknown type {}!", gitType).as_ref(),
+                format!("Malformed object {}, size is not a number.", sha).as_ref(),
             ));
         }
     };
 
-    object_write(&*c, true)
+    let h = (String::from_utf8_lossy(&header[..xIdx]).into_owned(), size);
+    repo.header_cache
+        .borrow_mut()
+        .insert(sha.to_owned(), h.clone());
+    Ok(h)
 }
 
-// EndRegion: Reading/Writing Objects
-
-/// Region: Log
-
-pub fn cmd_log(commit: &str) -> Result<(), WyagError> {
-    let repo = match repo_find(".", false)? {
-        Some(gr) => gr,
-        None => {
-            println!("No repository was found, cannot use wyag-log");
-            return Ok(());
-        }
+/// Loose objects bigger than this on disk are streamed through the decoder instead of being read whole first,
+/// unless `core.bigFileThreshold` says otherwise. This is git's own default.
+const BIG_FILE_THRESHOLD_DEFAULT: u64 = 512 * 1024 * 1024;
+
+/// Reads `core.bigFileThreshold`, which may carry a `k`, `m` or `g` suffix.
+fn big_file_threshold(repo: &GitRepository) -> Result<u64, WyagError> {
+    let raw = match repo.conf.section(Some("core".to_owned())) {
+        Some(core) => core
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("bigFileThreshold"))
+            .map(|(_, v)| v.to_owned()),
+        None => None,
     };
-
-    println!("digraph wyaglog{{");
-    let o = object_find(&repo, commit, None, true)?;
-    if let None = o {
-        println!("No such object: {}", commit);
+    match raw {
+        None => Ok(BIG_FILE_THRESHOLD_DEFAULT),
+        Some(r) => match config_parse_size(&r) {
+            Some(n) => Ok(n),
+            None => Err(WyagError::new(
+                format!("Bad numeric config value '{}' for core.bigFileThreshold", r).as_ref(),
+            )),
+        },
     }
-    let mut v: Vec<String> = Vec::new();
-    log_graphviz(&repo, String::from(o.unwrap()), &mut v)?;
-    println!("}}");
-    Ok(())
 }
 
-fn log_graphviz<'a>(
-    repo: &GitRepository,
-    sha: String,
-    seen: &mut Vec<String>,
-) -> Result<(), WyagError> {
-    if seen.contains(&sha) {
-        return Ok(());
-    }
-    let sha2 = sha.clone();
-    seen.push(sha);
-    let commit: GitCommit = match object_read(repo, sha2.as_ref())? {
-        GObj::Commit(y) => y,
-        _ => return Err(WyagError::new("??")),
+/// Parses a size as git config writes them: a number, optionally followed by `k`, `m` or `g` (powers of 1024).
+fn config_parse_size(raw: &str) -> Option<u64> {
+    let raw = raw.trim();
+    let (digits, unit) = match raw.chars().last() {
+        Some('k') | Some('K') => (&raw[..raw.len() - 1], 1024),
+        Some('m') | Some('M') => (&raw[..raw.len() - 1], 1024 * 1024),
+        Some('g') | Some('G') => (&raw[..raw.len() - 1], 1024 * 1024 * 1024),
+        _ => (raw, 1),
     };
-
-    /* Base Case: the initial commit. */
-    let cc = commit.kvlm.clone();
-    if !commit.kvlm.contains_key("parent") {
-        return Ok(());
+    match digits.parse::<u64>() {
+        Ok(n) => n.checked_mul(unit),
+        Err(_) => None,
     }
+}
 
-    /* Recurse Case */
-    let parents = cc["parents"].clone();
-    for p in parents {
-        println!("c_{} -> c_{}", sha2, &p);
-        match log_graphviz(repo, p, seen) {
-            Ok(_) => (),
-            Err(m) => return Err(m),
-        };
+/// Reads and inflates the loose object file at `path`.
+///
+/// Files up to `threshold` bytes are slurped into memory and decoded, bigger ones are decoded straight off
+/// the disk so that the compressed and decompressed copies aren't both held in memory at once.
+fn object_inflate(path: &Path, sha: &str, threshold: u64) -> Result<Vec<u8>, WyagError> {
+    if object_inflate_streams(path, sha, threshold)? {
+        object_inflate_stream(path, sha)
+    } else {
+        object_inflate_slurp(path, sha)
     }
-
-    Ok(())
 }
 
-fn kvlm_parse(
-    raw: Vec<u8>,
-    start: usize,
-    dict: &mut LinkedHashMap<String, Vec<String>>,
-) -> &LinkedHashMap<String, Vec<String>> {
-    // Finding the first space
-    let space = raw.iter().skip(start).position(|&r| r == b' ');
-
-    // Finding the first newline
-    let newline = raw.iter().skip(start).position(|&r| r == b'\n');
-
-    // If a space appears before a newline, we have a new Key value
-
-    // Base Case
-    // ====
-    // If newline appears first, (or there is no space at all, in which case return -1),
-    // we assume a blank line. A blank line means the remainder of the data is the message
-
-    if space.is_none() || newline.unwrap() < space.unwrap() {
-        assert_eq!(newline.unwrap(), start);
-        let key = "".to_owned();
-        let value = match str::from_utf8(&raw[start + 1..]) {
-            Ok(s) => s.to_owned(),
-            Err(m) => return dict,
-        };
-        dict.insert(key, vec![value]);
-        return dict;
+/// Whether `object_inflate` takes the streaming path for the file at `path`.
+fn object_inflate_streams(path: &Path, sha: &str, threshold: u64) -> Result<bool, WyagError> {
+    match std::fs::metadata(path) {
+        Ok(md) => Ok(md.len() > threshold),
+        Err(m) => Err(WyagError::new_with_error(
+            format!(
+                "Failed to read git object file {}. This error happened before deflating.",
+                sha
+            )
+            .as_ref(),
+            Box::new(m),
+        )),
     }
+}
 
-    // Recursive Case
-    // ===
-    // We read the key-value pair and recurse for the next
-    let key = match str::from_utf8(&raw[start..space.unwrap()]) {
-        Ok(s) => s.to_owned(),
+fn object_inflate_slurp(path: &Path, sha: &str) -> Result<Vec<u8>, WyagError> {
+    // read the raw bytes of the file.
+    let raw = match std::fs::read(path) {
+        Ok(bv) => bv,
         Err(m) => {
-            panic!("Failed to parse key in kvlm");
-            // return Err(WyagError::new_with_error(
-            //     "Failed to parse key in kvlm",
-            //     Box::new(m),
-            // ));
+            return Err(WyagError::new_with_error(
+                format!(
+                    "Failed to read git object file {}. This error happened before deflating.",
+                    sha
+                )
+                .as_ref(),
+                Box::new(m),
+            ));
         }
     };
 
-    // Find the end of the value.  Continuation lines begin with a
-    // space, so we loop until we find a "\n" not followed by a space.
-    let mut end = start;
-    loop {
-        match raw.iter().skip(end + 1).position(|&r| r == b'\n') {
-            Some(i) => end = i,
-            None => break,
-        }
-        if raw[end + 1] != b' ' {
-            break;
-        }
-    }
-
-    // Grab the value
-    // Also, drop the leading space on continuation lines
-    let rVal = raw[space.unwrap() + 1..end].to_vec();
-    let mut value: String = String::from_utf8(rVal).unwrap();
-    value = value.replace("\n ", "\n");
-
-    // Don't overwrite values
-    if dict.contains_key(&key) {
-        let x = dict.get_mut(&key).unwrap();
-        x.push(String::from(value));
+    // decode the zlib enconded data
+    match decode_reader(raw) {
+        Ok(s) => Ok(s),
+        Err(m) => Err(WyagError::new_with_error(
+            format!("Failed to decode ZLIB encoded byte array: {0}", sha).as_ref(),
+            Box::new(m),
+        )),
     }
-
-    kvlm_parse(raw, end + 1, dict)
 }
 
-fn kvlm_serialize(hm: &LinkedHashMap<String, Vec<String>>) -> String {
-    let mut ret = "".to_owned();
-    let mut main = String::new();
-
-    // Output Fields
-    for (k, v) in hm.iter() {
-        // Skip the message itself
-        if k == "" {
-            main = String::from(v[0].as_ref());
-            continue;
-        }
-        for val in v {
-            ret.push_str(" ");
-            ret.push_str(val.replace("\n", "\n ").as_ref());
-            ret.push('\n');
+fn object_inflate_stream(path: &Path, sha: &str) -> Result<Vec<u8>, WyagError> {
+    let fd = match std::fs::File::open(path) {
+        Ok(f) => f,
+        Err(m) => {
+            return Err(WyagError::new_with_error(
+                format!(
+                    "Failed to open git object file {}. This error happened before deflating.",
+                    sha
+                )
+                .as_ref(),
+                Box::new(m),
+            ));
         }
-    }
-
-    // append message
-    ret.push('\n');
-    ret.push_str(main.as_ref());
-
-    ret
-}
-
-#[cfg(test)]
-mod parse_log_tests {
-    use super::*;
+    };
 
-    #[test]
-    fn parse_empty_log() {
-        let s = "";
-        let mut hm: LinkedHashMap<String, Vec<String>> = LinkedHashMap::new();
-        kvlm_parse(s.as_bytes().to_vec(), 0, &mut hm);
-        assert_eq!(hm.len(), 0);
+    let mut z = flate2::bufread::ZlibDecoder::new(io::BufReader::new(fd));
+    let mut byteBuf: Vec<u8> = Vec::new();
+    match z.read_to_end(&mut byteBuf) {
+        Ok(_) => Ok(byteBuf),
+        Err(m) => Err(WyagError::new_with_error(
+            format!("Failed to decode ZLIB encoded object file: {0}", sha).as_ref(),
+            Box::new(m),
+        )),
     }
 }
 
-/// EndRegion: Log
-
-/// Region: Tree
+fn decode_reader(bytes: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    let mut z = ZlibDecoder::new(&bytes[..]);
+    let mut byteBuf: Vec<u8> = Vec::new();
+    z.read_to_end(&mut byteBuf)?;
+    Ok(byteBuf)
+}
 
-struct GitTreeLeaf {
-    mode: Vec<u8>,
-    path: Vec<u8>,
+/// Result of `object_write`: the object's SHA, and where it was stored if it was actually written.
+struct WrittenObject {
     sha: String,
+    path: Option<PathBuf>,
 }
 
-fn tree_parse_one(raw: &[u8], start: usize) -> Result<(usize, GitTreeLeaf), WyagError> {
-    /* Find the space terminator for the File Mode */
-    let x = match raw.iter().skip(start).position(|&r| r == b' ') {
-        Some(i) => i,
-        None => {
-            return Err(WyagError::new(
-                "no space found in raw byte stream of tree parse",
-            ));
-        }
-    };
-    assert!(x - start == 5 || x - start == 6);
-
-    /* Read the File Mode */
-    let mode = raw[start..x].to_vec();
-
-    /* Find the NULL terminator for the path */
-    let y = match raw.iter().skip(start).position(|&r| r == b'\x00') {
-        Some(i) => i,
-        None => {
-            return Err(WyagError::new(
-                "no null terminator found in raw byte stream of tree parse",
-            ));
-        }
-    };
-
-    /* and read the path */
-    let path = raw[x + 1..y].to_vec();
-
-    /* read the SHA1 and convert to a hex string */
-    let sha_raw = raw[y + 1..y + 21].to_vec();
-    let sha_u32 = sha_parse_u32(&sha_raw);
-    let sha_str = sha_parse_str(sha_u32);
-
-    let pos = y + 21;
-    let data: GitTreeLeaf = GitTreeLeaf {
-        mode: mode,
-        path: path,
-        sha: sha_str,
-    };
-    Ok((pos, data))
+/// Writes the GitObject to its appropriate location in the repo
+/// 4.4
+fn object_write(obj: &GitObject, actually_write: bool) -> Result<WrittenObject, WyagError> {
+    // serialize the data
+    let data = obj.serialize()?;
+    object_write_raw(obj.repo(), obj.fmt(), &data, actually_write)
 }
 
-fn tree_parse(raw: &[u8]) -> Result<Vec<GitTreeLeaf>, WyagError> {
-    let mut pos: usize = 0;
-    let max: usize = raw.len();
-    let mut v: Vec<GitTreeLeaf> = Vec::new();
-
-    while pos < max {
-        let (pos_m, data) = tree_parse_one(raw, pos)?;
-        pos += pos_m;
-        v.push(data);
-    }
-
-    Ok(v)
+/// Hashes `payload` as an object of type `otype`, and writes it into the repository if `actually_write`.
+///
+/// The payload is taken as already serialized, so importers and tests can store content as-is.
+pub fn write_raw_object(
+    repo: &GitRepository,
+    otype: ObjectType,
+    payload: &[u8],
+    actually_write: bool,
+) -> Result<String, WyagError> {
+    Ok(object_write_raw(Some(repo), otype, payload, actually_write)?.sha)
 }
 
-fn tree_serialize(tree: &GitTree) -> Result<Vec<u8>, WyagError> {
-    let mut ret: Vec<u8> = Vec::new();
-
-    for g in &tree.items {
-        ret.extend(g.mode.iter());
-        ret.push(b' ');
-        ret.extend(g.path.iter());
-        ret.push(b'\x00');
-        let i = u32::from_str_radix(&g.sha, 16);
+fn object_write_raw(
+    repo: Option<&GitRepository>,
+    otype: ObjectType,
+    data: &[u8],
+    actually_write: bool,
+) -> Result<WrittenObject, WyagError> {
+    let size = data.len() as u64;
+    let hashed = object_write_stream(repo, otype, &mut &data[..], size, false)?;
+    if !actually_write {
+        return Ok(hashed);
     }
-
-    Ok(ret)
-}
-
-/// TODO TEST ME
-fn sha_parse_u32(v: &Vec<u8>) -> u32 {
-    let mut buff: [u8; 4] = [0, 0, 0, 0];
-    let mut sha: u32 = 0;
-    for (i, byte) in v.iter().enumerate() {
-        if i % 4 == 0 {
-            sha += u32::from_be_bytes(buff);
-            buff = [0, 0, 0, 0];
+    // Objects are immutable, so one that is already stored needs no recompressing
+    if let Some(gr) = repo {
+        if object_exists(gr, &hashed.sha) {
+            return Ok(WrittenObject {
+                path: Some(object_path(gr, &hashed.sha)),
+                sha: hashed.sha,
+            });
         }
-        buff[i % 4] = *byte;
     }
-    sha
+    object_write_stream(repo, otype, &mut &data[..], size, true)
 }
 
-/// TODO TEST ME
-fn sha_parse_str(i: u32) -> String {
-    format!("{:x}", i)
-}
+/// Size of the chunks `object_write_stream` reads its payload in.
+const OBJECT_STREAM_CHUNK: usize = 64 * 1024;
 
-pub fn cmd_ls_tree(name: &str) -> Result<(), WyagError> {
-    let repo = match repo_find(".", false)? {
-        Some(gr) => gr,
-        None => {
-            println!("No repository was found, cannot use wyag-log");
-            return Ok(());
-        }
-    };
+/// The temporary file an object is streamed into. It is deleted when dropped, unless `kept` was set
+/// once the file was moved into place, so no early return can leave it behind in `objects/`.
+struct TempObjectFile {
+    path: PathBuf,
+    kept: bool,
+}
 
-    let of = match object_find(&repo, name, Some("tree"), true)? {
-        Some(s) => s,
-        None => {
-            println!("no object found for the type: {}", "tree");
-            return Ok(());
-        }
-    };
-    let tree: GitTree = match object_read(&repo, of.as_ref())? {
-        GObj::Tree(a) => a,
-        _ => {
-            return Err(WyagError::new(
-                "Expected to retrieve a Tree, but received some other type instead",
-            ));
+impl Drop for TempObjectFile {
+    fn drop(&mut self) {
+        if !self.kept {
+            let _ = std::fs::remove_file(&self.path);
         }
-    };
+    }
+}
 
-    for item in tree.items {
-        let mode_a: String = String::from_utf8(item.mode).unwrap();
-        let mut first: String = "0".repeat(6);
-        first.push_str(mode_a.as_ref());
-        /* Git's ls-tree displays the type of the object pointed to. */
-        let om = match object_read(&repo, item.sha.as_ref())? {
-            GObj::Tree(a) => a.fmt().to_vec(),
-            GObj::Tag(t) => t.fmt().to_vec(),
-            GObj::Blob(b) => b.fmt().to_vec(),
-            GObj::Commit(c) => c.fmt().to_vec(),
-            _ => {
+/// Hashes (and optionally writes) an object whose `size`-byte payload is read from `payload` in fixed-size chunks,
+/// so that peak memory stays bounded no matter how large the payload is.
+///
+/// Since the object's path depends on its sha, the compressed object is streamed into a temporary file
+/// under `objects/` and moved into place once the hash is known.
+fn object_write_stream(
+    repo: Option<&GitRepository>,
+    otype: ObjectType,
+    payload: &mut Read,
+    size: u64,
+    actually_write: bool,
+) -> Result<WrittenObject, WyagError> {
+    // Add header
+    let mut header: Vec<u8> = Vec::new();
+    header.extend(otype.as_bytes());
+    header.extend(vec![b' ']);
+    header.extend(size.to_string().into_bytes());
+    header.extend(vec![b'\x00']);
+
+    // The encoder comes first so its file is closed before the guard deletes it
+    let mut encoder: Option<(ZlibEncoder<std::fs::File>, TempObjectFile)> = None;
+    if actually_write {
+        let repo = match repo {
+            Some(r) => r,
+            None => {
                 return Err(WyagError::new(
-                    "Failed when retrieving object type during ls-tree",
+                    "Cannot write an object without a repository to write it to",
                 ));
             }
         };
-        let second = match String::from_utf8(om) {
-            Ok(s) => s,
+        require_writable(repo)?;
+        let tmp_name = format!(
+            "tmp_obj_{}_{}",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or(0)
+        );
+        let tmp = repo_file_gr(repo, true, vec!["objects", &tmp_name])?;
+        let f = match std::fs::File::create(&tmp) {
+            Ok(f) => f,
             Err(m) => {
                 return Err(WyagError::new_with_error(
-                    "Failed to parse item type in ls-tree.",
+                    "Failed to create temporary object file",
                     Box::new(m),
                 ));
             }
         };
+        let tmp = TempObjectFile {
+            path: tmp,
+            kept: false,
+        };
+        encoder = Some((ZlibEncoder::new(f, Compression::default()), tmp));
+    }
 
-        let fourth = match String::from_utf8(item.path) {
-            Ok(s) => s,
+    // compute hash, compressing alongside
+    let mut sha = crypto::sha1::Sha1::new();
+    sha.input(&header);
+    if let Some((e, _)) = encoder.as_mut() {
+        if let Err(m) = e.write_all(&header) {
+            return Err(WyagError::new_with_error(
+                "failed to zlib compress object",
+                Box::new(m),
+            ));
+        }
+    }
+    let mut buf = vec![0u8; OBJECT_STREAM_CHUNK];
+    let mut read_total: u64 = 0;
+    loop {
+        let n = match payload.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref m) if m.kind() == io::ErrorKind::Interrupted => continue,
             Err(m) => {
                 return Err(WyagError::new_with_error(
-                    "Failed to parse item path in ls-tree.",
+                    "Failed to read object payload",
                     Box::new(m),
                 ));
             }
         };
-
-        println!("{} {} {}\t{}", first, second, item.sha, fourth);
+        read_total += n as u64;
+        sha.input(&buf[..n]);
+        if let Some((e, _)) = encoder.as_mut() {
+            if let Err(m) = e.write_all(&buf[..n]) {
+                return Err(WyagError::new_with_error(
+                    "failed to zlib compress object",
+                    Box::new(m),
+                ));
+            }
+        }
+    }
+    if read_total != size {
+        return Err(WyagError::new(
+            format!(
+                "Object payload was {} bytes, but {} were expected. Did the file change while it was being hashed?",
+                read_total, size
+            )
+            .as_ref(),
+        ));
     }
+    let outStr = sha.result_str();
+    let mut written: Option<PathBuf> = None;
 
-    Ok(())
-}
+    if let Some((e, mut tmp)) = encoder {
+        match e.finish() {
+            Ok(_) => (),
+            Err(m) => {
+                return Err(WyagError::new_with_error(
+                    "Failed to finish zlib compressing object",
+                    Box::new(m),
+                ));
+            }
+        };
 
-#[cfg(test)]
-mod tree_tests {
+        // compute path
+        let path = repo_file_gr(
+            repo.unwrap(),
+            true,
+            vec!["objects", &outStr[..2], &outStr[2..]],
+        )?;
+        // A streamed payload's sha is only known now; keep any copy that is already stored
+        if path.is_file() {
+            return Ok(WrittenObject {
+                sha: outStr,
+                path: Some(path),
+            });
+        }
+        match std::fs::rename(&tmp.path, &path) {
+            Ok(_) => tmp.kept = true,
+            Err(m) => {
+                return Err(WyagError::new_with_error(
+                    "Failed to write GitObject to file. See inner error for more information.",
+                    Box::new(m),
+                ));
+            }
+        };
+        written = Some(path);
+    }
 
-    #[test]
-    fn treeTest() {}
+    Ok(WrittenObject {
+        sha: outStr,
+        path: written,
+    })
 }
 
-/// EndRegion: Tree
-
-/// Region: Checkout
+/// Checks whether `sha` is present as a loose object, without reading or decompressing it.
+fn object_exists(repo: &GitRepository, sha: &str) -> bool {
+    if sha.len() < 3 || !sha.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return false;
+    }
+    object_path(repo, &sha.to_lowercase()).is_file()
+}
 
-pub fn cmd_checkout(sha: &str, path: &str) -> Result<(), WyagError> {
-    let repo = match repo_find(".", false)? {
-        Some(gr) => gr,
-        None => {
-            println!("No repository was found, cannot use wyag-checkout");
-            return Ok(());
+/// Where the loose object `sha` lives: in this repository's `objects/` if it is there, else in the first
+/// alternate object directory holding it. When nobody has it, the local path is returned.
+fn object_path(repo: &GitRepository, sha: &str) -> PathBuf {
+    let local = repo_path_gr(repo, vec!["objects", &sha[..2], &sha[2..]]);
+    if local.is_file() {
+        return local;
+    }
+    for dir in object_alternates(repo) {
+        let p = dir.join(&sha[..2]).join(&sha[2..]);
+        if p.is_file() {
+            return p;
         }
-    };
+    }
+    local
+}
 
-    let of = match object_find(&repo, sha, None, true)? {
-        Some(s) => s,
-        None => {
-            println!("no object found for the type: {}", "commit");
-            return Ok(());
-        }
+/// Decodes a string of hex digits, two per byte, in either case.
+fn hex_to_bytes(s: &str) -> Result<Vec<u8>, WyagError> {
+    if s.len() % 2 != 0 {
+        return Err(WyagError::new(
+            format!("'{}' has an odd number of hex digits", s).as_ref(),
+        ));
+    }
+    let digit = |c: u8| match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
     };
-
-    let o: GitTree = match object_read(&repo, of.as_ref())? {
-        // GObj::Blob(x) => Box::new(x),
-        GObj::Commit(y) => match object_read(&repo, y.kvlm.get("tree").unwrap()[0].as_ref()) {
-            Ok(gobj) => match gobj {
-                GObj::Tree(gobj) => gobj,
-                _ => {
-                    return Err(WyagError::new(
-                        "Expected a tree from this commit, but failed to retreive one",
-                    ));
-                }
-            },
-            Err(m) => {
-                return Err(WyagError::new_with_error(
-                    "Expected commit to contain a tree with the value 'tree' but got nothing",
-                    Box::new(m),
+    let mut ret: Vec<u8> = Vec::with_capacity(s.len() / 2);
+    for pair in s.as_bytes().chunks(2) {
+        match (digit(pair[0]), digit(pair[1])) {
+            (Some(hi), Some(lo)) => ret.push(hi << 4 | lo),
+            _ => {
+                return Err(WyagError::new(
+                    format!("'{}' is not a hex string", s).as_ref(),
                 ));
             }
-        },
-        // GObj::Tag(z) => Box::new(z),
-        GObj::Tree(a) => a,
-        _ => {
-            return Err(WyagError::new(
-                "encountered an error trying to read object in cmd_checkout. Expected a tree object or a commit object, got something else",
-            ));
         }
-    };
+    }
+    Ok(ret)
+}
 
-    /* Verify path is empty directory */
-    let p: PathBuf = PathBuf::from(path);
-    if p.exists() {
-        if !p.is_dir() {
-            return Err(WyagError::new("Supplied path was not a directory"));
-        } else if let Some(_x) = std::fs::read_dir(&p)
-            .expect("can't view this directory. Do you have permission?")
-            .next()
+/// Encodes `b` as lowercase hex, the way object names are written.
+fn bytes_to_hex(b: &[u8]) -> String {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut ret = String::with_capacity(b.len() * 2);
+    for byte in b {
+        ret.push(DIGITS[(byte >> 4) as usize] as char);
+        ret.push(DIGITS[(byte & 0xf) as usize] as char);
+    }
+    ret
+}
+
+/// The tree of a commit that has no files at all, in a SHA-1 repository.
+const EMPTY_TREE: &str = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+/// The blob of an empty file, in a SHA-1 repository.
+const EMPTY_BLOB: &str = "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391";
+const EMPTY_TREE_SHA256: &str = "6ef19b41225c5369f1c104d45d8d85efa9b057b53b14b4b9b939dd74decc5321";
+const EMPTY_BLOB_SHA256: &str = "473a0f4c3be8a93681a267e3b1e9a7dcda1185436fe141f7749120a303721813";
+
+/// Whether the repository names its objects by SHA-256, per `extensions.objectFormat`. SHA-1 otherwise.
+fn object_format_is_sha256(repo: &GitRepository) -> bool {
+    match repo.conf.section(Some("extensions".to_owned())) {
+        Some(ext) => ext.iter().any(|(k, v)| {
+            k.eq_ignore_ascii_case("objectFormat") && v.eq_ignore_ascii_case("sha256")
+        }),
+        None => false,
+    }
+}
+
+/// Errors if `extensions.objectFormat` names an algorithm other than sha1 or sha256.
+fn object_format_validate(repo: &GitRepository) -> Result<(), WyagError> {
+    let ext = match repo.conf.section(Some("extensions".to_owned())) {
+        Some(ext) => ext,
+        None => return Ok(()),
+    };
+    for (k, v) in ext.iter() {
+        if k.eq_ignore_ascii_case("objectFormat")
+            && !v.eq_ignore_ascii_case("sha1")
+            && !v.eq_ignore_ascii_case("sha256")
         {
             return Err(WyagError::new(
-                "Cannot create Git object directory, su pplied path is not empty.",
+                format!("Unknown object format {}, expected sha1 or sha256", v).as_ref(),
             ));
         }
     }
-    if let Err(m) = std::fs::create_dir(&p) {
-        return Err(WyagError::new_with_error(
-            "Failed to checkout git object: Error creating directory path",
-            Box::new(m),
-        ));
+    Ok(())
+}
+
+/// How many hex digits an object name has under the repository's hash algorithm.
+fn object_hex_width(repo: &GitRepository) -> usize {
+    if object_format_is_sha256(repo) {
+        64
+    } else {
+        40
+    }
+}
+
+/// Checks the repository for what wyag can tell is damaged: so far, loose objects named for the wrong hash algorithm.
+pub fn cmd_fsck() -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            return Err(WyagError::new(
+                "No repository was found, cannot use wyag-fsck",
+            ));
+        }
     };
 
-    tree_checkout(&repo, o, path)
+    object_format_check(&repo)
 }
 
-fn tree_checkout(repo: &GitRepository, tree: GitTree, path: &str) -> Result<(), WyagError> {
-    for item in tree.items {
-        let path_utf8 = match String::from_utf8(item.path) {
-            Ok(s) => s,
+/// Checks that every loose object is named with the width of the repository's hash algorithm.
+///
+/// A store holding both widths was only partly migrated (or is corrupt), and can't be read reliably.
+/// Files under `objects/` that aren't hex names, like in-progress writes, are not considered.
+fn object_format_check(repo: &GitRepository) -> Result<(), WyagError> {
+    let width = object_hex_width(repo);
+    let objects = repo_path_gr(repo, vec!["objects"]);
+    let dirs = match std::fs::read_dir(&objects) {
+        Ok(d) => d,
+        Err(_) => return Ok(()),
+    };
+    let mut bad: Vec<String> = Vec::new();
+    for dir in dirs.filter_map(|d| d.ok()) {
+        let prefix = dir.file_name().to_string_lossy().into_owned();
+        if prefix.len() != 2 || !prefix.bytes().all(|b| b.is_ascii_hexdigit()) {
+            continue;
+        }
+        let files = match std::fs::read_dir(dir.path()) {
+            Ok(f) => f,
             Err(m) => {
                 return Err(WyagError::new_with_error(
-                    "Failed to parse item path tree_checkout.",
+                    "Failed to read the objects directory",
                     Box::new(m),
                 ));
             }
         };
-
-        let dest: PathBuf = PathBuf::from(path).join(path_utf8);
-
-        match object_read(&repo, &item.sha)? {
-            GObj::Tree(a) => {
-                if let Err(m) = std::fs::create_dir(&dest) {
-                    return Err(WyagError::new_with_error(
-                        "Failed to create destination folder during tree_checkout",
-                        Box::new(m),
-                    ));
-                };
-                tree_checkout(&repo, a, dest.to_str().unwrap())?;
-            }
-            GObj::Blob(b) => {
-                if let Err(m) = std::fs::write(dest, b.blob_data) {
-                    return Err(WyagError::new_with_error(
-                        "Failed to write blob data to disk during tree_checkout",
-                        Box::new(m),
-                    ));
-                }
-            }
-            _ => {
-                return Err(WyagError::new(
-                    "Expected to retrieve a Tree or a Blob, but received some other type instead",
-                ));
+        for f in files.filter_map(|f| f.ok()) {
+            let rest = f.file_name().to_string_lossy().into_owned();
+            if rest.bytes().all(|b| b.is_ascii_hexdigit()) && rest.len() + 2 != width {
+                bad.push(format!("{}{}", prefix, rest));
             }
-        };
+        }
     }
-
-    Ok(())
+    if bad.len() == 0 {
+        return Ok(());
+    }
+    bad.sort();
+    Err(WyagError::new(
+        format!(
+            "The object store mixes hash algorithms: expected {}-digit {} object names, but found {}",
+            width,
+            if width == 64 { "sha256" } else { "sha1" },
+            bad.join(", ")
+        )
+        .as_ref(),
+    ))
 }
-/// EndRegion: Checkout
-
-/// Region: Ref
 
-fn ref_resolve(repo: &GitRepository, ref_str: &str) -> Result<String, WyagError> {
-    let path = repo_file_gr(&repo, false, vec![ref_str])?;
-    let s = match std::fs::read_to_string(path) {
-        Ok(s) => s.trim().to_owned(),
-        Err(m) => {
-            return Err(WyagError::new_with_error(
-                "Failed to read file",
-                Box::new(m),
-            ));
-        }
-    };
-    if s.starts_with("ref: ") {
-        return ref_resolve(repo, s.as_ref());
+/// The name of the empty tree under the repository's hash algorithm.
+///
+/// It is well known, so it can be compared against or used without the tree being in the object store.
+fn empty_tree_sha(repo: &GitRepository) -> &'static str {
+    if object_format_is_sha256(repo) {
+        EMPTY_TREE_SHA256
     } else {
-        return Ok(s.to_owned());
+        EMPTY_TREE
     }
 }
 
-enum RefType {
-    RefTypeSha(String),
-    RefTypeDict(LinkedHashMap<String, RefType>),
+/// The name of the empty blob under the repository's hash algorithm.
+///
+/// Like the empty tree, it is well known, so checkout writes an empty file for it without reading the store.
+fn empty_blob_sha(repo: &GitRepository) -> &'static str {
+    if object_format_is_sha256(repo) {
+        EMPTY_BLOB_SHA256
+    } else {
+        EMPTY_BLOB
+    }
 }
 
-fn ref_list(
-    repo: &GitRepository,
-    path: Option<&str>,
-) -> Result<LinkedHashMap<String, RefType>, WyagError> {
-    let realPath: PathBuf = match path {
-        Some(p) => PathBuf::from(p),
-        None => repo_dir_gr(repo, false, vec!["refs"])?,
+/// The extra object directories listed in `objects/info/alternates`, one per line.
+///
+/// Relative entries are relative to this repository's `objects/` directory, as git resolves them.
+/// A missing or unreadable alternates file just means there are none.
+fn object_alternates(repo: &GitRepository) -> Vec<PathBuf> {
+    let objects = repo_path_gr(repo, vec!["objects"]);
+    let raw = match std::fs::read_to_string(objects.join("info").join("alternates")) {
+        Ok(s) => s,
+        Err(_) => return Vec::new(),
     };
+    raw.lines()
+        .map(|l| l.trim())
+        .filter(|l| l.len() > 0 && !l.starts_with('#'))
+        .map(|l| objects.join(l))
+        .collect()
+}
 
-    let mut ret: LinkedHashMap<String, RefType> = LinkedHashMap::new();
-
-    // Git shows refs sorted.  To do the same, we use
-    // a LinkedHashMap and sort the output of the directory read
-    let mut i = std::fs::read_dir(realPath).expect("Failed to read path.");
-    while let Some(item) = i.next() {
-        let can = match item {
-            Ok(fd) => fd,
+/// Lists the SHA of every loose object in the repository, in sorted order.
+///
+/// Packed objects are not included. Anything under `objects/` not shaped like a loose object is skipped.
+pub fn loose_objects(repo: &GitRepository) -> Result<impl Iterator<Item = String>, WyagError> {
+    let mut ret: Vec<String> = Vec::new();
+    let width = object_hex_width(repo);
+    let objects = repo_path_gr(repo, vec!["objects"]);
+    let dirs = match std::fs::read_dir(&objects) {
+        Ok(d) => d,
+        Err(m) => {
+            return Err(WyagError::new_with_error(
+                "Failed to read the objects directory",
+                Box::new(m),
+            ));
+        }
+    };
+    for dir in dirs {
+        let dir = match dir {
+            Ok(d) => d,
             Err(m) => {
                 return Err(WyagError::new_with_error(
                     "Failed to read item in directory",
@@ -1423,144 +1911,8595 @@ fn ref_list(
                 ));
             }
         };
-
-        let cf = can
-            .file_name()
-            .to_str()
-            .expect("Failed to unpack OsString while reading ref_list")
-            .to_owned();
-        if can.path().is_dir() {
-            let r = ref_list(repo, Some(can.path().to_str().unwrap()))?;
-            ret.insert(cf, RefType::RefTypeDict(r));
-        } else {
-            ret.insert(
-                cf.clone(),
-                RefType::RefTypeSha(ref_resolve(&repo, cf.as_ref())?),
-            );
+        let prefix = dir.file_name().to_string_lossy().into_owned();
+        if prefix.len() != 2 || !prefix.bytes().all(|b| b.is_ascii_hexdigit()) {
+            continue;
+        }
+        let files = match std::fs::read_dir(dir.path()) {
+            Ok(f) => f,
+            Err(m) => {
+                return Err(WyagError::new_with_error(
+                    "Failed to read the objects directory",
+                    Box::new(m),
+                ));
+            }
+        };
+        for f in files {
+            let f = match f {
+                Ok(f) => f,
+                Err(m) => {
+                    return Err(WyagError::new_with_error(
+                        "Failed to read item in directory",
+                        Box::new(m),
+                    ));
+                }
+            };
+            let rest = f.file_name().to_string_lossy().into_owned();
+            if rest.len() + 2 == width && rest.bytes().all(|b| b.is_ascii_hexdigit()) {
+                ret.push(format!("{}{}", prefix, rest).to_lowercase());
+            }
         }
     }
-    Ok(ret)
+    ret.sort();
+    Ok(ret.into_iter())
 }
 
+/// Like `loose_objects`, but only yields objects of type `otype`.
 ///
-/// with_hash should be default true
-/// predix should be default empty string
-fn show_ref(
+/// Only each object's header is inflated to decide its type, so large blobs are cheap to skip.
+pub fn loose_objects_of_type(
     repo: &GitRepository,
-    refs: LinkedHashMap<String, RefType>,
-    with_hash: bool,
-    prefix: Option<&str>,
-) {
-    for (k, v) in refs {
-        match v {
-            RefType::RefTypeSha(s) => {
-                let first = if with_hash {
-                    s + " "
-                } else {
-                    String::default()
-                };
-                let second = if let Some(p) = prefix {
-                    let mut p = PathBuf::from(p);
-                    let mut st = String::default();
-                    st.push(std::path::MAIN_SEPARATOR);
-                    p = p.join(st);
-                    p.to_str().unwrap().to_owned()
-                } else {
-                    String::default()
-                };
-                format!("{}{}{}", first, second, k);
+    otype: ObjectType,
+) -> Result<impl Iterator<Item = String>, WyagError> {
+    let mut ret: Vec<String> = Vec::new();
+    for sha in loose_objects(repo)? {
+        if object_read_header(repo, &sha)?.0 == otype.as_str() {
+            ret.push(sha);
+        }
+    }
+    Ok(ret.into_iter())
+}
+
+fn object_find<'a>(
+    repo: &GitRepository,
+    name: &'a str,
+    fmt: Option<&str>,
+    follow: bool,
+) -> Result<Option<String>, WyagError> {
+    let rvec: Vec<String> = object_resolve(repo, name)?;
+    if rvec.len() == 0 {
+        let errStr = format!("No such reference: {}", &name);
+        return Err(WyagError::new(errStr.as_ref()));
+    }
+    if rvec.len() > 1 {
+        let errStr = format!(
+            "Ambiguous reference {0}: Candidates are:\n - {1}.",
+            &name,
+            rvec.join("\n - ")
+        );
+        return Err(WyagError::new(errStr.as_ref()));
+    }
+
+    let mut sha = rvec[0].to_owned();
+    if let None = fmt {
+        return Ok(Some(sha));
+    }
+
+    loop {
+        let mut o = object_read(repo, sha.as_ref())?;
+        let fmtmatcher = match &o {
+            GObj::Blob(b) => b.fmt(),
+            GObj::Commit(c) => c.fmt(),
+            GObj::Tag(t) => t.fmt(),
+            GObj::Tree(tr) => tr.fmt(),
+        };
+        if fmtmatcher.as_str() == fmt.unwrap() {
+            return Ok(Some(sha));
+        }
+        if !follow {
+            return Ok(None);
+        }
+        /* follow tags */
+        match &o {
+            GObj::Tag(t) => sha = t.kvlm["object"][0].to_owned(),
+            GObj::Commit(c) => {
+                if fmt != Some("tree") {
+                    return Ok(None);
+                }
+                sha = c.tree_sha()?;
             }
-            RefType::RefTypeDict(d) => show_ref(repo, d, with_hash, prefix),
+            GObj::Blob(_) | GObj::Tree(_) => return Ok(None),
         }
     }
 }
 
-pub fn cmd_show_ref() -> Result<(), WyagError> {
+/// Resolve name to an object hash in repo.
+///
+/// This function is aware of:
+///
+/// - the HEAD literal
+/// - short and long hashes
+/// - tags
+/// - branches
+/// - remote branches
+fn object_resolve(repo: &GitRepository, name: &str) -> Result<Vec<String>, WyagError> {
+    let mut candidates: Vec<String> = Vec::new();
+    let hash_re = Regex::new(r"^[0-9A-Fa-f]{4,40}$").unwrap();
+
+    /* Empty string? abort */
+    if name.trim().len() == 0 {
+        return Ok(candidates);
+    }
+
+    /* @ alone is shorthand for HEAD */
+    let name = if name == "@" { "HEAD" } else { name };
+
+    /* HEAD is nonambiguous */
+    if name == "HEAD" {
+        candidates.push(ref_resolve(repo, "HEAD")?);
+        return Ok(candidates);
+    }
+
+    /* :/text names the youngest commit whose message contains text */
+    if name.starts_with(":/") {
+        candidates.push(commit_search_message(repo, &name[2..])?);
+        return Ok(candidates);
+    }
+
+    /* name~N and name^N walk back through parents of whatever name resolves to */
+    if let Some(i) = name.find(|c| c == '~' || c == '^') {
+        if i > 0 {
+            let base = object_resolve(repo, &name[..i])?;
+            if base.len() != 1 {
+                return Ok(base);
+            }
+            candidates.push(rev_walk_parents(repo, &base[0], &name[i..])?);
+            return Ok(candidates);
+        }
+    }
+
+    /* A ref name wins over an abbreviated hash spelled the same */
+    if let Some(sha) = object_resolve_ref(repo, name)? {
+        candidates.push(sha);
+        return Ok(candidates);
+    }
+
+    if hash_re.is_match(name) {
+        let nlen = name.len();
+        let nlower = name.to_lowercase();
+        if nlen == 40 {
+            /* this is a complete hash */
+            candidates.push(nlower);
+            return Ok(candidates);
+        } else if nlen >= 4 {
+            /// This is a small hash 4 seems to be the minimal length
+            /// for git to consider something a short hash.
+            /// This limit is documented in man git-rev-parse
+            let prefix = nlower[0..2].as_ref();
+            let path = repo_dir_gr(repo, false, vec!["objects", prefix])?;
+            let rem: &str = nlower[2..].as_ref();
+            let mut i = std::fs::read_dir(path).expect("Failed to read path.");
+            while let Some(item) = i.next() {
+                match item {
+                    Ok(fd) => {
+                        let ff = fd.file_name();
+                        let fname: &str = ff.to_str().unwrap(); //.to_owned();
+                        if fname.starts_with(&rem) {
+                            let mut s = String::default();
+                            s.push_str(prefix);
+                            s.push_str(fname);
+                            candidates.push(s);
+                        }
+                    }
+                    Err(m) => {
+                        return Err(WyagError::new_with_error(
+                            "Failed to read item in directory",
+                            Box::new(m),
+                        ));
+                    }
+                };
+            }
+        }
+    }
+
+    Ok(candidates)
+}
+
+/// Looks `name` up as a ref, trying the same full names as git and in the same order:
+/// `<name>`, `refs/<name>`, `refs/tags/<name>`, `refs/heads/<name>`, `refs/remotes/<name>` and
+/// `refs/remotes/<name>/HEAD`. The first one that exists wins.
+fn object_resolve_ref(repo: &GitRepository, name: &str) -> Result<Option<String>, WyagError> {
+    let all = refs(repo)?;
+    let full_names = vec![
+        name.to_owned(),
+        format!("refs/{}", name),
+        format!("refs/tags/{}", name),
+        format!("refs/heads/{}", name),
+        format!("refs/remotes/{}", name),
+        format!("refs/remotes/{}/HEAD", name),
+    ];
+    for full in full_names {
+        match all.get(&full) {
+            Some(RefTarget::Direct(sha)) => return Ok(Some(sha.to_owned())),
+            Some(RefTarget::Symbolic(target)) => return Ok(Some(ref_resolve(repo, target)?)),
+            None => (),
+        }
+    }
+    Ok(None)
+}
+
+/// Finds the most recent commit, reachable from any ref or HEAD, whose message contains `text`.
+///
+/// Commits are visited newest-first by committer date, like `git rev-parse ':/text'`.
+fn commit_search_message(repo: &GitRepository, text: &str) -> Result<String, WyagError> {
+    let mut starts: Vec<String> = Vec::new();
+    ref_list_shas(&ref_list(repo, None)?, &mut starts);
+    if let Ok(head) = ref_resolve(repo, "HEAD") {
+        starts.push(head);
+    }
+
+    let mut seen: std::collections::HashSet<String> = std::collections::HashSet::new();
+    let mut queue: std::collections::BinaryHeap<(i64, String)> =
+        std::collections::BinaryHeap::new();
+    for sha in starts {
+        if let Some(c) = commit_search_peel(repo, sha)? {
+            queue.push(c);
+        }
+    }
+
+    while let Some((_, sha)) = queue.pop() {
+        if !seen.insert(sha.clone()) {
+            continue;
+        }
+
+        let commit = match object_read(repo, &sha)? {
+            GObj::Commit(c) => c,
+            GObj::Blob(_) | GObj::Tag(_) | GObj::Tree(_) => continue,
+        };
+        if commit.message().contains(text) {
+            return Ok(sha);
+        }
+        if let Some(parents) = commit.kvlm.get("parent") {
+            for p in parents {
+                if let Some(c) = commit_search_peel(repo, p.to_owned())? {
+                    queue.push(c);
+                }
+            }
+        }
+    }
+
+    Err(WyagError::new(
+        format!("No commit message matches ':/{}'", text).as_ref(),
+    ))
+}
+
+/// Peels tags down to a commit and returns it keyed by committer time, or None if it isn't a commit.
+fn commit_search_peel(
+    repo: &GitRepository,
+    sha: String,
+) -> Result<Option<(i64, String)>, WyagError> {
+    match object_read(repo, &sha)? {
+        GObj::Commit(c) => Ok(Some((commit_time(&c), sha))),
+        GObj::Tag(t) => match t.kvlm.get("object") {
+            Some(o) => commit_search_peel(repo, o[0].to_owned()),
+            None => Ok(None),
+        },
+        GObj::Blob(_) | GObj::Tree(_) => Ok(None),
+    }
+}
+
+/// Applies a chain of `~N` (N first parents back) and `^N` (the Nth parent; `^0` is the commit
+/// itself) operators to commit `sha`. N defaults to 1 for both.
+fn rev_walk_parents(repo: &GitRepository, sha: &str, ops: &str) -> Result<String, WyagError> {
+    let mut sha = sha.to_owned();
+    let mut rest = ops;
+    while rest.len() > 0 {
+        // Taken as a char, so a non-ASCII suffix is an error rather than a bad slice
+        let op = rest.chars().next().unwrap();
+        let start = op.len_utf8();
+        let digits = rest[start..]
+            .find(|c: char| !c.is_ascii_digit())
+            .map(|j| j + start)
+            .unwrap_or(rest.len());
+        let n: usize = match &rest[start..digits] {
+            "" => 1,
+            d => match d.parse() {
+                Ok(n) => n,
+                Err(_) => {
+                    return Err(WyagError::new(
+                        format!("Bad revision suffix {}", ops).as_ref(),
+                    ));
+                }
+            },
+        };
+        rest = &rest[digits..];
+
+        match op {
+            '~' => {
+                for _ in 0..n {
+                    sha = rev_parent(repo, &sha, 1, ops)?;
+                }
+            }
+            '^' => sha = rev_parent(repo, &sha, n, ops)?,
+            _ => {
+                return Err(WyagError::new(
+                    format!("Bad revision suffix {}", ops).as_ref(),
+                ));
+            }
+        }
+    }
+    Ok(sha)
+}
+
+/// The `n`th parent (1-based) of the commit `sha` peels to, or the commit itself for `n == 0`.
+fn rev_parent(repo: &GitRepository, sha: &str, n: usize, ops: &str) -> Result<String, WyagError> {
+    let commit = match commit_search_peel(repo, sha.to_owned())? {
+        Some((_, c)) => c,
+        None => {
+            return Err(WyagError::new(
+                format!("{} is not a commit, cannot apply {}", sha, ops).as_ref(),
+            ));
+        }
+    };
+    if n == 0 {
+        return Ok(commit);
+    }
+    let parents = match object_read(repo, &commit)? {
+        GObj::Commit(c) => c.parents(),
+        GObj::Blob(_) | GObj::Tree(_) | GObj::Tag(_) => Vec::new(),
+    };
+    match parents.get(n - 1) {
+        Some(p) => Ok(p.to_owned()),
+        None => Err(WyagError::new(
+            format!("{} has no parent number {}", commit, n).as_ref(),
+        )),
+    }
+}
+
+/// Reads the timestamp out of a commit's `committer Name <email> <time> <tz>` line. 0 if absent.
+fn commit_time(commit: &GitCommit) -> i64 {
+    match commit.kvlm.get("committer") {
+        Some(c) => c[0]
+            .rsplit(' ')
+            .nth(1)
+            .and_then(|t| t.parse().ok())
+            .unwrap_or(0),
+        None => 0,
+    }
+}
+
+pub fn cmd_rev_parse(name: &str, gtype: Option<&str>) -> Result<(), WyagError> {
     let repo = match repo_find(".", false)? {
         Some(gr) => gr,
         None => {
-            println!("No repository was found, cannot use wyag-show_ref");
+            println!("No repository was found, cannot use rev_parse");
+            return Ok(());
+        }
+    };
+
+    match object_find(&repo, name, gtype, true)? {
+        Some(s) => println!("{}", s),
+        None => println!(""),
+    };
+
+    Ok(())
+}
+
+pub fn cmd_rev_parse_abbrev_ref(name: &str) -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            println!("No repository was found, cannot use rev_parse");
+            return Ok(());
+        }
+    };
+
+    println!("{}", rev_parse_abbrev_ref(&repo, name)?);
+    Ok(())
+}
+
+/// The short ref name for `name`: the branch HEAD is on, or `HEAD` when detached.
+///
+/// Names that aren't branches print as their SHA, like git.
+fn rev_parse_abbrev_ref(repo: &GitRepository, name: &str) -> Result<String, WyagError> {
+    if name == "HEAD" {
+        return match head_state(repo)? {
+            HeadState::Attached(b) => Ok(b),
+            HeadState::Detached(_) => Ok("HEAD".to_owned()),
+            HeadState::Unborn(b) => Err(WyagError::new(
+                format!("HEAD points at {}, which has no commits yet", b).as_ref(),
+            )),
+        };
+    }
+
+    let branch = name.trim_start_matches("refs/heads/");
+    let refname = format!("refs/heads/{}", branch);
+    if repo_path_gr(repo, refname.split('/').collect()).is_file()
+        || packed_refs_lookup(repo, &refname)?.is_some()
+    {
+        return Ok(branch.to_owned());
+    }
+
+    match object_find(repo, name, None, true)? {
+        Some(s) => Ok(s),
+        None => Err(WyagError::new(
+            format!("unknown revision {}", name).as_ref(),
+        )),
+    }
+}
+
+pub fn cmd_rev_parse_short(
+    name: &str,
+    gtype: Option<&str>,
+    len: Option<usize>,
+) -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            println!("No repository was found, cannot use rev_parse");
             return Ok(());
         }
-    };
+    };
+
+    let sha = match object_find(&repo, name, gtype, true)? {
+        Some(s) => s,
+        None => {
+            return Err(WyagError::new(
+                format!("unknown revision {}", name).as_ref(),
+            ));
+        }
+    };
+    println!(
+        "{}",
+        sha_abbrev(&repo, &sha, len.unwrap_or(SHA_ABBREV_DEFAULT))?
+    );
+    Ok(())
+}
+
+/// Prints the SHA `name` resolves to, or fails without printing anything if it doesn't name exactly one existing object.
+pub fn cmd_rev_parse_verify(name: &str, gtype: Option<&str>) -> Result<(), WyagError> {
+    let repo = match repo_find(".", true)? {
+        Some(gr) => gr,
+        None => return Err(WyagError::new("No repository was found")),
+    };
+
+    println!("{}", rev_parse_verify(&repo, name, gtype)?);
+    Ok(())
+}
+
+/// Prints the absolute path of the top of the worktree.
+pub fn cmd_rev_parse_show_toplevel() -> Result<(), WyagError> {
+    println!("{}", rev_parse_show_toplevel(".")?.display());
+    Ok(())
+}
+
+/// Prints the absolute path of the gitdir.
+pub fn cmd_rev_parse_git_dir() -> Result<(), WyagError> {
+    println!("{}", rev_parse_git_dir(".")?.display());
+    Ok(())
+}
+
+fn rev_parse_show_toplevel(start: &str) -> Result<PathBuf, WyagError> {
+    let found = match repo_discover(start, true)? {
+        Some(f) => f,
+        None => return Err(WyagError::new("No repository was found")),
+    };
+    if is_bare(&found.repo) {
+        return Err(WyagError::new("this operation must be run in a work tree"));
+    }
+    rev_parse_absolute(&found.path)
+}
+
+fn rev_parse_git_dir(start: &str) -> Result<PathBuf, WyagError> {
+    match repo_find(start, true)? {
+        Some(gr) => rev_parse_absolute(&gr.gitdir),
+        None => Err(WyagError::new("No repository was found")),
+    }
+}
+
+fn rev_parse_absolute(p: &Path) -> Result<PathBuf, WyagError> {
+    match p.canonicalize() {
+        Ok(c) => Ok(c),
+        Err(m) => Err(WyagError::new_with_error(
+            format!("Failed to make {} absolute", p.display()).as_ref(),
+            Box::new(m),
+        )),
+    }
+}
+
+fn rev_parse_verify(
+    repo: &GitRepository,
+    name: &str,
+    gtype: Option<&str>,
+) -> Result<String, WyagError> {
+    match object_find(repo, name, gtype, true) {
+        Ok(Some(sha)) => {
+            if object_exists(repo, &sha) {
+                return Ok(sha);
+            }
+        }
+        Ok(None) | Err(_) => (),
+    };
+    Err(WyagError::new("Needed a single revision"))
+}
+
+/// How many characters `--short` abbreviates to when none are asked for.
+const SHA_ABBREV_DEFAULT: usize = 7;
+
+/// Shortens `sha` to at least `min_len` characters, lengthening it until no other object shares the prefix.
+///
+/// Lengths below 4 are raised to 4, the shortest prefix object_resolve will look up.
+fn sha_abbrev(repo: &GitRepository, sha: &str, min_len: usize) -> Result<String, WyagError> {
+    let mut len = std::cmp::max(min_len, 4);
+    while len < sha.len() {
+        let prefix = &sha[..len];
+        if object_resolve(repo, prefix)?.len() <= 1 {
+            return Ok(prefix.to_owned());
+        }
+        len += 1;
+    }
+    Ok(sha.to_owned())
+}
+
+pub fn cmd_cat_file(gtype: &str, obj: &str) -> Result<(), WyagError> {
+    let repo = repo_find(".", false)?;
+    if gtype == "commit" {
+        if let Some(gr) = repo {
+            return cmd_cat_file_commit(&gr, obj);
+        }
+    }
+    cat_file(repo, gtype, obj)
+}
+
+/// Writes the canonical bytes of commit `obj` exactly, warning on stderr if they don't hash back to its SHA.
+fn cmd_cat_file_commit(repo: &GitRepository, obj: &str) -> Result<(), WyagError> {
+    let (sha, raw, rehashed) = cat_file_commit(repo, obj)?;
+    if let Err(m) = io::stdout().write_all(&raw) {
+        return Err(WyagError::new_with_error(
+            "Failed to write commit contents to stdout",
+            Box::new(m),
+        ));
+    }
+    if rehashed != sha {
+        eprintln!(
+            "warning: commit {} re-serializes to {}, so its stored form was not canonical",
+            sha, rehashed
+        );
+    }
+    Ok(())
+}
+
+/// Re-serializes commit `obj` from its parsed kvlm, returning its SHA, the serialized bytes, and the SHA those bytes hash to.
+fn cat_file_commit(
+    repo: &GitRepository,
+    obj: &str,
+) -> Result<(String, Vec<u8>, String), WyagError> {
+    let sha = match object_find(repo, obj, Some("commit"), true)? {
+        Some(s) => s,
+        None => {
+            return Err(WyagError::new(format!("{} is not a commit", obj).as_ref()));
+        }
+    };
+    let raw = match object_read(repo, &sha)? {
+        GObj::Commit(c) => c.serialize()?.to_vec(),
+        GObj::Blob(_) | GObj::Tag(_) | GObj::Tree(_) => {
+            return Err(WyagError::new(format!("{} is not a commit", obj).as_ref()));
+        }
+    };
+    let rehashed = object_write_raw(None, ObjectType::Commit, &raw, false)?.sha;
+    Ok((sha, raw, rehashed))
+}
+
+/// Prints blob `obj` as it would be checked out at `path`, i.e. after any smudge filter `.gitattributes` assigns to that path.
+pub fn cmd_cat_file_filters(obj: &str, path: &str) -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            println!("No git repository was found, cannot cat-file");
+            return Ok(());
+        }
+    };
+
+    let data = cat_file_filtered(&repo, obj, path)?;
+    if let Err(m) = io::stdout().write_all(&data) {
+        return Err(WyagError::new_with_error(
+            "Failed to write blob contents to stdout",
+            Box::new(m),
+        ));
+    }
+    Ok(())
+}
+
+fn cat_file_filtered(repo: &GitRepository, obj: &str, path: &str) -> Result<Vec<u8>, WyagError> {
+    let of = match object_find(repo, obj, Some("blob"), true)? {
+        Some(s) => s,
+        None => {
+            return Err(WyagError::new(format!("{} is not a blob", obj).as_ref()));
+        }
+    };
+    match object_read(repo, &of)? {
+        GObj::Blob(b) => filter_smudge(repo, &attributes_load(repo)?, path, b.blob_data),
+        GObj::Commit(_) | GObj::Tag(_) | GObj::Tree(_) => {
+            Err(WyagError::new(format!("{} is not a blob", obj).as_ref()))
+        }
+    }
+}
+
+fn cat_file<'a>(repo: Option<GitRepository>, gtype: &str, obj: &str) -> Result<(), WyagError> {
+    let repo = match repo {
+        Some(gr) => gr,
+        None => {
+            println!("No git repository was found, cannot cat-file");
+            return Ok(());
+        }
+    };
+    let of = match object_find(&repo, obj, Some(gtype), true)? {
+        Some(s) => s,
+        None => {
+            println!("no object found for the type: {}", gtype);
+            return Ok(());
+        }
+    };
+    let o: Box<dyn GitObject> = match object_read(&repo, of.as_ref())? {
+        GObj::Blob(x) => Box::new(x),
+        GObj::Commit(y) => Box::new(y),
+        GObj::Tag(z) => Box::new(z),
+        GObj::Tree(a) => Box::new(a),
+    };
+    let s = (*o).serialize()?.to_vec();
+    cat_file_write(&mut io::stdout(), s)
+}
+
+/// How much of an object `cat_file_is_binary` looks at, like git's own binary detection.
+const CAT_FILE_SNIFF_LEN: usize = 8000;
+
+/// Guesses whether `data` is binary from a NUL byte in its first chunk.
+fn cat_file_is_binary(data: &[u8]) -> bool {
+    data[..data.len().min(CAT_FILE_SNIFF_LEN)].contains(&b'\x00')
+}
+
+/// Writes an object's content byte-for-byte. Text gets a trailing newline, and binary content a warning on stderr;
+/// text that isn't valid UTF-8 (say, Latin-1) is still written as-is rather than rejected.
+fn cat_file_write<W: Write>(out: &mut W, data: Vec<u8>) -> Result<(), WyagError> {
+    let res = if cat_file_is_binary(&data) {
+        eprintln!("warning: binary output");
+        out.write_all(&data)
+    } else {
+        out.write_all(&data).and_then(|_| out.write_all(b"\n"))
+    };
+    match res {
+        Ok(_) => Ok(()),
+        Err(m) => Err(WyagError::new_with_error(
+            "Failed to write object contents to stdout",
+            Box::new(m),
+        )),
+    }
+}
+
+/// Prints the type (`-t`) or size (`-s`) of an object.
+///
+/// With `allow_unknown_type`, objects whose type isn't blob/commit/tag/tree are reported
+/// as-is instead of being rejected, which is handy when poking at corrupt or experimental objects.
+pub fn cmd_cat_file_info(size: bool, obj: &str, allow_unknown_type: bool) -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            println!("No git repository was found, cannot cat-file");
+            return Ok(());
+        }
+    };
+    let sha = match object_find(&repo, obj, None, true)? {
+        Some(s) => s,
+        None => {
+            println!("no object found for the name: {}", obj);
+            return Ok(());
+        }
+    };
+
+    let (gtype, len) = cat_file_info(&repo, &sha, allow_unknown_type)?;
+    if size {
+        println!("{}", len);
+    } else {
+        println!("{}", gtype);
+    }
+    Ok(())
+}
+
+fn cat_file_info(
+    repo: &GitRepository,
+    sha: &str,
+    allow_unknown_type: bool,
+) -> Result<(String, usize), WyagError> {
+    let (gtype, size) = object_read_header(repo, sha)?;
+    match ObjectType::from_bytes(gtype.as_bytes()) {
+        Some(_) => (),
+        None if allow_unknown_type => (),
+        None => {
+            return Err(WyagError::new(
+                format!(
+                    "Unknown type {} for object {}. Use --allow-unknown-type to inspect it anyway.",
+                    gtype, sha
+                )
+                .as_ref(),
+            ));
+        }
+    };
+    Ok((gtype, size))
+}
+
+/// Reads object names from stdin, one per line, and prints `<sha> <type> <size>` for each, or `<name> missing`.
+pub fn cmd_cat_file_batch_check() -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            println!("No git repository was found, cannot cat-file");
+            return Ok(());
+        }
+    };
+
+    let stdin = io::stdin();
+    for line in cat_file_batch_check(&repo, stdin.lock())? {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// The `--batch-check` line for every name in `input`. Only object headers are read, never their contents.
+fn cat_file_batch_check<R: io::BufRead>(
+    repo: &GitRepository,
+    input: R,
+) -> Result<Vec<String>, WyagError> {
+    let mut ret: Vec<String> = Vec::new();
+    for line in input.lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(m) => {
+                return Err(WyagError::new_with_error(
+                    "Failed to read object names from stdin",
+                    Box::new(m),
+                ));
+            }
+        };
+        let name = line.trim();
+        if name.len() == 0 {
+            continue;
+        }
+        let found = match object_find(repo, name, None, true) {
+            Ok(Some(sha)) => object_read_header(repo, &sha).ok().map(|h| (sha, h)),
+            Ok(None) | Err(_) => None,
+        };
+        match found {
+            Some((sha, (gtype, size))) => ret.push(format!("{} {} {}", sha, gtype, size)),
+            None => ret.push(format!("{} missing", name)),
+        }
+    }
+    Ok(ret)
+}
+
+/// Prints the logical size of `obj` followed by how many bytes its compressed loose object takes on disk.
+pub fn cmd_cat_file_disk_size(obj: &str) -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            println!("No git repository was found, cannot cat-file");
+            return Ok(());
+        }
+    };
+    let sha = match object_find(&repo, obj, None, true)? {
+        Some(s) => s,
+        None => {
+            println!("no object found for the name: {}", obj);
+            return Ok(());
+        }
+    };
+
+    let (size, disk) = cat_file_disk_size(&repo, &sha)?;
+    println!("{} {}", size, disk);
+    Ok(())
+}
+
+/// The logical size of object `sha`, and the length of its loose object file.
+fn cat_file_disk_size(repo: &GitRepository, sha: &str) -> Result<(usize, u64), WyagError> {
+    let (_, size) = object_read_header(repo, sha)?;
+    let path = object_path(repo, sha);
+    match std::fs::metadata(&path) {
+        Ok(md) => Ok((size, md.len())),
+        Err(m) => Err(WyagError::new_with_error(
+            format!("Failed to read the size of object {} on disk", sha).as_ref(),
+            Box::new(m),
+        )),
+    }
+}
+
+/// Prints the type of the object `name` resolves to; a shorthand for `cat-file -t`.
+pub fn cmd_object_type(name: &str) -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            return Err(WyagError::new(
+                "No repository was found, cannot use wyag-object-type",
+            ));
+        }
+    };
+
+    println!("{}", object_type_named(&repo, name)?);
+    Ok(())
+}
+
+/// Resolves `name` without peeling it, and reads the type from the object's header.
+fn object_type_named(repo: &GitRepository, name: &str) -> Result<String, WyagError> {
+    let sha = match object_find(repo, name, None, true) {
+        Ok(Some(s)) => s,
+        Ok(None) => {
+            return Err(WyagError::new(
+                format!("{} does not name an object", name).as_ref(),
+            ));
+        }
+        Err(m) => {
+            return Err(WyagError::new_with_error(
+                format!("Failed to resolve {} to an object", name).as_ref(),
+                Box::new(m),
+            ));
+        }
+    };
+    Ok(object_read_header(repo, &sha)?.0)
+}
+
+pub fn cmd_hash_object(actually_write: bool, gtype: &str, path: &str) -> Result<(), WyagError> {
+    let mut grOpt: Option<GitRepository> = None;
+    if actually_write {
+        grOpt = repo_find(".", true)?;
+    }
+
+    let mut fd = match std::fs::File::open(path) {
+        Ok(f) => f,
+        Err(m) => {
+            return Err(WyagError::new_with_error(
+                "Failed to open file at specified path for hash-object",
+                Box::new(m),
+            ));
+        }
+    };
+
+    // Attributes match worktree paths; a file from outside the worktree is looked up as given
+    let attr_path = match &grOpt {
+        Some(gr) => match worktree_relative(gr, path) {
+            Ok(rel) => rel.to_str().unwrap().to_owned(),
+            Err(_) => path.to_owned(),
+        },
+        None => path.to_owned(),
+    };
+    let sha1 = hash_object(&mut fd, gtype, &attr_path, grOpt.as_ref())?;
+    println!("{}", sha1);
+    Ok(())
+}
+
+fn hash_object(
+    fd: &mut std::fs::File,
+    gitType: &str,
+    path: &str,
+    repo: Option<&GitRepository>,
+) -> Result<String, WyagError> {
+    let otype = match ObjectType::from_bytes(gitType.as_bytes()) {
+        Some(t) => t,
+        None => {
+            return Err(WyagError::new(
+                format!("Unknown type {}!", gitType).as_ref(),
+            ));
+        }
+    };
+    let attributes = match (repo, otype) {
+        (Some(gr), ObjectType::Blob) => attributes_load(gr)?,
+        _ => Vec::new(),
+    };
+    /* Unfiltered blobs need no parsing, so they are streamed rather than read into memory */
+    if otype == ObjectType::Blob {
+        let filtered = match repo {
+            Some(gr) => {
+                filter_lookup(gr, &attributes, path, "clean").is_some()
+                    || eol_mode(gr, &attributes, path) != EolMode::Untouched
+            }
+            None => false,
+        };
+        if !filtered {
+            let size = match fd.metadata() {
+                Ok(m) => m.len(),
+                Err(m) => {
+                    return Err(WyagError::new_with_error(
+                        "Failed to perform hash-object",
+                        Box::new(m),
+                    ));
+                }
+            };
+            return Ok(object_write_stream(repo, otype, fd, size, repo.is_some())?.sha);
+        }
+    }
+
+    let mut bytes: Vec<u8> = Vec::new();
+    match fd.read_to_end(&mut bytes) {
+        Ok(_) => (),
+        Err(m) => {
+            return Err(WyagError::new_with_error(
+                "Failed to perform hash-object",
+                Box::new(m),
+            ));
+        }
+    };
+    /* Blobs going into the repository pass through any configured clean filter */
+    if let (Some(gr), ObjectType::Blob) = (repo, otype) {
+        bytes = filter_clean(gr, &attributes, path, bytes)?;
+    }
+    let bytes = bytes.as_slice();
+
+    let mut c: Box<GitObject>;
+    match otype {
+        ObjectType::Commit => c = Box::new(GitCommit::new(repo, bytes)),
+        ObjectType::Tree => c = Box::new(GitTree::new(repo, bytes)),
+        ObjectType::Tag => c = Box::new(GitTag::new(repo, bytes)),
+        ObjectType::Blob => c = Box::new(GitBlob::new(repo, bytes)),
+    };
+
+    c.deserialize(bytes.to_vec())?;
+
+    Ok(object_write(&*c, repo.is_some())?.sha)
+}
+
+/// Hashes every non-ignored file under `dir` as a blob, honoring any `.gitignore` files found along the way.
+///
+/// Returns `(sha, path)` pairs sorted by path, where path is relative to `dir` and uses `/` separators.
+/// Clean filters are looked up by the path relative to the worktree, as `.gitattributes` patterns are.
+fn hash_object_dir(
+    dir: &str,
+    repo: Option<&GitRepository>,
+) -> Result<Vec<(String, String)>, WyagError> {
+    let mut files: Vec<String> = Vec::new();
+    let mut rules: Vec<GitIgnoreRule> = match repo {
+        Some(gr) => ignore_exclude_rules(gr)?,
+        None => Vec::new(),
+    };
+    ignore_walk(Path::new(dir), "", &mut rules, false, &mut files)?;
+    let (prefix, attributes) = match repo {
+        Some(gr) => (worktree_relative(gr, dir)?, attributes_load(gr)?),
+        None => (PathBuf::new(), Vec::new()),
+    };
+
+    let mut ret: Vec<(String, String)> = Vec::new();
+    for rel in files {
+        let bytes = match std::fs::read(Path::new(dir).join(&rel)) {
+            Ok(b) => b,
+            Err(m) => {
+                return Err(WyagError::new_with_error(
+                    format!("Failed to read {} for hash-object", rel).as_ref(),
+                    Box::new(m),
+                ));
+            }
+        };
+        let bytes = match repo {
+            Some(gr) => filter_clean(gr, &attributes, prefix.join(&rel).to_str().unwrap(), bytes)?,
+            None => bytes,
+        };
+        let blob = GitBlob::new(repo, &bytes);
+        let sha = object_write(&blob, repo.is_some())?.sha;
+        ret.push((sha, rel));
+    }
+    Ok(ret)
+}
+
+pub fn cmd_hash_object_recursive(actually_write: bool, dir: &str) -> Result<(), WyagError> {
+    let mut grOpt: Option<GitRepository> = None;
+    if actually_write {
+        grOpt = repo_find(".", true)?;
+    }
+
+    for (sha, rel) in hash_object_dir(dir, grOpt.as_ref())? {
+        println!("{} {}", sha, rel);
+    }
+    Ok(())
+}
+
+// EndRegion: Reading/Writing Objects
+
+/// Region: Log
+
+/// Prints the history below `commit` as a graphviz digraph.
+///
+/// With `topo_order`, each commit's edges are printed together, in topological order: no commit comes
+/// before any of its children, and a line of history is shown in one piece before its siblings are.
+/// With `labels`, every node is labelled with its short SHA and the first line of its message.
+pub fn cmd_log(
+    commit: &str,
+    first_parent: bool,
+    topo_order: bool,
+    labels: bool,
+) -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            println!("No repository was found, cannot use wyag-log");
+            return Ok(());
+        }
+    };
+
+    let o = match object_find(&repo, commit, None, true)? {
+        Some(s) => s,
+        None => {
+            println!("No such object: {}", commit);
+            return Ok(());
+        }
+    };
+    let mut v: Vec<ObjectId> = Vec::new();
+    let mut edges: Vec<String> = Vec::new();
+    if topo_order {
+        for (sha, parents) in log_topo_order(&repo, &o, first_parent)? {
+            if parents.len() == 0 {
+                edges.push(format!("c_{}", sha));
+            }
+            for p in parents {
+                edges.push(format!("c_{} -> c_{}", sha, p));
+            }
+            v.push(ObjectId::try_from(sha)?);
+        }
+    } else {
+        log_graphviz(&repo, o, &mut v, first_parent, &mut edges)?;
+    }
+    if labels {
+        let shas: Vec<String> = v.into_iter().map(String::from).collect();
+        edges.extend(log_labels(&repo, &shas)?);
+    }
+    println!("digraph wyaglog{{");
+    for e in edges {
+        println!("{}", e);
+    }
+    println!("}}");
+    Ok(())
+}
+
+/// Collects the `c_<child> -> c_<parent>` edges of the history below `sha` into `out`.
+///
+/// With `first_parent`, merges only lead on to their first parent, leaving out the branches merged in.
+fn log_graphviz<'a>(
+    repo: &GitRepository,
+    sha: String,
+    seen: &mut Vec<ObjectId>,
+    first_parent: bool,
+    out: &mut Vec<String>,
+) -> Result<(), WyagError> {
+    let id = ObjectId::try_from(sha.as_str())?;
+    if seen.contains(&id) {
+        return Ok(());
+    }
+    let sha2 = sha.clone();
+    seen.push(id);
+    let commit: GitCommit = match object_read(repo, sha2.as_ref())? {
+        GObj::Commit(y) => y,
+        GObj::Blob(_) | GObj::Tag(_) | GObj::Tree(_) => {
+            return Err(WyagError::new(
+                format!("Expected {} to be a commit while walking the log", sha2).as_ref(),
+            ));
+        }
+    };
+
+    /* Base Case: the initial commit. */
+    let mut parents = commit.parents();
+    if parents.len() == 0 {
+        return Ok(());
+    }
+
+    /* Recurse Case */
+    if first_parent {
+        parents.truncate(1);
+    }
+    for p in parents {
+        out.push(format!("c_{} -> c_{}", sha2, &p));
+        match log_graphviz(repo, p, seen, first_parent, out) {
+            Ok(_) => (),
+            Err(m) => return Err(m),
+        };
+    }
+
+    Ok(())
+}
+
+/// One `c_<sha> [label="..."]` node statement per commit in `shas`, giving its short SHA and subject line.
+fn log_labels(repo: &GitRepository, shas: &[String]) -> Result<Vec<String>, WyagError> {
+    let mut ret: Vec<String> = Vec::new();
+    for sha in shas {
+        let subject = match object_read(repo, sha)? {
+            GObj::Commit(c) => match c.encoding() {
+                Some(e) if !e.eq_ignore_ascii_case("utf-8") => format!("{} ({})", c.subject(), e),
+                _ => c.subject().to_owned(),
+            },
+            GObj::Blob(_) | GObj::Tag(_) | GObj::Tree(_) => {
+                return Err(WyagError::new(
+                    format!("Expected {} to be a commit while walking the log", sha).as_ref(),
+                ));
+            }
+        };
+        ret.push(format!(
+            "c_{} [label=\"{} {}\"]",
+            sha,
+            &sha[..7],
+            log_dot_escape(&subject)
+        ));
+    }
+    Ok(ret)
+}
+
+/// Escapes `s` for use inside a double-quoted DOT string.
+fn log_dot_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => (),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Orders every commit reachable from `start` so that each one comes before all of its parents,
+/// returning them along with the parents followed.
+///
+/// This is Kahn's algorithm, with the ready commits kept on a stack rather than a queue, so that after a
+/// commit its first parent's line is continued as far as it goes before a merged-in branch is started.
+fn log_topo_order(
+    repo: &GitRepository,
+    start: &str,
+    first_parent: bool,
+) -> Result<Vec<(String, Vec<String>)>, WyagError> {
+    // Build the DAG: each commit's parents, and how many of its children have yet to be emitted
+    let mut parents: HashMap<String, Vec<String>> = HashMap::new();
+    let mut children: HashMap<String, usize> = HashMap::new();
+    let mut todo: Vec<String> = vec![start.to_owned()];
+    children.insert(start.to_owned(), 0);
+    while let Some(sha) = todo.pop() {
+        if parents.contains_key(&sha) {
+            continue;
+        }
+        let mut ps = match object_read(repo, &sha)? {
+            GObj::Commit(c) => c.parents(),
+            GObj::Blob(_) | GObj::Tag(_) | GObj::Tree(_) => {
+                return Err(WyagError::new(
+                    format!("Expected {} to be a commit while walking the log", sha).as_ref(),
+                ));
+            }
+        };
+        if first_parent {
+            ps.truncate(1);
+        }
+        for p in &ps {
+            *children.entry(p.to_owned()).or_insert(0) += 1;
+            todo.push(p.to_owned());
+        }
+        parents.insert(sha, ps);
+    }
+
+    let mut ret: Vec<(String, Vec<String>)> = Vec::new();
+    let mut ready: Vec<String> = vec![start.to_owned()];
+    while let Some(sha) = ready.pop() {
+        let ps = parents.remove(&sha).unwrap_or_default();
+        // Pushed in reverse, so the first parent is the next one popped
+        for p in ps.iter().rev() {
+            let left = children.get_mut(p).unwrap();
+            *left -= 1;
+            if *left == 0 {
+                ready.push(p.to_owned());
+            }
+        }
+        ret.push((sha, ps));
+    }
+    Ok(ret)
+}
+
+fn kvlm_parse(
+    raw: Vec<u8>,
+    start: usize,
+    dict: &mut LinkedHashMap<String, Vec<String>>,
+) -> &LinkedHashMap<String, Vec<String>> {
+    // Nothing left to read
+    if start >= raw.len() {
+        return dict;
+    }
+
+    // Finding the first space
+    let space = raw
+        .iter()
+        .skip(start)
+        .position(|&r| r == b' ')
+        .map(|i| i + start);
+
+    // Finding the first newline
+    let newline = raw
+        .iter()
+        .skip(start)
+        .position(|&r| r == b'\n')
+        .map(|i| i + start);
+
+    // If a space appears before a newline, we have a new Key value
+
+    // Base Case
+    // ====
+    // If newline appears first, (or there is no space at all, in which case return -1),
+    // we assume a blank line. A blank line means the remainder of the data is the message
+
+    if space.is_none() || (newline.is_some() && newline.unwrap() < space.unwrap()) {
+        assert_eq!(newline, Some(start));
+        let key = "".to_owned();
+        let value = match str::from_utf8(&raw[start + 1..]) {
+            Ok(s) => s.to_owned(),
+            Err(_m) => return dict,
+        };
+        dict.insert(key, vec![value]);
+        return dict;
+    }
+    let space = space.unwrap();
+
+    // Recursive Case
+    // ===
+    // We read the key-value pair and recurse for the next
+    let key = match str::from_utf8(&raw[start..space]) {
+        Ok(s) => s.to_owned(),
+        Err(_m) => {
+            panic!("Failed to parse key in kvlm");
+            // return Err(WyagError::new_with_error(
+            //     "Failed to parse key in kvlm",
+            //     Box::new(m),
+            // ));
+        }
+    };
+
+    // Find the end of the value.  Continuation lines begin with a
+    // space, so we loop until we find a "\n" not followed by a space.
+    let mut end = start;
+    loop {
+        match raw.iter().skip(end + 1).position(|&r| r == b'\n') {
+            Some(i) => end = end + 1 + i,
+            None => {
+                end = raw.len();
+                break;
+            }
+        }
+        if end + 1 >= raw.len() || raw[end + 1] != b' ' {
+            break;
+        }
+    }
+
+    // Grab the value
+    // Also, drop the leading space on continuation lines
+    let rVal = raw[space + 1..end].to_vec();
+    let mut value: String = String::from_utf8_lossy(&rVal).into_owned();
+    value = value.replace("\n ", "\n");
+
+    // Don't overwrite values
+    if dict.contains_key(&key) {
+        let x = dict.get_mut(&key).unwrap();
+        x.push(String::from(value));
+    } else {
+        dict.insert(key, vec![value]);
+    }
+
+    kvlm_parse(raw, end + 1, dict)
+}
+
+/// Decodes a commit that isn't UTF-8, headers and message alike, for display.
+///
+/// ISO-8859-1 (Latin-1) maps each byte to the code point of the same value; for any other encoding, or none,
+/// the bytes are read as UTF-8 with the invalid ones replaced by U+FFFD.
+fn commit_decode(encoding: Option<&str>, raw: &[u8]) -> String {
+    match encoding.map(|e| e.to_ascii_lowercase()) {
+        Some(ref e) if e == "iso-8859-1" || e == "iso8859-1" || e == "latin1" || e == "latin-1" => {
+            raw.iter().map(|&b| b as char).collect()
+        }
+        _ => String::from_utf8_lossy(raw).into_owned(),
+    }
+}
+
+/// Inverse of kvlm_parse.
+///
+/// Only header values get continuation indentation; the message is written back untouched, so
+/// blank lines, trailing spaces, CRLFs and leading spaces in it survive a round trip.
+fn kvlm_serialize(hm: &LinkedHashMap<String, Vec<String>>) -> String {
+    let mut ret = "".to_owned();
+    let mut main = String::new();
+
+    // Output Fields
+    for (k, v) in hm.iter() {
+        // Skip the message itself
+        if k == "" {
+            main = v[0].clone();
+            continue;
+        }
+        for val in v {
+            ret.push_str(k);
+            ret.push_str(" ");
+            ret.push_str(val.replace("\n", "\n ").as_ref());
+            ret.push('\n');
+        }
+    }
+
+    // append message
+    ret.push('\n');
+    ret.push_str(main.as_ref());
+
+    ret
+}
+
+#[cfg(test)]
+mod parse_log_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn parse_commit_round_trips() {
+        let s = "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\nparent aaaa\nparent bbbb\nauthor A <a@b> 1 +0000\nmergetag object cccc\n type commit\n\nA message\n";
+        let mut hm: LinkedHashMap<String, Vec<String>> = LinkedHashMap::new();
+        kvlm_parse(s.as_bytes().to_vec(), 0, &mut hm);
+        assert_eq!(hm["tree"], vec!["4b825dc642cb6eb9a060e54bf8d69288fbee4904"]);
+        assert_eq!(hm["parent"], vec!["aaaa", "bbbb"]);
+        assert_eq!(hm["mergetag"], vec!["object cccc\ntype commit"]);
+        assert_eq!(hm[""], vec!["A message\n"]);
+        assert_eq!(kvlm_serialize(&hm), s);
+    }
+
+    #[test]
+    fn message_body_round_trips_untouched() {
+        let message = "Subject line  \r\n\r\nFirst paragraph, with trailing space \nstill first\n\n\nThird paragraph\n    indented code\n \n";
+        let s = format!(
+            "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\nauthor A <a@b> 1 +0000\n\n{}",
+            message
+        );
+        let mut hm: LinkedHashMap<String, Vec<String>> = LinkedHashMap::new();
+        kvlm_parse(s.as_bytes().to_vec(), 0, &mut hm);
+        assert_eq!(hm[""], vec![message]);
+        assert_eq!(kvlm_serialize(&hm), s);
+    }
+
+    #[test]
+    fn latin1_message_round_trips_and_decodes_for_display() {
+        let mut raw: Vec<u8> = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+            author Ren\xe9 <r@b> 1 +0000\n\
+            encoding ISO-8859-1\n\
+            \n\
+            Caf"
+        .to_vec();
+        raw.extend(b"\xe9 cr\xe8me\n");
+
+        let mut commit = GitCommit::new(None, &[]);
+        commit.deserialize(raw.clone()).unwrap();
+        assert_eq!(commit.encoding(), Some("ISO-8859-1"));
+        assert_eq!(commit.subject(), "Caf\u{e9} cr\u{e8}me");
+        assert!(commit.kvlm["author"][0].starts_with("Ren\u{e9} "));
+        assert_eq!(commit.serialize().unwrap(), raw);
+
+        // An edited commit is written back as UTF-8
+        commit
+            .kvlm
+            .insert("encoding".to_owned(), vec!["UTF-8".to_owned()]);
+        assert!(str::from_utf8(&commit.serialize().unwrap()).is_ok());
+
+        // log notes the encoding next to the decoded subject
+        let path = scratch_dir("wyag_latin1_log");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let sha = write_loose_object(&gr, b"commit", &raw);
+        let labels = log_labels(&gr, &[sha.clone()]).unwrap();
+        assert_eq!(
+            labels,
+            vec![format!(
+                "c_{} [label=\"{} Caf\u{e9} cr\u{e8}me (ISO-8859-1)\"]",
+                sha,
+                &sha[..7]
+            )]
+        );
+
+        // Without a known encoding, undecodable bytes are replaced rather than dropping the message
+        let unlabelled: Vec<u8> =
+            b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\nbad \xff byte\n".to_vec();
+        let mut commit = GitCommit::new(None, &[]);
+        commit.deserialize(unlabelled.clone()).unwrap();
+        assert_eq!(commit.message(), "bad \u{fffd} byte\n");
+        assert_eq!(commit.serialize().unwrap(), unlabelled);
+    }
+
+    #[test]
+    fn message_starting_with_a_space_round_trips() {
+        let s = "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\ngpgsig -----BEGIN-----\n \n abc\n -----END-----\n\n leading space\n second line\n";
+        let mut hm: LinkedHashMap<String, Vec<String>> = LinkedHashMap::new();
+        kvlm_parse(s.as_bytes().to_vec(), 0, &mut hm);
+        assert_eq!(hm["gpgsig"], vec!["-----BEGIN-----\n\nabc\n-----END-----"]);
+        assert_eq!(hm[""], vec![" leading space\n second line\n"]);
+        assert_eq!(kvlm_serialize(&hm), s);
+    }
+
+    #[test]
+    fn first_parent_skips_merged_branches() {
+        let path = scratch_dir("wyag_log_first_parent");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let root = write_commit(&gr, &[], 1000, "Root\n");
+        let main = write_commit(&gr, &[&root], 2000, "Main\n");
+        let side = write_commit(&gr, &[&root], 2500, "Side\n");
+        let merge = write_commit(&gr, &[&main, &side], 3000, "Merge\n");
+
+        let mut all: Vec<String> = Vec::new();
+        log_graphviz(&gr, merge.clone(), &mut Vec::new(), false, &mut all).unwrap();
+        assert_eq!(all.len(), 4);
+        assert!(all.contains(&format!("c_{} -> c_{}", merge, side)));
+        assert!(all.contains(&format!("c_{} -> c_{}", side, root)));
+
+        let mut mainline: Vec<String> = Vec::new();
+        log_graphviz(&gr, merge.clone(), &mut Vec::new(), true, &mut mainline).unwrap();
+        assert_eq!(
+            mainline,
+            vec![
+                format!("c_{} -> c_{}", merge, main),
+                format!("c_{} -> c_{}", main, root),
+            ]
+        );
+        assert!(!mainline.iter().any(|e| e.contains(&side)));
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn topo_order_puts_children_first_and_keeps_branches_together() {
+        let path = scratch_dir("wyag_log_topo_order");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let root = write_commit(&gr, &[], 1000, "Root\n");
+        let main1 = write_commit(&gr, &[&root], 2000, "Main 1\n");
+        let side = write_commit(&gr, &[&root], 2500, "Side\n");
+        let main2 = write_commit(&gr, &[&main1], 3000, "Main 2\n");
+        let merge = write_commit(&gr, &[&main2, &side], 4000, "Merge\n");
+
+        let order: Vec<String> = log_topo_order(&gr, &merge, false)
+            .unwrap()
+            .into_iter()
+            .map(|(sha, _)| sha)
+            .collect();
+        // By date, side would come between main2 and main1
+        assert_eq!(
+            order,
+            vec![
+                merge.clone(),
+                main2.clone(),
+                main1.clone(),
+                side.clone(),
+                root.clone()
+            ]
+        );
+
+        let mainline: Vec<String> = log_topo_order(&gr, &merge, true)
+            .unwrap()
+            .into_iter()
+            .map(|(sha, _)| sha)
+            .collect();
+        assert_eq!(mainline, vec![merge, main2, main1, root]);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn labels_carry_the_escaped_subject_line() {
+        let path = scratch_dir("wyag_log_labels");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let root = write_commit(&gr, &[], 1000, "Fix \"quoted\" C:\\path\n\nBody line\n");
+
+        assert_eq!(
+            log_labels(&gr, &[root.clone()]).unwrap(),
+            vec![format!(
+                "c_{} [label=\"{} Fix \\\"quoted\\\" C:\\\\path\"]",
+                root,
+                &root[..7]
+            )]
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn merge_commits_have_several_parents() {
+        let payload =
+            "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\nparent aaaa\nparent bbbb\n\nMerge\n";
+        let mut merge = GitCommit::new(None, &[]);
+        merge.deserialize(payload.as_bytes().to_vec()).unwrap();
+        assert_eq!(merge.parents(), vec!["aaaa", "bbbb"]);
+        assert!(merge.is_merge_commit());
+
+        let payload = "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\nparent aaaa\n\nPlain\n";
+        let mut plain = GitCommit::new(None, &[]);
+        plain.deserialize(payload.as_bytes().to_vec()).unwrap();
+        assert_eq!(plain.parents(), vec!["aaaa"]);
+        assert!(!plain.is_merge_commit());
+
+        let payload = "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\nRoot\n";
+        let mut root = GitCommit::new(None, &[]);
+        root.deserialize(payload.as_bytes().to_vec()).unwrap();
+        assert!(root.parents().is_empty());
+        assert!(!root.is_merge_commit());
+    }
+
+    #[test]
+    fn commit_without_tree_errors_instead_of_panicking() {
+        let path = scratch_dir("wyag_commit_no_tree");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let payload = "author A U Thor <author@example.com> 1 +0000\n\nNo tree\n";
+        let mut c = GitCommit::new(None, &[]);
+        c.deserialize(payload.as_bytes().to_vec()).unwrap();
+        assert!(c.tree_sha().is_err());
+
+        let sha = write_loose_object(&gr, b"commit", payload.as_bytes());
+        assert!(object_find(&gr, &sha, Some("tree"), true).is_err());
+
+        let payload = "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\nRoot\n";
+        let mut c = GitCommit::new(None, &[]);
+        c.deserialize(payload.as_bytes().to_vec()).unwrap();
+        assert_eq!(
+            c.tree_sha().unwrap(),
+            "4b825dc642cb6eb9a060e54bf8d69288fbee4904"
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn parse_empty_log() {
+        let s = "";
+        let mut hm: LinkedHashMap<String, Vec<String>> = LinkedHashMap::new();
+        kvlm_parse(s.as_bytes().to_vec(), 0, &mut hm);
+        assert_eq!(hm.len(), 0);
+    }
+}
+
+/// Reports whether a commit carries a signature. The signature itself isn't checked.
+pub fn cmd_verify_commit(name: &str) -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            println!("No repository was found, cannot use wyag-verify-commit");
+            return Ok(());
+        }
+    };
+
+    if verify_commit(&repo, name)? {
+        println!("signed");
+    } else {
+        println!("unsigned");
+    }
+    Ok(())
+}
+
+fn verify_commit(repo: &GitRepository, name: &str) -> Result<bool, WyagError> {
+    let sha = match object_find(repo, name, Some("commit"), true)? {
+        Some(s) => s,
+        None => {
+            return Err(WyagError::new(format!("{} is not a commit", name).as_ref()));
+        }
+    };
+    match object_read(repo, &sha)? {
+        GObj::Commit(c) => Ok(c.gpgsig().is_some()),
+        GObj::Blob(_) | GObj::Tree(_) | GObj::Tag(_) => {
+            Err(WyagError::new(format!("{} is not a commit", name).as_ref()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod verify_commit_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    const SIGNATURE: &str =
+        "-----BEGIN PGP SIGNATURE-----\n\niQEzBAABCAAdFiEE\n=abcd\n-----END PGP SIGNATURE-----";
+
+    #[test]
+    fn detects_and_extracts_signature() {
+        let path = scratch_dir("wyag_verify_commit");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let payload = format!(
+            "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+             author A U Thor <author@example.com> 1000 +0000\n\
+             committer A U Thor <author@example.com> 1000 +0000\n\
+             gpgsig {}\n\
+             \n\
+             Signed work\n",
+            SIGNATURE.replace("\n", "\n ")
+        );
+        let signed = write_loose_object(&gr, b"commit", payload.as_bytes());
+        let unsigned = write_commit(&gr, &[], 1000, "Plain work\n");
+
+        match object_read(&gr, &signed).unwrap() {
+            GObj::Commit(c) => {
+                assert_eq!(c.gpgsig(), Some(SIGNATURE));
+                assert_eq!(c.kvlm[""], vec!["Signed work\n"]);
+                assert_eq!(c.serialize().unwrap(), payload.as_bytes().to_vec());
+            }
+            _ => panic!("expected a commit"),
+        }
+        assert!(verify_commit(&gr, &signed).unwrap());
+        assert!(!verify_commit(&gr, &unsigned).unwrap());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
+
+/// EndRegion: Log
+
+/// Region: Commit
+
+/// Replaces the HEAD commit with one carrying `message`, keeping its tree and parents.
+///
+/// The `pre-commit` and `commit-msg` hooks run first, unless `no_verify`.
+pub fn cmd_commit_amend(message: &str, no_verify: bool) -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            println!("No repository was found, cannot use wyag-commit");
+            return Ok(());
+        }
+    };
+
+    let message = if no_verify {
+        message.to_owned()
+    } else {
+        commit_run_hooks(&repo, message)?
+    };
+    let sha = match commit_env_date("GIT_COMMITTER_DATE")? {
+        Some((now, tz)) => commit_amend(&repo, &message, now, Some(&tz))?,
+        None => commit_amend(&repo, &message, commit_now(), None)?,
+    };
+    println!("{}", sha);
+    Ok(())
+}
+
+/// Records a new commit on top of HEAD with `message`.
+///
+/// wyag can't write the index yet, so the new commit's tree is always HEAD's: without `allow_empty` this refuses,
+/// as there's never anything new to commit.
+/// With `dry_run`, reports the tree and commit that would be recorded, but writes no objects and moves no refs.
+/// Otherwise the `pre-commit` and `commit-msg` hooks run first, unless `no_verify`.
+pub fn cmd_commit(
+    message: &str,
+    allow_empty: bool,
+    dry_run: bool,
+    no_verify: bool,
+) -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            println!("No repository was found, cannot use wyag-commit");
+            return Ok(());
+        }
+    };
+
+    let tree = match head_state(&repo)? {
+        HeadState::Attached(_) | HeadState::Detached(_) => {
+            match object_find(&repo, "HEAD", Some("tree"), true)? {
+                Some(t) => t,
+                None => return Err(WyagError::new("HEAD does not have a tree")),
+            }
+        }
+        HeadState::Unborn(_) => write_raw_object(&repo, ObjectType::Tree, &[], !dry_run)?,
+    };
+    // A commit that would be refused anyway doesn't get as far as the hooks
+    commit_refuse_empty(&repo, &tree, allow_empty)?;
+    let message = if dry_run || no_verify {
+        message.to_owned()
+    } else {
+        commit_run_hooks(&repo, message)?
+    };
+    let (now, tz) = match commit_env_date("GIT_COMMITTER_DATE")? {
+        Some(date) => date,
+        None => (commit_now(), "+0000".to_owned()),
+    };
+    let sha = commit_create(&repo, &tree, &message, allow_empty, now, &tz, !dry_run)?;
+    if dry_run {
+        let on = match head_state(&repo)? {
+            HeadState::Attached(b) | HeadState::Unborn(b) => format!("On branch {}", b),
+            HeadState::Detached(_) => "HEAD detached".to_owned(),
+        };
+        println!("{}", on);
+        println!("Would commit tree {} as {}", tree, sha);
+        return Ok(());
+    }
+    println!("{}", sha);
+    Ok(())
+}
+
+/// Writes a commit of `tree` whose parent is HEAD's commit (if any), and moves HEAD's branch onto it.
+///
+/// Unless `allow_empty`, a commit that changes nothing is refused, as commit_refuse_empty does.
+/// Both author and committer are stamped with time `now` at UTC offset `tz`, like `+0100`.
+/// Without `actually_write`, only the SHA the commit would have is computed: nothing is stored and no ref moves.
+fn commit_create(
+    repo: &GitRepository,
+    tree: &str,
+    message: &str,
+    allow_empty: bool,
+    now: i64,
+    tz: &str,
+    actually_write: bool,
+) -> Result<String, WyagError> {
+    let (refname, parent) = match head_state(repo)? {
+        HeadState::Attached(b) => {
+            let refname = format!("refs/heads/{}", b);
+            let sha = ref_resolve(repo, &refname)?;
+            (refname, Some(sha))
+        }
+        HeadState::Detached(sha) => ("HEAD".to_owned(), Some(sha)),
+        HeadState::Unborn(b) => (format!("refs/heads/{}", b), None),
+    };
+    commit_refuse_empty(repo, tree, allow_empty)?;
+
+    let ident = match commit_ident(repo) {
+        Some(i) => format!("{} {} {}", i, now, tz),
+        None => {
+            return Err(WyagError::new(
+                "Author identity unknown, set user.name and user.email in the repository config",
+            ));
+        }
+    };
+    let mut message = message.to_owned();
+    if !message.ends_with('\n') {
+        message.push('\n');
+    }
+
+    let mut kvlm: LinkedHashMap<String, Vec<String>> = LinkedHashMap::new();
+    kvlm.insert("tree".to_owned(), vec![tree.to_owned()]);
+    if let Some(p) = parent {
+        kvlm.insert("parent".to_owned(), vec![p]);
+    }
+    kvlm.insert("author".to_owned(), vec![ident.clone()]);
+    kvlm.insert("committer".to_owned(), vec![ident]);
+    kvlm.insert("".to_owned(), vec![message]);
+
+    let mut commit = GitCommit::new(Some(repo), &[]);
+    commit.kvlm = kvlm;
+    let sha = object_write(&commit, actually_write)?.sha;
+    if actually_write {
+        ref_update(repo, &refname, &sha)?;
+    }
+    Ok(sha)
+}
+
+/// Errors, unless `allow_empty`, if a commit of `tree` would change nothing: if its tree is HEAD's,
+/// or it would be a root commit of the empty tree.
+fn commit_refuse_empty(
+    repo: &GitRepository,
+    tree: &str,
+    allow_empty: bool,
+) -> Result<(), WyagError> {
+    if allow_empty {
+        return Ok(());
+    }
+    let parent = match head_state(repo)? {
+        HeadState::Attached(b) => Some(ref_resolve(repo, &format!("refs/heads/{}", b))?),
+        HeadState::Detached(sha) => Some(sha),
+        HeadState::Unborn(_) => None,
+    };
+    let parent_tree = match &parent {
+        Some(p) => match object_read(repo, p)? {
+            GObj::Commit(c) => c.tree_sha()?,
+            GObj::Blob(_) | GObj::Tree(_) | GObj::Tag(_) => {
+                return Err(WyagError::new(
+                    format!("HEAD points at {}, which is not a commit", p).as_ref(),
+                ));
+            }
+        },
+        None => empty_tree_sha(repo).to_owned(),
+    };
+    if tree == parent_tree {
+        return Err(WyagError::new(
+            "nothing to commit (use --allow-empty to record a commit anyway)",
+        ));
+    }
+    Ok(())
+}
+
+/// Runs the `pre-commit` hook, then `commit-msg` on the message, and returns the message as `commit-msg` left it.
+///
+/// Either hook exiting non-zero aborts the commit. The message is handed over in `.git/COMMIT_EDITMSG`,
+/// which `commit-msg` may rewrite.
+fn commit_run_hooks(repo: &GitRepository, message: &str) -> Result<String, WyagError> {
+    require_writable(repo)?;
+    hook_run(repo, "pre-commit", &[])?;
+
+    let editmsg = repo_path_gr(repo, vec!["COMMIT_EDITMSG"]);
+    if let Err(m) = std::fs::write(&editmsg, message) {
+        return Err(WyagError::new_with_error(
+            "Failed to write COMMIT_EDITMSG",
+            Box::new(m),
+        ));
+    }
+    hook_run(repo, "commit-msg", &[editmsg.as_os_str()])?;
+    match std::fs::read_to_string(&editmsg) {
+        Ok(m) => Ok(m),
+        Err(m) => Err(WyagError::new_with_error(
+            "Failed to read back COMMIT_EDITMSG",
+            Box::new(m),
+        )),
+    }
+}
+
+/// Runs `.git/hooks/<name>` with `args` from the worktree root, erroring if it exits non-zero.
+///
+/// A hook that's missing, or (on unix) not executable, is skipped, as git does.
+fn hook_run(repo: &GitRepository, name: &str, args: &[&std::ffi::OsStr]) -> Result<(), WyagError> {
+    let hook = repo_path_gr(repo, vec!["hooks", name]);
+    let md = match std::fs::metadata(&hook) {
+        Ok(md) if md.is_file() => md,
+        _ => return Ok(()),
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if md.permissions().mode() & 0o111 == 0 {
+            return Ok(());
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = md;
+
+    let status = std::process::Command::new(&hook)
+        .args(args)
+        .current_dir(&repo.worktree)
+        .status();
+    match status {
+        Ok(s) if s.success() => Ok(()),
+        Ok(_) => Err(WyagError::new(
+            format!("The {} hook failed, so the commit was aborted", name).as_ref(),
+        )),
+        Err(m) => Err(WyagError::new_with_error(
+            format!("Failed to run the {} hook", name).as_ref(),
+            Box::new(m),
+        )),
+    }
+}
+
+/// Seconds since the epoch, for committer timestamps.
+///
+/// wyag can't look up the local UTC offset, so dates it takes from the clock are in UTC. Set `GIT_COMMITTER_DATE`
+/// to record another offset.
+fn commit_now() -> i64 {
+    match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(_) => 0,
+    }
+}
+
+/// The date in environment variable `var`, like `GIT_COMMITTER_DATE`, as seconds and UTC offset, if it's set.
+fn commit_env_date(var: &str) -> Result<Option<(i64, String)>, WyagError> {
+    match std::env::var(var) {
+        Ok(v) if v.trim().len() > 0 => match commit_date_parse(&v) {
+            Some(date) => Ok(Some(date)),
+            None => Err(WyagError::new(
+                format!("{} is not a date wyag understands: {}", var, v).as_ref(),
+            )),
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Parses git's raw date format, `<seconds> <+hhmm>`, where the seconds may start with `@`.
+/// Without an offset the date is taken to be UTC. Other formats, like ISO 8601, aren't understood yet.
+fn commit_date_parse(raw: &str) -> Option<(i64, String)> {
+    let mut parts = raw.split_whitespace();
+    let secs: i64 = match parts.next() {
+        Some(s) => s.trim_start_matches('@').parse().ok()?,
+        None => return None,
+    };
+    let tz = match parts.next() {
+        Some(tz) => tz,
+        None => "+0000",
+    };
+    let well_formed = tz.len() == 5
+        && (tz.starts_with('+') || tz.starts_with('-'))
+        && tz[1..].bytes().all(|b| b.is_ascii_digit());
+    if !well_formed || parts.next().is_some() {
+        return None;
+    }
+    Some((secs, tz.to_owned()))
+}
+
+/// Writes the amended commit and moves whatever HEAD points at onto it. The old commit is left dangling.
+///
+/// wyag can't write the index yet, so the tree is carried over unchanged.
+/// The committer is restamped with time `now`, at offset `tz` if one is given, and otherwise at the old one.
+fn commit_amend(
+    repo: &GitRepository,
+    message: &str,
+    now: i64,
+    tz: Option<&str>,
+) -> Result<String, WyagError> {
+    let (refname, old) = match head_state(repo)? {
+        HeadState::Attached(b) => {
+            let refname = format!("refs/heads/{}", b);
+            let sha = ref_resolve(repo, &refname)?;
+            (refname, sha)
+        }
+        HeadState::Detached(sha) => ("HEAD".to_owned(), sha),
+        HeadState::Unborn(b) => {
+            return Err(WyagError::new(
+                format!("Cannot amend, {} has no commits yet", b).as_ref(),
+            ));
+        }
+    };
+
+    let mut kvlm = match object_read(repo, &old)? {
+        GObj::Commit(c) => c.kvlm,
+        GObj::Blob(_) | GObj::Tree(_) | GObj::Tag(_) => {
+            return Err(WyagError::new(
+                format!("HEAD points at {}, which is not a commit", old).as_ref(),
+            ));
+        }
+    };
+
+    // A signature would no longer match the rewritten content
+    kvlm.remove("gpgsig");
+    if let Some(committer) = kvlm.get_mut("committer") {
+        committer[0] = commit_restamp(repo, &committer[0], now, tz);
+    }
+    let mut message = message.to_owned();
+    if !message.ends_with('\n') {
+        message.push('\n');
+    }
+    kvlm.remove("");
+    kvlm.insert("".to_owned(), vec![message]);
+
+    let mut commit = GitCommit::new(Some(repo), &[]);
+    commit.kvlm = kvlm;
+    let sha = object_write(&commit, true)?.sha;
+    ref_update(repo, &refname, &sha)?;
+    Ok(sha)
+}
+
+/// Rewrites a `Name <email> <time> <tz>` line for time `now`, taking the identity from `user.name`
+/// and `user.email` when they're configured, and keeping the old one otherwise. The old offset is kept
+/// too, unless `new_tz` replaces it.
+fn commit_restamp(repo: &GitRepository, line: &str, now: i64, new_tz: Option<&str>) -> String {
+    let (ident, tz) = match line.rfind('>') {
+        Some(i) => {
+            let tz = line[i + 1..].trim().split(' ').nth(1).unwrap_or("+0000");
+            (line[..i + 1].to_owned(), tz.to_owned())
+        }
+        None => (line.to_owned(), "+0000".to_owned()),
+    };
+
+    let ident = commit_ident(repo).unwrap_or(ident);
+    format!("{} {} {}", ident, now, new_tz.unwrap_or(&tz))
+}
+
+/// `Name <email>` from `user.name` and `user.email`, if both are configured.
+fn commit_ident(repo: &GitRepository) -> Option<String> {
+    match repo.conf.section(Some("user".to_owned())) {
+        Some(user) => match (user.get("name"), user.get("email")) {
+            (Some(n), Some(e)) => Some(format!("{} <{}>", n, e)),
+            _ => None,
+        },
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod commit_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn message_splits_into_subject_and_body() {
+        let path = scratch_dir("wyag_commit_subject_body");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let read = |sha: &str| match object_read(&gr, sha).unwrap() {
+            GObj::Commit(c) => (
+                c.message().to_owned(),
+                c.subject().to_owned(),
+                c.body().to_owned(),
+            ),
+            _ => panic!("expected a commit"),
+        };
+
+        let multi = write_commit(
+            &gr,
+            &[],
+            1000,
+            "Subject line\n\nFirst paragraph.\n\nSecond.\n",
+        );
+        assert_eq!(
+            read(&multi),
+            (
+                "Subject line\n\nFirst paragraph.\n\nSecond.\n".to_owned(),
+                "Subject line".to_owned(),
+                "First paragraph.\n\nSecond.\n".to_owned()
+            )
+        );
+
+        let single = write_commit(&gr, &[], 1000, "Only a subject\n");
+        assert_eq!(read(&single).1, "Only a subject");
+        assert_eq!(read(&single).2, "");
+
+        let empty = write_commit(&gr, &[], 1000, "");
+        assert_eq!(read(&empty), (String::new(), String::new(), String::new()));
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn amend_replaces_head_with_same_parent() {
+        let path = scratch_dir("wyag_commit_amend");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let first = write_commit(&gr, &[], 1000, "First\n");
+        let second = write_commit(&gr, &[&first], 2000, "Secnod\n");
+        set_ref(&gr, "refs/heads/master", &second);
+
+        let amended = commit_amend(&gr, "Second", 3000, None).unwrap();
+        assert_ne!(amended, second);
+        assert_eq!(ref_resolve(&gr, "refs/heads/master").unwrap(), amended);
+        assert!(object_exists(&gr, &second));
+
+        match object_read(&gr, &amended).unwrap() {
+            GObj::Commit(c) => {
+                assert_eq!(c.kvlm["parent"], vec![first.clone()]);
+                assert_eq!(c.kvlm[""], vec!["Second\n"]);
+                assert_eq!(
+                    c.kvlm["tree"],
+                    vec!["4b825dc642cb6eb9a060e54bf8d69288fbee4904"]
+                );
+                assert_eq!(
+                    c.kvlm["author"],
+                    vec!["A U Thor <author@example.com> 2000 +0000"]
+                );
+                assert_eq!(
+                    c.kvlm["committer"],
+                    vec!["A U Thor <author@example.com> 3000 +0000"]
+                );
+            }
+            _ => panic!("expected a commit"),
+        }
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn commit_without_changes_is_refused() {
+        let path = scratch_dir("wyag_commit_no_changes");
+        let gr = with_identity(GitRepository::repo_create(&path).unwrap());
+        let first = write_commit(&gr, &[], 1000, "First\n");
+        set_ref(&gr, "refs/heads/master", &first);
+
+        // Checked on its own too, so it can be before the hooks run
+        assert!(commit_refuse_empty(&gr, EMPTY_TREE, false).is_err());
+        commit_refuse_empty(&gr, EMPTY_TREE, true).unwrap();
+
+        assert!(commit_create(&gr, EMPTY_TREE, "Again", false, 2000, "+0000", true).is_err());
+        assert_eq!(ref_resolve(&gr, "refs/heads/master").unwrap(), first);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn allow_empty_commits_the_parents_tree() {
+        let path = scratch_dir("wyag_commit_allow_empty");
+        let gr = with_identity(GitRepository::repo_create(&path).unwrap());
+        let first = write_commit(&gr, &[], 1000, "First\n");
+        set_ref(&gr, "refs/heads/master", &first);
+
+        let sha = commit_create(&gr, EMPTY_TREE, "Again", true, 2000, "+0000", true).unwrap();
+        assert_eq!(ref_resolve(&gr, "refs/heads/master").unwrap(), sha);
+        match object_read(&gr, &sha).unwrap() {
+            GObj::Commit(c) => {
+                assert_eq!(c.tree_sha().unwrap(), EMPTY_TREE);
+                assert_eq!(c.parents(), vec![first.clone()]);
+                assert_eq!(c.kvlm[""], vec!["Again\n"]);
+                assert_eq!(
+                    c.kvlm["committer"],
+                    vec!["A U Thor <author@example.com> 2000 +0000"]
+                );
+            }
+            _ => panic!("expected a commit"),
+        }
+
+        // A first commit of nothing at all needs the flag too
+        let path2 = scratch_dir("wyag_commit_allow_empty_root");
+        let gr2 = with_identity(GitRepository::repo_create(&path2).unwrap());
+        assert!(commit_create(&gr2, EMPTY_TREE, "Root", false, 1000, "+0000", true).is_err());
+        let root = commit_create(&gr2, EMPTY_TREE, "Root", true, 1000, "+0000", true).unwrap();
+        assert_eq!(ref_resolve(&gr2, "refs/heads/master").unwrap(), root);
+
+        std::fs::remove_dir_all(&path).unwrap();
+        std::fs::remove_dir_all(&path2).unwrap();
+    }
+
+    /// Installs an executable shell script as hook `name`.
+    #[cfg(unix)]
+    fn write_hook(gr: &GitRepository, name: &str, script: &str) {
+        use std::os::unix::fs::PermissionsExt;
+        let hook = repo_file_gr(gr, true, vec!["hooks", name]).unwrap();
+        std::fs::write(&hook, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failing_pre_commit_hook_aborts_the_commit() {
+        let path = scratch_dir("wyag_commit_hook_fails");
+        let gr = with_identity(GitRepository::repo_create(&path).unwrap());
+        write_hook(&gr, "pre-commit", "exit 1");
+
+        let err = commit_run_hooks(&gr, "Blocked\n").err().unwrap();
+        assert!(err.to_string().contains("pre-commit hook failed"));
+
+        // Not executable, so not a hook at all
+        let hook = repo_path_gr(&gr, vec!["hooks", "pre-commit"]);
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(commit_run_hooks(&gr, "Allowed\n").unwrap(), "Allowed\n");
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn hooks_need_a_writable_repository() {
+        let path = scratch_dir("wyag_commit_hook_readonly");
+        GitRepository::repo_create(&path).unwrap();
+        let gr = GitRepository::open_readonly(&path).unwrap();
+
+        assert!(commit_run_hooks(&gr, "Read-only\n").is_err());
+        assert!(!repo_path_gr(&gr, vec!["COMMIT_EDITMSG"]).exists());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn passing_hooks_let_the_commit_proceed_with_the_edited_message() {
+        let path = scratch_dir("wyag_commit_hook_passes");
+        let gr = with_identity(GitRepository::repo_create(&path).unwrap());
+        assert_eq!(commit_run_hooks(&gr, "No hooks\n").unwrap(), "No hooks\n");
+
+        write_hook(&gr, "pre-commit", "exit 0");
+        write_hook(&gr, "commit-msg", "echo 'Signed-off-by: Hook' >> \"$1\"");
+        let message = commit_run_hooks(&gr, "Subject\n").unwrap();
+        assert_eq!(message, "Subject\nSigned-off-by: Hook\n");
+        let sha = commit_create(&gr, EMPTY_TREE, &message, true, 1000, "+0000", true).unwrap();
+        assert_eq!(ref_resolve(&gr, "refs/heads/master").unwrap(), sha);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn dry_run_writes_no_commit_and_leaves_the_branch() {
+        let path = scratch_dir("wyag_commit_dry_run");
+        let gr = with_identity(GitRepository::repo_create(&path).unwrap());
+        let first = write_commit(&gr, &[], 1000, "First\n");
+        set_ref(&gr, "refs/heads/master", &first);
+        let blob = write_loose_object(&gr, b"blob", b"changed\n");
+        let tree = write_tree(&gr, &[("100644", "a.txt", &blob)]);
+        let before = loose_objects(&gr).unwrap().count();
+
+        let planned = commit_create(&gr, &tree, "Change", false, 2000, "+0000", false).unwrap();
+        assert!(!object_exists(&gr, &planned));
+        assert_eq!(loose_objects(&gr).unwrap().count(), before);
+        assert_eq!(ref_resolve(&gr, "refs/heads/master").unwrap(), first);
+
+        // The plan names exactly the commit a real run records
+        let sha = commit_create(&gr, &tree, "Change", false, 2000, "+0000", true).unwrap();
+        assert_eq!(sha, planned);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn amending_a_latin1_commit_drops_its_encoding() {
+        let path = scratch_dir("wyag_commit_amend_latin1");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let raw: Vec<u8> = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+            author Ren\xe9 <r@b> 1000 +0000\n\
+            committer Ren\xe9 <r@b> 1000 +0000\n\
+            encoding ISO-8859-1\n\
+            \n\
+            Caf\xe9\n"
+            .to_vec();
+        let old = write_loose_object(&gr, b"commit", &raw);
+        set_ref(&gr, "refs/heads/master", &old);
+
+        let amended = commit_amend(&gr, "Caf\u{e9} cr\u{e8}me", 2000, None).unwrap();
+        let file = repo_path_gr(&gr, vec!["objects", &amended[..2], &amended[2..]]);
+        let data = object_inflate_slurp(&file, &amended).unwrap();
+        let text = str::from_utf8(&data).unwrap();
+        assert!(!text.contains("encoding"));
+        assert!(text.contains("author Ren\u{e9} <r@b> 1000 +0000\n"));
+        match object_read(&gr, &amended).unwrap() {
+            GObj::Commit(c) => {
+                assert_eq!(c.encoding(), None);
+                assert_eq!(c.message(), "Caf\u{e9} cr\u{e8}me\n");
+            }
+            _ => panic!("expected a commit"),
+        }
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn dates_keep_the_offset_they_are_given() {
+        assert_eq!(
+            commit_date_parse("1700000000 -0500"),
+            Some((1700000000, "-0500".to_owned()))
+        );
+        assert_eq!(
+            commit_date_parse("@1700000000 +0130"),
+            Some((1700000000, "+0130".to_owned()))
+        );
+        assert_eq!(
+            commit_date_parse("1700000000"),
+            Some((1700000000, "+0000".to_owned()))
+        );
+        assert_eq!(commit_date_parse("2023-11-14T22:13:20"), None);
+        assert_eq!(commit_date_parse("1700000000 0500"), None);
+        assert_eq!(commit_date_parse("1700000000 +05:00"), None);
+
+        let path = scratch_dir("wyag_commit_tz");
+        let gr = with_identity(GitRepository::repo_create(&path).unwrap());
+        let committer = |sha: &str| match object_read(&gr, sha).unwrap() {
+            GObj::Commit(c) => c.kvlm["committer"][0].clone(),
+            _ => panic!("expected a commit"),
+        };
+        let sha = commit_create(&gr, EMPTY_TREE, "Root", true, 1000, "-0500", true).unwrap();
+        assert_eq!(committer(&sha), "A U Thor <author@example.com> 1000 -0500");
+
+        // Amending keeps the old offset unless it's given a new one
+        let sha = commit_amend(&gr, "Again", 2000, None).unwrap();
+        assert_eq!(committer(&sha), "A U Thor <author@example.com> 2000 -0500");
+        let sha = commit_amend(&gr, "Once more", 3000, Some("+0900")).unwrap();
+        assert_eq!(committer(&sha), "A U Thor <author@example.com> 3000 +0900");
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn amend_needs_a_commit() {
+        let path = scratch_dir("wyag_commit_amend_unborn");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        assert!(commit_amend(&gr, "Nothing", 1000, None).is_err());
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
+
+/// EndRegion: Commit
+
+/// Region: Tree
+
+struct GitTreeLeaf {
+    mode: Vec<u8>,
+    path: Vec<u8>,
+    sha: String,
+}
+
+/// The file modes git writes into trees: regular file, executable, symlink, directory and gitlink.
+const TREE_MODES: [&[u8]; 5] = [b"100644", b"100755", b"120000", b"40000", b"160000"];
+
+fn tree_parse_one(raw: &[u8], start: usize) -> Result<(usize, GitTreeLeaf), WyagError> {
+    /* Find the space terminator for the File Mode */
+    let x = match raw.iter().skip(start).position(|&r| r == b' ') {
+        Some(i) => start + i,
+        None => {
+            return Err(WyagError::new(
+                "no space found in raw byte stream of tree parse",
+            ));
+        }
+    };
+
+    /* Read and validate the File Mode */
+    let mode = raw[start..x].to_vec();
+    if !mode.iter().all(|b| b'0' <= *b && *b <= b'7') || !TREE_MODES.contains(&mode.as_slice()) {
+        return Err(WyagError::new(
+            format!(
+                "invalid file mode '{}' in tree entry at byte {}, expected one of 100644, 100755, 120000, 40000, 160000",
+                String::from_utf8_lossy(&mode),
+                start
+            )
+            .as_ref(),
+        ));
+    }
+
+    /* Find the NULL terminator for the path */
+    let y = match raw.iter().skip(x).position(|&r| r == b'\x00') {
+        Some(i) => x + i,
+        None => {
+            return Err(WyagError::new(
+                "no null terminator found in raw byte stream of tree parse",
+            ));
+        }
+    };
+
+    /* and read the path */
+    let path = raw[x + 1..y].to_vec();
+
+    /* read the SHA1 and convert to a hex string */
+    if raw.len() < y + 21 {
+        return Err(WyagError::new(
+            "tree entry was cut short, expected a 20 byte SHA after the path",
+        ));
+    }
+    let sha_str = bytes_to_hex(&raw[y + 1..y + 21]);
+
+    let pos = y + 21;
+    let data: GitTreeLeaf = GitTreeLeaf {
+        mode: mode,
+        path: path,
+        sha: sha_str,
+    };
+    Ok((pos, data))
+}
+
+fn tree_parse(raw: &[u8]) -> Result<Vec<GitTreeLeaf>, WyagError> {
+    let mut pos: usize = 0;
+    let max: usize = raw.len();
+    let mut v: Vec<GitTreeLeaf> = Vec::new();
+
+    while pos < max {
+        let (pos_m, data) = tree_parse_one(raw, pos)?;
+        pos = pos_m;
+        v.push(data);
+    }
+
+    Ok(v)
+}
+
+fn tree_serialize(tree: &GitTree) -> Result<Vec<u8>, WyagError> {
+    let mut ret: Vec<u8> = Vec::new();
+
+    for g in &tree.items {
+        ret.extend(g.mode.iter());
+        ret.push(b' ');
+        ret.extend(g.path.iter());
+        ret.push(b'\x00');
+        let sha = hex_to_bytes(&g.sha)?;
+        if sha.len() != 20 {
+            return Err(WyagError::new(
+                format!(
+                    "tree entry {} names {}, which is not a full SHA",
+                    String::from_utf8_lossy(&g.path),
+                    g.sha
+                )
+                .as_ref(),
+            ));
+        }
+        ret.extend(sha);
+    }
+
+    Ok(ret)
+}
+
+/// One entry of a tree, as seen by library consumers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeEntry {
+    /// File mode as written in the tree, e.g. `100644` or `40000`
+    pub mode: String,
+    /// Name of the entry within its tree
+    pub path: String,
+    pub sha: String,
+    /// What the entry points at, derived from the mode the way git's ls-tree does
+    pub kind: ObjectType,
+}
+
+impl<'a> GitTree<'a> {
+    fn entries(&self) -> impl Iterator<Item = TreeEntry> + '_ {
+        self.items.iter().map(|item| TreeEntry {
+            mode: String::from_utf8_lossy(&item.mode).into_owned(),
+            path: String::from_utf8_lossy(&item.path).into_owned(),
+            sha: item.sha.to_owned(),
+            kind: match item.mode.as_slice() {
+                b"40000" => ObjectType::Tree,
+                b"160000" => ObjectType::Commit,
+                _ => ObjectType::Blob,
+            },
+        })
+    }
+}
+
+/// Lists the entries of the tree `name` resolves to.
+///
+/// ```
+/// # use std::io::Write;
+/// # let dir = std::env::temp_dir().join("wyag_doc_tree_entries");
+/// # let _ = std::fs::remove_dir_all(&dir);
+/// let repo = wyag_rust::GitRepository::repo_create(dir.to_str().unwrap()).unwrap();
+/// # // A tree holding one file, hello.txt, written straight into the object store
+/// # let mut raw = b"tree 37\x00100644 hello.txt\x00".to_vec();
+/// # raw.extend(&[0xce, 0x01, 0x36, 0x25, 0x03, 0x0b, 0xa8, 0xdb, 0xa9, 0x06,
+/// #             0xf7, 0x56, 0x96, 0x7f, 0x9e, 0x9c, 0xa3, 0x94, 0x46, 0x4a]);
+/// # let mut e = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+/// # e.write_all(&raw).unwrap();
+/// # let objects = dir.join(".git").join("objects").join("aa");
+/// # std::fs::create_dir_all(&objects).unwrap();
+/// # std::fs::write(objects.join("a96ced2d9a1c8e72c56b253a0e2fe78393feb7"), e.finish().unwrap()).unwrap();
+/// let tree = "aaa96ced2d9a1c8e72c56b253a0e2fe78393feb7";
+/// for entry in wyag_rust::tree_entries(&repo, tree).unwrap() {
+///     assert_eq!(entry.path, "hello.txt");
+///     assert_eq!(entry.mode, "100644");
+///     assert_eq!(entry.kind, wyag_rust::ObjectType::Blob);
+///     assert_eq!(entry.sha, "ce013625030ba8dba906f756967f9e9ca394464a");
+/// }
+/// # std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+pub fn tree_entries(
+    repo: &GitRepository,
+    name: &str,
+) -> Result<impl Iterator<Item = TreeEntry>, WyagError> {
+    let sha = match object_find(repo, name, Some("tree"), true)? {
+        Some(s) => s,
+        None => {
+            return Err(WyagError::new(format!("{} is not a tree", name).as_ref()));
+        }
+    };
+    match object_read(repo, &sha)? {
+        GObj::Tree(t) => Ok(t.entries().collect::<Vec<TreeEntry>>().into_iter()),
+        GObj::Blob(_) | GObj::Commit(_) | GObj::Tag(_) => {
+            Err(WyagError::new(format!("{} is not a tree", name).as_ref()))
+        }
+    }
+}
+
+/// The mode and SHA of the entry at `path` below tree `sha`, walking one subtree per path component.
+///
+/// `None` if some component is missing, or a non-tree is in the way of the rest of the path.
+fn tree_lookup(
+    repo: &GitRepository,
+    sha: &str,
+    path: &Path,
+) -> Result<Option<(Vec<u8>, String)>, WyagError> {
+    let mut found: Option<(Vec<u8>, String)> = None;
+    let mut tree_sha = sha.to_owned();
+    for component in path.components() {
+        let name = component.as_os_str().to_string_lossy().into_owned();
+        if let Some((mode, _)) = &found {
+            if mode != b"40000" {
+                return Ok(None);
+            }
+        }
+        if tree_sha == empty_tree_sha(repo) {
+            return Ok(None);
+        }
+        let tree = match object_read(repo, &tree_sha)? {
+            GObj::Tree(t) => t,
+            GObj::Blob(_) | GObj::Commit(_) | GObj::Tag(_) => {
+                return Err(WyagError::new(
+                    format!("Expected {} to be a tree", tree_sha).as_ref(),
+                ));
+            }
+        };
+        match tree.items.into_iter().find(|i| i.path == name.as_bytes()) {
+            Some(item) => {
+                tree_sha = item.sha.clone();
+                found = Some((item.mode, item.sha));
+            }
+            None => return Ok(None),
+        }
+    }
+    Ok(found)
+}
+
+/// Lists the tree `name` resolves to. With `recursive`, subtrees are descended into and only
+/// their entries are listed, with paths relative to the top tree.
+///
+/// Gitlinks (mode 160000) name commits in a submodule's own object store, so they are listed but never
+/// descended into. `recurse_submodules` is accepted for compatibility, but does nothing yet, since wyag
+/// doesn't know how to open a submodule's repository.
+pub fn cmd_ls_tree(
+    name: &str,
+    recursive: bool,
+    _recurse_submodules: bool,
+) -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            println!("No repository was found, cannot use wyag-log");
+            return Ok(());
+        }
+    };
+
+    for line in ls_tree_named(&repo, name, recursive)? {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// Lists whatever tree `name` peels to: a tree, or the tree of a commit or of a tag pointing at one.
+fn ls_tree_named(
+    repo: &GitRepository,
+    name: &str,
+    recursive: bool,
+) -> Result<Vec<String>, WyagError> {
+    let of = match object_find(repo, name, Some("tree"), true)? {
+        Some(s) => s,
+        None => {
+            return Err(WyagError::new(
+                format!("{} is not a tree, nor a commit or tag leading to one", name).as_ref(),
+            ));
+        }
+    };
+
+    let mut lines: Vec<String> = Vec::new();
+    ls_tree(repo, &of, "", recursive, &mut lines)?;
+    Ok(lines)
+}
+
+fn ls_tree(
+    repo: &GitRepository,
+    sha: &str,
+    prefix: &str,
+    recursive: bool,
+    out: &mut Vec<String>,
+) -> Result<(), WyagError> {
+    let tree: GitTree = match object_read(repo, sha)? {
+        GObj::Tree(a) => a,
+        GObj::Blob(_) | GObj::Commit(_) | GObj::Tag(_) => {
+            return Err(WyagError::new(
+                "Expected to retrieve a Tree, but received some other type instead",
+            ));
+        }
+    };
+
+    for entry in tree.entries() {
+        let path = format!("{}{}", prefix, entry.path);
+        if recursive && entry.kind == ObjectType::Tree {
+            ls_tree(repo, &entry.sha, &format!("{}/", path), recursive, out)?;
+            continue;
+        }
+        /* Git's ls-tree displays the type of the object pointed to, as implied by its mode. */
+        out.push(format!(
+            "{:0>6} {} {}\t{}",
+            entry.mode,
+            entry.kind.as_str(),
+            entry.sha,
+            path
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tree_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn tree_serialize_round_trips_parsed_entries() {
+        let mut raw: Vec<u8> = b"100644 a.txt\x00".to_vec();
+        raw.extend(hex_to_bytes("ce013625030ba8dba906f756967f9e9ca394464a").unwrap());
+        raw.extend(b"40000 dir\x00");
+        raw.extend(hex_to_bytes("4b825dc642cb6eb9a060e54bf8d69288fbee4904").unwrap());
+
+        let tree = GitTree {
+            repo: None,
+            items: tree_parse(&raw).unwrap(),
+        };
+        assert_eq!(
+            tree.items[1].sha,
+            "4b825dc642cb6eb9a060e54bf8d69288fbee4904"
+        );
+        assert_eq!(tree_serialize(&tree).unwrap(), raw);
+
+        let short = GitTree {
+            repo: None,
+            items: vec![GitTreeLeaf {
+                mode: b"100644".to_vec(),
+                path: b"a.txt".to_vec(),
+                sha: "ce0136".to_owned(),
+            }],
+        };
+        assert!(tree_serialize(&short).is_err());
+    }
+
+    #[test]
+    fn recursive_ls_tree_stops_at_gitlinks() {
+        let path = scratch_dir("wyag_ls_tree_gitlink");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let blob = write_loose_object(&gr, b"blob", b"hello\n");
+        let sub = write_tree(&gr, &[("100644", "b.txt", &blob)]);
+        // The submodule's commit lives in its own repository, not this one
+        let module = "1111111111111111111111111111111111111111";
+        let tree = write_tree(
+            &gr,
+            &[
+                ("100644", "a.txt", &blob),
+                ("40000", "dir", &sub),
+                ("160000", "module", module),
+            ],
+        );
+
+        let mut lines: Vec<String> = Vec::new();
+        ls_tree(&gr, &tree, "", true, &mut lines).unwrap();
+        assert_eq!(
+            lines,
+            vec![
+                format!("100644 blob {}\ta.txt", blob),
+                format!("100644 blob {}\tdir/b.txt", blob),
+                format!("160000 commit {}\tmodule", module),
+            ]
+        );
+
+        let mut lines: Vec<String> = Vec::new();
+        ls_tree(&gr, &tree, "", false, &mut lines).unwrap();
+        assert_eq!(lines[1], format!("040000 tree {}\tdir", sub));
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn ls_tree_peels_commits_and_tags_to_their_tree() {
+        let path = scratch_dir("wyag_ls_tree_head");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let blob = write_loose_object(&gr, b"blob", b"hello\n");
+        let tree = write_tree(&gr, &[("100644", "hello.txt", &blob)]);
+        let commit = write_loose_object(
+            &gr,
+            b"commit",
+            format!(
+                "tree {}\nauthor A U Thor <author@example.com> 1 +0000\n\nFirst\n",
+                tree
+            )
+            .as_bytes(),
+        );
+        let tag = write_loose_object(
+            &gr,
+            b"tag",
+            format!("object {}\ntype commit\ntag v1\n\nv1\n", commit).as_bytes(),
+        );
+        set_ref(&gr, "refs/heads/master", &commit);
+        set_ref(&gr, "refs/tags/v1", &tag);
+
+        for name in &["HEAD", "master", "v1", commit.as_str(), tree.as_str()] {
+            assert_eq!(
+                ls_tree_named(&gr, name, false).unwrap(),
+                vec![format!("100644 blob {}\thello.txt", blob)],
+                "{} should peel to its tree",
+                name
+            );
+        }
+        assert!(ls_tree_named(&gr, &blob, false).is_err());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    fn tree_entry(mode: &str, path: &str, sha: u8) -> Vec<u8> {
+        let mut v: Vec<u8> = Vec::new();
+        v.extend(mode.as_bytes());
+        v.push(b' ');
+        v.extend(path.as_bytes());
+        v.push(b'\x00');
+        v.extend(vec![sha; 20]);
+        v
+    }
+
+    #[test]
+    fn treeTest() {
+        let mut raw = tree_entry("100644", "a.txt", 0xab);
+        raw.extend(tree_entry("40000", "dir", 0x01));
+        let items = tree_parse(&raw).unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].mode, b"100644".to_vec());
+        assert_eq!(items[0].path, b"a.txt".to_vec());
+        assert_eq!(items[0].sha, "ab".repeat(20));
+        assert_eq!(items[1].mode, b"40000".to_vec());
+        assert_eq!(items[1].sha, "01".repeat(20));
+    }
+
+    #[test]
+    fn tree_parse_rejects_invalid_modes() {
+        for mode in &["10x644", "100646", "777", ""] {
+            let raw = tree_entry(mode, "a.txt", 0xab);
+            match tree_parse(&raw) {
+                Ok(_) => panic!("mode '{}' should have been rejected", mode),
+                Err(e) => assert!(e._message.contains("invalid file mode")),
+            }
+        }
+    }
+
+    #[test]
+    fn tree_parse_rejects_truncated_sha() {
+        let mut raw = tree_entry("100644", "a.txt", 0xab);
+        raw.truncate(raw.len() - 5);
+        assert!(tree_parse(&raw).is_err());
+    }
+}
+
+/// EndRegion: Tree
+
+/// Region: Diff
+
+/// One difference between two trees, as reported by diff_trees.
+#[derive(Debug, PartialEq)]
+enum TreeChange {
+    Added {
+        path: String,
+        sha: String,
+    },
+    Deleted {
+        path: String,
+        sha: String,
+    },
+    Modified {
+        path: String,
+        old: String,
+        new: String,
+    },
+    /// Only reported when rename detection is on: the same content moved from one path to another
+    Renamed {
+        from: String,
+        to: String,
+        sha: String,
+    },
+}
+
+impl TreeChange {
+    /// The `git diff --name-status` letter for this change.
+    fn status(&self) -> char {
+        match self {
+            TreeChange::Added { .. } => 'A',
+            TreeChange::Deleted { .. } => 'D',
+            TreeChange::Modified { .. } => 'M',
+            TreeChange::Renamed { .. } => 'R',
+        }
+    }
+}
+
+/// Compares the trees `old` and `new` file by file, recursing into subtrees. Either side may be
+/// None, standing for an empty tree.
+///
+/// With `renames`, a deleted path and an added one holding the identical blob are reported as a
+/// single rename instead. Changes come out sorted by path.
+fn diff_trees(
+    repo: &GitRepository,
+    old: Option<&str>,
+    new: Option<&str>,
+    renames: bool,
+) -> Result<Vec<TreeChange>, WyagError> {
+    let mut before: Vec<(String, String, String)> = Vec::new();
+    let mut after: Vec<(String, String, String)> = Vec::new();
+    if let Some(sha) = old {
+        tree_flatten(repo, sha, "", &mut before)?;
+    }
+    if let Some(sha) = new {
+        tree_flatten(repo, sha, "", &mut after)?;
+    }
+    Ok(diff_paths(
+        before.into_iter().map(|(p, _, s)| (p, s)).collect(),
+        after.into_iter().map(|(p, _, s)| (p, s)).collect(),
+        renames,
+    ))
+}
+
+/// Compares two `path -> sha` listings, the way diff_trees compares two flattened trees.
+fn diff_paths(
+    before: std::collections::BTreeMap<String, String>,
+    after: std::collections::BTreeMap<String, String>,
+    renames: bool,
+) -> Vec<TreeChange> {
+    let mut changes: Vec<TreeChange> = Vec::new();
+    for (path, sha) in before.iter() {
+        match after.get(path) {
+            Some(n) if n == sha => (),
+            Some(n) => changes.push(TreeChange::Modified {
+                path: path.to_owned(),
+                old: sha.to_owned(),
+                new: n.to_owned(),
+            }),
+            None => changes.push(TreeChange::Deleted {
+                path: path.to_owned(),
+                sha: sha.to_owned(),
+            }),
+        }
+    }
+    for (path, sha) in after.iter() {
+        if !before.contains_key(path) {
+            changes.push(TreeChange::Added {
+                path: path.to_owned(),
+                sha: sha.to_owned(),
+            });
+        }
+    }
+
+    if renames {
+        changes = diff_detect_renames(changes);
+    }
+    changes.sort_by(|a, b| diff_change_path(a).cmp(diff_change_path(b)));
+    changes
+}
+
+/// Pairs each deletion with the first unclaimed addition of the same blob, turning both into a rename.
+fn diff_detect_renames(changes: Vec<TreeChange>) -> Vec<TreeChange> {
+    let mut added: Vec<Option<TreeChange>> = Vec::new();
+    let mut rest: Vec<TreeChange> = Vec::new();
+    for c in changes {
+        match c {
+            TreeChange::Added { .. } => added.push(Some(c)),
+            _ => rest.push(c),
+        }
+    }
+
+    let mut ret: Vec<TreeChange> = Vec::new();
+    for c in rest {
+        let (from, sha) = match &c {
+            TreeChange::Deleted { path, sha } => (path.to_owned(), sha.to_owned()),
+            _ => {
+                ret.push(c);
+                continue;
+            }
+        };
+        let found = added.iter_mut().find(|a| match a {
+            Some(TreeChange::Added { sha: s, .. }) => *s == sha,
+            _ => false,
+        });
+        match found {
+            Some(slot) => {
+                if let Some(TreeChange::Added { path, .. }) = slot.take() {
+                    ret.push(TreeChange::Renamed {
+                        from: from,
+                        to: path,
+                        sha: sha,
+                    });
+                }
+            }
+            None => ret.push(c),
+        }
+    }
+    ret.extend(added.into_iter().filter_map(|a| a));
+    ret
+}
+
+/// The path a change is sorted by; the destination, for renames.
+fn diff_change_path(c: &TreeChange) -> &str {
+    match c {
+        TreeChange::Added { path, .. }
+        | TreeChange::Deleted { path, .. }
+        | TreeChange::Modified { path, .. } => path,
+        TreeChange::Renamed { to, .. } => to,
+    }
+}
+
+/// Lists every non-tree entry below tree `tree_sha` as `(full path, mode, sha)`, in tree order.
+///
+/// Gitlinks are listed like blobs, since they too are leaves of the tree.
+fn flatten_tree(
+    repo: &GitRepository,
+    tree_sha: &str,
+) -> Result<Vec<(PathBuf, Vec<u8>, String)>, WyagError> {
+    let mut flat: Vec<(String, String, String)> = Vec::new();
+    tree_flatten(repo, tree_sha, "", &mut flat)?;
+    Ok(flat
+        .into_iter()
+        .map(|(path, mode, sha)| (PathBuf::from(path), mode.into_bytes(), sha))
+        .collect())
+}
+
+/// Collects `(path, mode, sha)` for every non-tree entry below tree `sha`, with paths prefixed by `prefix`.
+///
+/// The empty tree is known to hold nothing, so it is never read; it need not even be in the object store.
+fn tree_flatten(
+    repo: &GitRepository,
+    sha: &str,
+    prefix: &str,
+    out: &mut Vec<(String, String, String)>,
+) -> Result<(), WyagError> {
+    if sha == empty_tree_sha(repo) {
+        return Ok(());
+    }
+    let tree = match object_read(repo, sha)? {
+        GObj::Tree(t) => t,
+        GObj::Blob(_) | GObj::Commit(_) | GObj::Tag(_) => {
+            return Err(WyagError::new(
+                format!("Expected {} to be a tree", sha).as_ref(),
+            ));
+        }
+    };
+    for entry in tree.entries() {
+        let path = if prefix.len() == 0 {
+            entry.path
+        } else {
+            format!("{}/{}", prefix, entry.path)
+        };
+        match entry.kind {
+            ObjectType::Tree => tree_flatten(repo, &entry.sha, &path, out)?,
+            _ => out.push((path, entry.mode, entry.sha)),
+        }
+    }
+    Ok(())
+}
+
+/// Lines of unchanged context printed around each hunk, unless asked otherwise
+pub const DIFF_CONTEXT_DEFAULT: usize = 3;
+
+/// Compares the trees `old` and `new` resolve to, printing a `git diff --name-status` line per change,
+/// or with `patch` a unified diff using `context` lines of context. With `quiet` nothing is printed.
+///
+/// Returns whether there were any differences, for `--exit-code` and `--quiet` to exit 1 on.
+pub fn cmd_diff(
+    old: &str,
+    new: &str,
+    patch: bool,
+    context: usize,
+    quiet: bool,
+) -> Result<bool, WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            return Err(WyagError::new(
+                "No repository was found, cannot use wyag-diff",
+            ));
+        }
+    };
+
+    let changes = diff_named(&repo, old, new)?;
+    diff_print(&repo, &changes, patch, context, quiet)
+}
+
+/// Like cmd_diff, but compares HEAD's tree with the index, so shows what the next commit would change.
+pub fn cmd_diff_cached(patch: bool, context: usize, quiet: bool) -> Result<bool, WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            return Err(WyagError::new(
+                "No repository was found, cannot use wyag-diff",
+            ));
+        }
+    };
+    // The index belongs to a worktree, so like git a bare repository has none to compare
+    require_worktree(&repo)?;
+
+    let changes = diff_cached(&repo)?;
+    diff_print(&repo, &changes, patch, context, quiet)
+}
+
+/// Prints `changes` as cmd_diff does, and returns whether there were any.
+fn diff_print(
+    repo: &GitRepository,
+    changes: &[TreeChange],
+    patch: bool,
+    context: usize,
+    quiet: bool,
+) -> Result<bool, WyagError> {
+    if quiet {
+        return Ok(changes.len() > 0);
+    }
+    for change in changes {
+        if patch {
+            print!("{}", diff_patch(repo, change, context)?);
+        } else {
+            println!("{}\t{}", change.status(), diff_change_path(change));
+        }
+    }
+    Ok(changes.len() > 0)
+}
+
+/// The staged changes: HEAD's tree (empty on an unborn branch) against the index.
+///
+/// Only stage 0 entries are compared; the sides of an unresolved conflict aren't staged for the next commit.
+fn diff_cached(repo: &GitRepository) -> Result<Vec<TreeChange>, WyagError> {
+    let before = match head_state(repo)? {
+        HeadState::Attached(_) | HeadState::Detached(_) => {
+            match object_find(repo, "HEAD", Some("tree"), true)? {
+                Some(t) => flatten_tree(repo, &t)?,
+                None => return Err(WyagError::new("HEAD does not have a tree")),
+            }
+        }
+        HeadState::Unborn(_) => Vec::new(),
+    };
+    let staged = index_read(repo)?
+        .into_iter()
+        .filter(|e| e.flag_stage == 0)
+        .map(|e| (e.path, e.obj))
+        .collect();
+    Ok(diff_paths(
+        before
+            .into_iter()
+            .map(|(p, _, s)| (p.to_string_lossy().into_owned(), s))
+            .collect(),
+        staged,
+        false,
+    ))
+}
+
+/// Diffs the trees that the names `old` and `new` peel to.
+fn diff_named(repo: &GitRepository, old: &str, new: &str) -> Result<Vec<TreeChange>, WyagError> {
+    let mut trees: Vec<String> = Vec::new();
+    for name in &[old, new] {
+        match object_find(repo, name, Some("tree"), true)? {
+            Some(s) => trees.push(s),
+            None => {
+                return Err(WyagError::new(
+                    format!("{} is not a tree, nor a commit or tag leading to one", name).as_ref(),
+                ));
+            }
+        };
+    }
+    diff_trees(repo, Some(&trees[0]), Some(&trees[1]), false)
+}
+
+/// Renders `change` as a `diff --git` section, with the hunks of its blobs' line-by-line diff.
+///
+/// Blobs containing a NUL byte aren't diffed line by line, and are reported as `Binary files differ` instead.
+fn diff_patch(
+    repo: &GitRepository,
+    change: &TreeChange,
+    context: usize,
+) -> Result<String, WyagError> {
+    let (from, to, old, new) = match change {
+        TreeChange::Added { path, sha } => (None, Some(path), None, Some(sha)),
+        TreeChange::Deleted { path, sha } => (Some(path), None, Some(sha), None),
+        TreeChange::Modified { path, old, new } => (Some(path), Some(path), Some(old), Some(new)),
+        TreeChange::Renamed { from, to, .. } => (Some(from), Some(to), None, None),
+    };
+    let a = format!("a/{}", from.or(to).unwrap());
+    let b = format!("b/{}", to.or(from).unwrap());
+    let mut out = format!("diff --git {} {}\n", a, b);
+
+    if let TreeChange::Renamed { from, to, .. } = change {
+        out.push_str(&format!("rename from {}\nrename to {}\n", from, to));
+        return Ok(out);
+    }
+
+    let old_data = match old {
+        Some(sha) => read_raw(repo, sha)?.1,
+        None => Vec::new(),
+    };
+    let new_data = match new {
+        Some(sha) => read_raw(repo, sha)?.1,
+        None => Vec::new(),
+    };
+    if eol_is_binary(&old_data) || eol_is_binary(&new_data) {
+        out.push_str(&format!("Binary files {} and {} differ\n", a, b));
+        return Ok(out);
+    }
+
+    out.push_str(&format!(
+        "--- {}\n+++ {}\n",
+        if from.is_some() { &a } else { "/dev/null" },
+        if to.is_some() { &b } else { "/dev/null" }
+    ));
+    out.push_str(&diff_unified(&old_data, &new_data, context));
+    Ok(out)
+}
+
+/// Produces the `@@ -a,b +c,d @@` hunks turning `old` into `new`, each with up to `context` unchanged
+/// lines around its changes. Hunks whose context would overlap are merged into one.
+///
+/// The lines both files start and end with are set aside first, and the edit script for the rest comes from diff_myers.
+fn diff_unified(old: &[u8], new: &[u8], context: usize) -> String {
+    let a = diff_split_lines(old);
+    let b = diff_split_lines(new);
+    let prefix = a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+
+    // (op, line) with op one of ' ', '-' or '+'
+    let mut ops: Vec<(char, &[u8])> = a[..prefix].iter().map(|l| (' ', *l)).collect();
+    ops.extend(diff_myers(
+        &a[prefix..a.len() - suffix],
+        &b[prefix..b.len() - suffix],
+    ));
+    ops.extend(a[a.len() - suffix..].iter().map(|l| (' ', *l)));
+
+    let changed: Vec<usize> = (0..ops.len()).filter(|&k| ops[k].0 != ' ').collect();
+    let mut out = String::new();
+    let mut k = 0;
+    while k < changed.len() {
+        let start = changed[k].saturating_sub(context);
+        let mut end = changed[k];
+        while k + 1 < changed.len() && changed[k + 1] - end <= 2 * context + 1 {
+            k += 1;
+            end = changed[k];
+        }
+        let end = std::cmp::min(end + context + 1, ops.len());
+        k += 1;
+
+        // Line numbers where the hunk starts on either side
+        let old_start = ops[..start].iter().filter(|o| o.0 != '+').count();
+        let new_start = ops[..start].iter().filter(|o| o.0 != '-').count();
+        let old_len = ops[start..end].iter().filter(|o| o.0 != '+').count();
+        let new_len = ops[start..end].iter().filter(|o| o.0 != '-').count();
+        out.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            diff_hunk_range(old_start, old_len),
+            diff_hunk_range(new_start, new_len)
+        ));
+        for (op, line) in &ops[start..end] {
+            out.push(*op);
+            let text = String::from_utf8_lossy(line);
+            if text.ends_with('\n') {
+                out.push_str(&text);
+            } else {
+                out.push_str(&text);
+                out.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+    }
+    out
+}
+
+/// How many edits diff_myers searches through before settling for a correct but not minimal script.
+const DIFF_MAX_EDITS: usize = 2000;
+
+/// The shortest edit script turning `a` into `b`, by Myers' O(ND) greedy algorithm, as diff_unified's `(op, line)` pairs.
+///
+/// Backtracking needs the furthest point reached on each diagonal at every step, so memory grows with the
+/// square of the number of edits rather than with the files' lengths. Past DIFF_MAX_EDITS edits the search
+/// stops, and all of `a` is deleted and all of `b` added instead.
+fn diff_myers<'a>(a: &[&'a [u8]], b: &[&'a [u8]]) -> Vec<(char, &'a [u8])> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = std::cmp::min(a.len() + b.len(), DIFF_MAX_EDITS) as isize;
+    let offset = max + 1;
+    // v[offset + k] is the furthest x reached on diagonal k = x - y
+    let mut v = vec![0isize; (2 * max + 3) as usize];
+    // trace[d] is v over diagonals -d..=d as it stood before step d
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    for d in 0..=max {
+        trace.push(v[(offset - d) as usize..=(offset + d) as usize].to_vec());
+        let mut k = -d;
+        while k <= d {
+            let i = (offset + k) as usize;
+            let mut x = if k == -d || (k != d && v[i - 1] < v[i + 1]) {
+                v[i + 1]
+            } else {
+                v[i - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[i] = x;
+            if x >= n && y >= m {
+                return diff_myers_backtrack(a, b, &trace);
+            }
+            k += 2;
+        }
+    }
+
+    let mut ops: Vec<(char, &[u8])> = a.iter().map(|l| ('-', *l)).collect();
+    ops.extend(b.iter().map(|l| ('+', *l)));
+    ops
+}
+
+/// Walks diff_myers' `trace` back from the end of both files, recovering the edit script it found.
+fn diff_myers_backtrack<'a>(
+    a: &[&'a [u8]],
+    b: &[&'a [u8]],
+    trace: &[Vec<isize>],
+) -> Vec<(char, &'a [u8])> {
+    let (mut x, mut y) = (a.len() as isize, b.len() as isize);
+    let mut ops: Vec<(char, &[u8])> = Vec::new();
+    for (d, vd) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let at = |k: isize| vd[(k + d) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let (prev_x, prev_y) = if d == 0 {
+            (0, 0)
+        } else {
+            (at(prev_k), at(prev_k) - prev_k)
+        };
+        while x > prev_x && y > prev_y {
+            ops.push((' ', a[x as usize - 1]));
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            if x == prev_x {
+                ops.push(('+', b[y as usize - 1]));
+            } else {
+                ops.push(('-', a[x as usize - 1]));
+            }
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    ops.reverse();
+    ops
+}
+
+/// Splits `data` after every `\n`, keeping the terminators so a missing final newline still counts as a change.
+fn diff_split_lines(data: &[u8]) -> Vec<&[u8]> {
+    let mut lines: Vec<&[u8]> = Vec::new();
+    let mut begin = 0;
+    for (i, &c) in data.iter().enumerate() {
+        if c == b'\n' {
+            lines.push(&data[begin..i + 1]);
+            begin = i + 1;
+        }
+    }
+    if begin < data.len() {
+        lines.push(&data[begin..]);
+    }
+    lines
+}
+
+/// Formats one side of a hunk header. A count of 1 is left out, and an empty side names the line before it.
+fn diff_hunk_range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, len),
+    }
+}
+
+#[cfg(test)]
+mod diff_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn cached_reports_staged_changes_against_head() {
+        let path = scratch_dir("wyag_diff_cached");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let a = write_loose_object(&gr, b"blob", b"a\n");
+        let b = write_loose_object(&gr, b"blob", b"b\n");
+        let a2 = write_loose_object(&gr, b"blob", b"a, edited\n");
+
+        // On an unborn branch everything staged is new
+        let index = index_bytes(&[(0o100644, &a, "a.txt")]);
+        std::fs::write(repo_path_gr(&gr, vec!["index"]), index).unwrap();
+        assert_eq!(
+            diff_cached(&gr).unwrap(),
+            vec![TreeChange::Added {
+                path: "a.txt".to_owned(),
+                sha: a.clone()
+            }]
+        );
+
+        let tree = write_tree(&gr, &[("100644", "a.txt", &a), ("100644", "b.txt", &b)]);
+        let commit = write_loose_object(
+            &gr,
+            b"commit",
+            format!("tree {}\n\nFirst\n", tree).as_bytes(),
+        );
+        set_ref(&gr, "refs/heads/master", &commit);
+        let index = index_bytes(&[(0o100644, &a2, "a.txt"), (0o100644, &b, "b.txt")]);
+        std::fs::write(repo_path_gr(&gr, vec!["index"]), index).unwrap();
+        assert_eq!(
+            diff_cached(&gr).unwrap(),
+            vec![TreeChange::Modified {
+                path: "a.txt".to_owned(),
+                old: a.clone(),
+                new: a2.clone()
+            }]
+        );
+
+        // Unstaging a file shows as its deletion
+        let index = index_bytes(&[(0o100644, &a, "a.txt")]);
+        std::fs::write(repo_path_gr(&gr, vec!["index"]), index).unwrap();
+        assert_eq!(
+            diff_cached(&gr).unwrap(),
+            vec![TreeChange::Deleted {
+                path: "b.txt".to_owned(),
+                sha: b.clone()
+            }]
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn exit_code_says_whether_anything_differs() {
+        let path = scratch_dir("wyag_diff_exit_code");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let a = write_loose_object(&gr, b"blob", b"a\n");
+        let a2 = write_loose_object(&gr, b"blob", b"a, edited\n");
+        let old = write_tree(&gr, &[("100644", "a.txt", &a)]);
+        let new = write_tree(&gr, &[("100644", "a.txt", &a2)]);
+
+        // --exit-code prints the changes, --quiet doesn't; both report whether there were any
+        for quiet in &[false, true] {
+            let changes = diff_named(&gr, &old, &new).unwrap();
+            assert!(diff_print(&gr, &changes, false, DIFF_CONTEXT_DEFAULT, *quiet).unwrap());
+            let changes = diff_named(&gr, &old, &old).unwrap();
+            assert!(!diff_print(&gr, &changes, false, DIFF_CONTEXT_DEFAULT, *quiet).unwrap());
+        }
+
+        let commit = write_loose_object(
+            &gr,
+            b"commit",
+            format!("tree {}\n\nFirst\n", old).as_bytes(),
+        );
+        set_ref(&gr, "refs/heads/master", &commit);
+        for quiet in &[false, true] {
+            let index = index_bytes(&[(0o100644, &a, "a.txt")]);
+            std::fs::write(repo_path_gr(&gr, vec!["index"]), index).unwrap();
+            let changes = diff_cached(&gr).unwrap();
+            assert!(!diff_print(&gr, &changes, true, DIFF_CONTEXT_DEFAULT, *quiet).unwrap());
+
+            let index = index_bytes(&[(0o100644, &a2, "a.txt")]);
+            std::fs::write(repo_path_gr(&gr, vec!["index"]), index).unwrap();
+            let changes = diff_cached(&gr).unwrap();
+            assert!(diff_print(&gr, &changes, true, DIFF_CONTEXT_DEFAULT, *quiet).unwrap());
+        }
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn reports_adds_deletes_and_modifications() {
+        let path = scratch_dir("wyag_diff_trees");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let a = write_loose_object(&gr, b"blob", b"a\n");
+        let b = write_loose_object(&gr, b"blob", b"b\n");
+        let c = write_loose_object(&gr, b"blob", b"c\n");
+        let sub = write_tree(&gr, &[("100644", "inner.txt", &a)]);
+        let old = write_tree(
+            &gr,
+            &[
+                ("100644", "keep.txt", &a),
+                ("100644", "gone.txt", &b),
+                ("40000", "dir", &sub),
+            ],
+        );
+        let sub2 = write_tree(&gr, &[("100644", "inner.txt", &c)]);
+        let new = write_tree(
+            &gr,
+            &[
+                ("100644", "keep.txt", &a),
+                ("100644", "new.txt", &c),
+                ("40000", "dir", &sub2),
+            ],
+        );
+
+        let changes = diff_trees(&gr, Some(&old), Some(&new), false).unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                TreeChange::Modified {
+                    path: "dir/inner.txt".to_owned(),
+                    old: a.clone(),
+                    new: c.clone()
+                },
+                TreeChange::Deleted {
+                    path: "gone.txt".to_owned(),
+                    sha: b.clone()
+                },
+                TreeChange::Added {
+                    path: "new.txt".to_owned(),
+                    sha: c.clone()
+                },
+            ]
+        );
+        let status: String = changes.iter().map(|c| c.status()).collect();
+        assert_eq!(status, "MDA");
+        assert_eq!(
+            diff_trees(&gr, Some(&old), Some(&old), true).unwrap(),
+            vec![]
+        );
+        assert_eq!(diff_trees(&gr, None, Some(&sub), false).unwrap().len(), 1);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn identical_content_at_a_new_path_is_a_rename() {
+        let path = scratch_dir("wyag_diff_trees_rename");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let a = write_loose_object(&gr, b"blob", b"some content\n");
+        let b = write_loose_object(&gr, b"blob", b"other\n");
+        let old = write_tree(
+            &gr,
+            &[("100644", "old.txt", &a), ("100644", "stay.txt", &b)],
+        );
+        let new = write_tree(
+            &gr,
+            &[("100644", "moved.txt", &a), ("100644", "stay.txt", &b)],
+        );
+
+        assert_eq!(
+            diff_trees(&gr, Some(&old), Some(&new), true).unwrap(),
+            vec![TreeChange::Renamed {
+                from: "old.txt".to_owned(),
+                to: "moved.txt".to_owned(),
+                sha: a.clone()
+            }]
+        );
+        assert_eq!(
+            diff_trees(&gr, Some(&old), Some(&new), false)
+                .unwrap()
+                .len(),
+            2
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn flatten_tree_gives_full_paths_modes_and_shas() {
+        let path = scratch_dir("wyag_flatten_tree");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let a = write_loose_object(&gr, b"blob", b"a\n");
+        let b = write_loose_object(&gr, b"blob", b"b\n");
+        let deep = write_tree(&gr, &[("100755", "run.sh", &b)]);
+        let sub = write_tree(&gr, &[("40000", "deep", &deep), ("100644", "x.txt", &a)]);
+        let top = write_tree(&gr, &[("100644", "a.txt", &a), ("40000", "sub", &sub)]);
+
+        assert_eq!(
+            flatten_tree(&gr, &top).unwrap(),
+            vec![
+                (PathBuf::from("a.txt"), b"100644".to_vec(), a.clone()),
+                (
+                    PathBuf::from("sub").join("deep").join("run.sh"),
+                    b"100755".to_vec(),
+                    b.clone()
+                ),
+                (
+                    PathBuf::from("sub").join("x.txt"),
+                    b"100644".to_vec(),
+                    a.clone()
+                ),
+            ]
+        );
+        // The empty tree doesn't have to be stored to be flattened
+        assert_eq!(flatten_tree(&gr, EMPTY_TREE).unwrap(), vec![]);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn unified_hunks_carry_context_and_merge_when_close() {
+        let old = b"a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\n";
+        let new = b"a\nb\nc\nD\ne\nf\ng\nh\ni\nj\nk\nl\nm\n";
+        assert_eq!(
+            diff_unified(old, new, 3),
+            "@@ -1,7 +1,7 @@\n a\n b\n c\n-d\n+D\n e\n f\n g\n\
+             @@ -10,3 +10,4 @@\n j\n k\n l\n+m\n"
+        );
+        assert_eq!(
+            diff_unified(old, new, 5),
+            "@@ -1,12 +1,13 @@\n a\n b\n c\n-d\n+D\n e\n f\n g\n h\n i\n j\n k\n l\n+m\n"
+        );
+        assert_eq!(diff_unified(b"", b"new\n", 3), "@@ -0,0 +1 @@\n+new\n");
+        assert_eq!(
+            diff_unified(b"x\n", b"x", 3),
+            "@@ -1 +1 @@\n-x\n+x\n\\ No newline at end of file\n"
+        );
+        assert_eq!(diff_unified(old, old, 3), "");
+    }
+
+    #[test]
+    fn myers_finds_a_shortest_script_and_large_files_stay_cheap() {
+        let a: Vec<&[u8]> = vec![b"a\n", b"b\n", b"c\n", b"a\n", b"b\n", b"b\n", b"a\n"];
+        let b: Vec<&[u8]> = vec![b"c\n", b"b\n", b"a\n", b"b\n", b"a\n", b"c\n"];
+        let ops = diff_myers(&a, &b);
+        assert_eq!(ops.iter().filter(|o| o.0 != ' ').count(), 5);
+        let old: Vec<&[u8]> = ops.iter().filter(|o| o.0 != '+').map(|o| o.1).collect();
+        let new: Vec<&[u8]> = ops.iter().filter(|o| o.0 != '-').map(|o| o.1).collect();
+        assert_eq!((old, new), (a, b));
+
+        // 50k lines with one edit in the middle: only the differing line is searched
+        let mut old = String::new();
+        for n in 0..50000 {
+            old.push_str(&format!("line {}\n", n));
+        }
+        let new = old.replace("line 25000\n", "changed\n");
+        assert_eq!(
+            diff_unified(old.as_bytes(), new.as_bytes(), 1),
+            "@@ -25000,3 +25000,3 @@\n line 24999\n-line 25000\n+changed\n line 25001\n"
+        );
+
+        // Past DIFF_MAX_EDITS, everything is replaced rather than searched for
+        let many: Vec<Vec<u8>> = (0..DIFF_MAX_EDITS + 1)
+            .map(|n| format!("{}\n", n).into_bytes())
+            .collect();
+        let others: Vec<Vec<u8>> = (0..DIFF_MAX_EDITS + 1)
+            .map(|n| format!("x{}\n", n).into_bytes())
+            .collect();
+        let a: Vec<&[u8]> = many.iter().map(|l| l.as_ref()).collect();
+        let b: Vec<&[u8]> = others.iter().map(|l| l.as_ref()).collect();
+        let ops = diff_myers(&a, &b);
+        assert!(ops[..a.len()].iter().all(|o| o.0 == '-'));
+        assert!(ops[a.len()..].iter().all(|o| o.0 == '+'));
+    }
+
+    #[test]
+    fn named_diff_is_empty_only_for_identical_trees() {
+        let path = scratch_dir("wyag_diff_named");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let a = write_loose_object(&gr, b"blob", b"a\n");
+        let b = write_loose_object(&gr, b"blob", b"b\n");
+        let t1 = write_tree(&gr, &[("100644", "f.txt", &a)]);
+        let t2 = write_tree(&gr, &[("100644", "f.txt", &b)]);
+        let c1 = write_loose_object(&gr, b"commit", format!("tree {}\n\nfirst\n", t1).as_bytes());
+        set_ref(&gr, "refs/heads/master", &c1);
+
+        assert_eq!(diff_named(&gr, "HEAD", &t1).unwrap(), vec![]);
+        assert_eq!(diff_named(&gr, "HEAD", &t2).unwrap().len(), 1);
+        assert!(diff_named(&gr, "HEAD", &a).is_err());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn patch_reports_binary_blobs_without_hunks() {
+        let path = scratch_dir("wyag_diff_patch");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let t1 = write_loose_object(&gr, b"blob", b"one\ntwo\n");
+        let t2 = write_loose_object(&gr, b"blob", b"one\n2\n");
+        let b1 = write_loose_object(&gr, b"blob", b"\x00\x01");
+        let b2 = write_loose_object(&gr, b"blob", b"\x00\x02");
+
+        let text = TreeChange::Modified {
+            path: "f.txt".to_owned(),
+            old: t1,
+            new: t2,
+        };
+        assert_eq!(
+            diff_patch(&gr, &text, 3).unwrap(),
+            "diff --git a/f.txt b/f.txt\n--- a/f.txt\n+++ b/f.txt\n\
+             @@ -1,2 +1,2 @@\n one\n-two\n+2\n"
+        );
+        let binary = TreeChange::Modified {
+            path: "f.bin".to_owned(),
+            old: b1,
+            new: b2,
+        };
+        assert_eq!(
+            diff_patch(&gr, &binary, 3).unwrap(),
+            "diff --git a/f.bin b/f.bin\nBinary files a/f.bin and b/f.bin differ\n"
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
+
+/// EndRegion: Diff
+
+/// Region: Checkout
+
+/// Checks the commit or tree `sha` out into the empty (or missing) directory `path`.
+///
+/// With `set_times`, every file written gets the commit's committer timestamp as its modification time,
+/// so repeated checkouts of the same commit are byte-for-byte and time-for-time identical.
+/// With `force`, `path` may already hold files; any that are in the way of the checkout are replaced.
+pub fn cmd_checkout(sha: &str, path: &str, set_times: bool, force: bool) -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            println!("No repository was found, cannot use wyag-checkout");
+            return Ok(());
+        }
+    };
+    require_worktree(&repo)?;
+    require_writable(&repo)?;
+    if let Some(commit) = checkout(&repo, sha, path, set_times, force)? {
+        println!("Note: switching to '{}'.\n", sha);
+        println!("You are in 'detached HEAD' state. Commits made now belong to no branch,");
+        println!("and are lost once you switch to another one, unless one is created for them.\n");
+        println!("HEAD is now at {}", &commit[..7]);
+    }
+    Ok(())
+}
+
+/// Restores each of `paths` in the worktree as it is in `commit`, leaving HEAD and every other file alone.
+pub fn cmd_checkout_paths(commit: &str, paths: &[String]) -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            println!("No repository was found, cannot use wyag-checkout");
+            return Ok(());
+        }
+    };
+    require_worktree(&repo)?;
+    require_writable(&repo)?;
+    let attributes = attributes_load(&repo)?;
+    for path in paths {
+        let rel = worktree_relative(&repo, path)?;
+        checkout_path(&repo, &attributes, commit, &rel)?;
+    }
+    Ok(())
+}
+
+/// Discards the worktree changes to each of `paths`, restoring them from the index.
+///
+/// wyag can't write the index yet, so `staged` (resetting index entries) is refused.
+pub fn cmd_restore(paths: &[String], staged: bool) -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            return Err(WyagError::new(
+                "No repository was found, cannot use wyag-restore",
+            ));
+        }
+    };
+    require_worktree(&repo)?;
+    require_writable(&repo)?;
+    let attributes = attributes_load(&repo)?;
+    for path in paths {
+        let rel = worktree_relative(&repo, path)?;
+        restore(&repo, &attributes, &rel, staged)?;
+    }
+    Ok(())
+}
+
+/// Restores the worktree-relative `rel` to its version in the index, with everything below it for a directory.
+///
+/// A repository without an index (wyag can't write one yet) is restored from HEAD instead, which is what it would hold.
+fn restore(
+    repo: &GitRepository,
+    attributes: &[GitAttributeRule],
+    rel: &Path,
+    staged: bool,
+) -> Result<(), WyagError> {
+    if staged {
+        return Err(WyagError::new(
+            "restore --staged needs to reset the index, and wyag can't write the index yet",
+        ));
+    }
+    if !repo_path_gr(repo, vec!["index"]).exists() {
+        if let HeadState::Unborn(b) = head_state(repo)? {
+            return Err(WyagError::new(
+                format!("Nothing to restore from: branch {} has no commits yet", b).as_ref(),
+            ));
+        }
+        return checkout_path(repo, attributes, "HEAD", rel);
+    }
+
+    let prefix = rel.to_str().unwrap().replace('\\', "/");
+    let entries: Vec<GitIndexEntry> = index_read(repo)?
+        .into_iter()
+        .filter(|e| {
+            prefix.is_empty() || e.path == prefix || e.path.starts_with(&format!("{}/", prefix))
+        })
+        .collect();
+    if entries.is_empty() {
+        return Err(WyagError::new(
+            format!(
+                "pathspec '{}' did not match any file known to the index",
+                rel.display()
+            )
+            .as_ref(),
+        ));
+    }
+    if let Some(e) = entries.iter().find(|e| e.flag_stage != 0) {
+        return Err(WyagError::new(
+            format!("path '{}' is unmerged", e.path).as_ref(),
+        ));
+    }
+
+    for e in entries {
+        let dest = Path::new(&repo.worktree).join(&e.path);
+        let erel = Path::new(&e.path);
+        let mode = format!("{:o}", e.mode_octal());
+        // A submodule is left to itself, like git does; only its directory is made sure of
+        let is_gitlink = mode == "160000";
+        let is_dir = checkout_clear_path(&dest, erel, is_gitlink, true)?;
+        let target = if is_gitlink {
+            Some(dest.as_path())
+        } else {
+            dest.parent()
+        };
+        if let (Some(dir), false) = (target, is_dir) {
+            if let Err(m) = std::fs::create_dir_all(dir) {
+                return Err(WyagError::new_with_error(
+                    format!("Failed to create the directory of {}", e.path).as_ref(),
+                    Box::new(m),
+                ));
+            }
+        }
+        if !is_gitlink {
+            write_blob_entry(&dest, mode.as_bytes(), repo, attributes, &e.obj, erel)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the entry at the worktree-relative `rel` in `commit`'s tree into the worktree, replacing whatever is there.
+///
+/// A directory is restored with everything below it. wyag can't write the index yet, so only the worktree is updated.
+fn checkout_path(
+    repo: &GitRepository,
+    attributes: &[GitAttributeRule],
+    commit: &str,
+    rel: &Path,
+) -> Result<(), WyagError> {
+    let tree = match object_find(repo, commit, Some("tree"), true)? {
+        Some(t) => t,
+        None => {
+            return Err(WyagError::new(
+                format!("{} is not a commit, nor a tree", commit).as_ref(),
+            ));
+        }
+    };
+    let (mode, sha) = match tree_lookup(repo, &tree, rel)? {
+        Some(e) => e,
+        None => {
+            return Err(WyagError::new(
+                format!(
+                    "pathspec '{}' did not match any file known to {}",
+                    rel.display(),
+                    commit
+                )
+                .as_ref(),
+            ));
+        }
+    };
+
+    let dest = Path::new(&repo.worktree).join(rel);
+    let is_dir = checkout_clear_path(&dest, rel, mode == b"40000", true)?;
+    match mode.as_slice() {
+        b"40000" => {
+            if !is_dir {
+                if let Err(m) = std::fs::create_dir_all(&dest) {
+                    return Err(WyagError::new_with_error(
+                        format!("Failed to create {}", rel.display()).as_ref(),
+                        Box::new(m),
+                    ));
+                }
+            }
+            match object_read(repo, &sha)? {
+                GObj::Tree(t) => tree_checkout(
+                    repo,
+                    attributes,
+                    t,
+                    dest.to_str().unwrap(),
+                    rel,
+                    None,
+                    true,
+                    None,
+                ),
+                GObj::Blob(_) | GObj::Commit(_) | GObj::Tag(_) => Err(WyagError::new(
+                    format!("Expected {} to be a tree", sha).as_ref(),
+                )),
+            }
+        }
+        b"160000" => Err(WyagError::new(
+            format!(
+                "{} is a submodule, which wyag cannot check out",
+                rel.display()
+            )
+            .as_ref(),
+        )),
+        _ => {
+            if let Some(parent) = dest.parent() {
+                if let Err(m) = std::fs::create_dir_all(parent) {
+                    return Err(WyagError::new_with_error(
+                        format!("Failed to create the directory of {}", rel.display()).as_ref(),
+                        Box::new(m),
+                    ));
+                }
+            }
+            write_blob_entry(&dest, &mode, repo, attributes, &sha, rel)
+        }
+    }
+}
+
+/// Checks out `sha`, then points HEAD at what was checked out: at the branch itself when `sha` names
+/// one (loose or packed), or straight at the commit otherwise. A tree, or HEAD itself (or `@`), leaves HEAD alone.
+///
+/// Returns the commit HEAD was detached at, if it was.
+fn checkout(
+    repo: &GitRepository,
+    sha: &str,
+    path: &str,
+    set_times: bool,
+    force: bool,
+) -> Result<Option<String>, WyagError> {
+    let of = match object_find(repo, sha, None, true)? {
+        Some(s) => s,
+        None => {
+            println!("no object found for the type: {}", "commit");
+            return Ok(None);
+        }
+    };
+
+    let mut commit_date: Option<i64> = None;
+    let o: GitTree = match object_read(repo, of.as_ref())? {
+        // GObj::Blob(x) => Box::new(x),
+        // A commit of the empty tree has nothing to write, and its tree needn't be stored
+        GObj::Commit(y) if y.tree_sha()? == empty_tree_sha(repo) => {
+            commit_date = Some(commit_time(&y));
+            GitTree::new(Some(repo), &[])
+        }
+        GObj::Commit(y) => match object_read(repo, y.tree_sha()?.as_ref()) {
+            Ok(GObj::Tree(gobj)) => {
+                commit_date = Some(commit_time(&y));
+                gobj
+            }
+            Ok(GObj::Blob(_)) | Ok(GObj::Commit(_)) | Ok(GObj::Tag(_)) => {
+                return Err(WyagError::new(
+                    "Expected a tree from this commit, but failed to retreive one",
+                ));
+            }
+            Err(m) => {
+                return Err(WyagError::new_with_error(
+                    "Expected commit to contain a tree with the value 'tree' but got nothing",
+                    Box::new(m),
+                ));
+            }
+        },
+        // GObj::Tag(z) => Box::new(z),
+        GObj::Tree(a) => a,
+        GObj::Blob(_) | GObj::Tag(_) => {
+            return Err(WyagError::new(
+                "encountered an error trying to read object in cmd_checkout. Expected a tree object or a commit object, got something else",
+            ));
+        }
+    };
+
+    /* Verify path is empty directory */
+    let p: PathBuf = PathBuf::from(path);
+    if p.exists() {
+        if !p.is_dir() {
+            return Err(WyagError::new("Supplied path was not a directory"));
+        } else if let (false, Some(_x)) = (
+            force,
+            std::fs::read_dir(&p)
+                .expect("can't view this directory. Do you have permission?")
+                .next(),
+        ) {
+            return Err(WyagError::new(
+                "Cannot create Git object directory, su pplied path is not empty.",
+            ));
+        }
+    }
+    if !p.is_dir() {
+        if let Err(m) = std::fs::create_dir(&p) {
+            return Err(WyagError::new_with_error(
+                "Failed to checkout git object: Error creating directory path",
+                Box::new(m),
+            ));
+        };
+    }
+
+    let mtime = match (set_times, commit_date) {
+        (false, _) => None,
+        (true, Some(t)) => {
+            // Committer dates before 1970 are negative, so step back from the epoch for those
+            let secs = std::time::Duration::from_secs(t.unsigned_abs());
+            let time = if t >= 0 {
+                std::time::UNIX_EPOCH.checked_add(secs)
+            } else {
+                std::time::UNIX_EPOCH.checked_sub(secs)
+            };
+            match time {
+                Some(time) => Some(time),
+                None => {
+                    return Err(WyagError::new(
+                        format!(
+                            "The commit date {} of {} is out of range for a file time",
+                            t, sha
+                        )
+                        .as_ref(),
+                    ));
+                }
+            }
+        }
+        (true, None) => {
+            return Err(WyagError::new(
+                format!(
+                    "{} is a tree, which has no commit date to take file times from",
+                    sha
+                )
+                .as_ref(),
+            ));
+        }
+    };
+    let sparse = sparse_checkout_cone(repo)?;
+    tree_checkout(
+        repo,
+        &attributes_load(repo)?,
+        o,
+        path,
+        Path::new(""),
+        mtime,
+        force,
+        sparse.as_ref().map(|c| c.as_slice()),
+    )?;
+
+    if commit_date.is_none() || sha == "HEAD" || sha == "@" {
+        return Ok(None);
+    }
+    let branch = sha.trim_start_matches("refs/heads/");
+    let refname = format!("refs/heads/{}", branch);
+    let is_branch = branch_name_valid(branch)
+        && (repo_path_gr(repo, refname.split('/').collect()).is_file()
+            || packed_refs_lookup(repo, &refname)?.is_some());
+    let (head, detached) = if is_branch {
+        (format!("ref: refs/heads/{}\n", branch), None)
+    } else {
+        (format!("{}\n", of), Some(of.clone()))
+    };
+    if let Err(m) = std::fs::write(repo_path_gr(repo, vec!["HEAD"]), head) {
+        return Err(WyagError::new_with_error(
+            "Failed to update HEAD after checkout",
+            Box::new(m),
+        ));
+    }
+    Ok(detached)
+}
+
+/// Switches HEAD to the new, unborn branch `name`, so that the next commit starts a history of its own.
+pub fn cmd_checkout_orphan(name: &str) -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            println!("No repository was found, cannot use wyag-checkout");
+            return Ok(());
+        }
+    };
+    checkout_orphan(&repo, name)?;
+    println!("Switched to a new branch '{}'", name);
+    Ok(())
+}
+
+/// Points HEAD at `refs/heads/<name>` without creating the ref. The worktree is left untouched.
+fn checkout_orphan(repo: &GitRepository, name: &str) -> Result<(), WyagError> {
+    require_writable(repo)?;
+    if !branch_name_valid(name) {
+        return Err(WyagError::new(
+            format!("'{}' is not a valid branch name.", name).as_ref(),
+        ));
+    }
+    let refname = format!("refs/heads/{}", name);
+    if repo_path_gr(repo, refname.split('/').collect()).exists()
+        || packed_refs_lookup(repo, &refname)?.is_some()
+    {
+        return Err(WyagError::new(
+            format!("A branch named '{}' already exists.", name).as_ref(),
+        ));
+    }
+    if let Err(m) = std::fs::write(
+        repo_path_gr(repo, vec!["HEAD"]),
+        format!("ref: {}\n", refname),
+    ) {
+        return Err(WyagError::new_with_error(
+            "Failed to update HEAD",
+            Box::new(m),
+        ));
+    }
+    Ok(())
+}
+
+/// Writes the contents of `tree` into the directory `path`.
+///
+/// `rel` is the location of `tree` relative to the root of the checkout, used to look up attributes.
+/// With an `mtime`, each file written (but not symlinks) has its modification time set to it.
+/// With `force`, anything already at an entry's path is replaced; otherwise a directory where a file
+/// belongs (or the reverse) is reported as a conflict.
+/// With a `sparse` cone, only the entries it takes in are written.
+/// `attributes` are the worktree's, loaded once by the caller, and pick each blob's smudge filter.
+fn tree_checkout(
+    repo: &GitRepository,
+    attributes: &[GitAttributeRule],
+    tree: GitTree,
+    path: &str,
+    rel: &Path,
+    mtime: Option<std::time::SystemTime>,
+    force: bool,
+    sparse: Option<&[PathBuf]>,
+) -> Result<(), WyagError> {
+    for item in tree.items {
+        let path_utf8 = match String::from_utf8(item.path) {
+            Ok(s) => s,
+            Err(m) => {
+                return Err(WyagError::new_with_error(
+                    "Failed to parse item path tree_checkout.",
+                    Box::new(m),
+                ));
+            }
+        };
+
+        let dest: PathBuf = PathBuf::from(path).join(&path_utf8);
+        let rel_dest: PathBuf = rel.join(&path_utf8);
+
+        // A gitlink's commit lives in the submodule's own repository, so like git it is checked out as an
+        // empty directory for the submodule to be cloned into
+        let is_gitlink = item.mode == b"160000";
+        let wants_dir = item.mode == b"40000" || is_gitlink;
+
+        if let Some(cone) = sparse {
+            let wanted = if wants_dir {
+                sparse_cone_has_dir(cone, &rel_dest)
+            } else {
+                sparse_cone_has_file(cone, &rel_dest)
+            };
+            if !wanted {
+                continue;
+            }
+        }
+
+        let is_dir = checkout_clear_path(&dest, &rel_dest, wants_dir, force)?;
+
+        if is_gitlink {
+            if !is_dir {
+                if let Err(m) = std::fs::create_dir(&dest) {
+                    return Err(WyagError::new_with_error(
+                        format!("Failed to create {}", rel_dest.display()).as_ref(),
+                        Box::new(m),
+                    ));
+                }
+            }
+            continue;
+        }
+
+        if item.mode != b"40000" {
+            write_blob_entry(&dest, &item.mode, repo, attributes, &item.sha, &rel_dest)?;
+            if let (Some(t), false) = (mtime, item.mode == b"120000") {
+                let res = std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(&dest)
+                    .and_then(|f| f.set_modified(t));
+                if let Err(m) = res {
+                    return Err(WyagError::new_with_error(
+                        format!(
+                            "Failed to set the modification time of {}",
+                            rel_dest.display()
+                        )
+                        .as_ref(),
+                        Box::new(m),
+                    ));
+                }
+            }
+            continue;
+        }
+
+        match object_read(&repo, &item.sha)? {
+            GObj::Tree(a) => {
+                // an existing directory is checked out into rather than recreated
+                if !is_dir {
+                    if let Err(m) = std::fs::create_dir(&dest) {
+                        return Err(WyagError::new_with_error(
+                            "Failed to create destination folder during tree_checkout",
+                            Box::new(m),
+                        ));
+                    };
+                }
+                tree_checkout(
+                    &repo,
+                    attributes,
+                    a,
+                    dest.to_str().unwrap(),
+                    &rel_dest,
+                    mtime,
+                    force,
+                    sparse,
+                )?;
+            }
+            GObj::Blob(_) | GObj::Commit(_) | GObj::Tag(_) => {
+                return Err(WyagError::new(
+                    "Expected to retrieve a Tree or a Blob, but received some other type instead",
+                ));
+            }
+        };
+    }
+
+    Ok(())
+}
+
+/// The directories of a cone-mode sparse checkout, if `core.sparseCheckout` is on and `info/sparse-checkout` exists.
+///
+/// Only cone patterns are understood: each `/dir/` line adds a directory, and the `/*` and `!/*/` lines that
+/// cone mode always starts with are implied. A `/dir/` followed by `!/dir/*/` is only the parent of a deeper
+/// cone directory, as `sparse-checkout set a/b` writes for `a`: its own files are in, its other subdirectories
+/// aren't, which the deeper entry already implies. Comments and blank lines are skipped.
+fn sparse_checkout_cone(repo: &GitRepository) -> Result<Option<Vec<PathBuf>>, WyagError> {
+    let enabled = match repo.conf.section(Some("core".to_owned())) {
+        Some(core) => core.iter().any(|(k, v)| {
+            k.eq_ignore_ascii_case("sparseCheckout") && v.trim().eq_ignore_ascii_case("true")
+        }),
+        None => false,
+    };
+    if !enabled {
+        return Ok(None);
+    }
+    let raw = match std::fs::read_to_string(repo_path_gr(repo, vec!["info", "sparse-checkout"])) {
+        Ok(r) => r,
+        Err(ref m) if m.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(m) => {
+            return Err(WyagError::new_with_error(
+                "Failed to read info/sparse-checkout",
+                Box::new(m),
+            ));
+        }
+    };
+    let mut cone: Vec<PathBuf> = Vec::new();
+    let mut parents: Vec<PathBuf> = Vec::new();
+    for line in raw.lines() {
+        let line = line.trim();
+        if line.len() == 0 || line.starts_with('#') || line == "/*" || line == "!/*/" {
+            continue;
+        }
+        if line.starts_with('!') {
+            // `!/dir/*/` leaves out dir's subdirectories, so dir is only a parent
+            let dir = line[1..].trim_end_matches("*/").trim_matches('/');
+            if dir.len() > 0 {
+                parents.push(PathBuf::from(dir));
+            }
+            continue;
+        }
+        let dir = line.trim_matches('/');
+        if dir.len() > 0 {
+            cone.push(PathBuf::from(dir));
+        }
+    }
+    cone.retain(|dir| !parents.contains(dir));
+    Ok(Some(cone))
+}
+
+/// Whether the cone takes in file `rel`: every file at the top level, in a cone directory, or directly in one of
+/// their parent directories.
+fn sparse_cone_has_file(cone: &[PathBuf], rel: &Path) -> bool {
+    let parent = match rel.parent() {
+        Some(p) if p.as_os_str().len() > 0 => p,
+        _ => return true,
+    };
+    cone.iter()
+        .any(|dir| parent.starts_with(dir) || dir.starts_with(parent))
+}
+
+/// Whether directory `rel` is in a cone directory, or leads to one.
+fn sparse_cone_has_dir(cone: &[PathBuf], rel: &Path) -> bool {
+    cone.iter()
+        .any(|dir| rel.starts_with(dir) || dir.starts_with(rel))
+}
+
+/// Makes way at `dest` for a directory (`want_dir`) or a file, returning whether a directory is already there to reuse.
+///
+/// A directory in the way of a file, or a file in the way of a directory, is a conflict unless `force` is set,
+/// in which case it is removed. With `force`, an existing file is also removed, so it can be recreated with the right kind.
+fn checkout_clear_path(
+    dest: &Path,
+    rel: &Path,
+    want_dir: bool,
+    force: bool,
+) -> Result<bool, WyagError> {
+    let md = match std::fs::symlink_metadata(dest) {
+        Ok(md) => md,
+        Err(_) => return Ok(false),
+    };
+    let found_dir = md.is_dir();
+    if found_dir == want_dir {
+        if !found_dir && force {
+            if let Err(m) = std::fs::remove_file(dest) {
+                return Err(WyagError::new_with_error(
+                    format!("Failed to replace {}", rel.display()).as_ref(),
+                    Box::new(m),
+                ));
+            }
+        }
+        return Ok(found_dir);
+    }
+    if !force {
+        return Err(WyagError::new(
+            format!(
+                "Checkout conflict at {}: the tree has a {} there, but the worktree has a {} (use --force to replace it)",
+                rel.display(),
+                if want_dir { "directory" } else { "file" },
+                if found_dir { "directory" } else { "file" }
+            )
+            .as_ref(),
+        ));
+    }
+    let res = if found_dir {
+        std::fs::remove_dir_all(dest)
+    } else {
+        std::fs::remove_file(dest)
+    };
+    if let Err(m) = res {
+        return Err(WyagError::new_with_error(
+            format!(
+                "Failed to remove {}, which was in the way of the checkout",
+                rel.display()
+            )
+            .as_ref(),
+            Box::new(m),
+        ));
+    }
+    Ok(false)
+}
+
+/// Writes blob `sha` to `dest` as the kind of file its tree `mode` calls for: a regular file,
+/// an executable one, or a symlink whose target is the blob's content.
+///
+/// `rel` is the worktree-relative path, used to look up any smudge filter in `attributes`.
+fn write_blob_entry(
+    dest: &Path,
+    mode: &[u8],
+    repo: &GitRepository,
+    attributes: &[GitAttributeRule],
+    sha: &str,
+    rel: &Path,
+) -> Result<(), WyagError> {
+    // Like the empty tree, the empty blob is well known, and needn't be in the store to be checked out
+    let data = if sha == empty_blob_sha(repo) {
+        Vec::new()
+    } else {
+        let (dfmt, data) = object_read_raw(repo, sha)?;
+        if ObjectType::from_bytes(&dfmt) != Some(ObjectType::Blob) {
+            return Err(WyagError::new(
+                format!(
+                    "Expected {} to be a blob, but it is a {}",
+                    sha,
+                    String::from_utf8_lossy(&dfmt)
+                )
+                .as_ref(),
+            ));
+        }
+        data
+    };
+
+    match mode {
+        b"120000" => {
+            let target = match String::from_utf8(data) {
+                Ok(s) => s,
+                Err(m) => {
+                    return Err(WyagError::new_with_error(
+                        "Symlink target is not valid unicode",
+                        Box::new(m),
+                    ));
+                }
+            };
+            #[cfg(unix)]
+            let res = std::os::unix::fs::symlink(&target, dest);
+            // Without symlinks, fall back to a plain file holding the target, like core.symlinks=false
+            #[cfg(not(unix))]
+            let res = std::fs::write(dest, target);
+            if let Err(m) = res {
+                return Err(WyagError::new_with_error(
+                    "Failed to create symlink during tree_checkout",
+                    Box::new(m),
+                ));
+            }
+        }
+        b"100644" | b"100755" => {
+            /* Blobs coming out of the repository pass through any configured smudge filter */
+            let data = filter_smudge(repo, attributes, rel.to_str().unwrap(), data)?;
+            if let Err(m) = std::fs::write(dest, data) {
+                return Err(WyagError::new_with_error(
+                    "Failed to write blob data to disk during tree_checkout",
+                    Box::new(m),
+                ));
+            }
+            #[cfg(unix)]
+            {
+                if mode == b"100755" {
+                    use std::os::unix::fs::PermissionsExt;
+                    let res = std::fs::metadata(dest).and_then(|md| {
+                        let mut perms = md.permissions();
+                        perms.set_mode(perms.mode() | 0o111);
+                        std::fs::set_permissions(dest, perms)
+                    });
+                    if let Err(m) = res {
+                        return Err(WyagError::new_with_error(
+                            "Failed to mark file as executable during tree_checkout",
+                            Box::new(m),
+                        ));
+                    }
+                }
+            }
+        }
+        _ => {
+            return Err(WyagError::new(
+                format!(
+                    "Cannot check out {} with mode {}",
+                    rel.display(),
+                    String::from_utf8_lossy(mode)
+                )
+                .as_ref(),
+            ));
+        }
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod checkout_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn writes_regular_and_executable_files() {
+        let path = scratch_dir("wyag_write_blob_entry");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let sha = write_loose_object(&gr, b"blob", b"#!/bin/sh\necho hi\n");
+        let root = PathBuf::from(&path);
+
+        let regular = root.join("regular");
+        write_blob_entry(&regular, b"100644", &gr, &[], &sha, Path::new("regular")).unwrap();
+        assert_eq!(std::fs::read(&regular).unwrap(), b"#!/bin/sh\necho hi\n");
+
+        let exec = root.join("exec");
+        write_blob_entry(&exec, b"100755", &gr, &[], &sha, Path::new("exec")).unwrap();
+        assert_eq!(std::fs::read(&exec).unwrap(), b"#!/bin/sh\necho hi\n");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&regular).unwrap().permissions().mode();
+            assert_eq!(mode & 0o111, 0);
+            let mode = std::fs::metadata(&exec).unwrap().permissions().mode();
+            assert_eq!(mode & 0o111, 0o111);
+        }
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_symlinks() {
+        let path = scratch_dir("wyag_write_blob_entry_symlink");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let sha = write_loose_object(&gr, b"blob", b"target.txt");
+        let link = PathBuf::from(&path).join("link");
+
+        write_blob_entry(&link, b"120000", &gr, &[], &sha, Path::new("link")).unwrap();
+        assert_eq!(
+            std::fs::read_link(&link).unwrap(),
+            PathBuf::from("target.txt")
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_non_blobs_and_unknown_modes() {
+        let path = scratch_dir("wyag_write_blob_entry_bad");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let blob = write_loose_object(&gr, b"blob", b"x");
+        let commit = write_commit(&gr, &[], 1000, "c\n");
+        let dest = PathBuf::from(&path).join("out");
+
+        assert!(write_blob_entry(&dest, b"100644", &gr, &[], &commit, Path::new("out")).is_err());
+        assert!(write_blob_entry(&dest, b"160000", &gr, &[], &blob, Path::new("out")).is_err());
+        assert!(!dest.exists());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn checking_out_a_commit_detaches_head_and_a_branch_attaches_it() {
+        let path = scratch_dir("wyag_checkout_detached");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let blob = write_loose_object(&gr, b"blob", b"hello\n");
+        let tree = write_tree(&gr, &[("100644", "a.txt", &blob)]);
+        let commit = write_loose_object(
+            &gr,
+            b"commit",
+            format!("tree {}\n\nfirst\n", tree).as_bytes(),
+        );
+        set_ref(&gr, "refs/heads/topic", &commit);
+        let head = || std::fs::read_to_string(repo_path_gr(&gr, vec!["HEAD"])).unwrap();
+        let out = |name: &str| PathBuf::from(&path).join(name).to_str().unwrap().to_owned();
+
+        assert_eq!(
+            checkout(&gr, &tree, &out("tree"), false, false).unwrap(),
+            None
+        );
+        assert_eq!(head(), "ref: refs/heads/master\n");
+
+        assert_eq!(
+            checkout(&gr, &commit, &out("commit"), false, false).unwrap(),
+            Some(commit.clone())
+        );
+        assert_eq!(head(), format!("{}\n", commit));
+        assert_eq!(
+            head_state(&gr).unwrap(),
+            HeadState::Detached(commit.clone())
+        );
+
+        assert_eq!(
+            checkout(&gr, "topic", &out("branch"), false, false).unwrap(),
+            None
+        );
+        assert_eq!(head(), "ref: refs/heads/topic\n");
+
+        // `@` is HEAD, so it stays on the branch
+        assert_eq!(checkout(&gr, "@", &out("at"), false, false).unwrap(), None);
+        assert_eq!(head(), "ref: refs/heads/topic\n");
+
+        // A branch that only exists in packed-refs is still a branch
+        std::fs::write(
+            repo_path_gr(&gr, vec!["packed-refs"]),
+            format!("# pack-refs with: peeled\n{} refs/heads/packed\n", commit),
+        )
+        .unwrap();
+        assert_eq!(
+            checkout(&gr, "packed", &out("packed"), false, false).unwrap(),
+            None
+        );
+        assert_eq!(head(), "ref: refs/heads/packed\n");
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn orphan_branches_start_a_new_root_commit() {
+        let path = scratch_dir("wyag_checkout_orphan");
+        let gr = with_identity(GitRepository::repo_create(&path).unwrap());
+        let first = write_commit(&gr, &[], 1000, "First\n");
+        set_ref(&gr, "refs/heads/master", &first);
+
+        assert!(checkout_orphan(&gr, "master").is_err());
+        assert!(checkout_orphan(&gr, "../escape").is_err());
+        checkout_orphan(&gr, "docs").unwrap();
+        assert_eq!(
+            head_state(&gr).unwrap(),
+            HeadState::Unborn("docs".to_owned())
+        );
+
+        let blob = write_loose_object(&gr, b"blob", b"docs\n");
+        let tree = write_tree(&gr, &[("100644", "README", &blob)]);
+        let sha = commit_create(&gr, &tree, "Docs", false, 2000, "+0000", true).unwrap();
+        match object_read(&gr, &sha).unwrap() {
+            GObj::Commit(c) => assert_eq!(c.parents(), Vec::<String>::new()),
+            _ => panic!("expected a commit"),
+        }
+        assert_eq!(ref_resolve(&gr, "refs/heads/docs").unwrap(), sha);
+        assert_eq!(ref_resolve(&gr, "refs/heads/master").unwrap(), first);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn set_times_stamps_files_with_the_commit_date() {
+        let path = scratch_dir("wyag_checkout_set_times");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let blob = write_loose_object(&gr, b"blob", b"hello\n");
+        let sub = write_tree(&gr, &[("100644", "inner.txt", &blob)]);
+        let tree = write_tree(&gr, &[("100644", "a.txt", &blob), ("40000", "dir", &sub)]);
+        let commit = write_loose_object(
+            &gr,
+            b"commit",
+            format!(
+                "tree {}\n\
+                 author A U Thor <author@example.com> 1500000000 +0000\n\
+                 committer A U Thor <author@example.com> 1600000000 +0000\n\
+                 \n\
+                 stamped\n",
+                tree
+            )
+            .as_bytes(),
+        );
+        let date = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1600000000);
+        let out = PathBuf::from(&path).join("out");
+
+        checkout(&gr, &commit, out.to_str().unwrap(), true, false).unwrap();
+        for file in &[out.join("a.txt"), out.join("dir").join("inner.txt")] {
+            assert_eq!(std::fs::metadata(file).unwrap().modified().unwrap(), date);
+        }
+
+        // A date before the epoch is negative, and is stamped as such rather than wrapping around
+        let old = write_loose_object(
+            &gr,
+            b"commit",
+            format!(
+                "tree {}\n\
+                 committer A U Thor <author@example.com> -86400 +0000\n\
+                 \n\
+                 before the epoch\n",
+                tree
+            )
+            .as_bytes(),
+        );
+        let before = PathBuf::from(&path).join("before");
+        checkout(&gr, &old, before.to_str().unwrap(), true, false).unwrap();
+        assert_eq!(
+            std::fs::metadata(before.join("a.txt"))
+                .unwrap()
+                .modified()
+                .unwrap(),
+            std::time::UNIX_EPOCH - std::time::Duration::from_secs(86400)
+        );
+
+        let plain = PathBuf::from(&path).join("plain");
+        checkout(&gr, &commit, plain.to_str().unwrap(), false, false).unwrap();
+        assert_ne!(
+            std::fs::metadata(plain.join("a.txt"))
+                .unwrap()
+                .modified()
+                .unwrap(),
+            date
+        );
+        let from_tree = PathBuf::from(&path).join("from_tree");
+        assert!(checkout(&gr, &tree, from_tree.to_str().unwrap(), true, false).is_err());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn directory_where_a_file_belongs_is_a_conflict_unless_forced() {
+        let path = scratch_dir("wyag_checkout_df_conflict");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let blob = write_loose_object(&gr, b"blob", b"hello\n");
+        let tree = write_tree(&gr, &[("100644", "foo", &blob)]);
+        let out = PathBuf::from(&path).join("out");
+        std::fs::create_dir_all(out.join("foo").join("nested")).unwrap();
+        let tree_obj = || match object_read(&gr, &tree).unwrap() {
+            GObj::Tree(t) => t,
+            _ => panic!("expected a tree"),
+        };
+
+        let err = tree_checkout(
+            &gr,
+            &[],
+            tree_obj(),
+            out.to_str().unwrap(),
+            Path::new(""),
+            None,
+            false,
+            None,
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains(
+            "Checkout conflict at foo: the tree has a file there, but the worktree has a directory"
+        ));
+        assert!(out.join("foo").is_dir());
+
+        // Without --force, a populated directory is refused outright
+        assert!(checkout(&gr, &tree, out.to_str().unwrap(), false, false).is_err());
+        checkout(&gr, &tree, out.to_str().unwrap(), false, true).unwrap();
+        assert_eq!(std::fs::read(out.join("foo")).unwrap(), b"hello\n");
+
+        // and the reverse: a file where the tree has a directory
+        let nested = write_tree(&gr, &[("40000", "foo", &tree)]);
+        checkout(&gr, &nested, out.to_str().unwrap(), false, true).unwrap();
+        assert_eq!(
+            std::fs::read(out.join("foo").join("foo")).unwrap(),
+            b"hello\n"
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn the_empty_blob_checks_out_without_being_stored() {
+        let path = scratch_dir("wyag_checkout_empty_blob");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let tree = write_tree(&gr, &[("100644", "empty", empty_blob_sha(&gr))]);
+        assert!(!object_exists(&gr, empty_blob_sha(&gr)));
+        let out = PathBuf::from(&path).join("out");
+
+        checkout(&gr, &tree, out.to_str().unwrap(), false, false).unwrap();
+        assert_eq!(std::fs::read(out.join("empty")).unwrap(), b"");
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn gitlinks_check_out_as_empty_directories() {
+        let path = scratch_dir("wyag_checkout_gitlink");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let blob = write_loose_object(&gr, b"blob", b"hello\n");
+        // The submodule's commit isn't in this repository
+        let module = "0123456789abcdef0123456789abcdef01234567";
+        let tree = write_tree(
+            &gr,
+            &[("100644", "README", &blob), ("160000", "module", module)],
+        );
+        let out = PathBuf::from(&path).join("out");
+
+        checkout(&gr, &tree, out.to_str().unwrap(), false, false).unwrap();
+        assert_eq!(std::fs::read(out.join("README")).unwrap(), b"hello\n");
+        assert!(out.join("module").is_dir());
+        assert_eq!(std::fs::read_dir(out.join("module")).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn sparse_cone_writes_only_the_listed_directory() {
+        let path = scratch_dir("wyag_checkout_sparse");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let blob = write_loose_object(&gr, b"blob", b"hello\n");
+        let deep = write_tree(&gr, &[("100644", "d.txt", &blob)]);
+        let docs = write_tree(
+            &gr,
+            &[("100644", "guide.md", &blob), ("40000", "deep", &deep)],
+        );
+        let src = write_tree(&gr, &[("100644", "main.rs", &blob)]);
+        let tree = write_tree(
+            &gr,
+            &[
+                ("40000", "docs", &docs),
+                ("100644", "README", &blob),
+                ("40000", "src", &src),
+            ],
+        );
+        std::fs::write(
+            repo_file_gr(&gr, true, vec!["info", "sparse-checkout"]).unwrap(),
+            "/*\n!/*/\n/docs/\n",
+        )
+        .unwrap();
+        let out = PathBuf::from(&path).join("out");
+
+        // The pattern file alone does nothing until core.sparseCheckout is on
+        checkout(&gr, &tree, out.to_str().unwrap(), false, false).unwrap();
+        assert!(out.join("src").join("main.rs").is_file());
+
+        let mut conf = Ini::load_from_file(gr.gitdir.join("config")).unwrap();
+        conf.with_section(Some("core".to_owned()))
+            .set("sparseCheckout", "true");
+        let gr = GitRepository { conf: conf, ..gr };
+        let sparse = PathBuf::from(&path).join("sparse");
+        checkout(&gr, &tree, sparse.to_str().unwrap(), false, false).unwrap();
+        assert!(sparse.join("README").is_file());
+        assert!(sparse.join("docs").join("guide.md").is_file());
+        assert!(sparse.join("docs").join("deep").join("d.txt").is_file());
+        assert!(!sparse.join("src").exists());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn nested_sparse_cone_takes_only_the_parents_files() {
+        let path = scratch_dir("wyag_checkout_sparse_nested");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let blob = write_loose_object(&gr, b"blob", b"hello\n");
+        let leaf = write_tree(&gr, &[("100644", "f.txt", &blob)]);
+        let a = write_tree(
+            &gr,
+            &[
+                ("100644", "top.txt", &blob),
+                ("40000", "b", &leaf),
+                ("40000", "other", &leaf),
+            ],
+        );
+        let tree = write_tree(&gr, &[("40000", "a", &a), ("100644", "README", &blob)]);
+        // What `git sparse-checkout set a/b` writes
+        std::fs::write(
+            repo_file_gr(&gr, true, vec!["info", "sparse-checkout"]).unwrap(),
+            "/*\n!/*/\n/a/\n!/a/*/\n/a/b/\n",
+        )
+        .unwrap();
+        let mut conf = Ini::load_from_file(gr.gitdir.join("config")).unwrap();
+        conf.with_section(Some("core".to_owned()))
+            .set("sparseCheckout", "true");
+        let gr = GitRepository { conf: conf, ..gr };
+
+        let cone = sparse_checkout_cone(&gr).unwrap().unwrap();
+        assert!(!sparse_cone_has_dir(&cone, Path::new("a/other")));
+        let out = PathBuf::from(&path).join("out");
+        checkout(&gr, &tree, out.to_str().unwrap(), false, false).unwrap();
+        assert!(out.join("README").is_file());
+        assert!(out.join("a").join("top.txt").is_file());
+        assert!(out.join("a").join("b").join("f.txt").is_file());
+        assert!(!out.join("a").join("other").exists());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn restoring_one_path_leaves_the_rest_alone() {
+        let path = scratch_dir("wyag_checkout_one_path");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let old_a = write_loose_object(&gr, b"blob", b"old a\n");
+        let old_b = write_loose_object(&gr, b"blob", b"old b\n");
+        let old_sub = write_tree(&gr, &[("100644", "c.txt", &old_b)]);
+        let tree = write_tree(
+            &gr,
+            &[
+                ("100644", "a.txt", &old_a),
+                ("100644", "b.txt", &old_b),
+                ("40000", "sub", &old_sub),
+            ],
+        );
+        let old = write_loose_object(&gr, b"commit", format!("tree {}\n\nold\n", tree).as_bytes());
+        let newer = write_commit(&gr, &[&old], 2000, "newer\n");
+        set_ref(&gr, "refs/heads/master", &newer);
+        let root = PathBuf::from(&path);
+        std::fs::write(root.join("a.txt"), "new a\n").unwrap();
+        std::fs::write(root.join("b.txt"), "new b\n").unwrap();
+
+        checkout_path(&gr, &[], &old, Path::new("a.txt")).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("a.txt")).unwrap(),
+            "old a\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.join("b.txt")).unwrap(),
+            "new b\n"
+        );
+        assert_eq!(ref_resolve(&gr, "HEAD").unwrap(), newer);
+
+        checkout_path(&gr, &[], &old, Path::new("sub/c.txt")).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("sub").join("c.txt")).unwrap(),
+            "old b\n"
+        );
+
+        let err = checkout_path(&gr, &[], &old, Path::new("missing.txt"))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("did not match any file"));
+        assert!(checkout_path(&gr, &[], &old, Path::new("a.txt/deeper")).is_err());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn restore_reverts_a_modified_file_to_head() {
+        let path = scratch_dir("wyag_restore");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let root = PathBuf::from(&path);
+        std::fs::write(root.join("a.txt"), "edited\n").unwrap();
+        assert!(restore(&gr, &[], Path::new("a.txt"), false).is_err());
+
+        let blob = write_loose_object(&gr, b"blob", b"committed\n");
+        let tree = write_tree(&gr, &[("100644", "a.txt", &blob)]);
+        let commit = write_loose_object(
+            &gr,
+            b"commit",
+            format!("tree {}\n\nfirst\n", tree).as_bytes(),
+        );
+        set_ref(&gr, "refs/heads/master", &commit);
+
+        restore(&gr, &[], Path::new("a.txt"), false).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("a.txt")).unwrap(),
+            "committed\n"
+        );
+
+        std::fs::remove_file(root.join("a.txt")).unwrap();
+        restore(&gr, &[], Path::new("a.txt"), false).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("a.txt")).unwrap(),
+            "committed\n"
+        );
+
+        let err = restore(&gr, &[], Path::new("a.txt"), true).err().unwrap();
+        assert!(err.to_string().contains("can't write the index"));
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn restore_takes_files_from_the_index() {
+        let path = scratch_dir("wyag_restore_index");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let root = PathBuf::from(&path);
+        let committed = write_loose_object(&gr, b"blob", b"committed\n");
+        let staged = write_loose_object(&gr, b"blob", b"staged\n");
+        let tree = write_tree(&gr, &[("100644", "a.txt", &committed)]);
+        let commit = write_loose_object(
+            &gr,
+            b"commit",
+            format!("tree {}\n\nfirst\n", tree).as_bytes(),
+        );
+        set_ref(&gr, "refs/heads/master", &commit);
+        let index = index_bytes(&[
+            (0o100644, &staged, "a.txt"),
+            (0o100644, &staged, "dir/b.txt"),
+            (0o100755, &staged, "dir/sub/run.sh"),
+        ]);
+        std::fs::write(repo_path_gr(&gr, vec!["index"]), index).unwrap();
+
+        std::fs::write(root.join("a.txt"), "edited\n").unwrap();
+        restore(&gr, &[], Path::new("a.txt"), false).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("a.txt")).unwrap(),
+            "staged\n"
+        );
+
+        // A directory brings back everything the index has below it
+        restore(&gr, &[], Path::new("dir"), false).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(root.join("dir").join("b.txt")).unwrap(),
+            "staged\n"
+        );
+        assert!(root.join("dir").join("sub").join("run.sh").is_file());
+
+        let err = restore(&gr, &[], Path::new("di"), false).err().unwrap();
+        assert!(err
+            .to_string()
+            .contains("did not match any file known to the index"));
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
+/// EndRegion: Checkout
+
+/// Region: Ref
+
+/// Points `refname` (e.g. `refs/heads/master`, or `HEAD` when detached) at `sha`, creating directories as needed.
+fn ref_update(repo: &GitRepository, refname: &str, sha: &str) -> Result<(), WyagError> {
+    require_writable(repo)?;
+    let path = repo_file_gr(repo, true, refname.split('/').collect())?;
+    if let Err(m) = std::fs::write(path, format!("{}\n", sha)) {
+        return Err(WyagError::new_with_error(
+            format!("Failed to update ref {}", refname).as_ref(),
+            Box::new(m),
+        ));
+    }
+    Ok(())
+}
+
+fn ref_resolve(repo: &GitRepository, ref_str: &str) -> Result<String, WyagError> {
+    let path = repo_file_gr(&repo, false, vec![ref_str])?;
+    let s = match std::fs::read_to_string(path) {
+        Ok(s) => s.trim().to_owned(),
+        Err(m) => {
+            return Err(WyagError::new_with_error(
+                "Failed to read file",
+                Box::new(m),
+            ));
+        }
+    };
+    if s.starts_with("ref: ") {
+        return ref_resolve(repo, &s["ref: ".len()..]);
+    } else {
+        return Ok(s.to_owned());
+    }
+}
+
+enum RefType {
+    RefTypeSha(String),
+    RefTypeDict(LinkedHashMap<String, RefType>),
+}
+
+fn ref_list(
+    repo: &GitRepository,
+    path: Option<&str>,
+) -> Result<LinkedHashMap<String, RefType>, WyagError> {
+    let realPath: PathBuf = match path {
+        Some(p) => PathBuf::from(p),
+        None => repo_dir_gr(repo, false, vec!["refs"])?,
+    };
+
+    let mut ret: LinkedHashMap<String, RefType> = LinkedHashMap::new();
+
+    // Git shows refs sorted.  To do the same, we use
+    // a LinkedHashMap and sort the output of the directory read
+    let mut i = std::fs::read_dir(realPath).expect("Failed to read path.");
+    while let Some(item) = i.next() {
+        let can = match item {
+            Ok(fd) => fd,
+            Err(m) => {
+                return Err(WyagError::new_with_error(
+                    "Failed to read item in directory",
+                    Box::new(m),
+                ));
+            }
+        };
+
+        let cf = can
+            .file_name()
+            .to_str()
+            .expect("Failed to unpack OsString while reading ref_list")
+            .to_owned();
+        if can.path().is_dir() {
+            let r = ref_list(repo, Some(can.path().to_str().unwrap()))?;
+            ret.insert(cf, RefType::RefTypeDict(r));
+        } else {
+            ret.insert(
+                cf.clone(),
+                RefType::RefTypeSha(ref_resolve(&repo, can.path().to_str().unwrap())?),
+            );
+        }
+    }
+    Ok(ret)
+}
+
+/// Flattens the output of `ref_list` into the SHAs it points to.
+fn ref_list_shas(refs: &LinkedHashMap<String, RefType>, out: &mut Vec<String>) {
+    for (_, v) in refs {
+        match v {
+            RefType::RefTypeSha(s) => out.push(s.to_owned()),
+            RefType::RefTypeDict(d) => ref_list_shas(d, out),
+        }
+    }
+}
+
+/// Flattens the output of `ref_list` into ref names, joining nested directories with `/`.
+fn ref_list_names(refs: &LinkedHashMap<String, RefType>, prefix: &str, out: &mut Vec<String>) {
+    for (k, v) in refs {
+        let name = format!("{}{}", prefix, k);
+        match v {
+            RefType::RefTypeSha(_) => out.push(name),
+            RefType::RefTypeDict(d) => ref_list_names(d, &format!("{}/", name), out),
+        }
+    }
+}
+
+///
+/// with_hash should be default true
+/// predix should be default empty string
+fn show_ref(
+    repo: &GitRepository,
+    refs: LinkedHashMap<String, RefType>,
+    with_hash: bool,
+    prefix: Option<&str>,
+) {
+    for (k, v) in refs {
+        match v {
+            RefType::RefTypeSha(s) => {
+                let first = if with_hash {
+                    s + " "
+                } else {
+                    String::default()
+                };
+                let second = if let Some(p) = prefix {
+                    let mut p = PathBuf::from(p);
+                    let mut st = String::default();
+                    st.push(std::path::MAIN_SEPARATOR);
+                    p = p.join(st);
+                    p.to_str().unwrap().to_owned()
+                } else {
+                    String::default()
+                };
+                format!("{}{}{}", first, second, k);
+            }
+            RefType::RefTypeDict(d) => show_ref(repo, d, with_hash, prefix),
+        }
+    }
+}
+
+pub fn cmd_show_ref() -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            println!("No repository was found, cannot use wyag-show_ref");
+            return Ok(());
+        }
+    };
+
+    let reflist = ref_list(&repo, None)?;
+    show_ref(&repo, reflist, false, Some("refs"));
+    Ok(())
+}
+
+/// Prints the SHA of the exact ref `name` (e.g. `refs/heads/master`), erroring if it doesn't exist.
+///
+/// Unlike `object_find`, no short-name or hash matching is attempted: scripts rely on this to check refs.
+pub fn cmd_show_ref_verify(name: &str) -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            return Err(WyagError::new(
+                "No repository was found, cannot use wyag-show_ref",
+            ));
+        }
+    };
+
+    let sha = show_ref_verify(&repo, name)?;
+    println!("{} {}", sha, name);
+    Ok(())
+}
+
+/// Reads candidate ref names from stdin, one per line, and echoes those the repository doesn't already have.
+pub fn cmd_show_ref_exclude_existing() -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            return Err(WyagError::new(
+                "No repository was found, cannot use wyag-show_ref",
+            ));
+        }
+    };
+
+    let stdin = io::stdin();
+    for line in show_ref_exclude_existing(&repo, stdin.lock())? {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// Keeps the lines of `input` whose ref doesn't exist yet.
+///
+/// A line is either a bare ref name or `<sha> <refname>`, as `show-ref` prints; a trailing peel
+/// marker `^{}` is ignored when checking for the ref.
+fn show_ref_exclude_existing<R: io::BufRead>(
+    repo: &GitRepository,
+    input: R,
+) -> Result<Vec<String>, WyagError> {
+    let existing = refs(repo)?;
+
+    let mut ret: Vec<String> = Vec::new();
+    for line in input.lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(m) => {
+                return Err(WyagError::new_with_error(
+                    "Failed to read ref names from stdin",
+                    Box::new(m),
+                ));
+            }
+        };
+        let line = line.trim_end();
+        let name = match line.split_whitespace().last() {
+            Some(n) => n,
+            None => continue,
+        };
+        let name = name.trim_end_matches("^{}");
+        if !name.starts_with("refs/") || !existing.contains_key(name) {
+            ret.push(line.to_owned());
+        }
+    }
+    Ok(ret)
+}
+
+fn show_ref_verify(repo: &GitRepository, name: &str) -> Result<String, WyagError> {
+    let not_valid = format!("'{}' - not a valid ref", name);
+    if !name.starts_with("refs/") {
+        return Err(WyagError::new(not_valid.as_ref()));
+    }
+    let path = repo_path_gr(repo, name.split('/').collect());
+    if path.is_file() {
+        return ref_resolve(repo, name);
+    }
+    match packed_refs_lookup(repo, name)? {
+        Some(sha) => Ok(sha),
+        None => Err(WyagError::new(not_valid.as_ref())),
+    }
+}
+
+#[cfg(test)]
+mod show_ref_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn verify_existing_ref_returns_sha() {
+        let path = scratch_dir("wyag_show_ref_verify");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let sha = "ce013625030ba8dba906f756967f9e9ca394464a";
+        std::fs::write(
+            repo_path_gr(&gr, vec!["refs", "heads", "master"]),
+            format!("{}\n", sha),
+        )
+        .unwrap();
+
+        assert_eq!(show_ref_verify(&gr, "refs/heads/master").unwrap(), sha);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn verify_finds_packed_only_ref() {
+        let path = scratch_dir("wyag_show_ref_verify_packed");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let packed = "# pack-refs with: peeled fully-peeled sorted\n\
+                      1111111111111111111111111111111111111111 refs/tags/v1.0\n";
+        std::fs::write(repo_path_gr(&gr, vec!["packed-refs"]), packed).unwrap();
+
+        assert_eq!(
+            show_ref_verify(&gr, "refs/tags/v1.0").unwrap(),
+            "1111111111111111111111111111111111111111"
+        );
+        assert!(show_ref_verify(&gr, "refs/tags/v2.0").is_err());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn for_each_ref_filters_by_glob_and_prefix() {
+        let path = scratch_dir("wyag_for_each_ref");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let commit = write_commit(&gr, &[], 1000, "Initial commit\n");
+        let tag = write_loose_object(
+            &gr,
+            b"tag",
+            format!(
+                "object {}\ntype commit\ntag v2\ntagger A U Thor <author@example.com> 1000 +0000\n\nv2\n",
+                commit
+            )
+            .as_bytes(),
+        );
+        set_ref(&gr, "refs/heads/master", &commit);
+        set_ref(&gr, "refs/heads/topic", &commit);
+        set_ref(&gr, "refs/tags/v1", &commit);
+        set_ref(&gr, "refs/tags/v2", &tag);
+
+        assert_eq!(
+            for_each_ref(&gr, Some("refs/tags/*")).unwrap(),
+            vec![
+                format!("{} commit\trefs/tags/v1", commit),
+                format!("{} tag\trefs/tags/v2", tag),
+            ]
+        );
+        assert_eq!(for_each_ref(&gr, Some("refs/heads")).unwrap().len(), 2);
+        assert_eq!(for_each_ref(&gr, Some("refs/head")).unwrap().len(), 0);
+        assert_eq!(for_each_ref(&gr, None).unwrap().len(), 4);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn exclude_existing_echoes_only_missing_refs() {
+        let path = scratch_dir("wyag_show_ref_exclude_existing");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let sha = "ce013625030ba8dba906f756967f9e9ca394464a";
+        set_ref(&gr, "refs/heads/master", sha);
+        set_ref(&gr, "refs/tags/v1", sha);
+        // Packed refs exist too, though they have no loose file
+        let packed = format!("# pack-refs with: peeled\n{} refs/tags/v0\n", sha);
+        std::fs::write(repo_path_gr(&gr, vec!["packed-refs"]), packed).unwrap();
+
+        let input = format!(
+            "refs/heads/master\nrefs/heads/topic\n{} refs/tags/v1^{{}}\n\n{} refs/tags/v2\nrefs/tags/v0\n",
+            sha, sha
+        );
+        let missing = show_ref_exclude_existing(&gr, input.as_bytes()).unwrap();
+        assert_eq!(
+            missing,
+            vec![
+                "refs/heads/topic".to_owned(),
+                format!("{} refs/tags/v2", sha)
+            ]
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn verify_missing_ref_errors() {
+        let path = scratch_dir("wyag_show_ref_verify_missing");
+        let gr = GitRepository::repo_create(&path).unwrap();
+
+        assert!(show_ref_verify(&gr, "refs/heads/master").is_err());
+        // short names are not accepted by --verify
+        assert!(show_ref_verify(&gr, "master").is_err());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn refs_merges_loose_packed_and_head() {
+        let path = scratch_dir("wyag_refs_map");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let a = "ce013625030ba8dba906f756967f9e9ca394464a";
+        let b = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+        set_ref(&gr, "refs/heads/master", a);
+        set_ref(&gr, "refs/heads/topic/x", a);
+        std::fs::write(
+            repo_path_gr(&gr, vec!["refs", "heads", "alias"]),
+            "ref: refs/heads/master\n",
+        )
+        .unwrap();
+        std::fs::write(
+            repo_path_gr(&gr, vec!["packed-refs"]),
+            format!(
+                "# pack-refs with: peeled\n{} refs/heads/master\n{} refs/tags/v1\n^{}\n",
+                b, b, a
+            ),
+        )
+        .unwrap();
+
+        let map = refs(&gr).unwrap();
+        let names: Vec<&String> = map.keys().collect();
+        assert_eq!(
+            names,
+            vec![
+                "HEAD",
+                "refs/heads/alias",
+                "refs/heads/master",
+                "refs/heads/topic/x",
+                "refs/tags/v1"
+            ]
+        );
+        assert_eq!(
+            map["HEAD"],
+            RefTarget::Symbolic("refs/heads/master".to_owned())
+        );
+        assert_eq!(
+            map["refs/heads/alias"],
+            RefTarget::Symbolic("refs/heads/master".to_owned())
+        );
+        // loose refs win over stale packed entries
+        assert_eq!(map["refs/heads/master"], RefTarget::Direct(a.to_owned()));
+        assert_eq!(map["refs/tags/v1"], RefTarget::Direct(b.to_owned()));
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
+
+/// What a ref points at.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RefTarget {
+    /// An object SHA
+    Direct(String),
+    /// Another ref, as in `ref: refs/heads/master`
+    Symbolic(String),
+}
+
+/// Every ref in the repository, loose or packed, plus HEAD, keyed by full name.
+///
+/// Symbolic refs are reported as such rather than followed. A loose ref hides a packed one of the same name.
+///
+/// ```
+/// use wyag_rust::RefTarget;
+/// # let dir = std::env::temp_dir().join("wyag_doc_refs");
+/// # let _ = std::fs::remove_dir_all(&dir);
+/// let repo = wyag_rust::GitRepository::repo_create(dir.to_str().unwrap()).unwrap();
+/// # std::fs::write(
+/// #     dir.join(".git").join("refs").join("heads").join("master"),
+/// #     "ce013625030ba8dba906f756967f9e9ca394464a\n",
+/// # ).unwrap();
+/// for (name, target) in wyag_rust::refs(&repo).unwrap() {
+///     match target {
+///         RefTarget::Direct(sha) => println!("{} {}", sha, name),
+///         RefTarget::Symbolic(to) => println!("{} -> {}", name, to),
+///     }
+/// }
+/// # std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+pub fn refs(
+    repo: &GitRepository,
+) -> Result<std::collections::BTreeMap<String, RefTarget>, WyagError> {
+    let mut ret: std::collections::BTreeMap<String, RefTarget> = std::collections::BTreeMap::new();
+    for (sha, name) in packed_refs_read(repo)? {
+        ret.insert(name, RefTarget::Direct(sha));
+    }
+
+    let mut loose: Vec<String> = Vec::new();
+    refs_walk_loose(&repo_path_gr(repo, vec!["refs"]), "refs", &mut loose)?;
+    loose.push("HEAD".to_owned());
+    for name in loose {
+        let path = repo_path_gr(repo, name.split('/').collect());
+        if !path.is_file() {
+            continue;
+        }
+        let s = match std::fs::read_to_string(&path) {
+            Ok(s) => s.trim().to_owned(),
+            Err(m) => {
+                return Err(WyagError::new_with_error(
+                    format!("Failed to read ref {}", name).as_ref(),
+                    Box::new(m),
+                ));
+            }
+        };
+        let target = if s.starts_with("ref: ") {
+            RefTarget::Symbolic(s["ref: ".len()..].to_owned())
+        } else {
+            RefTarget::Direct(s)
+        };
+        ret.insert(name, target);
+    }
+    Ok(ret)
+}
+
+/// Prints `<sha> <type>\t<refname>` for every ref under `refs/` matching `pattern` (all of them when None).
+pub fn cmd_for_each_ref(pattern: Option<&str>) -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            return Err(WyagError::new(
+                "No repository was found, cannot use wyag-for-each-ref",
+            ));
+        }
+    };
+
+    for line in for_each_ref(&repo, pattern)? {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// Lists the refs matching `pattern`, sorted by name, with the SHA they resolve to and that object's type.
+///
+/// An annotated tag reports `tag`, its own type, as git does. Symbolic refs show their target's object.
+fn for_each_ref(repo: &GitRepository, pattern: Option<&str>) -> Result<Vec<String>, WyagError> {
+    let mut ret: Vec<String> = Vec::new();
+    for (name, target) in refs(repo)? {
+        if !name.starts_with("refs/") {
+            continue;
+        }
+        if let Some(p) = pattern {
+            if !ref_pattern_match(p, &name) {
+                continue;
+            }
+        }
+        let sha = match target {
+            RefTarget::Direct(s) => s,
+            RefTarget::Symbolic(_) => ref_resolve(repo, &name)?,
+        };
+        let (otype, _) = object_read_header(repo, &sha)?;
+        ret.push(format!("{} {}\t{}", sha, otype, name));
+    }
+    Ok(ret)
+}
+
+/// Matches a full ref name against a for-each-ref style pattern.
+///
+/// A pattern matches as a glob (`refs/tags/v*`), or as a prefix ending at a `/` (`refs/heads` matches
+/// `refs/heads/topic` but `refs/head` matches nothing).
+fn ref_pattern_match(pattern: &str, refname: &str) -> bool {
+    if glob_match(pattern, refname) {
+        return true;
+    }
+    let prefix = pattern.trim_end_matches('/');
+    refname.starts_with(prefix) && refname[prefix.len()..].starts_with('/')
+}
+
+/// Collects the names of the loose ref files under `dir`, which is the ref directory `prefix`.
+fn refs_walk_loose(dir: &Path, prefix: &str, out: &mut Vec<String>) -> Result<(), WyagError> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(m) => {
+            return Err(WyagError::new_with_error(
+                "Failed to read refs directory",
+                Box::new(m),
+            ));
+        }
+    };
+    for entry in entries {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(m) => {
+                return Err(WyagError::new_with_error(
+                    "Failed to read item in directory",
+                    Box::new(m),
+                ));
+            }
+        };
+        let name = format!("{}/{}", prefix, entry.file_name().to_string_lossy());
+        if entry.path().is_dir() {
+            refs_walk_loose(&entry.path(), &name, out)?;
+        } else {
+            out.push(name);
+        }
+    }
+    Ok(())
+}
+
+/// EndRegion: Ref
+
+/// Region: Branch
+
+/// What `.git/HEAD` points at.
+#[derive(Debug, PartialEq)]
+enum HeadState {
+    /// On a branch that has commits
+    Attached(String),
+    /// Holding a raw commit SHA rather than a branch
+    Detached(String),
+    /// On a branch with no commits yet, e.g. a fresh repository
+    Unborn(String),
+}
+
+/// Reads `.git/HEAD` and works out which of the three states it is in.
+fn head_state(repo: &GitRepository) -> Result<HeadState, WyagError> {
+    let head = match std::fs::read_to_string(repo_path_gr(repo, vec!["HEAD"])) {
+        Ok(s) => s.trim().to_owned(),
+        Err(m) => {
+            return Err(WyagError::new_with_error(
+                "Failed to read HEAD",
+                Box::new(m),
+            ));
+        }
+    };
+
+    if head.starts_with("ref: ") {
+        let refname = &head["ref: ".len()..];
+        let branch = refname.trim_start_matches("refs/heads/").to_owned();
+        let exists = repo_path_gr(repo, refname.split('/').collect()).is_file()
+            || packed_refs_lookup(repo, refname)?.is_some();
+        if exists {
+            Ok(HeadState::Attached(branch))
+        } else {
+            Ok(HeadState::Unborn(branch))
+        }
+    } else if head.len() == 40 && head.bytes().all(|b| b.is_ascii_hexdigit()) {
+        Ok(HeadState::Detached(head))
+    } else {
+        Err(WyagError::new(
+            format!("HEAD contains neither a ref nor a SHA: {}", head).as_ref(),
+        ))
+    }
+}
+
+/// The short name of the branch HEAD is on (e.g. `master`), whether or not it has commits yet.
+///
+/// None when HEAD is detached.
+fn current_branch(repo: &GitRepository) -> Result<Option<String>, WyagError> {
+    match head_state(repo)? {
+        HeadState::Attached(b) | HeadState::Unborn(b) => Ok(Some(b)),
+        HeadState::Detached(_) => Ok(None),
+    }
+}
+
+pub fn cmd_branch_list() -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            println!("No repository was found, cannot use wyag-branch");
+            return Ok(());
+        }
+    };
+
+    for line in branch_list(&repo)? {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// Lists the branches as `git branch` prints them, marking the current one with a `*`.
+///
+/// A detached HEAD gets its own `* (HEAD detached at <sha>)` line at the top.
+fn branch_list(repo: &GitRepository) -> Result<Vec<String>, WyagError> {
+    let mut names: Vec<String> = Vec::new();
+    let heads = repo_dir_gr(repo, false, vec!["refs", "heads"])?;
+    ref_list_names(
+        &ref_list(repo, Some(heads.to_str().unwrap()))?,
+        "",
+        &mut names,
+    );
+    for (_, r) in packed_refs_read(repo)? {
+        if r.starts_with("refs/heads/") && !names.contains(&r["refs/heads/".len()..].to_owned()) {
+            names.push(r["refs/heads/".len()..].to_owned());
+        }
+    }
+    names.sort();
+
+    let head = head_state(repo)?;
+    let mut lines: Vec<String> = Vec::new();
+    if let HeadState::Detached(sha) = &head {
+        lines.push(format!("* (HEAD detached at {})", &sha[..7]));
+    }
+    for name in names {
+        let current = match &head {
+            HeadState::Attached(b) => *b == name,
+            _ => false,
+        };
+        lines.push(format!("{} {}", if current { "*" } else { " " }, name));
+    }
+    Ok(lines)
+}
+
+pub fn cmd_branch_rename(old: Option<&str>, new: &str, force: bool) -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            println!("No repository was found, cannot use wyag-branch");
+            return Ok(());
+        }
+    };
+
+    branch_rename(&repo, old, new, force)
+}
+
+/// Whether `name` is usable as a branch: it can't escape `refs/heads` or leave an empty path component.
+fn branch_name_valid(name: &str) -> bool {
+    !(name.len() == 0 || name.starts_with('/') || name.ends_with('/') || name.contains(".."))
+}
+
+/// Renames the branch `old` (or the current branch when None) to `new`.
+///
+/// Moves the ref (loose or packed) and its reflog, and repoints HEAD if it was on the old branch.
+/// Refuses to clobber an existing `new` unless `force` is set.
+fn branch_rename(
+    repo: &GitRepository,
+    old: Option<&str>,
+    new: &str,
+    force: bool,
+) -> Result<(), WyagError> {
+    require_writable(repo)?;
+    let current = current_branch(repo)?;
+    let old = match (old, &current) {
+        (Some(o), _) => o.to_owned(),
+        (None, Some(b)) => b.to_owned(),
+        (None, None) => {
+            return Err(WyagError::new(
+                "HEAD is not on a branch, name the branch to rename",
+            ));
+        }
+    };
+
+    let old_ref = format!("refs/heads/{}", old);
+    let new_ref = format!("refs/heads/{}", new);
+    for name in &[old.as_str(), new] {
+        if !branch_name_valid(name) {
+            return Err(WyagError::new(
+                format!("'{}' is not a valid branch name.", name).as_ref(),
+            ));
+        }
+    }
+
+    let old_path = repo_path_gr(repo, old_ref.split('/').collect());
+    let new_path = repo_path_gr(repo, new_ref.split('/').collect());
+    let old_exists = old_path.is_file() || packed_refs_lookup(repo, &old_ref)?.is_some();
+    if !old_exists {
+        return Err(WyagError::new(
+            format!("No branch named '{}'.", old).as_ref(),
+        ));
+    }
+    if old_ref != new_ref
+        && !force
+        && (new_path.exists() || packed_refs_lookup(repo, &new_ref)?.is_some())
+    {
+        return Err(WyagError::new(
+            format!("A branch named '{}' already exists.", new).as_ref(),
+        ));
+    }
+
+    /* Move the ref itself */
+    let sha = if old_path.is_file() {
+        ref_resolve(repo, &old_ref)?
+    } else {
+        packed_refs_lookup(repo, &old_ref)?.unwrap()
+    };
+    packed_refs_remove(repo, &old_ref)?;
+    packed_refs_remove(repo, &new_ref)?;
+    if old_path.is_file() {
+        if let Err(m) = std::fs::remove_file(&old_path) {
+            return Err(WyagError::new_with_error(
+                "Failed to remove the old branch ref",
+                Box::new(m),
+            ));
+        }
+    }
+    let new_path = repo_file_gr(repo, true, new_ref.split('/').collect())?;
+    if let Err(m) = std::fs::write(&new_path, format!("{}\n", sha)) {
+        return Err(WyagError::new_with_error(
+            "Failed to write the new branch ref",
+            Box::new(m),
+        ));
+    }
+
+    /* Move the reflog, if there is one */
+    let mut old_log: Vec<&str> = vec!["logs"];
+    old_log.extend(old_ref.split('/'));
+    let old_log = repo_path_gr(repo, old_log);
+    if old_log.is_file() {
+        let mut new_log: Vec<&str> = vec!["logs"];
+        new_log.extend(new_ref.split('/'));
+        let new_log = repo_file_gr(repo, true, new_log)?;
+        if let Err(m) = std::fs::rename(&old_log, &new_log) {
+            return Err(WyagError::new_with_error(
+                "Failed to move the branch reflog",
+                Box::new(m),
+            ));
+        }
+    }
+
+    /* Follow the rename in HEAD */
+    if current == Some(old) {
+        if let Err(m) = std::fs::write(
+            repo_path_gr(repo, vec!["HEAD"]),
+            format!("ref: {}\n", new_ref),
+        ) {
+            return Err(WyagError::new_with_error(
+                "Failed to update HEAD",
+                Box::new(m),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn cmd_branch_set_upstream(upstream: &str, local: Option<&str>) -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            println!("No repository was found, cannot use wyag-branch");
+            return Ok(());
+        }
+    };
+
+    let local = branch_set_upstream(&repo, upstream, local)?;
+    println!("branch '{}' set up to track '{}'.", local, upstream);
+    Ok(())
+}
+
+/// Makes `local` (or the current branch when None) track `upstream`, writing `branch.<local>.remote`
+/// and `branch.<local>.merge` into the repository's config file. Returns the branch configured.
+///
+/// `upstream` is a remote-tracking branch, `<remote>/<branch>`, or failing that a local branch.
+/// Either way its ref has to exist.
+fn branch_set_upstream(
+    repo: &GitRepository,
+    upstream: &str,
+    local: Option<&str>,
+) -> Result<String, WyagError> {
+    require_writable(repo)?;
+    let local = match (local, current_branch(repo)?) {
+        (Some(l), _) => l.to_owned(),
+        (None, Some(b)) => b,
+        (None, None) => {
+            return Err(WyagError::new(
+                "HEAD is not on a branch, name the branch to set the upstream of",
+            ));
+        }
+    };
+    let ref_exists = |refname: &str| -> Result<bool, WyagError> {
+        Ok(repo_path_gr(repo, refname.split('/').collect()).is_file()
+            || packed_refs_lookup(repo, refname)?.is_some())
+    };
+    if !ref_exists(&format!("refs/heads/{}", local))? {
+        return Err(WyagError::new(
+            format!("No branch named '{}'.", local).as_ref(),
+        ));
+    }
+
+    let (remote, merge) = match upstream.find('/') {
+        Some(i) if ref_exists(&format!("refs/remotes/{}", upstream))? => {
+            (&upstream[..i], &upstream[i + 1..])
+        }
+        _ if ref_exists(&format!("refs/heads/{}", upstream))? => (".", upstream),
+        _ => {
+            return Err(WyagError::new(
+                format!(
+                    "the requested upstream branch '{}' does not exist",
+                    upstream
+                )
+                .as_ref(),
+            ));
+        }
+    };
+
+    let path = repo_path_gr(repo, vec!["config"]);
+    let mut conf = match Ini::load_from_file(&path) {
+        Ok(c) => c,
+        Err(m) => {
+            return Err(WyagError::new_with_error(
+                "Failed to read the repository's config",
+                Box::new(m),
+            ));
+        }
+    };
+    conf.with_section(Some(format!("branch \"{}\"", local)))
+        .set("remote", remote)
+        .set("merge", format!("refs/heads/{}", merge));
+    if let Err(m) = conf.write_to_file(&path) {
+        return Err(WyagError::new_with_error(
+            "Failed to write the repository's config",
+            Box::new(m),
+        ));
+    }
+    Ok(local)
+}
+
+/// The upstream branch `branch` tracks, as `<remote>/<branch>` (or just the branch, for a local one),
+/// from `branch.<name>.remote` and `branch.<name>.merge`. None unless both are set.
+fn branch_upstream(repo: &GitRepository, branch: &str) -> Option<String> {
+    let section = repo.conf.section(Some(format!("branch \"{}\"", branch)))?;
+    let remote = section.get("remote")?;
+    let merge = section.get("merge")?;
+    let merge = merge.trim_start_matches("refs/heads/");
+    if remote == "." {
+        Some(merge.to_owned())
+    } else {
+        Some(format!("{}/{}", remote, merge))
+    }
+}
+
+#[cfg(test)]
+mod branch_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn head_state_attached_detached_unborn() {
+        let path = scratch_dir("wyag_head_state");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        assert_eq!(
+            head_state(&gr).unwrap(),
+            HeadState::Unborn("master".to_owned())
+        );
+        assert_eq!(branch_list(&gr).unwrap(), Vec::<String>::new());
+
+        let sha = write_commit(&gr, &[], 1000, "Initial commit\n");
+        set_ref(&gr, "refs/heads/master", &sha);
+        set_ref(&gr, "refs/heads/topic", &sha);
+        assert_eq!(
+            head_state(&gr).unwrap(),
+            HeadState::Attached("master".to_owned())
+        );
+        assert_eq!(branch_list(&gr).unwrap(), vec!["* master", "  topic"]);
+
+        std::fs::write(repo_path_gr(&gr, vec!["HEAD"]), format!("{}\n", sha)).unwrap();
+        assert_eq!(head_state(&gr).unwrap(), HeadState::Detached(sha.clone()));
+        assert_eq!(
+            branch_list(&gr).unwrap(),
+            vec![
+                format!("* (HEAD detached at {})", &sha[..7]),
+                "  master".to_owned(),
+                "  topic".to_owned()
+            ]
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn current_branch_attached_and_detached() {
+        let path = scratch_dir("wyag_current_branch");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let sha = write_commit(&gr, &[], 1000, "Initial commit\n");
+        set_ref(&gr, "refs/heads/feature/x", &sha);
+        std::fs::write(
+            repo_path_gr(&gr, vec!["HEAD"]),
+            "ref: refs/heads/feature/x\n",
+        )
+        .unwrap();
+        assert_eq!(current_branch(&gr).unwrap(), Some("feature/x".to_owned()));
+
+        std::fs::write(repo_path_gr(&gr, vec!["HEAD"]), format!("{}\n", sha)).unwrap();
+        assert_eq!(current_branch(&gr).unwrap(), None);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn rename_current_branch_moves_head() {
+        let path = scratch_dir("wyag_branch_rename");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let sha = write_commit(&gr, &[], 1000, "Initial commit\n");
+        set_ref(&gr, "refs/heads/master", &sha);
+        let log = repo_file_gr(&gr, true, vec!["logs", "refs", "heads", "master"]).unwrap();
+        std::fs::write(&log, "reflog\n").unwrap();
+
+        branch_rename(&gr, None, "main", false).unwrap();
+
+        assert!(!repo_path_gr(&gr, vec!["refs", "heads", "master"]).exists());
+        assert_eq!(ref_resolve(&gr, "refs/heads/main").unwrap(), sha);
+        assert_eq!(ref_resolve(&gr, "HEAD").unwrap(), sha);
+        assert_eq!(
+            std::fs::read_to_string(repo_path_gr(&gr, vec!["HEAD"])).unwrap(),
+            "ref: refs/heads/main\n"
+        );
+        assert!(!log.exists());
+        assert!(repo_path_gr(&gr, vec!["logs", "refs", "heads", "main"]).is_file());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn set_upstream_writes_remote_and_merge() {
+        let path = scratch_dir("wyag_branch_set_upstream");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let sha = write_commit(&gr, &[], 1000, "Initial commit\n");
+        set_ref(&gr, "refs/heads/master", &sha);
+        set_ref(&gr, "refs/heads/topic", &sha);
+        set_ref(&gr, "refs/remotes/origin/main", &sha);
+
+        assert!(branch_set_upstream(&gr, "origin/nope", None).is_err());
+        assert!(branch_set_upstream(&gr, "origin/main", Some("nope")).is_err());
+        assert_eq!(
+            branch_set_upstream(&gr, "origin/main", None).unwrap(),
+            "master"
+        );
+        branch_set_upstream(&gr, "master", Some("topic")).unwrap();
+
+        let conf = Ini::load_from_file(gr.gitdir.join("config")).unwrap();
+        let master = conf.section(Some("branch \"master\"".to_owned())).unwrap();
+        assert_eq!(master.get("remote").unwrap(), "origin");
+        assert_eq!(master.get("merge").unwrap(), "refs/heads/main");
+        let topic = conf.section(Some("branch \"topic\"".to_owned())).unwrap();
+        assert_eq!(topic.get("remote").unwrap(), ".");
+        assert_eq!(topic.get("merge").unwrap(), "refs/heads/master");
+        // The rest of the config survives the rewrite
+        assert_eq!(
+            conf.section(Some("core".to_owned()))
+                .unwrap()
+                .get("repositoryformatversion")
+                .unwrap(),
+            "0"
+        );
+
+        let gr = GitRepository { conf: conf, ..gr };
+        assert_eq!(
+            branch_upstream(&gr, "master"),
+            Some("origin/main".to_owned())
+        );
+        assert_eq!(branch_upstream(&gr, "topic"), Some("master".to_owned()));
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn rename_refuses_existing_target_without_force() {
+        let path = scratch_dir("wyag_branch_rename_force");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let first = write_commit(&gr, &[], 1000, "Initial commit\n");
+        let second = write_commit(&gr, &[&first], 2000, "Second commit\n");
+        set_ref(&gr, "refs/heads/master", &first);
+        set_ref(&gr, "refs/heads/topic", &second);
+
+        assert!(branch_rename(&gr, Some("topic"), "master", false).is_err());
+        assert_eq!(ref_resolve(&gr, "refs/heads/topic").unwrap(), second);
+
+        branch_rename(&gr, Some("topic"), "master", true).unwrap();
+        assert_eq!(ref_resolve(&gr, "refs/heads/master").unwrap(), second);
+        assert!(!repo_path_gr(&gr, vec!["refs", "heads", "topic"]).exists());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn rename_refuses_a_source_outside_refs_heads() {
+        let path = scratch_dir("wyag_branch_rename_escape");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let first = write_commit(&gr, &[], 1000, "Initial commit\n");
+        set_ref(&gr, "refs/heads/master", &first);
+        set_ref(&gr, "refs/tags/v1", &first);
+
+        let err = branch_rename(&gr, Some("../tags/v1"), "stolen", false)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("not a valid branch name"));
+        assert_eq!(ref_resolve(&gr, "refs/tags/v1").unwrap(), first);
+        assert!(!repo_path_gr(&gr, vec!["refs", "heads", "stolen"]).exists());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
+
+/// EndRegion: Branch
+
+/// Region: Tag
+
+pub fn cmd_tag(name: &str, obj: &str, createTagObject: bool) -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            println!("No repository was found, cannot use wyag-tag");
+            return Ok(());
+        }
+    };
+
+    if name.len() > 0 {
+        let tagType = if createTagObject { "object" } else { "ref" };
+        tag_create(name, obj, tagType)
+    } else {
+        for t in tag_list(&repo, None)? {
+            println!("{}", t);
+        }
+        Ok(())
+    }
+}
+
+/// Prints the names of the tags matching the shell glob `pattern`, or of every tag if there is none.
+pub fn cmd_tag_list(pattern: Option<&str>) -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            println!("No repository was found, cannot use wyag-tag");
+            return Ok(());
+        }
+    };
+
+    for t in tag_list(&repo, pattern)? {
+        println!("{}", t);
+    }
+    Ok(())
+}
+
+/// Tag names, loose or packed, in sorted order, optionally filtered with `glob_match`.
+fn tag_list(repo: &GitRepository, pattern: Option<&str>) -> Result<Vec<String>, WyagError> {
+    Ok(refs(repo)?
+        .keys()
+        .filter(|r| r.starts_with("refs/tags/"))
+        .map(|r| r["refs/tags/".len()..].to_owned())
+        .filter(|t| pattern.map(|p| glob_match(p, t)).unwrap_or(true))
+        .collect())
+}
+
+fn tag_create(name: &str, obj: &str, tagType: &str) -> Result<(), WyagError> {
+    Ok(())
+}
+
+pub fn cmd_tag_delete(name: &str) -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            println!("No repository was found, cannot use wyag-tag");
+            return Ok(());
+        }
+    };
+
+    let sha = tag_delete(&repo, name)?;
+    println!("Deleted tag '{}' (was {})", name, &sha[..7.min(sha.len())]);
+    Ok(())
+}
+
+/// Removes the tag `name`, from `.git/refs/tags` and from `packed-refs`, returning the SHA it pointed to.
+fn tag_delete(repo: &GitRepository, name: &str) -> Result<String, WyagError> {
+    require_writable(repo)?;
+    // The same rules as branch names, so `../heads/master` can't reach outside refs/tags
+    if !branch_name_valid(name) {
+        return Err(WyagError::new(
+            format!("'{}' is not a valid tag name.", name).as_ref(),
+        ));
+    }
+    let refname = format!("refs/tags/{}", name);
+    let mut sha: Option<String> = None;
+
+    let loose = repo_path_gr(repo, refname.split('/').collect());
+    if loose.is_file() {
+        sha = Some(ref_resolve(repo, &refname)?);
+        if let Err(m) = std::fs::remove_file(&loose) {
+            return Err(WyagError::new_with_error(
+                format!("Failed to delete tag file {}", refname).as_ref(),
+                Box::new(m),
+            ));
+        }
+    }
+
+    if let Some(packed) = packed_refs_remove(repo, &refname)? {
+        if sha.is_none() {
+            sha = Some(packed);
+        }
+    }
+
+    match sha {
+        Some(s) => Ok(s),
+        None => Err(WyagError::new(
+            format!("tag '{}' not found.", name).as_ref(),
+        )),
+    }
+}
+
+/// Reads `.git/packed-refs` into `(sha, refname)` pairs, skipping comments and peeled lines.
+fn packed_refs_read(repo: &GitRepository) -> Result<Vec<(String, String)>, WyagError> {
+    let mut ret: Vec<(String, String)> = Vec::new();
+    let path = repo_path_gr(repo, vec!["packed-refs"]);
+    if !path.is_file() {
+        return Ok(ret);
+    }
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(s) => s,
+        Err(m) => {
+            return Err(WyagError::new_with_error(
+                "Failed to read packed-refs",
+                Box::new(m),
+            ));
+        }
+    };
+    for line in contents.lines() {
+        if line.starts_with('#') || line.starts_with('^') {
+            continue;
+        }
+        let mut parts = line.splitn(2, ' ');
+        if let (Some(s), Some(r)) = (parts.next(), parts.next()) {
+            ret.push((s.to_owned(), r.to_owned()));
+        }
+    }
+    Ok(ret)
+}
+
+/// Looks `refname` up in `.git/packed-refs`, returning the SHA it's packed with.
+fn packed_refs_lookup(repo: &GitRepository, refname: &str) -> Result<Option<String>, WyagError> {
+    for (s, r) in packed_refs_read(repo)? {
+        if r == refname {
+            return Ok(Some(s));
+        }
+    }
+    Ok(None)
+}
+
+/// Drops `refname` (and the peeled `^sha` line that may follow it) from `.git/packed-refs`.
+///
+/// Returns the SHA the packed ref pointed to, or None if it wasn't packed. The file is only rewritten if it changed.
+fn packed_refs_remove(repo: &GitRepository, refname: &str) -> Result<Option<String>, WyagError> {
+    let path = repo_path_gr(repo, vec!["packed-refs"]);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(s) => s,
+        Err(m) => {
+            return Err(WyagError::new_with_error(
+                "Failed to read packed-refs",
+                Box::new(m),
+            ));
+        }
+    };
+
+    let mut sha: Option<String> = None;
+    let mut kept = String::new();
+    let mut dropping = false;
+    for line in contents.lines() {
+        // peeled lines belong to the ref right above them
+        if line.starts_with('^') {
+            if !dropping {
+                kept.push_str(line);
+                kept.push('\n');
+            }
+            continue;
+        }
+        dropping = false;
+        let mut parts = line.splitn(2, ' ');
+        if let (Some(s), Some(r)) = (parts.next(), parts.next()) {
+            if !line.starts_with('#') && r == refname {
+                sha = Some(s.to_owned());
+                dropping = true;
+                continue;
+            }
+        }
+        kept.push_str(line);
+        kept.push('\n');
+    }
+
+    if sha.is_some() {
+        if let Err(m) = std::fs::write(&path, kept) {
+            return Err(WyagError::new_with_error(
+                "Failed to rewrite packed-refs",
+                Box::new(m),
+            ));
+        }
+    }
+    Ok(sha)
+}
+
+#[cfg(test)]
+mod tag_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn delete_loose_tag() {
+        let path = scratch_dir("wyag_tag_delete");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let sha = write_commit(&gr, &[], 1000, "Initial commit\n");
+        set_ref(&gr, "refs/tags/v1.0", &sha);
+
+        assert_eq!(tag_delete(&gr, "v1.0").unwrap(), sha);
+        assert!(!repo_path_gr(&gr, vec!["refs", "tags", "v1.0"]).exists());
+        match &ref_list(&gr, None).unwrap()["tags"] {
+            RefType::RefTypeDict(d) => assert!(!d.contains_key("v1.0")),
+            _ => panic!("expected refs/tags to be a directory"),
+        };
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn delete_refuses_names_outside_refs_tags() {
+        let path = scratch_dir("wyag_tag_delete_escape");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let sha = write_commit(&gr, &[], 1000, "Initial commit\n");
+        set_ref(&gr, "refs/heads/master", &sha);
+
+        assert!(tag_delete(&gr, "../heads/master").is_err());
+        assert!(tag_delete(&gr, "").is_err());
+        assert!(repo_path_gr(&gr, vec!["refs", "heads", "master"]).is_file());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn delete_packed_tag() {
+        let path = scratch_dir("wyag_tag_delete_packed");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let packed = "# pack-refs with: peeled fully-peeled sorted\n\
+                      1111111111111111111111111111111111111111 refs/heads/master\n\
+                      2222222222222222222222222222222222222222 refs/tags/v1.0\n\
+                      ^3333333333333333333333333333333333333333\n\
+                      4444444444444444444444444444444444444444 refs/tags/v2.0\n";
+        std::fs::write(repo_path_gr(&gr, vec!["packed-refs"]), packed).unwrap();
+
+        assert_eq!(
+            tag_delete(&gr, "v1.0").unwrap(),
+            "2222222222222222222222222222222222222222"
+        );
+        assert_eq!(
+            std::fs::read_to_string(repo_path_gr(&gr, vec!["packed-refs"])).unwrap(),
+            "# pack-refs with: peeled fully-peeled sorted\n\
+             1111111111111111111111111111111111111111 refs/heads/master\n\
+             4444444444444444444444444444444444444444 refs/tags/v2.0\n"
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn list_filters_by_pattern() {
+        let path = scratch_dir("wyag_tag_list_pattern");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let sha = "ce013625030ba8dba906f756967f9e9ca394464a";
+        set_ref(&gr, "refs/tags/v2.0", sha);
+        set_ref(&gr, "refs/tags/v1.1", sha);
+        set_ref(&gr, "refs/tags/v1.0", sha);
+        set_ref(&gr, "refs/heads/v1.5", sha);
+
+        assert_eq!(tag_list(&gr, Some("v1.*")).unwrap(), vec!["v1.0", "v1.1"]);
+        assert_eq!(tag_list(&gr, None).unwrap(), vec!["v1.0", "v1.1", "v2.0"]);
+        assert!(tag_list(&gr, Some("v3*")).unwrap().is_empty());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn delete_missing_tag_errors() {
+        let path = scratch_dir("wyag_tag_delete_missing");
+        let gr = GitRepository::repo_create(&path).unwrap();
+
+        assert!(tag_delete(&gr, "nope").is_err());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
+
+/// EndRegion: Tag
+
+/// Region: Status
+
+/// Prints the `## <branch>...<upstream>` line that `status -sb` starts with, then a record for each staged change
+/// and each untracked file.
+///
+/// Changes to tracked files in the worktree are not listed yet.
+/// With `null_terminated` (`-z`), records end in NUL instead of a newline and paths are left unquoted.
+pub fn cmd_status_short_branch(null_terminated: bool) -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            return Err(WyagError::new(
+                "No repository was found, cannot use wyag-status",
+            ));
+        }
+    };
+    require_worktree(&repo)?;
+    let mut records = status_staged(&repo)?;
+    records.extend(status_untracked(&repo)?);
+    let entries: Vec<(&str, &str)> = records
+        .iter()
+        .map(|(code, path)| (code.as_ref(), path.as_ref()))
+        .collect();
+    let out = status_format(&status_branch_header(&repo)?, &entries, null_terminated);
+    if let Err(m) = io::stdout().write_all(&out) {
+        return Err(WyagError::new_with_error(
+            "Failed to write status to stdout",
+            Box::new(m),
+        ));
+    }
+    Ok(())
+}
+
+/// Lays out the short-format records: the branch `header`, then one `XY path` record per entry.
+///
+/// Records end in a newline, with paths quoted the way git's `core.quotePath` does, or with `null_terminated`
+/// they end in NUL and paths go out byte-for-byte, so scripts can split on NUL whatever the paths contain.
+fn status_format(header: &str, entries: &[(&str, &str)], null_terminated: bool) -> Vec<u8> {
+    let end = if null_terminated { b'\x00' } else { b'\n' };
+    let mut out: Vec<u8> = Vec::new();
+    out.extend(header.as_bytes());
+    out.push(end);
+    for (code, path) in entries {
+        out.extend(code.as_bytes());
+        out.push(b' ');
+        if null_terminated {
+            out.extend(path.as_bytes());
+        } else {
+            out.extend(status_quote_path(path).as_bytes());
+        }
+        out.push(end);
+    }
+    out
+}
+
+/// Quotes `path` in double quotes with C-style escapes if it holds a quote, backslash, control character
+/// or non-ASCII byte; other paths, including ones with spaces, are returned as they are.
+fn status_quote_path(path: &str) -> String {
+    if !path
+        .bytes()
+        .any(|b| b == b'"' || b == b'\\' || b < 0x20 || b >= 0x7f)
+    {
+        return path.to_owned();
+    }
+    let mut out = String::from("\"");
+    for b in path.bytes() {
+        match b {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\t' => out.push_str("\\t"),
+            b if b < 0x20 || b >= 0x7f => out.push_str(&format!("\\{:03o}", b)),
+            b => out.push(b as char),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// The `XY` code and path of each change staged in the index against HEAD, like `A `, `M ` or `D `, in path order.
+///
+/// wyag can't write the index yet, so a repository without one has nothing staged.
+fn status_staged(repo: &GitRepository) -> Result<Vec<(String, String)>, WyagError> {
+    if !repo_path_gr(repo, vec!["index"]).exists() {
+        return Ok(Vec::new());
+    }
+    Ok(diff_cached(repo)?
+        .into_iter()
+        .map(|change| match change {
+            TreeChange::Added { path, .. } => ("A ".to_owned(), path),
+            TreeChange::Deleted { path, .. } => ("D ".to_owned(), path),
+            TreeChange::Modified { path, .. } => ("M ".to_owned(), path),
+            TreeChange::Renamed { to, .. } => ("R ".to_owned(), to),
+        })
+        .collect())
+}
+
+/// A `??` record for each file in the worktree that isn't tracked, leaving out ignored ones.
+///
+/// Each file is listed, as `-uall` does, rather than collapsing untracked directories. Tracked means in the index,
+/// or in HEAD's tree for a repository without one.
+fn status_untracked(repo: &GitRepository) -> Result<Vec<(String, String)>, WyagError> {
+    let tracked: std::collections::HashSet<String> = if repo_path_gr(repo, vec!["index"]).exists() {
+        index_read(repo)?.into_iter().map(|e| e.path).collect()
+    } else {
+        match head_state(repo)? {
+            HeadState::Attached(_) | HeadState::Detached(_) => {
+                match object_find(repo, "HEAD", Some("tree"), true)? {
+                    Some(t) => flatten_tree(repo, &t)?
+                        .into_iter()
+                        .map(|(p, _, _)| p.to_string_lossy().into_owned())
+                        .collect(),
+                    None => return Err(WyagError::new("HEAD does not have a tree")),
+                }
+            }
+            HeadState::Unborn(_) => std::collections::HashSet::new(),
+        }
+    };
+    Ok(walk_worktree(repo, false)?
+        .map(|p| p.to_string_lossy().replace('\\', "/"))
+        .filter(|p| !tracked.contains(p))
+        .map(|p| ("??".to_owned(), p))
+        .collect())
+}
+
+fn status_branch_header(repo: &GitRepository) -> Result<String, WyagError> {
+    Ok(match head_state(repo)? {
+        HeadState::Attached(b) => match branch_upstream(repo, &b) {
+            Some(up) => format!("## {}...{}", b, up),
+            None => format!("## {}", b),
+        },
+        HeadState::Unborn(b) => format!("## No commits yet on {}", b),
+        HeadState::Detached(_) => "## HEAD (no branch)".to_owned(),
+    })
+}
+
+#[cfg(test)]
+mod status_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn null_terminated_records_leave_paths_raw() {
+        let entries = [("??", "with space.txt"), ("??", "line\nbreak")];
+        assert_eq!(
+            status_format("## master", &entries, true),
+            b"## master\x00?? with space.txt\x00?? line\nbreak\x00".to_vec()
+        );
+        assert_eq!(
+            status_format("## master", &entries, false),
+            b"## master\n?? with space.txt\n?? \"line\\nbreak\"\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn staged_changes_are_listed_against_head() {
+        let path = scratch_dir("wyag_status_staged");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let a = write_loose_object(&gr, b"blob", b"a\n");
+        let a2 = write_loose_object(&gr, b"blob", b"a, edited\n");
+        let tree = write_tree(&gr, &[("100644", "a.txt", &a), ("100644", "gone.txt", &a)]);
+        let commit = write_loose_object(
+            &gr,
+            b"commit",
+            format!("tree {}\n\nfirst\n", tree).as_bytes(),
+        );
+        set_ref(&gr, "refs/heads/master", &commit);
+        assert!(status_staged(&gr).unwrap().is_empty());
+
+        let index = index_bytes(&[(0o100644, &a2, "a.txt"), (0o100644, &a, "with space.txt")]);
+        std::fs::write(repo_path_gr(&gr, vec!["index"]), index).unwrap();
+        let staged = status_staged(&gr).unwrap();
+        let entries: Vec<(&str, &str)> = staged
+            .iter()
+            .map(|(code, path)| (code.as_ref(), path.as_ref()))
+            .collect();
+        assert_eq!(
+            status_format("## master", &entries, true),
+            b"## master\x00M  a.txt\x00D  gone.txt\x00A  with space.txt\x00".to_vec()
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn untracked_files_are_listed_unless_ignored() {
+        let path = scratch_dir("wyag_status_untracked");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let root = PathBuf::from(&path);
+        let a = write_loose_object(&gr, b"blob", b"a\n");
+        let tree = write_tree(&gr, &[("100644", "a.txt", &a)]);
+        let commit = write_loose_object(
+            &gr,
+            b"commit",
+            format!("tree {}\n\nfirst\n", tree).as_bytes(),
+        );
+        set_ref(&gr, "refs/heads/master", &commit);
+        std::fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        std::fs::write(root.join("a.txt"), "a\n").unwrap();
+        std::fs::write(root.join("new.txt"), "new\n").unwrap();
+        std::fs::write(root.join("debug.log"), "noise\n").unwrap();
+
+        // Without an index, HEAD's files are the tracked ones
+        assert_eq!(
+            status_untracked(&gr).unwrap(),
+            vec![
+                ("??".to_owned(), ".gitignore".to_owned()),
+                ("??".to_owned(), "new.txt".to_owned())
+            ]
+        );
+
+        let index = index_bytes(&[(0o100644, &a, "a.txt"), (0o100644, &a, "new.txt")]);
+        std::fs::write(repo_path_gr(&gr, vec!["index"]), index).unwrap();
+        assert_eq!(
+            status_untracked(&gr).unwrap(),
+            vec![("??".to_owned(), ".gitignore".to_owned())]
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn branch_header_shows_the_upstream_when_there_is_one() {
+        let path = scratch_dir("wyag_status_branch_header");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        assert_eq!(
+            status_branch_header(&gr).unwrap(),
+            "## No commits yet on master"
+        );
+
+        let sha = write_commit(&gr, &[], 1000, "Initial commit\n");
+        set_ref(&gr, "refs/heads/master", &sha);
+        assert_eq!(status_branch_header(&gr).unwrap(), "## master");
+
+        let mut conf = Ini::load_from_file(gr.gitdir.join("config")).unwrap();
+        conf.with_section(Some("branch \"master\"".to_owned()))
+            .set("remote", "origin")
+            .set("merge", "refs/heads/main");
+        let gr = GitRepository { conf: conf, ..gr };
+        assert_eq!(
+            status_branch_header(&gr).unwrap(),
+            "## master...origin/main"
+        );
+
+        std::fs::write(repo_path_gr(&gr, vec!["HEAD"]), format!("{}\n", sha)).unwrap();
+        assert_eq!(status_branch_header(&gr).unwrap(), "## HEAD (no branch)");
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
+
+/// EndRegion: Status
+
+/// Region: Reachability
+
+/// Every object reachable from HEAD or any ref: commits lead to their tree and parents, trees to their
+/// entries, and tags to the object they tag.
+///
+/// Objects missing from the store are not included, nor is anything only they would lead to.
+/// Submodule commits found in trees belong to another repository and are skipped.
+fn reachable_objects(repo: &GitRepository) -> Result<std::collections::HashSet<String>, WyagError> {
+    let mut pending: Vec<String> = Vec::new();
+    for (_, target) in refs(repo)? {
+        if let RefTarget::Direct(sha) = target {
+            pending.push(sha);
+        }
+    }
+
+    let mut seen: std::collections::HashSet<ObjectId> = std::collections::HashSet::new();
+    while let Some(sha) = pending.pop() {
+        // A malformed name can't be in the store, so it counts as missing
+        let id = match ObjectId::try_from(sha.as_str()) {
+            Ok(id) => id,
+            Err(_) => continue,
+        };
+        if seen.contains(&id) || !object_exists(repo, id.as_str()) {
+            continue;
+        }
+        match object_read(repo, id.as_str())? {
+            GObj::Commit(c) => {
+                pending.push(c.tree_sha()?);
+                pending.extend(c.parents());
+            }
+            GObj::Tree(t) => {
+                for entry in t.entries() {
+                    if entry.kind != ObjectType::Commit {
+                        pending.push(entry.sha);
+                    }
+                }
+            }
+            GObj::Tag(t) => {
+                if let Some(o) = t.kvlm.get("object") {
+                    pending.extend(o.iter().cloned());
+                }
+            }
+            GObj::Blob(_) => (),
+        }
+        seen.insert(id);
+    }
+    Ok(seen.into_iter().map(String::from).collect())
+}
+
+#[cfg(test)]
+mod reachability_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn reachable_set_skips_dangling_objects() {
+        let path = scratch_dir("wyag_reachable_objects");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let a = write_loose_object(&gr, b"blob", b"a\n");
+        let b = write_loose_object(&gr, b"blob", b"b\n");
+        let sub = write_tree(&gr, &[("100644", "b.txt", &b)]);
+        let tree = write_tree(&gr, &[("100644", "a.txt", &a), ("40000", "sub", &sub)]);
+        let commit = write_loose_object(
+            &gr,
+            b"commit",
+            format!(
+                "tree {}\nauthor A U Thor <author@example.com> 1 +0000\n\nFirst\n",
+                tree
+            )
+            .as_bytes(),
+        );
+        let tag = write_loose_object(
+            &gr,
+            b"tag",
+            format!("object {}\ntype commit\ntag v1\n\nv1\n", commit).as_bytes(),
+        );
+        let dangling = write_loose_object(&gr, b"blob", b"nobody points here\n");
+        set_ref(&gr, "refs/tags/v1", &tag);
+
+        let reachable = reachable_objects(&gr).unwrap();
+        for sha in &[&tag, &commit, &tree, &sub, &a, &b] {
+            assert!(reachable.contains(*sha), "{} should be reachable", sha);
+        }
+        assert!(!reachable.contains(&dangling));
+        assert_eq!(reachable.len(), 6);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
+
+/// Prints the best common ancestor of all of `names`, folding them pairwise from the left.
+///
+/// Returns whether one was found; commits with unrelated histories have none.
+pub fn cmd_merge_base(names: &[String]) -> Result<bool, WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            return Err(WyagError::new(
+                "No repository was found, cannot use wyag-merge-base",
+            ));
+        }
+    };
+    let mut shas: Vec<String> = Vec::new();
+    for name in names {
+        shas.push(merge_base_resolve(&repo, name)?);
+    }
+    match merge_base_many(&repo, &shas)? {
+        Some(base) => {
+            println!("{}", base);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Whether commit `ancestor` is reachable from `descendant` (a commit counts as its own ancestor).
+pub fn cmd_merge_base_is_ancestor(ancestor: &str, descendant: &str) -> Result<bool, WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            return Err(WyagError::new(
+                "No repository was found, cannot use wyag-merge-base",
+            ));
+        }
+    };
+    let a = merge_base_resolve(&repo, ancestor)?;
+    let d = merge_base_resolve(&repo, descendant)?;
+    is_ancestor(&repo, &a, &d)
+}
+
+fn merge_base_resolve(repo: &GitRepository, name: &str) -> Result<String, WyagError> {
+    match object_find(repo, name, Some("commit"), true)? {
+        Some(s) => Ok(s),
+        None => Err(WyagError::new(
+            format!("Not a valid commit name {}", name).as_ref(),
+        )),
+    }
+}
+
+/// Every commit reachable from `sha`, `sha` included, mapped to its parents and commit time.
+///
+/// Each commit is read once, so callers can walk the graph again from the map without touching the object store.
+fn commit_ancestry(
+    repo: &GitRepository,
+    sha: &str,
+) -> Result<HashMap<String, (Vec<String>, i64)>, WyagError> {
+    let mut seen: HashMap<String, (Vec<String>, i64)> = HashMap::new();
+    let mut pending: Vec<String> = vec![sha.to_owned()];
+    while let Some(sha) = pending.pop() {
+        if seen.contains_key(&sha) {
+            continue;
+        }
+        match object_read(repo, &sha)? {
+            GObj::Commit(c) => {
+                let parents = c.parents();
+                pending.extend(parents.iter().cloned());
+                seen.insert(sha, (parents, commit_time(&c)));
+            }
+            GObj::Blob(_) | GObj::Tag(_) | GObj::Tree(_) => {
+                return Err(WyagError::new(
+                    format!("Expected {} to be a commit", sha).as_ref(),
+                ));
+            }
+        }
+    }
+    Ok(seen)
+}
+
+/// Every commit reachable from `sha`, `sha` included.
+fn commit_ancestors(
+    repo: &GitRepository,
+    sha: &str,
+) -> Result<std::collections::HashSet<String>, WyagError> {
+    Ok(commit_ancestry(repo, sha)?.into_keys().collect())
+}
+
+fn is_ancestor(repo: &GitRepository, ancestor: &str, descendant: &str) -> Result<bool, WyagError> {
+    Ok(commit_ancestors(repo, descendant)?.contains(ancestor))
+}
+
+/// The best common ancestor of `a` and `b`: one that isn't itself an ancestor of another common ancestor.
+///
+/// When several qualify (criss-cross merges), the most recently committed one is chosen, then the lowest SHA.
+fn merge_base(repo: &GitRepository, a: &str, b: &str) -> Result<Option<String>, WyagError> {
+    let ours = commit_ancestry(repo, a)?;
+    let theirs = commit_ancestry(repo, b)?;
+    let common: Vec<&String> = ours.keys().filter(|c| theirs.contains_key(*c)).collect();
+
+    // Everything strictly below a common ancestor is dominated by it. The ancestors of a common
+    // commit are all in `ours` already, so this walk needs no more object reads.
+    let mut dominated: std::collections::HashSet<&String> = std::collections::HashSet::new();
+    let mut pending: Vec<&String> = common.iter().flat_map(|c| ours[*c].0.iter()).collect();
+    while let Some(sha) = pending.pop() {
+        if dominated.insert(sha) {
+            pending.extend(ours[sha].0.iter());
+        }
+    }
+
+    let mut best: Vec<(i64, String)> = common
+        .into_iter()
+        .filter(|c| !dominated.contains(c))
+        .map(|c| (ours[c].1, c.to_owned()))
+        .collect();
+    best.sort_by(|x, y| y.0.cmp(&x.0).then(x.1.cmp(&y.1)));
+    Ok(best.into_iter().next().map(|(_, sha)| sha))
+}
+
+/// Folds `merge_base` over `shas` from the left, so three or more commits get an ancestor common to all of them.
+fn merge_base_many(repo: &GitRepository, shas: &[String]) -> Result<Option<String>, WyagError> {
+    let mut iter = shas.iter();
+    let mut base = match iter.next() {
+        Some(s) => s.to_owned(),
+        None => return Ok(None),
+    };
+    for sha in iter {
+        base = match merge_base(repo, &base, sha)? {
+            Some(b) => b,
+            None => return Ok(None),
+        };
+    }
+    Ok(Some(base))
+}
+
+#[cfg(test)]
+mod merge_base_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn three_branches_share_their_fork_point() {
+        let path = scratch_dir("wyag_merge_base_three");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let root = write_commit(&gr, &[], 1000, "root\n");
+        let fork = write_commit(&gr, &[&root], 2000, "fork\n");
+        let a = write_commit(&gr, &[&fork], 3000, "a\n");
+        let b = write_commit(&gr, &[&fork], 3001, "b\n");
+        let b2 = write_commit(&gr, &[&b], 4000, "b2\n");
+        let c = write_commit(&gr, &[&root], 3002, "c\n");
+
+        assert_eq!(merge_base(&gr, &a, &b2).unwrap(), Some(fork.clone()));
+        assert_eq!(
+            merge_base_many(&gr, &[a.clone(), b2.clone()]).unwrap(),
+            Some(fork.clone())
+        );
+        assert_eq!(
+            merge_base_many(&gr, &[a.clone(), b2.clone(), c.clone()]).unwrap(),
+            Some(root.clone())
+        );
+
+        let unrelated = write_commit(&gr, &[], 5000, "elsewhere\n");
+        assert_eq!(merge_base(&gr, &a, &unrelated).unwrap(), None);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn is_ancestor_holds_one_way_only() {
+        let path = scratch_dir("wyag_merge_base_is_ancestor");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let root = write_commit(&gr, &[], 1000, "root\n");
+        let child = write_commit(&gr, &[&root], 2000, "child\n");
+
+        assert!(is_ancestor(&gr, &root, &child).unwrap());
+        assert!(!is_ancestor(&gr, &child, &root).unwrap());
+        assert!(is_ancestor(&gr, &child, &child).unwrap());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
+
+/// EndRegion: Reachability
+
+/// Region: Repack
+
+/// Lists the loose objects a repack would move into a pack, one per line, without packing anything.
+pub fn cmd_repack_list() -> Result<(), WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            return Err(WyagError::new(
+                "No repository was found, cannot use wyag-repack",
+            ));
+        }
+    };
+
+    for sha in repack_candidates(&repo)? {
+        println!("{}", sha);
+    }
+    Ok(())
+}
+
+/// The loose objects reachable from HEAD or any ref, sorted. Unreachable objects are left for prune.
+fn repack_candidates(repo: &GitRepository) -> Result<Vec<String>, WyagError> {
+    let reachable = reachable_objects(repo)?;
+    Ok(loose_objects(repo)?
+        .filter(|sha| reachable.contains(sha))
+        .collect())
+}
+
+#[cfg(test)]
+mod repack_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn candidates_are_the_reachable_loose_objects() {
+        let path = scratch_dir("wyag_repack_list");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let blob = write_loose_object(&gr, b"blob", b"hello\n");
+        let tree = write_tree(&gr, &[("100644", "hello.txt", &blob)]);
+        let commit = write_loose_object(
+            &gr,
+            b"commit",
+            format!(
+                "tree {}\nauthor A U Thor <author@example.com> 1 +0000\n\nFirst\n",
+                tree
+            )
+            .as_bytes(),
+        );
+        let second = write_commit(&gr, &[&commit], 2, "Second");
+        let _dangling = write_loose_object(&gr, b"blob", b"nobody points here\n");
+        set_ref(&gr, "refs/heads/master", &second);
+
+        let mut expected = vec![blob, tree, commit, second];
+        expected.sort();
+        assert_eq!(repack_candidates(&gr).unwrap(), expected);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
+
+/// EndRegion: Repack
+
+/// Region: Config
+
+/// Prints the value of config `key` (e.g. `core.bare`, `remote.origin.url`). With `all`, prints every value
+/// of a multi-valued key one per line, in file order; otherwise only the last, which is the one that wins.
+///
+/// Returns whether the key was set at all.
+pub fn cmd_config_get(key: &str, all: bool) -> Result<bool, WyagError> {
+    let repo = match repo_find(".", false)? {
+        Some(gr) => gr,
+        None => {
+            return Err(WyagError::new(
+                "No repository was found, cannot use wyag-config",
+            ));
+        }
+    };
+    let values = config_get_all(&repo, key)?;
+    if all {
+        for v in &values {
+            println!("{}", v);
+        }
+    } else if let Some(v) = values.last() {
+        println!("{}", v);
+    }
+    Ok(values.len() > 0)
+}
+
+/// Every value of config `key`, in the order they appear in the config file.
+///
+/// `Ini` keeps only the last value of a repeated key, so the file is scanned here instead. As in git, section
+/// and variable names match case-insensitively, while subsection names (`remote "origin"`) are case-sensitive.
+fn config_get_all(repo: &GitRepository, key: &str) -> Result<Vec<String>, WyagError> {
+    let (section, name) = match (key.find('.'), key.rfind('.')) {
+        (Some(first), Some(last)) if first > 0 && last + 1 < key.len() => {
+            let sub = if first == last {
+                None
+            } else {
+                Some(&key[first + 1..last])
+            };
+            ((&key[..first], sub), &key[last + 1..])
+        }
+        _ => {
+            return Err(WyagError::new(
+                format!("key does not contain a section: {}", key).as_ref(),
+            ));
+        }
+    };
+
+    let raw = match std::fs::read_to_string(repo_path_gr(repo, vec!["config"])) {
+        Ok(r) => r,
+        Err(m) => {
+            return Err(WyagError::new_with_error(
+                "Failed to read the repository config",
+                Box::new(m),
+            ));
+        }
+    };
+
+    let mut values: Vec<String> = Vec::new();
+    let mut in_section = false;
+    for line in raw.lines() {
+        let line = line.trim();
+        if line.starts_with('#') || line.starts_with(';') || line.len() == 0 {
+            continue;
+        }
+        if line.starts_with('[') {
+            let header = line.trim_start_matches('[').trim_end_matches(']').trim();
+            let (sec, sub) = match header.find(' ') {
+                Some(i) => (&header[..i], Some(header[i..].trim().trim_matches('"'))),
+                None => (header, None),
+            };
+            in_section = sec.eq_ignore_ascii_case(section.0) && sub == section.1;
+            continue;
+        }
+        if !in_section {
+            continue;
+        }
+        let (k, v) = match line.find('=') {
+            Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+            // A bare name is a boolean set to true
+            None => (line, "true"),
+        };
+        if k.eq_ignore_ascii_case(name) {
+            let v = if v.len() >= 2 && v.starts_with('"') && v.ends_with('"') {
+                &v[1..v.len() - 1]
+            } else {
+                v
+            };
+            values.push(v.to_owned());
+        }
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn get_all_returns_every_fetch_line() {
+        let path = scratch_dir("wyag_config_get_all");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let config = repo_path_gr(&gr, vec!["config"]);
+        let mut raw = std::fs::read_to_string(&config).unwrap();
+        raw.push_str(
+            "[pack]\n\twindow = 10\n\tdepth = 50\n\
+             [remote \"origin\"]\n\
+             \turl = https://example.com/repo.git\n\
+             \tfetch = +refs/heads/*:refs/remotes/origin/*\n\
+             \tfetch = +refs/tags/*:refs/tags/*\n\
+             [remote \"Origin\"]\n\
+             \tfetch = +refs/notes/*:refs/notes/*\n",
+        );
+        std::fs::write(&config, raw).unwrap();
+
+        // Pack tuning is none of wyag's business, but mustn't stop the repository from opening
+        let gr = GitRepository::new(&path, false).unwrap();
+        assert_eq!(
+            config_get_all(&gr, "remote.origin.fetch").unwrap(),
+            vec![
+                "+refs/heads/*:refs/remotes/origin/*",
+                "+refs/tags/*:refs/tags/*"
+            ]
+        );
+        assert_eq!(config_get_all(&gr, "REMOTE.origin.FETCH").unwrap().len(), 2);
+        assert_eq!(config_get_all(&gr, "pack.window").unwrap(), vec!["10"]);
+        assert_eq!(
+            config_get_all(&gr, "pack.threads").unwrap(),
+            Vec::<String>::new()
+        );
+        assert!(config_get_all(&gr, "nosection").is_err());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
+
+/// EndRegion: Config
+
+/// Region: Attributes
+
+/// A single line of a `.gitattributes` file: a path pattern and the attributes it sets.
+///
+/// `text` is stored as "true", `-text` as "false", and `key=value` as given.
+/// `!key` removes any earlier setting for the matched path.
+struct GitAttributeRule {
+    pattern: String,
+    attrs: Vec<(String, Option<String>)>,
+}
+
+/// Reads and parses the `.gitattributes` file at the root of the worktree.
+///
+/// A missing file is not an error, it just means there are no rules.
+fn attributes_load(repo: &GitRepository) -> Result<Vec<GitAttributeRule>, WyagError> {
+    let p = PathBuf::from(&repo.worktree).join(".gitattributes");
+    if !p.is_file() {
+        return Ok(Vec::new());
+    }
+    match std::fs::read_to_string(&p) {
+        Ok(s) => Ok(attributes_parse(&s)),
+        Err(m) => Err(WyagError::new_with_error(
+            "Failed to read .gitattributes file",
+            Box::new(m),
+        )),
+    }
+}
+
+fn attributes_parse(raw: &str) -> Vec<GitAttributeRule> {
+    let mut rules: Vec<GitAttributeRule> = Vec::new();
+    for line in raw.lines() {
+        let line = line.trim();
+        if line.len() == 0 || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.split_whitespace();
+        let pattern = match parts.next() {
+            Some(p) => p.to_owned(),
+            None => continue,
+        };
+        let mut attrs: Vec<(String, Option<String>)> = Vec::new();
+        for a in parts {
+            if a.starts_with('-') {
+                attrs.push((a[1..].to_owned(), Some("false".to_owned())));
+            } else if a.starts_with('!') {
+                attrs.push((a[1..].to_owned(), None));
+            } else if let Some(i) = a.find('=') {
+                attrs.push((a[..i].to_owned(), Some(a[i + 1..].to_owned())));
+            } else {
+                attrs.push((a.to_owned(), Some("true".to_owned())));
+            }
+        }
+        rules.push(GitAttributeRule {
+            pattern: pattern,
+            attrs: attrs,
+        });
+    }
+    rules
+}
+
+/// Returns the value of `attr` for the repo-relative `path`. Later rules win over earlier ones.
+fn attributes_get(rules: &[GitAttributeRule], path: &str, attr: &str) -> Option<String> {
+    let path = path.replace('\\', "/");
+    let path = path.trim_start_matches("./");
+    let basename = match path.rfind('/') {
+        Some(i) => &path[i + 1..],
+        None => path,
+    };
+
+    let mut value: Option<String> = None;
+    for rule in rules {
+        // Patterns without a slash match the file name at any depth
+        let matched = if rule.pattern.contains('/') {
+            glob_match(rule.pattern.trim_start_matches('/'), path)
+        } else {
+            glob_match(&rule.pattern, basename)
+        };
+        if !matched {
+            continue;
+        }
+        for (k, v) in &rule.attrs {
+            if k == attr {
+                value = v.clone();
+            }
+        }
+    }
+    value
+}
+
+/// Shell-style wildcard matching, as used by `.gitattributes`.
+///
+/// `*` and `?` never match a `/`, `**` matches across directories, and `[...]`
+/// matches a character class (`[!...]` negates it).
+fn glob_match(pattern: &str, text: &str) -> bool {
+    glob_match_bytes(pattern.as_bytes(), text.as_bytes())
+}
+
+fn glob_match_bytes(p: &[u8], t: &[u8]) -> bool {
+    if p.len() == 0 {
+        return t.len() == 0;
+    }
+    match p[0] {
+        b'*' => {
+            if p.len() > 1 && p[1] == b'*' {
+                let mut rest = &p[2..];
+                if rest.len() > 0 && rest[0] == b'/' {
+                    rest = &rest[1..];
+                }
+                (0..=t.len()).any(|i| glob_match_bytes(rest, &t[i..]))
+            } else {
+                (0..=t.len())
+                    .take_while(|&i| i == 0 || t[i - 1] != b'/')
+                    .any(|i| glob_match_bytes(&p[1..], &t[i..]))
+            }
+        }
+        b'?' => t.len() > 0 && t[0] != b'/' && glob_match_bytes(&p[1..], &t[1..]),
+        b'[' => {
+            let end = match p.iter().skip(2).position(|&c| c == b']') {
+                Some(i) => i + 2,
+                None => return t.len() > 0 && t[0] == b'[' && glob_match_bytes(&p[1..], &t[1..]),
+            };
+            if t.len() == 0 {
+                return false;
+            }
+            let mut class = &p[1..end];
+            let negate = class[0] == b'!' || class[0] == b'^';
+            if negate {
+                class = &class[1..];
+            }
+            let mut found = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    if class[i] <= t[0] && t[0] <= class[i + 2] {
+                        found = true;
+                    }
+                    i += 3;
+                } else {
+                    if class[i] == t[0] {
+                        found = true;
+                    }
+                    i += 1;
+                }
+            }
+            found != negate && glob_match_bytes(&p[end + 1..], &t[1..])
+        }
+        c => t.len() > 0 && t[0] == c && glob_match_bytes(&p[1..], &t[1..]),
+    }
+}
+
+/// EndRegion: Attributes
+
+/// Region: Ignore
+
+/// A single pattern from a `.gitignore` file.
+struct GitIgnoreRule {
+    /// The glob itself, stripped of any leading `!`, leading `/` and trailing `/`
+    pattern: String,
+    /// Directory (relative to the walk root) of the `.gitignore` that defined this rule
+    base: String,
+    /// `!pattern` re-includes a previously ignored path
+    negate: bool,
+    /// `pattern/` only matches directories
+    dir_only: bool,
+    /// Patterns containing a `/` are matched against the whole path from `base`, not just the name
+    anchored: bool,
+}
+
+fn ignore_parse(raw: &str, base: &str) -> Vec<GitIgnoreRule> {
+    let mut rules: Vec<GitIgnoreRule> = Vec::new();
+    for line in raw.lines() {
+        let line = line.trim_end();
+        if line.len() == 0 || line.starts_with('#') {
+            continue;
+        }
+        let mut pattern = line;
+        let negate = pattern.starts_with('!');
+        if negate {
+            pattern = &pattern[1..];
+        }
+        let dir_only = pattern.ends_with('/');
+        if dir_only {
+            pattern = &pattern[..pattern.len() - 1];
+        }
+        let anchored = pattern.contains('/');
+        rules.push(GitIgnoreRule {
+            pattern: pattern.trim_start_matches('/').to_owned(),
+            base: base.to_owned(),
+            negate: negate,
+            dir_only: dir_only,
+            anchored: anchored,
+        });
+    }
+    rules
+}
+
+/// Checks `path` (relative to the walk root, `/` separated) against `rules`. The last matching rule wins.
+fn ignore_is_ignored(rules: &[GitIgnoreRule], path: &str, is_dir: bool) -> bool {
+    let mut ignored = false;
+    for rule in rules {
+        if rule.dir_only && !is_dir {
+            continue;
+        }
+        let rel = if rule.base.len() == 0 {
+            path
+        } else if path.starts_with(&rule.base) && path[rule.base.len()..].starts_with('/') {
+            &path[rule.base.len() + 1..]
+        } else {
+            continue;
+        };
+        let matched = if rule.anchored {
+            glob_match(&rule.pattern, rel)
+        } else {
+            let name = match rel.rfind('/') {
+                Some(i) => &rel[i + 1..],
+                None => rel,
+            };
+            glob_match(&rule.pattern, name)
+        };
+        if matched {
+            ignored = !rule.negate;
+        }
+    }
+    ignored
+}
+
+/// Loads the repository-local rules from `.git/info/exclude`.
+///
+/// These apply to the whole worktree but rank below any `.gitignore`, so they must come first in the rule list.
+fn ignore_exclude_rules(repo: &GitRepository) -> Result<Vec<GitIgnoreRule>, WyagError> {
+    let path = repo_path_gr(repo, vec!["info", "exclude"]);
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+    match std::fs::read_to_string(&path) {
+        Ok(s) => Ok(ignore_parse(&s, "")),
+        Err(m) => Err(WyagError::new_with_error(
+            "Failed to read .git/info/exclude",
+            Box::new(m),
+        )),
+    }
+}
+
+/// Collects every file below `root.join(rel)` that isn't ignored, skipping `.git`.
+///
+/// Each directory's `.gitignore` is added to `rules` while its contents are walked, so nested
+/// ignore files apply only to their own subtree. Entries are visited in sorted order.
+/// With `include_ignored` the rules are skipped and every file except those under `.git` is collected.
+fn ignore_walk(
+    root: &Path,
+    rel: &str,
+    rules: &mut Vec<GitIgnoreRule>,
+    include_ignored: bool,
+    out: &mut Vec<String>,
+) -> Result<(), WyagError> {
+    let dir = root.join(rel);
+    let rules_before = rules.len();
+    let gitignore = dir.join(".gitignore");
+    if gitignore.is_file() {
+        match std::fs::read_to_string(&gitignore) {
+            Ok(s) => rules.extend(ignore_parse(&s, rel)),
+            Err(m) => {
+                return Err(WyagError::new_with_error(
+                    "Failed to read .gitignore file",
+                    Box::new(m),
+                ));
+            }
+        };
+    }
+
+    let mut names: Vec<(String, bool)> = Vec::new();
+    let i = match std::fs::read_dir(&dir) {
+        Ok(i) => i,
+        Err(m) => {
+            return Err(WyagError::new_with_error(
+                "Failed to read directory while walking the worktree",
+                Box::new(m),
+            ));
+        }
+    };
+    for item in i {
+        match item {
+            Ok(fd) => {
+                let name = fd.file_name().to_string_lossy().into_owned();
+                names.push((name, fd.path().is_dir()));
+            }
+            Err(m) => {
+                return Err(WyagError::new_with_error(
+                    "Failed to read item in directory",
+                    Box::new(m),
+                ));
+            }
+        };
+    }
+    names.sort();
+
+    for (name, is_dir) in names {
+        if name == ".git" {
+            continue;
+        }
+        let child = if rel.len() == 0 {
+            name
+        } else {
+            format!("{}/{}", rel, name)
+        };
+        if !include_ignored && ignore_is_ignored(rules, &child, is_dir) {
+            continue;
+        }
+        if is_dir {
+            ignore_walk(root, &child, rules, include_ignored, out)?;
+        } else {
+            out.push(child);
+        }
+    }
+
+    rules.truncate(rules_before);
+    Ok(())
+}
+
+/// Yields the repo-relative path of every file in the worktree, in sorted order, never entering `.git`.
+///
+/// Files matched by `.git/info/exclude` or a `.gitignore` (nested ones apply to their own subtree) are
+/// left out unless `include_ignored` is set.
+fn walk_worktree(
+    repo: &GitRepository,
+    include_ignored: bool,
+) -> Result<impl Iterator<Item = PathBuf>, WyagError> {
+    let mut rules = ignore_exclude_rules(repo)?;
+    let mut files: Vec<String> = Vec::new();
+    ignore_walk(
+        Path::new(&repo.worktree),
+        "",
+        &mut rules,
+        include_ignored,
+        &mut files,
+    )?;
+    Ok(files.into_iter().map(PathBuf::from))
+}
+
+#[cfg(test)]
+mod ignore_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn ignore_rules_last_match_wins() {
+        let rules = ignore_parse("*.log\n!keep.log\nbuild/\n/root.txt\n", "");
+        assert!(ignore_is_ignored(&rules, "a.log", false));
+        assert!(ignore_is_ignored(&rules, "sub/a.log", false));
+        assert!(!ignore_is_ignored(&rules, "keep.log", false));
+        assert!(ignore_is_ignored(&rules, "build", true));
+        assert!(!ignore_is_ignored(&rules, "build", false));
+        assert!(ignore_is_ignored(&rules, "root.txt", false));
+        assert!(!ignore_is_ignored(&rules, "sub/root.txt", false));
+    }
+
+    #[test]
+    fn info_exclude_ranks_below_gitignore() {
+        let path = scratch_dir("wyag_ignore_info_exclude");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let root = PathBuf::from(&path);
+        std::fs::write(
+            repo_file_gr(&gr, true, vec!["info", "exclude"]).unwrap(),
+            "*.tmp\nsecret/\n",
+        )
+        .unwrap();
+        std::fs::write(root.join(".gitignore"), "!keep.tmp\n").unwrap();
+        std::fs::create_dir_all(root.join("secret")).unwrap();
+        std::fs::write(root.join("scratch.tmp"), "x").unwrap();
+        std::fs::write(root.join("keep.tmp"), "x").unwrap();
+        std::fs::write(root.join("secret").join("key"), "x").unwrap();
+        std::fs::write(root.join("tracked.txt"), "x").unwrap();
+
+        let untracked: Vec<String> = status_untracked(&gr)
+            .unwrap()
+            .into_iter()
+            .map(|(_, p)| p)
+            .collect();
+        assert_eq!(untracked, vec![".gitignore", "keep.tmp", "tracked.txt"]);
+
+        let hashed: Vec<String> = hash_object_dir(&path, Some(&gr))
+            .unwrap()
+            .into_iter()
+            .map(|(_, p)| p)
+            .collect();
+        assert_eq!(hashed, vec![".gitignore", "keep.tmp", "tracked.txt"]);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn hash_object_dir_skips_ignored_files() {
+        let path = scratch_dir("wyag_hash_object_dir");
+        let root = PathBuf::from(&path);
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::create_dir_all(root.join("build")).unwrap();
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        std::fs::write(root.join(".gitignore"), "*.log\nbuild/\n").unwrap();
+        std::fs::write(root.join("a.txt"), "hello\n").unwrap();
+        std::fs::write(root.join("sub").join("b.txt"), "").unwrap();
+        std::fs::write(root.join("debug.log"), "ignored\n").unwrap();
+        std::fs::write(root.join("build").join("out.txt"), "ignored\n").unwrap();
+        std::fs::write(root.join(".git").join("HEAD"), "ignored\n").unwrap();
+
+        let hashes = hash_object_dir(&path, None).unwrap();
+        assert_eq!(
+            hashes,
+            vec![
+                (
+                    "749504ca09128068ab007183124f9aa5d3fe5dad".to_owned(),
+                    ".gitignore".to_owned()
+                ),
+                (
+                    "ce013625030ba8dba906f756967f9e9ca394464a".to_owned(),
+                    "a.txt".to_owned()
+                ),
+                (
+                    "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391".to_owned(),
+                    "sub/b.txt".to_owned()
+                ),
+            ]
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn walk_worktree_skips_ignored_dirs_unless_asked() {
+        let path = scratch_dir("wyag_walk_worktree");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let root = PathBuf::from(&path);
+        std::fs::create_dir_all(root.join("target").join("debug")).unwrap();
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::write(root.join(".gitignore"), "target/\n").unwrap();
+        std::fs::write(root.join("src").join(".gitignore"), "*.bak\n").unwrap();
+        std::fs::write(root.join("src").join("main.rs"), "x").unwrap();
+        std::fs::write(root.join("src").join("main.rs.bak"), "x").unwrap();
+        std::fs::write(root.join("target").join("debug").join("app"), "x").unwrap();
+
+        let walked: Vec<PathBuf> = walk_worktree(&gr, false).unwrap().collect();
+        assert_eq!(
+            walked,
+            vec![
+                PathBuf::from(".gitignore"),
+                PathBuf::from("src/.gitignore"),
+                PathBuf::from("src/main.rs"),
+            ]
+        );
+
+        let all: Vec<PathBuf> = walk_worktree(&gr, true).unwrap().collect();
+        assert_eq!(
+            all,
+            vec![
+                PathBuf::from(".gitignore"),
+                PathBuf::from("src/.gitignore"),
+                PathBuf::from("src/main.rs"),
+                PathBuf::from("src/main.rs.bak"),
+                PathBuf::from("target/debug/app"),
+            ]
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
+
+/// EndRegion: Ignore
+
+/// Region: Filters
+
+/// Looks up `filter.<name>.<kind>` in the repository config.
+fn filter_command(repo: &GitRepository, name: &str, kind: &str) -> Option<String> {
+    let section = format!("filter \"{}\"", name);
+    match repo.conf.section(Some(section)) {
+        Some(s) => s.get(kind).map(|c| c.to_owned()),
+        None => None,
+    }
+}
+
+/// Runs `data` through the external filter for `path` named by its `filter=` attribute.
+///
+/// `kind` is either "clean" (worktree -> object store) or "smudge" (object store -> worktree).
+/// If no filter is configured for the path, the data is returned untouched.
+fn filter_apply(
+    repo: &GitRepository,
+    rules: &[GitAttributeRule],
+    path: &str,
+    kind: &str,
+    data: Vec<u8>,
+) -> Result<Vec<u8>, WyagError> {
+    match filter_lookup(repo, rules, path, kind) {
+        Some(cmd) => filter_run(&cmd, data),
+        None => Ok(data),
+    }
+}
+
+/// Finds the `kind` filter command that `rules` and the config set up for `path`, if any.
+fn filter_lookup(
+    repo: &GitRepository,
+    rules: &[GitAttributeRule],
+    path: &str,
+    kind: &str,
+) -> Option<String> {
+    let name = attributes_get(rules, path, "filter")?;
+    filter_command(repo, &name, kind)
+}
+
+/// Applies the clean filter, then line-ending normalization, used when content is written into the object store.
+///
+/// `rules` are the worktree's attributes, which callers load once per command with attributes_load.
+fn filter_clean(
+    repo: &GitRepository,
+    rules: &[GitAttributeRule],
+    path: &str,
+    data: Vec<u8>,
+) -> Result<Vec<u8>, WyagError> {
+    let data = filter_apply(repo, rules, path, "clean", data)?;
+    Ok(eol_to_git(eol_mode(repo, rules, path), data))
+}
+
+/// Applies line-ending conversion, then the smudge filter, used when content is checked out into the worktree.
+fn filter_smudge(
+    repo: &GitRepository,
+    rules: &[GitAttributeRule],
+    path: &str,
+    data: Vec<u8>,
+) -> Result<Vec<u8>, WyagError> {
+    let data = eol_to_worktree(eol_mode(repo, rules, path), data);
+    filter_apply(repo, rules, path, "smudge", data)
+}
+
+/// How the line endings of a path are converted between the worktree and the object store.
+#[derive(Debug, PartialEq)]
+enum EolMode {
+    /// Stored and checked out byte for byte
+    Untouched,
+    /// CRLFs are normalized to LF when stored, and checked out as stored
+    Lf,
+    /// CRLFs are normalized to LF when stored, and LFs become CRLF when checked out
+    Crlf,
+    /// Like `Lf` or `Crlf`, but only for content that doesn't look binary
+    Auto(bool),
+}
+
+/// Decides the `EolMode` for the repo-relative `path`.
+///
+/// The `text` and `eol` attributes take precedence: `-text` turns conversion off, `eol=lf` / `eol=crlf`
+/// pick the checkout ending, and a bare `text` falls back to `core.eol`. Only paths with no attributes
+/// follow `core.autocrlf` ("true" converts both ways, "input" only normalizes when storing).
+fn eol_mode(repo: &GitRepository, rules: &[GitAttributeRule], path: &str) -> EolMode {
+    let core = |key: &str| -> Option<String> {
+        match repo.conf.section(Some("core".to_owned())) {
+            Some(c) => c.get(key).map(|v| v.trim().to_lowercase()),
+            None => None,
+        }
+    };
+    let autocrlf = core("autocrlf");
+    let checkout_crlf = match attributes_get(rules, path, "eol") {
+        Some(ref e) if e == "crlf" => Some(true),
+        Some(ref e) if e == "lf" => Some(false),
+        _ => None,
+    };
+    let default_crlf = || match core("eol") {
+        Some(ref e) if e == "crlf" => true,
+        Some(ref e) if e == "lf" => false,
+        _ => autocrlf.as_ref().map(|a| a == "true").unwrap_or(false),
+    };
+
+    match attributes_get(rules, path, "text") {
+        Some(ref t) if t == "false" => EolMode::Untouched,
+        Some(ref t) if t == "auto" => EolMode::Auto(checkout_crlf.unwrap_or_else(default_crlf)),
+        Some(_) => {
+            if checkout_crlf.unwrap_or_else(default_crlf) {
+                EolMode::Crlf
+            } else {
+                EolMode::Lf
+            }
+        }
+        // eol= on its own implies text
+        None => match checkout_crlf {
+            Some(true) => EolMode::Crlf,
+            Some(false) => EolMode::Lf,
+            None => match autocrlf.as_ref().map(|a| a.as_str()) {
+                Some("true") => EolMode::Auto(true),
+                Some("input") => EolMode::Auto(false),
+                _ => EolMode::Untouched,
+            },
+        },
+    }
+}
+
+/// Content with a NUL byte is treated as binary, and never has its line endings touched.
+fn eol_is_binary(data: &[u8]) -> bool {
+    data.contains(&b'\x00')
+}
+
+/// Normalizes CRLF line endings to LF, for content on its way into the object store.
+fn eol_to_git(mode: EolMode, data: Vec<u8>) -> Vec<u8> {
+    match mode {
+        EolMode::Untouched => return data,
+        EolMode::Auto(_) if eol_is_binary(&data) => return data,
+        _ => (),
+    }
+    let mut out: Vec<u8> = Vec::with_capacity(data.len());
+    for (i, &b) in data.iter().enumerate() {
+        if b == b'\r' && data.get(i + 1) == Some(&b'\n') {
+            continue;
+        }
+        out.push(b);
+    }
+    out
+}
+
+/// Expands LF line endings to CRLF when the mode asks for it, for content on its way into the worktree.
+fn eol_to_worktree(mode: EolMode, data: Vec<u8>) -> Vec<u8> {
+    match mode {
+        EolMode::Crlf => (),
+        EolMode::Auto(true) if !eol_is_binary(&data) => (),
+        _ => return data,
+    }
+    let mut out: Vec<u8> = Vec::with_capacity(data.len());
+    for (i, &b) in data.iter().enumerate() {
+        if b == b'\n' && (i == 0 || data[i - 1] != b'\r') {
+            out.push(b'\r');
+        }
+        out.push(b);
+    }
+    out
+}
+
+/// Pipes `data` through the shell command `cmd` and returns what it printed.
+fn filter_run(cmd: &str, data: Vec<u8>) -> Result<Vec<u8>, WyagError> {
+    let mut shell = if cfg!(windows) {
+        let mut c = std::process::Command::new("cmd");
+        c.arg("/C");
+        c
+    } else {
+        let mut c = std::process::Command::new("sh");
+        c.arg("-c");
+        c
+    };
+    let mut child = match shell
+        .arg(cmd)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+    {
+        Ok(c) => c,
+        Err(m) => {
+            return Err(WyagError::new_with_error(
+                format!("Failed to start filter command '{}'", cmd).as_ref(),
+                Box::new(m),
+            ));
+        }
+    };
+
+    // Feed stdin from another thread so a filter that writes before it finishes reading can't deadlock us
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = std::thread::spawn(move || stdin.write_all(&data));
+
+    let output = match child.wait_with_output() {
+        Ok(o) => o,
+        Err(m) => {
+            return Err(WyagError::new_with_error(
+                format!("Failed to run filter command '{}'", cmd).as_ref(),
+                Box::new(m),
+            ));
+        }
+    };
+    if let Ok(Err(m)) = writer.join() {
+        return Err(WyagError::new_with_error(
+            format!("Failed to write to filter command '{}'", cmd).as_ref(),
+            Box::new(m),
+        ));
+    }
+    if !output.status.success() {
+        return Err(WyagError::new(
+            format!("Filter command '{}' exited with {}", cmd, output.status).as_ref(),
+        ));
+    }
+
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod filter_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    /// Runs `*.txt` files in `gr` through the filter driver `filter`, with the given clean and smudge commands.
+    fn with_filter(gr: GitRepository, filter: &str, clean: &str, smudge: &str) -> GitRepository {
+        std::fs::write(
+            PathBuf::from(&gr.worktree).join(".gitattributes"),
+            format!("*.txt filter={}\n", filter),
+        )
+        .unwrap();
+        let mut conf = Ini::load_from_file(gr.gitdir.join("config")).unwrap();
+        conf.with_section(Some(format!("filter \"{}\"", filter)))
+            .set("clean", clean)
+            .set("smudge", smudge);
+        GitRepository { conf: conf, ..gr }
+    }
+
+    #[test]
+    fn eol_attribute_overrides_autocrlf() {
+        let path = scratch_dir("wyag_filter_eol");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        std::fs::write(
+            PathBuf::from(&path).join(".gitattributes"),
+            "*.sh eol=lf\n*.bin -text\n",
+        )
+        .unwrap();
+        let mut conf = Ini::load_from_file(gr.gitdir.join("config")).unwrap();
+        conf.with_section(Some("core".to_owned()))
+            .set("autocrlf", "true");
+        let gr = GitRepository { conf: conf, ..gr };
+
+        // Stored with LF
+        let script = PathBuf::from(&path).join("run.sh");
+        std::fs::write(&script, "echo hi\r\nexit 0\r\n").unwrap();
+        let mut fd = std::fs::File::open(&script).unwrap();
+        let sha = hash_object(&mut fd, "blob", "run.sh", Some(&gr)).unwrap();
+        assert_eq!(
+            object_read_raw(&gr, &sha).unwrap().1,
+            b"echo hi\nexit 0\n".to_vec()
+        );
+
+        // Checked out with LF, despite core.autocrlf
+        let out = PathBuf::from(&path).join("out.sh");
+        let attributes = attributes_load(&gr).unwrap();
+        write_blob_entry(&out, b"100644", &gr, &attributes, &sha, Path::new("run.sh")).unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), b"echo hi\nexit 0\n".to_vec());
+
+        // Paths without attributes still follow core.autocrlf, and -text is left alone
+        let smudged = filter_smudge(
+            &gr,
+            &attributes_load(&gr).unwrap(),
+            "notes.txt",
+            b"a\nb\n".to_vec(),
+        )
+        .unwrap();
+        assert_eq!(smudged, b"a\r\nb\r\n".to_vec());
+        let cleaned = filter_clean(
+            &gr,
+            &attributes_load(&gr).unwrap(),
+            "data.bin",
+            b"a\r\n".to_vec(),
+        )
+        .unwrap();
+        assert_eq!(cleaned, b"a\r\n".to_vec());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn glob_matches_basic_wildcards() {
+        assert!(glob_match("*.txt", "hello.txt"));
+        assert!(!glob_match("*.txt", "dir/hello.txt"));
+        assert!(glob_match("**/*.txt", "dir/hello.txt"));
+        assert!(glob_match("h?llo.[a-z]xt", "hello.txt"));
+        assert!(!glob_match("h?llo.[!t]xt", "hello.txt"));
+    }
+
+    #[test]
+    fn attributes_later_rules_win() {
+        let rules = attributes_parse("*.txt filter=a\n# comment\ndocs/*.txt filter=b -text\n");
+        assert_eq!(
+            attributes_get(&rules, "x.txt", "filter"),
+            Some("a".to_owned())
+        );
+        assert_eq!(
+            attributes_get(&rules, "docs/x.txt", "filter"),
+            Some("b".to_owned())
+        );
+        assert_eq!(
+            attributes_get(&rules, "docs/x.txt", "text"),
+            Some("false".to_owned())
+        );
+        assert_eq!(attributes_get(&rules, "x.bin", "filter"), None);
+    }
+
+    #[test]
+    fn identity_filter_passes_content_through() {
+        let path = scratch_dir("wyag_filter_identity");
+        let gr = with_filter(
+            GitRepository::repo_create(&path).unwrap(),
+            "ident",
+            "cat",
+            "cat",
+        );
+
+        let data = b"some content\n".to_vec();
+        let cleaned =
+            filter_clean(&gr, &attributes_load(&gr).unwrap(), "a.txt", data.clone()).unwrap();
+        assert_eq!(cleaned, data);
+        let smudged = filter_smudge(&gr, &attributes_load(&gr).unwrap(), "a.txt", cleaned).unwrap();
+        assert_eq!(smudged, data);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn transforming_filter_changes_content() {
+        let path = scratch_dir("wyag_filter_tr");
+        let gr = with_filter(
+            GitRepository::repo_create(&path).unwrap(),
+            "upper",
+            "tr a-z A-Z",
+            "tr A-Z a-z",
+        );
+
+        let cleaned = filter_clean(
+            &gr,
+            &attributes_load(&gr).unwrap(),
+            "a.txt",
+            b"hello\n".to_vec(),
+        )
+        .unwrap();
+        assert_eq!(cleaned, b"HELLO\n".to_vec());
+        let smudged = filter_smudge(&gr, &attributes_load(&gr).unwrap(), "a.txt", cleaned).unwrap();
+        assert_eq!(smudged, b"hello\n".to_vec());
+
+        // Paths without the attribute are left alone
+        let untouched = filter_clean(
+            &gr,
+            &attributes_load(&gr).unwrap(),
+            "a.bin",
+            b"hello\n".to_vec(),
+        )
+        .unwrap();
+        assert_eq!(untouched, b"hello\n".to_vec());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn hash_object_dir_matches_attributes_against_worktree_paths() {
+        let path = scratch_dir("wyag_filter_hash_dir");
+        let gr = with_filter(
+            GitRepository::repo_create(&path).unwrap(),
+            "upper",
+            "tr a-z A-Z",
+            "tr A-Z a-z",
+        );
+        let root = PathBuf::from(&path);
+        std::fs::write(root.join(".gitattributes"), "sub/*.txt filter=upper\n").unwrap();
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub").join("a.txt"), "hello\n").unwrap();
+
+        // a.txt is sub/a.txt to .gitattributes, though it is hashed relative to sub
+        let hashed = hash_object_dir(root.join("sub").to_str().unwrap(), Some(&gr)).unwrap();
+        let upper = GitBlob::new(None, b"HELLO\n");
+        assert_eq!(
+            hashed,
+            vec![(object_write(&upper, false).unwrap().sha, "a.txt".to_owned())]
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn cat_file_filters_applies_smudge_for_path() {
+        let path = scratch_dir("wyag_filter_cat_file");
+        let gr = with_filter(
+            GitRepository::repo_create(&path).unwrap(),
+            "upper",
+            "tr a-z A-Z",
+            "tr A-Z a-z",
+        );
+        let sha = write_loose_object(&gr, b"blob", b"HELLO\n");
+
+        assert_eq!(
+            cat_file_filtered(&gr, &sha, "a.txt").unwrap(),
+            b"hello\n".to_vec()
+        );
+        assert_eq!(
+            cat_file_filtered(&gr, &sha, "a.bin").unwrap(),
+            b"HELLO\n".to_vec()
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn cat_file_filters_identity_matches_raw_blob() {
+        let path = scratch_dir("wyag_filter_cat_file_ident");
+        let gr = with_filter(
+            GitRepository::repo_create(&path).unwrap(),
+            "ident",
+            "cat",
+            "cat",
+        );
+        let sha = write_loose_object(&gr, b"blob", b"raw content\n");
+
+        assert_eq!(
+            cat_file_filtered(&gr, &sha, "a.txt").unwrap(),
+            b"raw content\n".to_vec()
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
+
+/// EndRegion: Filters
+
+#[derive(Debug, Default)]
+pub struct WyagError {
+    _message: String,
+    _err: Option<Box<dyn Error>>,
+}
+
+impl WyagError {
+    pub fn new(message: &str) -> WyagError {
+        WyagError {
+            _message: String::from(message),
+            _err: None,
+        }
+    }
+
+    pub fn new_with_error(message: &str, err: Box<std::error::Error>) -> WyagError {
+        WyagError {
+            _message: String::from(message),
+            _err: Some(err),
+        }
+    }
+}
+
+impl Error for WyagError {
+    fn description(&self) -> &str {
+        self._message.as_ref()
+    }
+}
+
+impl fmt::Display for WyagError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(e) = &self._err {
+            writeln!(f, "{}: {}", self._message, e)
+        } else {
+            writeln!(f, "{}", self._message)
+        }
+    }
+}
+
+#[cfg(test)]
+mod cat_file_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn cat_file() {}
+
+    #[test]
+    fn binary_blobs_are_written_raw() {
+        let binary = b"\x89PNG\r\n\x1a\n\x00\x00\xff\xfe".to_vec();
+        assert!(cat_file_is_binary(&binary));
+        let mut out: Vec<u8> = Vec::new();
+        cat_file_write(&mut out, binary.clone()).unwrap();
+        assert_eq!(out, binary);
+
+        let mut out: Vec<u8> = Vec::new();
+        cat_file_write(&mut out, b"plain text".to_vec()).unwrap();
+        assert_eq!(out, b"plain text\n");
+
+        // Not UTF-8, but no NUL either
+        let latin1 = b"caf\xe9 cr\xe8me".to_vec();
+        assert!(!cat_file_is_binary(&latin1));
+        let mut out: Vec<u8> = Vec::new();
+        cat_file_write(&mut out, latin1).unwrap();
+        assert_eq!(out, b"caf\xe9 cr\xe8me\n");
+
+        // A NUL past the sniffed chunk isn't looked for
+        let mut late = vec![b'a'; CAT_FILE_SNIFF_LEN];
+        late.push(0);
+        assert!(!cat_file_is_binary(&late));
+    }
+
+    #[test]
+    fn commit_reserializes_to_its_own_sha() {
+        let path = scratch_dir("wyag_cat_file_commit");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let root = write_commit(&gr, &[], 1000, "first");
+        let sha = write_commit(&gr, &[&root], 2000, "second\n\nwith a body");
+
+        let (found, raw, rehashed) = cat_file_commit(&gr, &sha).unwrap();
+        assert_eq!(found, sha);
+        assert_eq!(rehashed, sha);
+        let (_, stored) = object_read_raw(&gr, &sha).unwrap();
+        assert_eq!(raw, stored);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod parse_object_tests {
+    use super::*;
+
+    #[test]
+    fn parses_blob() {
+        match parse_object(b"blob 6\x00hello\n").unwrap() {
+            GObj::Blob(b) => {
+                assert_eq!(b.blob_data, b"hello\n".to_vec());
+                assert!(b.repo.is_none());
+            }
+            _ => panic!("expected a blob"),
+        }
+    }
+
+    #[test]
+    fn parses_tree() {
+        let mut raw = b"tree 37\x00100644 hello.txt\x00".to_vec();
+        raw.extend(&[
+            0xce, 0x01, 0x36, 0x25, 0x03, 0x0b, 0xa8, 0xdb, 0xa9, 0x06, 0xf7, 0x56, 0x96, 0x7f,
+            0x9e, 0x9c, 0xa3, 0x94, 0x46, 0x4a,
+        ]);
+        match parse_object(&raw).unwrap() {
+            GObj::Tree(t) => {
+                assert_eq!(t.items.len(), 1);
+                assert_eq!(t.items[0].mode, b"100644".to_vec());
+                assert_eq!(t.items[0].path, b"hello.txt".to_vec());
+                assert_eq!(t.items[0].sha, "ce013625030ba8dba906f756967f9e9ca394464a");
+            }
+            _ => panic!("expected a tree"),
+        }
+    }
+
+    #[test]
+    fn parses_commit() {
+        let payload = "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\nparent aaaa\n\nA message\n";
+        let raw = format!("commit {}\x00{}", payload.len(), payload);
+        match parse_object(raw.as_bytes()).unwrap() {
+            GObj::Commit(c) => {
+                assert_eq!(
+                    c.kvlm["tree"],
+                    vec!["4b825dc642cb6eb9a060e54bf8d69288fbee4904"]
+                );
+                assert_eq!(c.kvlm["parent"], vec!["aaaa"]);
+                assert_eq!(c.kvlm[""], vec!["A message\n"]);
+            }
+            _ => panic!("expected a commit"),
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        assert!(parse_object(b"blob 5\x00hello\n").is_err());
+        assert!(parse_object(b"blob six\x00hello\n").is_err());
+        assert!(parse_object(b"blob6\x00hello\n").is_err());
+        assert!(parse_object(b"blob 6 hello\n").is_err());
+        assert!(parse_object(b"widget 5\x00hello").is_err());
+    }
+}
+
+#[cfg(test)]
+mod cat_file_info_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn unknown_type_is_reported_when_allowed() {
+        let path = scratch_dir("wyag_cat_file_unknown");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let sha = write_loose_object(&gr, b"widget", b"hello");
+
+        let (gtype, size) = cat_file_info(&gr, &sha, true).unwrap();
+        assert_eq!(gtype, "widget");
+        assert_eq!(size, 5);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn object_type_reads_through_refs() {
+        let path = scratch_dir("wyag_object_type_named");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let c = write_commit(&gr, &[], 1, "first");
+        set_ref(&gr, "refs/heads/master", &c);
+
+        assert_eq!(object_type_named(&gr, "HEAD").unwrap(), "commit");
+        let err = object_type_named(&gr, "nosuchbranch").unwrap_err();
+        assert!(err.to_string().contains("nosuchbranch"));
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn unknown_type_errors_without_flag() {
+        let path = scratch_dir("wyag_cat_file_unknown_strict");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let sha = write_loose_object(&gr, b"widget", b"hello");
+
+        assert!(cat_file_info(&gr, &sha, false).is_err());
+        assert!(object_read(&gr, &sha).is_err());
+
+        let sha = write_loose_object(&gr, b"blob", b"hello");
+        let (gtype, size) = cat_file_info(&gr, &sha, false).unwrap();
+        assert_eq!(gtype, "blob");
+        assert_eq!(size, 5);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+    #[test]
+    fn repeated_header_reads_come_from_the_cache() {
+        let path = scratch_dir("wyag_cat_file_header_cache");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let sha = write_loose_object(&gr, b"blob", b"hello");
+        assert_eq!(
+            cat_file_info(&gr, &sha, false).unwrap(),
+            ("blob".to_owned(), 5)
+        );
+
+        // With the object gone from disk, only the cache can answer
+        std::fs::remove_file(repo_path_gr(&gr, vec!["objects", &sha[..2], &sha[2..]])).unwrap();
+        assert_eq!(
+            cat_file_info(&gr, &sha, false).unwrap(),
+            ("blob".to_owned(), 5)
+        );
+
+        gr.header_cache_clear();
+        assert!(cat_file_info(&gr, &sha, false).is_err());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn batch_check_reports_metadata_or_missing() {
+        let path = scratch_dir("wyag_cat_file_batch_check");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let blob = write_loose_object(&gr, b"blob", b"hello\n");
+        let commit = write_commit(&gr, &[], 1000, "First\n");
+        set_ref(&gr, "refs/heads/master", &commit);
+
+        let input = format!("{}\nHEAD\n\nnope\n{}\n", blob, "1".repeat(40));
+        let lines = cat_file_batch_check(&gr, input.as_bytes()).unwrap();
+        assert_eq!(
+            lines,
+            vec![
+                format!("{} blob 6", blob),
+                format!("{} commit 152", commit),
+                "nope missing".to_owned(),
+                format!("{} missing", "1".repeat(40)),
+            ]
+        );
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn disk_size_is_the_loose_file_length() {
+        let path = scratch_dir("wyag_cat_file_disk_size");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let payload = "hello hello hello hello hello hello\n".repeat(10);
+        let sha = write_loose_object(&gr, b"blob", payload.as_bytes());
+        let file = repo_path_gr(&gr, vec!["objects", &sha[..2], &sha[2..]]);
+
+        let (size, disk) = cat_file_disk_size(&gr, &sha).unwrap();
+        assert_eq!(size, payload.len());
+        assert_eq!(disk, std::fs::read(&file).unwrap().len() as u64);
+        assert!(disk < size as u64);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod object_inflate_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn stream_and_slurp_agree_on_large_objects() {
+        let path = scratch_dir("wyag_object_inflate_large");
+        let gr = GitRepository::repo_create(&path).unwrap();
+
+        // Pseudo-random bytes so the compressed file stays large
+        let mut payload: Vec<u8> = Vec::new();
+        let mut x: u32 = 12345;
+        for _ in 0..(3 * 1024 * 1024) {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            payload.push((x >> 16) as u8);
+        }
+        let sha = write_loose_object(&gr, b"blob", &payload);
+        let file = repo_path_gr(&gr, vec!["objects", &sha[..2], &sha[2..]]);
+        assert!(std::fs::metadata(&file).unwrap().len() > 1024 * 1024);
+
+        let streamed = object_inflate_stream(&file, &sha).unwrap();
+        let slurped = object_inflate_slurp(&file, &sha).unwrap();
+        assert_eq!(streamed, slurped);
+
+        match object_read(&gr, &sha).unwrap() {
+            GObj::Blob(b) => assert_eq!(b.blob_data, payload),
+            _ => panic!("expected a blob"),
+        }
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn every_compression_level_reads_back() {
+        let path = scratch_dir("wyag_object_inflate_levels");
+        let gr = GitRepository::repo_create(&path).unwrap();
+
+        // Runs of text between pseudo-random bytes, big enough to take many reads to inflate
+        let mut x: u32 = 777;
+        let mut big: Vec<u8> = Vec::new();
+        while big.len() < 300 * 1024 {
+            big.extend_from_slice(b"the same line of text, over and over\n");
+            for _ in 0..64 {
+                x = x.wrapping_mul(1103515245).wrapping_add(12345);
+                big.push((x >> 16) as u8);
+            }
+        }
+        let small: &[u8] = b"hello\n";
+
+        for payload in &[&b""[..], small, &big[..]] {
+            let sha = write_loose_object(&gr, b"blob", payload);
+            let file = repo_path_gr(&gr, vec!["objects", &sha[..2], &sha[2..]]);
+            let mut raw: Vec<u8> = format!("blob {}\x00", payload.len()).into_bytes();
+            raw.extend_from_slice(payload);
+
+            for level in 0..10 {
+                // Flushing between chunks splits the stream into several deflate blocks
+                let mut e = ZlibEncoder::new(Vec::new(), Compression::new(level));
+                for chunk in raw.chunks(10 * 1024) {
+                    e.write_all(chunk).unwrap();
+                    e.flush().unwrap();
+                }
+                std::fs::write(&file, e.finish().unwrap()).unwrap();
+                gr.header_cache_clear();
+
+                match object_read(&gr, &sha).unwrap() {
+                    GObj::Blob(b) => assert_eq!(&b.blob_data[..], *payload, "level {}", level),
+                    _ => panic!("expected a blob"),
+                }
+                assert_eq!(object_inflate_stream(&file, &sha).unwrap(), raw);
+                assert_eq!(
+                    object_read_header(&gr, &sha).unwrap(),
+                    ("blob".to_owned(), payload.len())
+                );
+            }
+        }
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn big_file_threshold_picks_the_streaming_path() {
+        let path = scratch_dir("wyag_object_inflate_threshold");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let mut x: u32 = 54321;
+        let payload: Vec<u8> = (0..64 * 1024)
+            .map(|_| {
+                x = x.wrapping_mul(1103515245).wrapping_add(12345);
+                (x >> 16) as u8
+            })
+            .collect();
+        let sha = write_loose_object(&gr, b"blob", &payload);
+        let file = repo_path_gr(&gr, vec!["objects", &sha[..2], &sha[2..]]);
+
+        // Moderately sized, so git's default slurps it
+        let threshold = big_file_threshold(&gr).unwrap();
+        assert_eq!(threshold, 512 * 1024 * 1024);
+        assert!(!object_inflate_streams(&file, &sha, threshold).unwrap());
+
+        let mut conf = Ini::load_from_file(gr.gitdir.join("config")).unwrap();
+        conf.with_section(Some("core".to_owned()))
+            .set("bigFileThreshold", "1k");
+        let gr = GitRepository { conf: conf, ..gr };
+        let threshold = big_file_threshold(&gr).unwrap();
+        assert_eq!(threshold, 1024);
+        assert!(object_inflate_streams(&file, &sha, threshold).unwrap());
+        match object_read(&gr, &sha).unwrap() {
+            GObj::Blob(b) => assert_eq!(b.blob_data, payload),
+            _ => panic!("expected a blob"),
+        }
+
+        assert_eq!(config_parse_size("3m"), Some(3 * 1024 * 1024));
+        assert_eq!(config_parse_size("2G"), Some(2 * 1024 * 1024 * 1024));
+        assert_eq!(config_parse_size("100"), Some(100));
+        assert_eq!(config_parse_size("lots"), None);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod loose_objects_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn type_filter_yields_only_commits() {
+        let path = scratch_dir("wyag_loose_objects");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let blob = write_loose_object(&gr, b"blob", b"hello\n");
+        let commit = write_commit(&gr, &[], 1, "first");
+        // the empty tree the commit points at isn't written, so there are just the two objects
+
+        let all: Vec<String> = loose_objects(&gr).unwrap().collect();
+        let mut expected = vec![blob.clone(), commit.clone()];
+        expected.sort();
+        assert_eq!(all, expected);
+
+        let commits: Vec<String> = loose_objects_of_type(&gr, ObjectType::Commit)
+            .unwrap()
+            .collect();
+        assert_eq!(commits, vec![commit]);
+        let blobs: Vec<String> = loose_objects_of_type(&gr, ObjectType::Blob)
+            .unwrap()
+            .collect();
+        assert_eq!(blobs, vec![blob]);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod object_exists_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn exists_only_for_written_objects() {
+        let path = scratch_dir("wyag_object_exists");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let sha = write_loose_object(&gr, b"blob", b"hello\n");
+
+        assert!(object_exists(&gr, &sha));
+        assert!(object_exists(&gr, &sha.to_uppercase()));
+        assert!(!object_exists(
+            &gr,
+            "0123456789abcdef0123456789abcdef01234567"
+        ));
+        assert!(!object_exists(&gr, "../../config"));
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn hex_round_trips_and_rejects_malformed_input() {
+        let sha = "4B825DC642cb6eb9a060e54bf8d69288fbee4904";
+        let bytes = hex_to_bytes(sha).unwrap();
+        assert_eq!(bytes.len(), 20);
+        assert_eq!(bytes[..3], [0x4b, 0x82, 0x5d]);
+        assert_eq!(bytes_to_hex(&bytes), sha.to_lowercase());
+        assert_eq!(bytes_to_hex(&[0x00, 0x0f, 0xf0, 0xff]), "000ff0ff");
+        assert_eq!(hex_to_bytes("").unwrap(), Vec::<u8>::new());
+
+        assert!(hex_to_bytes("abc").is_err());
+        assert!(hex_to_bytes("zz").is_err());
+        assert!(hex_to_bytes("0g").is_err());
+        assert!(hex_to_bytes("+1").is_err());
+    }
+
+    #[test]
+    fn empty_objects_follow_the_object_format() {
+        let path = scratch_dir("wyag_empty_object_shas");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        assert_eq!(
+            empty_tree_sha(&gr),
+            "4b825dc642cb6eb9a060e54bf8d69288fbee4904"
+        );
+        assert_eq!(write_loose_object(&gr, b"tree", b""), empty_tree_sha(&gr));
+        assert_eq!(write_loose_object(&gr, b"blob", b""), empty_blob_sha(&gr));
+
+        let mut conf = Ini::load_from_file(gr.gitdir.join("config")).unwrap();
+        conf.with_section(Some("extensions".to_owned()))
+            .set("objectformat", "sha256");
+        let gr = GitRepository { conf: conf, ..gr };
+        assert_eq!(empty_tree_sha(&gr), EMPTY_TREE_SHA256);
+        assert_eq!(empty_blob_sha(&gr), EMPTY_BLOB_SHA256);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn objects_are_found_through_alternates() {
+        let path = scratch_dir("wyag_object_alternates");
+        let root = PathBuf::from(&path);
+        let local = GitRepository::repo_create(root.join("local").to_str().unwrap()).unwrap();
+        let shared = GitRepository::repo_create(root.join("shared").to_str().unwrap()).unwrap();
+        let other = GitRepository::repo_create(root.join("other").to_str().unwrap()).unwrap();
+        let in_shared = write_loose_object(&shared, b"blob", b"shared\n");
+        let in_other = write_loose_object(&other, b"blob", b"other\n");
+        assert!(!object_exists(&local, &in_shared));
+
+        // One absolute entry, and one relative to local's objects directory
+        std::fs::write(
+            repo_file_gr(&local, true, vec!["objects", "info", "alternates"]).unwrap(),
+            format!(
+                "# shared objects\n{}\n../../../other/.git/objects\n",
+                shared.gitdir.join("objects").display()
+            ),
+        )
+        .unwrap();
+
+        assert!(object_exists(&local, &in_shared));
+        assert!(object_exists(&local, &in_other));
+        match object_read(&local, &in_shared).unwrap() {
+            GObj::Blob(b) => assert_eq!(b.blob_data, b"shared\n"),
+            _ => panic!("expected a blob"),
+        }
+        assert_eq!(object_read_header(&local, &in_other).unwrap().0, "blob");
+        assert!(!object_exists(
+            &local,
+            "0123456789abcdef0123456789abcdef01234567"
+        ));
 
-    let reflist = ref_list(&repo, None)?;
-    show_ref(&repo, reflist, false, Some("refs"));
-    Ok(())
+        std::fs::remove_dir_all(&path).unwrap();
+    }
 }
 
-/// EndRegion: Ref
+#[cfg(test)]
+mod rev_parse_tests {
+    use super::test_helpers::*;
+    use super::*;
 
-/// Region: Tag
+    #[test]
+    fn abbrev_ref_names_the_branch_or_head() {
+        let path = scratch_dir("wyag_rev_parse_abbrev_ref");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        assert!(rev_parse_abbrev_ref(&gr, "HEAD").is_err());
+
+        let sha = write_commit(&gr, &[], 1000, "Initial commit\n");
+        set_ref(&gr, "refs/heads/master", &sha);
+        assert_eq!(rev_parse_abbrev_ref(&gr, "HEAD").unwrap(), "master");
+        assert_eq!(
+            rev_parse_abbrev_ref(&gr, "refs/heads/master").unwrap(),
+            "master"
+        );
 
-pub fn cmd_tag(name: &str, obj: &str, createTagObject: bool) -> Result<(), WyagError> {
-    let repo = match repo_find(".", false)? {
-        Some(gr) => gr,
-        None => {
-            println!("No repository was found, cannot use wyag-tag");
-            return Ok(());
-        }
-    };
+        std::fs::write(repo_path_gr(&gr, vec!["HEAD"]), format!("{}\n", sha)).unwrap();
+        assert_eq!(rev_parse_abbrev_ref(&gr, "HEAD").unwrap(), "HEAD");
 
-    if name.len() > 0 {
-        let tagType = if createTagObject { "object" } else { "ref" };
-        tag_create(name, obj, tagType)
-    } else {
-        let refs = ref_list(&repo, None)?;
-        show_ref(&repo, refs, false, None);
-        Ok(())
+        std::fs::remove_dir_all(&path).unwrap();
     }
-}
 
-fn tag_create(name: &str, obj: &str, tagType: &str) -> Result<(), WyagError> {
-    Ok(())
+    #[test]
+    fn verify_needs_exactly_one_existing_object() {
+        let path = scratch_dir("wyag_rev_parse_verify");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let sha = write_commit(&gr, &[], 1000, "Initial commit\n");
+        set_ref(&gr, "refs/heads/master", &sha);
+
+        assert_eq!(rev_parse_verify(&gr, "HEAD", None).unwrap(), sha);
+        assert_eq!(rev_parse_verify(&gr, &sha[..7], None).unwrap(), sha);
+        assert_eq!(rev_parse_verify(&gr, &sha, Some("commit")).unwrap(), sha);
+
+        assert!(rev_parse_verify(&gr, "", None).is_err());
+        assert!(rev_parse_verify(&gr, "0123456789abcdef0123456789abcdef01234567", None).is_err());
+        assert!(rev_parse_verify(&gr, &sha, Some("blob")).is_err());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn short_is_unique_and_at_least_seven() {
+        let path = scratch_dir("wyag_rev_parse_short");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let sha = write_loose_object(&gr, b"blob", b"hello\n");
+        assert_eq!(sha, "ce013625030ba8dba906f756967f9e9ca394464a");
+
+        assert_eq!(
+            sha_abbrev(&gr, &sha, SHA_ABBREV_DEFAULT).unwrap(),
+            "ce01362"
+        );
+        assert_eq!(sha_abbrev(&gr, &sha, 2).unwrap(), "ce01");
+        assert_eq!(sha_abbrev(&gr, &sha, 12).unwrap(), "ce013625030b");
+
+        // A neighbour sharing the first eight characters forces a longer abbreviation
+        let neighbour = repo_file_gr(
+            &gr,
+            false,
+            vec!["objects", "ce", "01362510000000000000000000000000000000"],
+        )
+        .unwrap();
+        std::fs::write(neighbour, b"").unwrap();
+        let short = sha_abbrev(&gr, &sha, SHA_ABBREV_DEFAULT).unwrap();
+        assert_eq!(short, "ce0136250");
+        assert_eq!(object_resolve(&gr, &short).unwrap(), vec![sha.clone()]);
+        assert_eq!(sha_abbrev(&gr, &sha, 4).unwrap(), "ce0136250");
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
 }
 
-/// EndRegion: Tag
+#[cfg(test)]
+mod object_type_tests {
+    use super::*;
 
-#[derive(Debug, Default)]
-pub struct WyagError {
-    _message: String,
-    _err: Option<Box<dyn Error>>,
+    #[test]
+    fn round_trips_through_bytes() {
+        for t in &[
+            ObjectType::Blob,
+            ObjectType::Tree,
+            ObjectType::Commit,
+            ObjectType::Tag,
+        ] {
+            assert_eq!(ObjectType::from_bytes(t.as_bytes()), Some(*t));
+        }
+        assert_eq!(ObjectType::Commit.as_bytes(), b"commit");
+        assert_eq!(ObjectType::from_bytes(b"blobs"), None);
+        assert_eq!(ObjectType::from_bytes(b""), None);
+    }
 }
 
-impl WyagError {
-    pub fn new(message: &str) -> WyagError {
-        WyagError {
-            _message: String::from(message),
-            _err: None,
+#[cfg(test)]
+mod object_write_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn write_reports_path_on_disk() {
+        let path = scratch_dir("wyag_object_write_path");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let blob = GitBlob::new(Some(&gr), b"hello\n");
+
+        let dry = object_write(&blob, false).unwrap();
+        assert_eq!(dry.sha, "ce013625030ba8dba906f756967f9e9ca394464a");
+        assert!(dry.path.is_none());
+
+        let written = object_write(&blob, true).unwrap();
+        assert_eq!(written.sha, dry.sha);
+        let p = written.path.unwrap();
+        assert!(p.is_file());
+        assert!(p.ends_with("objects/ce/013625030ba8dba906f756967f9e9ca394464a"));
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn hash_object_reuses_one_borrowed_repo() {
+        let path = scratch_dir("wyag_hash_object_borrowed");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let a = PathBuf::from(&path).join("a.txt");
+        let b = PathBuf::from(&path).join("b.txt");
+        std::fs::write(&a, "hello\n").unwrap();
+        std::fs::write(&b, "").unwrap();
+
+        let mut fa = std::fs::File::open(&a).unwrap();
+        let mut fb = std::fs::File::open(&b).unwrap();
+        let sa = hash_object(&mut fa, "blob", "a.txt", Some(&gr)).unwrap();
+        let sb = hash_object(&mut fb, "blob", "b.txt", Some(&gr)).unwrap();
+        assert_eq!(sa, "ce013625030ba8dba906f756967f9e9ca394464a");
+        assert_eq!(sb, "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391");
+        assert!(object_exists(&gr, &sa));
+        assert!(object_exists(&gr, &sb));
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn large_files_stream_to_the_same_sha() {
+        let path = scratch_dir("wyag_hash_object_stream");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let file = PathBuf::from(&path).join("big.bin");
+        // Several chunks' worth, and not a multiple of the chunk size
+        let content: Vec<u8> = (0..(OBJECT_STREAM_CHUNK * 5 + 123))
+            .map(|i| (i % 251) as u8)
+            .collect();
+        std::fs::write(&file, &content).unwrap();
+
+        let expected = object_write(&GitBlob::new(None, &content), false)
+            .unwrap()
+            .sha;
+        let mut fd = std::fs::File::open(&file).unwrap();
+        let sha = hash_object(&mut fd, "blob", "big.bin", Some(&gr)).unwrap();
+        assert_eq!(sha, expected);
+        match object_read(&gr, &sha).unwrap() {
+            GObj::Blob(b) => assert_eq!(b.blob_data, content),
+            _ => panic!("expected a blob"),
         }
+
+        std::fs::remove_dir_all(&path).unwrap();
     }
 
-    pub fn new_with_error(message: &str, err: Box<std::error::Error>) -> WyagError {
-        WyagError {
-            _message: String::from(message),
-            _err: Some(err),
+    #[test]
+    fn a_failed_stream_leaves_no_temporary_file() {
+        let path = scratch_dir("wyag_object_write_stream_fails");
+        let gr = GitRepository::repo_create(&path).unwrap();
+
+        // Hands out one chunk, then fails
+        struct Failing(bool);
+        impl Read for Failing {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0 {
+                    return Err(io::Error::new(io::ErrorKind::Other, "disk went away"));
+                }
+                self.0 = true;
+                for b in buf.iter_mut() {
+                    *b = b'x';
+                }
+                Ok(buf.len())
+            }
+        }
+        let size = (OBJECT_STREAM_CHUNK * 3) as u64;
+        assert!(
+            object_write_stream(Some(&gr), ObjectType::Blob, &mut Failing(false), size, true)
+                .is_err()
+        );
+        // Too short a payload is an error too
+        assert!(
+            object_write_stream(Some(&gr), ObjectType::Blob, &mut &b"abc"[..], 4, true).is_err()
+        );
+
+        let objects = gr.gitdir.join("objects");
+        for entry in std::fs::read_dir(&objects).unwrap() {
+            let name = entry.unwrap().file_name();
+            assert!(
+                !name.to_string_lossy().starts_with("tmp_obj_"),
+                "{:?} was left behind",
+                name
+            );
         }
+
+        std::fs::remove_dir_all(&path).unwrap();
     }
-}
 
-impl Error for WyagError {
-    fn description(&self) -> &str {
-        self._message.as_ref()
+    #[test]
+    fn rewriting_an_existing_object_leaves_it_alone() {
+        let path = scratch_dir("wyag_object_write_existing");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let blob = GitBlob::new(Some(&gr), b"hello\n");
+
+        let first = object_write(&blob, true).unwrap();
+        let file = first.path.unwrap();
+        // Backdate the file, so a rewrite would visibly move its mtime
+        let old = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+        std::fs::File::open(&file)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+
+        let second = object_write(&blob, true).unwrap();
+        assert_eq!(second.sha, first.sha);
+        assert_eq!(second.path.unwrap(), file);
+        assert_eq!(std::fs::metadata(&file).unwrap().modified().unwrap(), old);
+
+        std::fs::remove_dir_all(&path).unwrap();
     }
-}
 
-impl fmt::Display for WyagError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if let Some(e) = &self._err {
-            writeln!(f, "Failed to do task: {}", e)
-        } else {
-            writeln!(f, "Failed to do task")
+    #[test]
+    fn read_raw_returns_type_and_payload() {
+        let path = scratch_dir("wyag_read_raw");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let sha = write_loose_object(&gr, b"blob", b"hello\n");
+        assert_eq!(
+            read_raw(&gr, &sha).unwrap(),
+            (ObjectType::Blob, b"hello\n".to_vec())
+        );
+
+        let odd = write_loose_object(&gr, b"widget", b"hello\n");
+        assert!(read_raw(&gr, &odd).is_err());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn raw_write_reads_back() {
+        let path = scratch_dir("wyag_object_write_raw");
+        let gr = GitRepository::repo_create(&path).unwrap();
+
+        let dry = write_raw_object(&gr, ObjectType::Blob, b"hello\n", false).unwrap();
+        assert_eq!(dry, "ce013625030ba8dba906f756967f9e9ca394464a");
+        assert!(!object_exists(&gr, &dry));
+
+        let sha = write_raw_object(&gr, ObjectType::Blob, b"hello\n", true).unwrap();
+        assert_eq!(sha, dry);
+        match object_read(&gr, &sha).unwrap() {
+            GObj::Blob(b) => assert_eq!(b.blob_data, b"hello\n".to_vec()),
+            _ => panic!("expected a blob"),
         }
+
+        std::fs::remove_dir_all(&path).unwrap();
     }
 }
 
 #[cfg(test)]
-mod cat_file_tests {
+mod object_resolve_tests {
+    use super::test_helpers::*;
+    use super::*;
 
     #[test]
-    fn cat_file() {}
+    fn at_is_head_and_takes_suffixes() {
+        let path = scratch_dir("wyag_resolve_at");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let first = write_commit(&gr, &[], 1000, "First\n");
+        let side = write_commit(&gr, &[&first], 1500, "Side\n");
+        let second = write_commit(&gr, &[&first, &side], 2000, "Merge\n");
+        let third = write_commit(&gr, &[&second], 3000, "Third\n");
+        set_ref(&gr, "refs/heads/master", &third);
+
+        assert_eq!(
+            object_resolve(&gr, "@").unwrap(),
+            object_resolve(&gr, "HEAD").unwrap()
+        );
+        assert_eq!(object_resolve(&gr, "@").unwrap(), vec![third.clone()]);
+        assert_eq!(object_resolve(&gr, "@~1").unwrap(), vec![second.clone()]);
+        assert_eq!(object_resolve(&gr, "@~2").unwrap(), vec![first.clone()]);
+        assert_eq!(object_resolve(&gr, "HEAD^").unwrap(), vec![second.clone()]);
+        assert_eq!(object_resolve(&gr, "@~^2").unwrap(), vec![side.clone()]);
+        assert_eq!(object_resolve(&gr, "@^0").unwrap(), vec![third.clone()]);
+        assert!(object_resolve(&gr, "@~3").is_err());
+        // A non-ASCII suffix is a bad revision, not a panic
+        assert!(object_resolve(&gr, "HEAD~1\u{e9}").is_err());
+        assert!(object_resolve(&gr, "HEAD^\u{e9}").is_err());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn message_search_finds_youngest_match() {
+        let path = scratch_dir("wyag_resolve_message_search");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let first = write_commit(&gr, &[], 1000, "Initial commit\n");
+        let second = write_commit(&gr, &[&first], 2000, "Add a fix for parsing\n");
+        let third = write_commit(&gr, &[&second], 3000, "Another fix\n");
+        let fourth = write_commit(&gr, &[&third], 4000, "Unrelated work\n");
+        set_ref(&gr, "refs/heads/master", &fourth);
+
+        assert_eq!(object_resolve(&gr, ":/fix").unwrap(), vec![third]);
+        assert_eq!(object_resolve(&gr, ":/parsing").unwrap(), vec![second]);
+        assert_eq!(object_resolve(&gr, ":/Initial").unwrap(), vec![first]);
+        assert!(object_resolve(&gr, ":/nothing like this").is_err());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn remote_tracking_branches_resolve() {
+        let path = scratch_dir("wyag_resolve_remotes");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let local = write_commit(&gr, &[], 1000, "Local\n");
+        let remote = write_commit(&gr, &[], 2000, "Remote\n");
+        set_ref(&gr, "refs/heads/master", &local);
+        set_ref(&gr, "refs/remotes/origin/main", &remote);
+        std::fs::write(
+            repo_path_gr(&gr, vec!["refs", "remotes", "origin", "HEAD"]),
+            "ref: refs/remotes/origin/main\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            object_resolve(&gr, "origin/main").unwrap(),
+            vec![remote.clone()]
+        );
+        assert_eq!(object_resolve(&gr, "origin").unwrap(), vec![remote.clone()]);
+        assert_eq!(
+            object_resolve(&gr, "remotes/origin/main").unwrap(),
+            vec![remote.clone()]
+        );
+        assert_eq!(object_resolve(&gr, "master").unwrap(), vec![local.clone()]);
+
+        // A local branch takes precedence over a remote-tracking one of the same name
+        set_ref(&gr, "refs/heads/origin/main", &local);
+        assert_eq!(object_resolve(&gr, "origin/main").unwrap(), vec![local]);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
 }
 
 #[cfg(test)]
@@ -1571,9 +10510,11 @@ mod path_tests {
     #[test]
     fn repo_path_blank() {
         let gr = GitRepository {
-            worktree: "",
+            worktree: String::new(),
             gitdir: PathBuf::new(),
             conf: ini::Ini::new(),
+            header_cache: RefCell::new(HashMap::new()),
+            read_only: false,
         };
 
         let p = repo_path_gr(&gr, vec![""]);
@@ -1583,9 +10524,11 @@ mod path_tests {
     #[test]
     fn repo_path_pwd() {
         let gr = GitRepository {
-            worktree: "",
+            worktree: String::new(),
             gitdir: PathBuf::new().join(""),
             conf: ini::Ini::new(),
+            header_cache: RefCell::new(HashMap::new()),
+            read_only: false,
         };
 
         let p = repo_path_gr(&gr, vec!["."]);
@@ -1595,9 +10538,11 @@ mod path_tests {
     #[test]
     fn repo_path_depth_one() {
         let gr = GitRepository {
-            worktree: "",
+            worktree: String::new(),
             gitdir: PathBuf::new().join(""),
             conf: ini::Ini::new(),
+            header_cache: RefCell::new(HashMap::new()),
+            read_only: false,
         };
 
         let p = repo_path_gr(&gr, vec![".", "this"]);
@@ -1607,9 +10552,11 @@ mod path_tests {
     #[test]
     fn repo_path_depth_two() {
         let gr = GitRepository {
-            worktree: "",
+            worktree: String::new(),
             gitdir: PathBuf::new().join(""),
             conf: ini::Ini::new(),
+            header_cache: RefCell::new(HashMap::new()),
+            read_only: false,
         };
 
         let p = repo_path_gr(&gr, vec![".", "this", "item.txt"]);
@@ -1619,9 +10566,11 @@ mod path_tests {
     #[test]
     fn repo_path_not_empty() {
         let gr = GitRepository {
-            worktree: "",
+            worktree: String::new(),
             gitdir: PathBuf::new().join(""),
             conf: ini::Ini::new(),
+            header_cache: RefCell::new(HashMap::new()),
+            read_only: false,
         };
 
         let p = repo_path_gr(&gr, vec![".", "this", "item.txt"]);
@@ -1631,9 +10580,11 @@ mod path_tests {
     #[test]
     fn repo_dir_should_return_because_exists_properly() {
         let gr = GitRepository {
-            worktree: "",
+            worktree: String::new(),
             gitdir: PathBuf::new().join(""),
             conf: ini::Ini::new(),
+            header_cache: RefCell::new(HashMap::new()),
+            read_only: false,
         };
 
         // match repo_dir_gr(&gr, false, vec![".", "this", "item.txt"]) {
@@ -1644,24 +10595,25 @@ mod path_tests {
 
     #[test]
     fn repo_dir_should_fail_because_exists_as_file() {
-        let gr = GitRepository {
-            worktree: "",
-            gitdir: PathBuf::new().join(""),
-            conf: ini::Ini::new(),
-        };
+        let path = super::test_helpers::scratch_dir("wyag_repo_dir_as_file");
+        std::fs::create_dir_all(&path).unwrap();
+        std::fs::write(PathBuf::from(&path).join("item.txt"), "x").unwrap();
 
-        // match repo_dir_gr(&gr, false, vec![".", "this", "item.txt"]) {
-        //     Ok(p) =>
-        // }
-        // assert_ne!(p.to_string_lossy(), "");
+        let err = repo_dir_path(&PathBuf::from(&path), true, vec!["item.txt"]).unwrap_err();
+        assert!(err.to_string().contains("already existed as a file"));
+        assert!(err.to_string().contains("item.txt"));
+
+        std::fs::remove_dir_all(&path).unwrap();
     }
 
     #[test]
     fn repo_dir_should_return_because_mk_dir_was_on() {
         let gr = GitRepository {
-            worktree: "",
+            worktree: String::new(),
             gitdir: PathBuf::new().join(""),
             conf: ini::Ini::new(),
+            header_cache: RefCell::new(HashMap::new()),
+            read_only: false,
         };
 
         // match repo_dir_gr(&gr, false, vec![".", "this", "item.txt"]) {
@@ -1672,16 +10624,18 @@ mod path_tests {
 
     #[test]
     fn repo_dir_should_fail_because_mk_dir_was_off() {
-        let gr = GitRepository {
-            worktree: "",
-            gitdir: PathBuf::new().join(""),
-            conf: ini::Ini::new(),
-        };
+        let path = super::test_helpers::scratch_dir("wyag_repo_dir_mk_dir_off");
+        std::fs::create_dir_all(&path).unwrap();
 
-        // match repo_dir_gr(&gr, false, vec![".", "this", "item.txt"]) {
-        //     Ok(p) =>
-        // }
-        // assert_ne!(p.to_string_lossy(), "");
+        let missing = repo_dir_path(&PathBuf::from(&path), false, vec!["missing"]).unwrap_err();
+        assert!(missing.to_string().contains("mk_dir was off"));
+        assert!(missing.to_string().contains("missing"));
+
+        std::fs::write(PathBuf::from(&path).join("item.txt"), "x").unwrap();
+        let as_file = repo_dir_path(&PathBuf::from(&path), false, vec!["item.txt"]).unwrap_err();
+        assert_ne!(missing.to_string(), as_file.to_string());
+
+        std::fs::remove_dir_all(&path).unwrap();
     }
 }
 
@@ -1749,6 +10703,337 @@ mod gitrepo_tests {
     }
 }
 
+#[cfg(test)]
+mod gitdir_file_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn dot_git_file_points_at_gitdir() {
+        let real = scratch_dir("wyag_gitdir_file_real");
+        let linked = scratch_dir("wyag_gitdir_file_linked");
+        GitRepository::repo_create(&real).unwrap();
+        let real_gitdir = PathBuf::from(&real).join(".git");
+
+        std::fs::create_dir_all(&linked).unwrap();
+        std::fs::write(
+            PathBuf::from(&linked).join(".git"),
+            format!("gitdir: {}\n", real_gitdir.to_str().unwrap()),
+        )
+        .unwrap();
+
+        let gr = GitRepository::new(&linked, false).unwrap();
+        assert_eq!(gr.gitdir, real_gitdir);
+        assert_eq!(gr.worktree, linked.as_str());
+
+        std::fs::remove_dir_all(&real).unwrap();
+        std::fs::remove_dir_all(&linked).unwrap();
+    }
+
+    #[test]
+    fn relative_gitdir_pointer_resolves_from_the_dot_git_file() {
+        let base = scratch_dir("wyag_gitdir_file_relative");
+        let real = PathBuf::from(&base).join("real-git-dir");
+        let linked = PathBuf::from(&base).join("linked");
+        std::fs::create_dir_all(&base).unwrap();
+        let created = GitRepository::repo_create(real.to_str().unwrap()).unwrap();
+        std::fs::create_dir_all(&linked).unwrap();
+        std::fs::write(linked.join(".git"), "gitdir: ../real-git-dir/.git\n").unwrap();
+
+        let gr = GitRepository::new(linked.to_str().unwrap(), false).unwrap();
+        assert!(gr.gitdir.is_absolute());
+        assert_eq!(gr.gitdir, created.gitdir.canonicalize().unwrap());
+
+        #[cfg(unix)]
+        {
+            // A .git that is a symlink to a gitdir works just like the directory itself
+            let symlinked = PathBuf::from(&base).join("symlinked");
+            std::fs::create_dir_all(&symlinked).unwrap();
+            std::os::unix::fs::symlink(&created.gitdir, symlinked.join(".git")).unwrap();
+            let gr = GitRepository::new(symlinked.to_str().unwrap(), false).unwrap();
+            assert_eq!(
+                gr.gitdir.canonicalize().unwrap(),
+                created.gitdir.canonicalize().unwrap()
+            );
+        }
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn dot_git_file_without_pointer_errors() {
+        let linked = scratch_dir("wyag_gitdir_file_bad");
+        std::fs::create_dir_all(&linked).unwrap();
+        std::fs::write(PathBuf::from(&linked).join(".git"), "nonsense\n").unwrap();
+
+        assert!(GitRepository::new(&linked, false).is_err());
+
+        std::fs::remove_dir_all(&linked).unwrap();
+    }
+
+    #[test]
+    fn missing_gitdir_error_names_the_path() {
+        let plain = scratch_dir("wyag_gitdir_missing");
+        std::fs::create_dir_all(&plain).unwrap();
+
+        let err = GitRepository::new(&plain, false).err().unwrap();
+        let shown = format!("{}", err);
+        assert!(shown.contains("Not a git path"));
+        assert!(shown.contains(PathBuf::from(&plain).join(".git").to_str().unwrap()));
+
+        std::fs::remove_dir_all(&plain).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod read_only_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn read_only_repo_reads_but_does_not_write() {
+        let path = scratch_dir("wyag_open_readonly");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let sha = write_loose_object(&gr, b"blob", b"hello\n");
+        let mut conf = Ini::load_from_file(gr.gitdir.join("config")).unwrap();
+        conf.with_section(Some("core".to_owned()))
+            .set("repositoryformatversion", "1");
+        conf.write_to_file(gr.gitdir.join("config")).unwrap();
+
+        assert!(GitRepository::new(&path, false).is_err());
+        let ro = GitRepository::open_readonly(&path).unwrap();
+        match object_read(&ro, &sha).unwrap() {
+            GObj::Blob(b) => assert_eq!(b.blob_data, b"hello\n".to_vec()),
+            _ => panic!("expected a blob"),
+        }
+
+        assert!(write_raw_object(&ro, ObjectType::Blob, b"new\n", true).is_err());
+        assert!(!object_exists(
+            &ro,
+            "3e757656cf36eca53338e520d134963a44f793f8"
+        ));
+        // hashing without writing is still fine
+        assert!(write_raw_object(&ro, ObjectType::Blob, b"new\n", false).is_ok());
+        assert!(ref_update(&ro, "refs/heads/master", &sha).is_err());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod object_format_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn sha1_names_in_a_sha256_store_are_flagged() {
+        let path = scratch_dir("wyag_object_format_mixed");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let sha1 = write_loose_object(&gr, b"blob", b"hello\n");
+        assert!(object_format_check(&gr).is_ok());
+
+        let mut conf = Ini::load_from_file(gr.gitdir.join("config")).unwrap();
+        conf.with_section(Some("core".to_owned()))
+            .set("repositoryformatversion", "1");
+        conf.with_section(Some("extensions".to_owned()))
+            .set("objectformat", "sha256");
+        conf.write_to_file(gr.gitdir.join("config")).unwrap();
+
+        // Opening doesn't scan the store; only fsck does
+        let ro = GitRepository::open_readonly(&path).unwrap();
+        let err = object_format_check(&ro).err().unwrap();
+        assert!(err.to_string().contains("mixes hash algorithms"));
+        assert!(err.to_string().contains(&sha1));
+
+        // Once only 64-digit names remain, the store is consistent again
+        std::fs::remove_file(repo_path_gr(&gr, vec!["objects", &sha1[..2], &sha1[2..]])).unwrap();
+        let wide = repo_file_gr(&gr, true, vec!["objects", "ab", &"c".repeat(62)]).unwrap();
+        std::fs::write(&wide, "").unwrap();
+        assert!(object_format_check(&ro).is_ok());
+        assert_eq!(
+            loose_objects(&ro).unwrap().collect::<Vec<String>>(),
+            vec![format!("ab{}", "c".repeat(62))]
+        );
+
+        // An algorithm wyag doesn't know is refused as soon as the repository is opened
+        conf.with_section(Some("extensions".to_owned()))
+            .set("objectformat", "md5");
+        conf.write_to_file(gr.gitdir.join("config")).unwrap();
+        assert!(GitRepository::open_readonly(&path).is_err());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod bare_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn bare_repos_refuse_worktree_commands() {
+        let path = scratch_dir("wyag_bare");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        assert!(!is_bare(&gr));
+        assert!(require_worktree(&gr).is_ok());
+
+        let config = repo_path_gr(&gr, vec!["config"]);
+        let mut conf = Ini::load_from_file(&config).unwrap();
+        conf.with_section(Some("core".to_owned()))
+            .set("bare", "true");
+        conf.write_to_file(&config).unwrap();
+
+        let gr = GitRepository::new(&path, false).unwrap();
+        assert!(is_bare(&gr));
+        match require_worktree(&gr) {
+            Err(e) => assert_eq!(e._message, "this operation must be run in a work tree"),
+            Ok(_) => panic!("expected a bare repository to be refused"),
+        }
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod repo_find_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    // GIT_DIR is process-wide, so tests that search for a repository take turns
+    static ENV_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    #[test]
+    fn finds_parent_repo_or_honours_git_dir() {
+        let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path = scratch_dir("wyag_repo_find");
+        let elsewhere = scratch_dir("wyag_repo_find_elsewhere");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let sub = PathBuf::from(&path).join("a").join("b");
+        std::fs::create_dir_all(&sub).unwrap();
+        std::fs::create_dir_all(&elsewhere).unwrap();
+
+        let found = repo_find(sub.to_str().unwrap(), true).unwrap().unwrap();
+        assert_eq!(
+            found.gitdir.canonicalize().unwrap(),
+            gr.gitdir.canonicalize().unwrap()
+        );
+        assert!(repo_find(&elsewhere, true).is_err());
+
+        let discovered = repo_discover(sub.to_str().unwrap(), true).unwrap().unwrap();
+        assert_eq!(
+            discovered.path,
+            PathBuf::from(&path).canonicalize().unwrap()
+        );
+        assert_eq!(
+            discovered.path.join(".git").canonicalize().unwrap(),
+            discovered.repo.gitdir.canonicalize().unwrap()
+        );
+        let missing = repo_discover(&elsewhere, true).err().unwrap();
+        assert!(missing._message.contains("not a git repository"));
+
+        let file = PathBuf::from(&elsewhere).join("hello.txt");
+        std::fs::write(&file, "hello\n").unwrap();
+        std::env::set_var("GIT_DIR", &gr.gitdir);
+        std::env::set_var("GIT_WORK_TREE", &elsewhere);
+        let found = repo_find(&elsewhere, true);
+        let hashed = cmd_hash_object(true, "blob", file.to_str().unwrap());
+        std::env::remove_var("GIT_DIR");
+        std::env::remove_var("GIT_WORK_TREE");
+
+        let found = found.unwrap().unwrap();
+        assert_eq!(found.gitdir, gr.gitdir);
+        assert_eq!(found.worktree, elsewhere);
+        hashed.unwrap();
+        assert!(object_exists(
+            &gr,
+            "ce013625030ba8dba906f756967f9e9ca394464a"
+        ));
+
+        std::fs::remove_dir_all(&path).unwrap();
+        std::fs::remove_dir_all(&elsewhere).unwrap();
+    }
+
+    #[test]
+    fn show_toplevel_and_git_dir_from_a_subdirectory() {
+        let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path = scratch_dir("wyag_rev_parse_toplevel");
+        GitRepository::repo_create(&path).unwrap();
+        let sub = PathBuf::from(&path).join("a").join("b");
+        std::fs::create_dir_all(&sub).unwrap();
+        let root = PathBuf::from(&path).canonicalize().unwrap();
+
+        let top = rev_parse_show_toplevel(sub.to_str().unwrap()).unwrap();
+        assert!(top.is_absolute());
+        assert_eq!(top, root);
+        let gitdir = rev_parse_git_dir(sub.to_str().unwrap()).unwrap();
+        assert!(gitdir.is_absolute());
+        assert_eq!(gitdir, root.join(".git"));
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod worktree_relative_tests {
+    use super::test_helpers::*;
+    use super::*;
+
+    #[test]
+    fn relative_and_absolute_paths_inside_the_worktree() {
+        let path = scratch_dir("wyag_worktree_relative");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let root = PathBuf::from(&path);
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::write(root.join("src").join("a.txt"), "a").unwrap();
+
+        let cwd = std::env::current_dir().unwrap();
+        let cwd_rel = pathdiff(&root.join("src").join("a.txt"), &cwd);
+        assert_eq!(
+            worktree_relative(&gr, cwd_rel.to_str().unwrap()).unwrap(),
+            PathBuf::from("src").join("a.txt")
+        );
+
+        let abs = root.join("src").join("..").join("src").join("gone.txt");
+        assert_eq!(
+            worktree_relative(&gr, abs.to_str().unwrap()).unwrap(),
+            PathBuf::from("src").join("gone.txt")
+        );
+        assert_eq!(worktree_relative(&gr, &path).unwrap(), PathBuf::new());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn paths_outside_the_worktree_error() {
+        let path = scratch_dir("wyag_worktree_relative_outside");
+        let gr = GitRepository::repo_create(&path).unwrap();
+        let outside = PathBuf::from(&path).join("..").join("elsewhere.txt");
+
+        assert!(worktree_relative(&gr, outside.to_str().unwrap()).is_err());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    /// Builds a `../`-style path to `target` from `base`, both absolute.
+    fn pathdiff(target: &Path, base: &Path) -> PathBuf {
+        let target = target.canonicalize().unwrap();
+        let base = base.canonicalize().unwrap();
+        let common = target
+            .components()
+            .zip(base.components())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let mut ret = PathBuf::new();
+        for _ in base.components().skip(common) {
+            ret.push("..");
+        }
+        for c in target.components().skip(common) {
+            ret.push(c);
+        }
+        ret
+    }
+}
+
 #[cfg(test)]
 mod git_object_read_tests {
 
@@ -1775,3 +11060,116 @@ mod git_object_read_tests {
     #[test]
     fn Read_GitBlob_Object_Fail() {}
 }
+
+#[cfg(test)]
+mod test_helpers {
+    use super::*;
+
+    /// Returns a path under the system temp directory that does not exist yet.
+    pub fn scratch_dir(name: &str) -> String {
+        let p = std::env::temp_dir().join(name);
+        if p.exists() {
+            std::fs::remove_dir_all(&p).expect("Failed to delete old test directory");
+        }
+        p.to_str().unwrap().to_owned()
+    }
+
+    /// Writes a tree holding `(mode, name, sha)` entries, in the order given.
+    pub fn write_tree(repo: &GitRepository, entries: &[(&str, &str, &str)]) -> String {
+        let mut payload: Vec<u8> = Vec::new();
+        for (mode, name, sha) in entries {
+            payload.extend(format!("{} {}\x00", mode, name).into_bytes());
+            payload.extend(hex_to_bytes(sha).unwrap());
+        }
+        write_loose_object(repo, b"tree", &payload)
+    }
+
+    /// Writes a commit with an empty tree, the given parents, committer time and message.
+    pub fn write_commit(
+        repo: &GitRepository,
+        parents: &[&str],
+        time: u64,
+        message: &str,
+    ) -> String {
+        let mut payload = String::from("tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n");
+        for p in parents {
+            payload.push_str(&format!("parent {}\n", p));
+        }
+        payload.push_str(&format!(
+            "author A U Thor <author@example.com> {} +0000\n",
+            time
+        ));
+        payload.push_str(&format!(
+            "committer A U Thor <author@example.com> {} +0000\n",
+            time
+        ));
+        payload.push('\n');
+        payload.push_str(message);
+        write_loose_object(repo, b"commit", payload.as_bytes())
+    }
+
+    /// Gives `gr` a `user.name` and `user.email`, so it can make commits.
+    pub fn with_identity(gr: GitRepository) -> GitRepository {
+        let mut conf = Ini::load_from_file(gr.gitdir.join("config")).unwrap();
+        conf.with_section(Some("user".to_owned()))
+            .set("name", "A U Thor")
+            .set("email", "author@example.com");
+        GitRepository { conf: conf, ..gr }
+    }
+
+    /// A version 2 index holding one entry per `(mode, sha, path)`, with made-up stat data.
+    pub fn index_bytes(entries: &[(u32, &str, &str)]) -> Vec<u8> {
+        let mut raw: Vec<u8> = b"DIRC".to_vec();
+        raw.extend(&2u32.to_be_bytes());
+        raw.extend(&(entries.len() as u32).to_be_bytes());
+        for (mode, sha, path) in entries {
+            let start = raw.len();
+            for field in &[
+                1600000000u32,
+                5,
+                1600000001,
+                6,
+                2049,
+                1234,
+                *mode,
+                1000,
+                1000,
+                6,
+            ] {
+                raw.extend(&field.to_be_bytes());
+            }
+            raw.extend(hex_to_bytes(sha).unwrap());
+            raw.extend(&(path.len() as u16).to_be_bytes());
+            raw.extend(path.as_bytes());
+            let len = raw.len() - start;
+            raw.extend(vec![0u8; (len / 8 + 1) * 8 - len]);
+        }
+        raw
+    }
+
+    /// Points the ref `name` (e.g. `refs/heads/master`) at `sha`.
+    pub fn set_ref(repo: &GitRepository, name: &str, sha: &str) {
+        let path = repo_file_gr(repo, true, name.split('/').collect()).unwrap();
+        std::fs::write(path, format!("{}\n", sha)).unwrap();
+    }
+
+    /// Zlib-compresses `<fmt> <size>\0<payload>` straight into the object store and returns its SHA.
+    pub fn write_loose_object(repo: &GitRepository, fmt: &[u8], payload: &[u8]) -> String {
+        let mut raw: Vec<u8> = Vec::new();
+        raw.extend(fmt);
+        raw.push(b' ');
+        raw.extend(payload.len().to_string().into_bytes());
+        raw.push(b'\x00');
+        raw.extend(payload);
+
+        let mut sha = crypto::sha1::Sha1::new();
+        sha.input(&raw);
+        let sha = sha.result_str();
+
+        let mut e = ZlibEncoder::new(Vec::new(), Compression::default());
+        e.write_all(&raw).unwrap();
+        let path = repo_file_gr(repo, true, vec!["objects", &sha[..2], &sha[2..]]).unwrap();
+        std::fs::write(path, e.finish().unwrap()).unwrap();
+        sha
+    }
+}
@@ -19,55 +19,297 @@ fn main() {
             );
             process::exit(1)
         }
+    } else if config.isBranch && config.args[0].len() == 0 {
+        if let Err(err) = lib::cmd_branch_list() {
+            eprintln!("Failed to perform branch: {}", err);
+            process::exit(1)
+        }
+    } else if config.isBranch && config.args[0] == "-u" {
+        let local: Option<&str> = if config.args[1].len() > 0 {
+            Some(config.args[1].as_ref())
+        } else {
+            None
+        };
+        if let Err(err) = lib::cmd_branch_set_upstream(config.args[2].as_ref(), local) {
+            eprintln!("Failed to perform branch: {}", err);
+            process::exit(1)
+        }
+    } else if config.isBranch {
+        let old: Option<&str> = if config.args[1].len() > 0 {
+            Some(config.args[1].as_ref())
+        } else {
+            None
+        };
+        if let Err(err) =
+            lib::cmd_branch_rename(old, config.args[2].as_ref(), config.args[0] == "-M")
+        {
+            eprintln!("Failed to perform branch: {}", err);
+            process::exit(1)
+        }
     } else if config.isCatFile {
-        if let Err(err) = lib::cmd_cat_file(config.args[0].as_ref(), config.args[1].as_ref()) {
+        let result = if config.args[0] == "--filters" {
+            lib::cmd_cat_file_filters(config.args[1].as_ref(), config.args[2].as_ref())
+        } else if config.args[0] == "--batch-check" {
+            lib::cmd_cat_file_batch_check()
+        } else if config.args[0] == "--disk-size" {
+            lib::cmd_cat_file_disk_size(config.args[1].as_ref())
+        } else if config.args[0] == "-t" || config.args[0] == "-s" {
+            let allowUnknown: bool = config.args[2].parse().expect("Failed to perform cat-file: somehow the --allow-unknown-type flag was misinterpreted as a non-boolean");
+            lib::cmd_cat_file_info(
+                config.args[0] == "-s",
+                config.args[1].as_ref(),
+                allowUnknown,
+            )
+        } else {
+            lib::cmd_cat_file(config.args[0].as_ref(), config.args[1].as_ref())
+        };
+        if let Err(err) = result {
             eprintln!("Failed to perform cat-file command\n{}", err);
             process::exit(1)
         }
+    } else if config.isForEachRef {
+        let pattern: Option<&str> = if config.args[0].len() != 0 {
+            Some(config.args[0].as_ref())
+        } else {
+            None
+        };
+        if let Err(err) = lib::cmd_for_each_ref(pattern) {
+            eprintln!("Failed to perform for-each-ref: {}", err);
+            process::exit(1)
+        }
     } else if config.isHashObject {
         let isW: bool = config.args[0]
             .parse()
             .expect("Failed to perform hash-object: somehow the -w flag was misinterpreted as a non-boolean");
-        if let Err(err) =
+        let isR: bool = config.args[3]
+            .parse()
+            .expect("Failed to perform hash-object: somehow the -r flag was misinterpreted as a non-boolean");
+        let result = if isR {
+            lib::cmd_hash_object_recursive(isW, config.args[2].as_ref())
+        } else {
             lib::cmd_hash_object(isW, config.args[1].as_ref(), config.args[2].as_ref())
-        {
+        };
+        if let Err(err) = result {
             eprintln!("Failed to perform hash-object\n{}", err);
             process::exit(1)
         }
     } else if config.isLog {
-        if let Err(err) = lib::cmd_log(config.args[0].as_ref()) {
+        let firstParent: bool = config.args[1].parse().expect(
+            "Failed to perform log: somehow the --first-parent flag was misinterpreted as a non-boolean",
+        );
+        let topoOrder: bool = config.args[2].parse().expect(
+            "Failed to perform log: somehow the --topo-order flag was misinterpreted as a non-boolean",
+        );
+        let labels: bool = config.args[3].parse().expect(
+            "Failed to perform log: somehow the --labels flag was misinterpreted as a non-boolean",
+        );
+        if let Err(err) = lib::cmd_log(config.args[0].as_ref(), firstParent, topoOrder, labels) {
             eprintln!("Failed to perform log: {}", err);
             process::exit(1)
         }
     } else if config.isLsTree {
-        if let Err(err) = lib::cmd_ls_tree(config.args[0].as_ref()) {
+        let recursive: bool = config.args[1].parse().expect(
+            "Failed to perform ls-tree: somehow the -r flag was misinterpreted as a non-boolean",
+        );
+        let recurseSubmodules: bool = config.args[2].parse().expect("Failed to perform ls-tree: somehow the --recurse-submodules flag was misinterpreted as a non-boolean");
+        if let Err(err) = lib::cmd_ls_tree(config.args[0].as_ref(), recursive, recurseSubmodules) {
             eprintln!("Failed to perform ls-tree: {}", err);
             process::exit(1)
         }
+    } else if config.isCheckout && config.args[0] == "--orphan" {
+        if let Err(err) = lib::cmd_checkout_orphan(config.args[1].as_ref()) {
+            eprintln!("Failed to perform checkout: {}", err);
+            process::exit(1)
+        }
+    } else if config.isCheckout && config.args[0] == "--" {
+        if let Err(err) = lib::cmd_checkout_paths(config.args[1].as_ref(), &config.args[2..]) {
+            eprintln!("Failed to perform checkout: {}", err);
+            process::exit(1)
+        }
     } else if config.isCheckout {
-        if let Err(err) = lib::cmd_checkout(config.args[0].as_ref(), config.args[1].as_ref()) {
+        let setTimes: bool = config.args[2].parse().expect("Failed to perform checkout: somehow the --set-times flag was misinterpreted as a non-boolean");
+        let isForce: bool = config.args[3].parse().expect("Failed to perform checkout: somehow the --force flag was misinterpreted as a non-boolean");
+        if let Err(err) = lib::cmd_checkout(
+            config.args[0].as_ref(),
+            config.args[1].as_ref(),
+            setTimes,
+            isForce,
+        ) {
             eprintln!("Failed to perform checkout: {}", err);
             process::exit(1)
         }
     } else if config.isShowRef {
-        if let Err(err) = lib::cmd_show_ref() {
+        let result = match config.args[0].as_ref() {
+            "verify" => lib::cmd_show_ref_verify(config.args[1].as_ref()),
+            "exclude-existing" => lib::cmd_show_ref_exclude_existing(),
+            _ => lib::cmd_show_ref(),
+        };
+        if let Err(err) = result {
             eprintln!("Failed to perform show-ref: {}", err);
             process::exit(1)
         }
+    } else if config.isStatus {
+        let isNull: bool = config.args[0].parse().expect(
+            "Failed to perform status: somehow the -z flag was misinterpreted as a non-boolean",
+        );
+        if let Err(err) = lib::cmd_status_short_branch(isNull) {
+            eprintln!("Failed to perform status: {}", err);
+            process::exit(1)
+        }
     } else if config.isTag {
         let isA: bool = config.args[2].parse().expect(
             "Failed to perform tag: somehow the -a flag was misinterpreted as a non-boolean",
         );
-        if let Err(err) = lib::cmd_tag(config.args[0].as_ref(), config.args[1].as_ref(), isA) {
+        let isD: bool = config.args[3].parse().expect(
+            "Failed to perform tag: somehow the -d flag was misinterpreted as a non-boolean",
+        );
+        let isL: bool = config.args[4].parse().expect(
+            "Failed to perform tag: somehow the -l flag was misinterpreted as a non-boolean",
+        );
+        let result = if isD {
+            lib::cmd_tag_delete(config.args[0].as_ref())
+        } else if isL {
+            let pattern: Option<&str> = if config.args[0].len() != 0 {
+                Some(config.args[0].as_ref())
+            } else {
+                None
+            };
+            lib::cmd_tag_list(pattern)
+        } else {
+            lib::cmd_tag(config.args[0].as_ref(), config.args[1].as_ref(), isA)
+        };
+        if let Err(err) = result {
             eprintln!("Failed to perform tag: {}", err);
             process::exit(1)
         }
-    } else if config.isRevParse {
-        let gOption: Option<&str> = None;
-        if config.args[0].len() != 0 {
-            gOption = Some(&config.args[0].to_owned());
+    } else if config.isCommit {
+        let isAmend: bool = config.args[1].parse().expect(
+            "Failed to perform commit: somehow the --amend flag was misinterpreted as a non-boolean",
+        );
+        let allowEmpty: bool = config.args[2].parse().expect("Failed to perform commit: somehow the --allow-empty flag was misinterpreted as a non-boolean");
+        let isDryRun: bool = config.args[3].parse().expect("Failed to perform commit: somehow the --dry-run flag was misinterpreted as a non-boolean");
+        let noVerify: bool = config.args[4].parse().expect("Failed to perform commit: somehow the --no-verify flag was misinterpreted as a non-boolean");
+        let result = if isAmend {
+            lib::cmd_commit_amend(config.args[0].as_ref(), noVerify)
+        } else {
+            lib::cmd_commit(config.args[0].as_ref(), allowEmpty, isDryRun, noVerify)
+        };
+        if let Err(err) = result {
+            eprintln!("Failed to perform commit: {}", err);
+            process::exit(1)
         }
-        if let Err(err) = lib::cmd_rev_parse(config.args[1].as_ref(), gOption) {
+    } else if config.isConfig {
+        match lib::cmd_config_get(config.args[1].as_ref(), config.args[0] == "--get-all") {
+            Ok(true) => (),
+            Ok(false) => process::exit(1),
+            Err(err) => {
+                eprintln!("Failed to perform config: {}", err);
+                process::exit(1)
+            }
+        }
+    } else if config.isDebugIndex {
+        if let Err(err) = lib::cmd_debug_index() {
+            eprintln!("Failed to perform debug-index: {}", err);
+            process::exit(1)
+        }
+    } else if config.isDiff {
+        let isPatch: bool = config.args[2].parse().expect(
+            "Failed to perform diff: somehow the -p flag was misinterpreted as a non-boolean",
+        );
+        let context: usize = config.args[3].parse().expect(
+            "Failed to perform diff: somehow the -U value was misinterpreted as a non-number",
+        );
+        let exitCode: bool = config.args[4].parse().expect("Failed to perform diff: somehow the --exit-code flag was misinterpreted as a non-boolean");
+        let isQuiet: bool = config.args[5].parse().expect(
+            "Failed to perform diff: somehow the --quiet flag was misinterpreted as a non-boolean",
+        );
+        let result = if config.args[0] == "--cached" {
+            lib::cmd_diff_cached(isPatch, context, isQuiet)
+        } else {
+            lib::cmd_diff(
+                config.args[0].as_ref(),
+                config.args[1].as_ref(),
+                isPatch,
+                context,
+                isQuiet,
+            )
+        };
+        match result {
+            Ok(differs) => {
+                if differs && (exitCode || isQuiet) {
+                    process::exit(1)
+                }
+            }
+            Err(err) => {
+                eprintln!("Failed to perform diff: {}", err);
+                process::exit(1)
+            }
+        }
+    } else if config.isMergeBase && config.args[0] == "--is-ancestor" {
+        match lib::cmd_merge_base_is_ancestor(config.args[1].as_ref(), config.args[2].as_ref()) {
+            Ok(true) => (),
+            Ok(false) => process::exit(1),
+            Err(err) => {
+                eprintln!("Failed to perform merge-base: {}", err);
+                process::exit(128)
+            }
+        }
+    } else if config.isMergeBase {
+        match lib::cmd_merge_base(&config.args) {
+            Ok(true) => (),
+            Ok(false) => process::exit(1),
+            Err(err) => {
+                eprintln!("Failed to perform merge-base: {}", err);
+                process::exit(128)
+            }
+        }
+    } else if config.isObjectType {
+        if let Err(err) = lib::cmd_object_type(config.args[0].as_ref()) {
+            eprintln!("Failed to perform object-type: {}", err);
+            process::exit(1)
+        }
+    } else if config.isRestore {
+        let isStaged: bool = config.args[0].parse().expect("Failed to perform restore: somehow the --staged flag was misinterpreted as a non-boolean");
+        if let Err(err) = lib::cmd_restore(&config.args[1..], isStaged) {
+            eprintln!("Failed to perform restore: {}", err);
+            process::exit(1)
+        }
+    } else if config.isFsck {
+        if let Err(err) = lib::cmd_fsck() {
+            eprintln!("Failed to perform fsck: {}", err);
+            process::exit(1)
+        }
+    } else if config.isRepack {
+        if let Err(err) = lib::cmd_repack_list() {
+            eprintln!("Failed to perform repack: {}", err);
+            process::exit(1)
+        }
+    } else if config.isVerifyCommit {
+        if let Err(err) = lib::cmd_verify_commit(config.args[0].as_ref()) {
+            eprintln!("Failed to perform verify-commit: {}", err);
+            process::exit(1)
+        }
+    } else if config.isRevParse {
+        let gOption: Option<&str> = if config.args[0].len() != 0 {
+            Some(config.args[0].as_ref())
+        } else {
+            None
+        };
+        let result = if config.args[2] == "--abbrev-ref" {
+            lib::cmd_rev_parse_abbrev_ref(config.args[1].as_ref())
+        } else if config.args[2] == "--short" {
+            let shortLen: Option<usize> = config.args[3].parse().ok();
+            lib::cmd_rev_parse_short(config.args[1].as_ref(), gOption, shortLen)
+        } else if config.args[2] == "--verify" {
+            lib::cmd_rev_parse_verify(config.args[1].as_ref(), gOption)
+        } else if config.args[2] == "--show-toplevel" {
+            lib::cmd_rev_parse_show_toplevel()
+        } else if config.args[2] == "--git-dir" {
+            lib::cmd_rev_parse_git_dir()
+        } else {
+            lib::cmd_rev_parse(config.args[1].as_ref(), gOption)
+        };
+        if let Err(err) = result {
             eprintln!("Failed to perform rev-parse: {}", err);
             process::exit(1)
         }
@@ -79,18 +321,30 @@ fn main() {
 struct Config {
     isInit: bool,
     isAdd: bool,
+    isBranch: bool,
     isCatFile: bool,
     isCheckout: bool,
     isCommit: bool,
+    isConfig: bool,
+    isDebugIndex: bool,
+    isDiff: bool,
+    isForEachRef: bool,
+    isFsck: bool,
     isHashObject: bool,
     isLog: bool,
     isLsTree: bool,
     isMerge: bool,
+    isMergeBase: bool,
+    isObjectType: bool,
     isRebase: bool,
+    isRepack: bool,
+    isRestore: bool,
     isRevParse: bool,
     isRm: bool,
     isShowRef: bool,
+    isStatus: bool,
     isTag: bool,
+    isVerifyCommit: bool,
     path: String,
     args: Vec<String>,
 }
@@ -128,8 +382,71 @@ fn parse_args(args: Vec<String>, c: &mut Config) {
 
             "cat-file" => {
                 c.isCatFile = true;
-                let gtype = match args.next() {
-                    Some(s) => s.to_owned(),
+                let mut mode = String::default();
+                let mut allowUnknown = false;
+                let mut filterPath = String::default();
+                let mut positional: Vec<String> = Vec::new();
+                while let Some(subarg) = args.next() {
+                    match subarg.as_ref() {
+                        "-h" | "--help" => usage_exit("cat-file"),
+                        "-t" | "-s" | "--filters" | "--disk-size" => mode = subarg.to_owned(),
+                        "--allow-unknown-type" => allowUnknown = true,
+                        "--batch-check" => {
+                            // object names come from stdin
+                            c.args = vec![subarg.to_owned()];
+                            return;
+                        }
+                        x if x.starts_with("--path=") => {
+                            filterPath = x["--path=".len()..].to_owned()
+                        }
+                        rest => positional.push(rest.to_owned()),
+                    }
+                }
+
+                if mode == "--filters" {
+                    let obj = match positional.pop() {
+                        Some(s) => s,
+                        None => {
+                            eprintln!(
+                                "cat-file --filters expects an object argument, received none"
+                            );
+                            process::exit(1)
+                        }
+                    };
+                    if filterPath.len() == 0 {
+                        eprintln!(
+                            "cat-file --filters needs --path=<path> to know which filters apply"
+                        );
+                        process::exit(1)
+                    }
+                    c.args = vec![mode, obj, filterPath];
+                    break;
+                }
+
+                if mode.len() != 0 {
+                    // -t, -s and --disk-size only take the object name
+                    let obj = match positional.pop() {
+                        Some(s) => s,
+                        None => {
+                            eprintln!(
+                                "cat-file {} expects an object argument, received none",
+                                mode
+                            );
+                            process::exit(1)
+                        }
+                    };
+                    c.args = vec![mode, obj, allowUnknown.to_string()];
+                    break;
+                }
+
+                if allowUnknown {
+                    eprintln!("--allow-unknown-type can only be used with -t or -s");
+                    process::exit(1)
+                }
+
+                let mut positional = positional.into_iter();
+                let gtype = match positional.next() {
+                    Some(s) => s,
                     None => {
                         eprintln!("cat-file expects two arguments, received none");
                         process::exit(1)
@@ -142,8 +459,8 @@ fn parse_args(args: Vec<String>, c: &mut Config) {
                     process::exit(1)
                 }
 
-                let obj = match args.next() {
-                    Some(s) => s.to_owned(),
+                let obj = match positional.next() {
+                    Some(s) => s,
                     None => {
                         eprintln!(
                             "cat-file expects two arguments, but did not receive a second argument"
@@ -155,17 +472,33 @@ fn parse_args(args: Vec<String>, c: &mut Config) {
                 break;
             }
 
+            "for-each-ref" => {
+                c.isForEachRef = true;
+                match args.next() {
+                    Some(h) if is_help(h) => usage_exit("for-each-ref"),
+                    Some(p) => c.args.push(p.to_owned()),
+                    None => c.args.push(String::default()),
+                };
+                break;
+            }
+
             "hash-object" => {
                 let mut path = String::from("x");
                 let mut isW = false;
+                let mut isR = false;
                 let mut gitType = String::from("blob");
                 c.isHashObject = true;
                 while let Some(subarg) = args.next() {
                     match subarg.as_ref() {
+                        "-h" | "--help" => usage_exit("hash-object"),
                         "-w" => {
                             isW = true;
                         }
 
+                        "-r" => {
+                            isR = true;
+                        }
+
                         "-t" => {
                             let gtype = match args.next() {
                                 Some(s) => s.to_owned(),
@@ -193,92 +526,257 @@ fn parse_args(args: Vec<String>, c: &mut Config) {
                     }
                 }
 
-                c.args = vec![isW.to_string(), gitType, path];
+                if isR && gitType != "blob" {
+                    eprintln!(
+                        "hash-object -r only hashes files as blobs, -t cannot be used with it"
+                    );
+                    process::exit(1)
+                }
+
+                c.args = vec![isW.to_string(), gitType, path, isR.to_string()];
                 break;
             }
 
             "log" => {
-                let commit = match args.next() {
-                    Some(s) => s,
-                    None => "HEAD",
-                };
+                let mut commit = "HEAD".to_owned();
+                let mut firstParent = false;
+                let mut topoOrder = false;
+                let mut labels = false;
+                while let Some(subarg) = args.next() {
+                    match subarg.as_ref() {
+                        "-h" | "--help" => usage_exit("log"),
+                        "--first-parent" => firstParent = true,
+                        "--topo-order" => topoOrder = true,
+                        "--labels" => labels = true,
+                        rest => commit = rest.to_owned(),
+                    }
+                }
                 c.isLog = true;
-                c.args.push(commit.to_owned());
+                c.args.push(commit);
+                c.args.push(firstParent.to_string());
+                c.args.push(topoOrder.to_string());
+                c.args.push(labels.to_string());
                 break;
             }
 
             "ls-tree" => {
-                let sha = match args.next() {
-                    Some(s) => s.to_owned(),
+                let mut recursive = false;
+                let mut recurseSubmodules = false;
+                let mut sha: Option<String> = None;
+                while let Some(subarg) = args.next() {
+                    match subarg.as_ref() {
+                        "-h" | "--help" => usage_exit("ls-tree"),
+                        "-r" => recursive = true,
+                        "--recurse-submodules" => recurseSubmodules = true,
+                        x => {
+                            sha = Some(x.to_owned());
+                            break;
+                        }
+                    }
+                }
+                let sha = match sha {
+                    Some(s) => s,
                     None => {
                         eprintln!("ls-tree takes a mandatory argument. requires the sha of the item to query.");
                         process::exit(1)
                     }
                 };
                 c.args.push(sha);
+                c.args.push(recursive.to_string());
+                c.args.push(recurseSubmodules.to_string());
                 c.isLsTree = true;
                 break;
             }
 
             "checkout" => {
                 c.isCheckout = true;
-                let obj = match args.next() {
+                let mut setTimes = false;
+                let mut isForce = false;
+                let mut positional: Vec<String> = Vec::new();
+                while let Some(subarg) = args.next() {
+                    match subarg.as_ref() {
+                        "-h" | "--help" => usage_exit("checkout"),
+                        "--set-times" => setTimes = true,
+                        "-f" | "--force" => isForce = true,
+                        "--orphan" => {
+                            let name = match args.next() {
+                                Some(n) => n.to_owned(),
+                                None => {
+                                    eprintln!(
+                                        "checkout --orphan requires the name of the new branch"
+                                    );
+                                    process::exit(1)
+                                }
+                            };
+                            c.args = vec![subarg.to_owned(), name];
+                            return;
+                        }
+                        "--" => {
+                            // checkout <commit> -- <path>...
+                            let commit = match positional.pop() {
+                                Some(s) => s,
+                                None => String::from("HEAD"),
+                            };
+                            let paths: Vec<String> = args.by_ref().map(|a| a.to_owned()).collect();
+                            if paths.len() == 0 {
+                                eprintln!("checkout -- requires at least one path to restore");
+                                process::exit(1)
+                            }
+                            c.args = vec![subarg.to_owned(), commit];
+                            c.args.extend(paths);
+                            return;
+                        }
+                        rest => positional.push(rest.to_owned()),
+                    }
+                }
+                let mut positional = positional.into_iter();
+                let obj = match positional.next() {
                     Some(s) => s,
                     None => {
                         eprintln!("checkout requires two arguments, the [commit or tree] to checkout, and the [path to checkout to]. Received neither.");
                         process::exit(1)
                     }
                 };
-                let path = match args.next() {
+                let path = match positional.next() {
                     Some(s) => s,
                     None => {
                         eprintln!("Checkout required two arguments, failed to receive the second.");
                         process::exit(1)
                     }
                 };
-                c.args.push(obj.to_owned());
-                c.args.push(path.to_owned());
+                c.args.push(obj);
+                c.args.push(path);
+                c.args.push(setTimes.to_string());
+                c.args.push(isForce.to_string());
                 break;
             }
 
             "show-ref" => {
                 c.isShowRef = true;
+                match args.next() {
+                    Some(h) if is_help(h) => usage_exit("show-ref"),
+                    Some(s) if s == "--verify" => {
+                        let name = match args.next() {
+                            Some(n) => n.to_owned(),
+                            None => {
+                                eprintln!("show-ref --verify requires the full name of a ref, e.g. refs/heads/master");
+                                process::exit(1)
+                            }
+                        };
+                        c.args.push("verify".to_owned());
+                        c.args.push(name);
+                    }
+                    Some(s) if s == "--exclude-existing" => {
+                        c.args.push("exclude-existing".to_owned());
+                    }
+                    _ => c.args.push(String::default()),
+                };
+                break;
+            }
+
+            "status" => {
+                let mut short = false;
+                let mut branch = false;
+                let mut isNull = false;
+                while let Some(subarg) = args.next() {
+                    match subarg.as_ref() {
+                        "-h" | "--help" => usage_exit("status"),
+                        "-s" | "--short" => short = true,
+                        "-b" | "--branch" => branch = true,
+                        "-z" | "--null" => isNull = true,
+                        "-sb" | "-bs" => {
+                            short = true;
+                            branch = true;
+                        }
+                        "-sbz" | "-zsb" => {
+                            short = true;
+                            branch = true;
+                            isNull = true;
+                        }
+                        rest => {
+                            eprintln!("status does not understand {}", rest);
+                            process::exit(1)
+                        }
+                    }
+                }
+                if !short || !branch {
+                    eprintln!("status can only print its branch line so far, with -sb");
+                    process::exit(1)
+                }
+                c.isStatus = true;
+                c.args = vec![isNull.to_string()];
                 break;
             }
 
             "tag" => {
                 c.isTag = true;
                 let mut isObject: bool = false;
-                match args.next() {
-                    None => (),
-                    Some(s) => match s.as_ref() {
-                        "-a" => {
-                            isObject = true;
-                        }
-                        _ => (),
-                    },
-                };
-                let name = match args.next() {
-                    None => "",
+                let mut isDelete: bool = false;
+                let mut isList: bool = false;
+                let mut positional: Vec<String> = Vec::new();
+                while let Some(subarg) = args.next() {
+                    match subarg.as_ref() {
+                        "-h" | "--help" => usage_exit("tag"),
+                        "-a" => isObject = true,
+                        "-d" => isDelete = true,
+                        "-l" | "--list" => isList = true,
+                        rest => positional.push(rest.to_owned()),
+                    }
+                }
+                let mut positional = positional.into_iter();
+                let name = match positional.next() {
+                    None => String::default(),
                     Some(n) => n,
                 };
-                let obj = match args.next() {
-                    None => "HEAD",
+                let obj = match positional.next() {
+                    None => "HEAD".to_owned(),
                     Some(o) => o,
                 };
 
-                c.args.push(name.to_owned());
-                c.args.push(obj.to_owned());
+                if isDelete && name.len() == 0 {
+                    eprintln!("tag -d requires the name of the tag to delete");
+                    process::exit(1)
+                }
+
+                c.args.push(name);
+                c.args.push(obj);
                 c.args.push(isObject.to_string());
+                c.args.push(isDelete.to_string());
+                c.args.push(isList.to_string());
 
                 break;
             }
 
+            "restore" => {
+                let mut isStaged = false;
+                let mut paths: Vec<String> = Vec::new();
+                while let Some(subarg) = args.next() {
+                    match subarg.as_ref() {
+                        "-h" | "--help" => usage_exit("restore"),
+                        "-S" | "--staged" => isStaged = true,
+                        "-W" | "--worktree" => (),
+                        rest => paths.push(rest.to_owned()),
+                    }
+                }
+                if paths.len() == 0 {
+                    eprintln!("restore requires at least one path to restore");
+                    process::exit(1)
+                }
+                c.isRestore = true;
+                c.args.push(isStaged.to_string());
+                c.args.extend(paths);
+                break;
+            }
+
             "rev-parse" => {
                 c.isRevParse = true;
-                c.args[0] = "".to_owned();
+                let mut wyagType = String::default();
+                let mut mode = String::default();
+                let mut shortLen = String::default();
+                let mut name = String::default();
                 while let Some(sa) = args.next() {
                     match sa.as_ref() {
+                        "-h" | "--help" => usage_exit("rev-parse"),
                         "--wyag-type" => {
                             let gtype = match args.next() {
                                 Some(s) => s.to_owned(),
@@ -297,21 +795,295 @@ fn parse_args(args: Vec<String>, c: &mut Config) {
                                     );
                                 process::exit(1)
                             };
-                            c.args[0] = gtype;
+                            wyagType = gtype;
+                        }
+                        "--abbrev-ref" | "--short" | "--verify" => mode = sa.to_owned(),
+                        "--show-toplevel" | "--git-dir" => {
+                            // These describe the repository, not a revision
+                            mode = sa.to_owned();
+                            break;
+                        }
+                        x if x.starts_with("--short=") => {
+                            let n = &x["--short=".len()..];
+                            if n.parse::<usize>().is_err() {
+                                eprintln!("--short expects a number of characters, received {}", n);
+                                process::exit(1)
+                            }
+                            mode = "--short".to_owned();
+                            shortLen = n.to_owned();
                         }
                         x => {
-                            c.args[1] = x.to_owned();
+                            name = x.to_owned();
                             break; // We have received a name, so we can quit parsing here
                         }
                     }
                 }
+                if name.len() == 0 && mode != "--show-toplevel" && mode != "--git-dir" {
+                    eprintln!("rev-parse requires the name of a revision to parse");
+                    process::exit(1)
+                }
+                c.args = vec![wyagType, name, mode, shortLen];
+                break;
+            }
+
+            "branch" => {
+                c.isBranch = true;
+                let mut mode = String::default();
+                let mut upstream = String::default();
+                let mut positional: Vec<String> = Vec::new();
+                while let Some(subarg) = args.next() {
+                    match subarg.as_ref() {
+                        "-h" | "--help" => usage_exit("branch"),
+                        "-m" | "--move" => mode = "-m".to_owned(),
+                        "-M" => mode = "-M".to_owned(),
+                        "-u" => match args.next() {
+                            Some(u) => {
+                                mode = "-u".to_owned();
+                                upstream = u.to_owned();
+                            }
+                            None => {
+                                eprintln!("branch -u requires the upstream branch to track");
+                                process::exit(1)
+                            }
+                        },
+                        x if x.starts_with("--set-upstream-to=") => {
+                            mode = "-u".to_owned();
+                            upstream = x["--set-upstream-to=".len()..].to_owned();
+                        }
+                        rest => positional.push(rest.to_owned()),
+                    }
+                }
+                if mode == "-u" {
+                    if positional.len() > 1 || upstream.len() == 0 {
+                        eprintln!("branch --set-upstream-to=<upstream> takes at most one branch");
+                        process::exit(1)
+                    }
+                    let local = positional.pop().unwrap_or_default();
+                    c.args = vec![mode, local, upstream];
+                    break;
+                }
+                if mode.len() == 0 {
+                    if positional.len() > 0 {
+                        eprintln!(
+                            "branch currently only supports listing, renaming with -m/-M, and -u"
+                        );
+                        process::exit(1)
+                    }
+                    c.args = vec![mode, String::default(), String::default()];
+                    break;
+                }
+                // with a single name, the current branch is the one renamed
+                let (old, new) = match positional.len() {
+                    1 => (String::default(), positional[0].to_owned()),
+                    2 => (positional[0].to_owned(), positional[1].to_owned()),
+                    _ => {
+                        eprintln!("branch {} takes [<oldbranch>] <newbranch>", mode);
+                        process::exit(1)
+                    }
+                };
+                c.args = vec![mode, old, new];
+                break;
+            }
+
+            "fsck" => {
+                if args.any(|a| is_help(a)) {
+                    usage_exit("fsck")
+                }
+                c.isFsck = true;
+                break;
+            }
+
+            "repack" => {
+                match args.next() {
+                    Some(h) if is_help(h) => usage_exit("repack"),
+                    Some(s) if s == "--list" => c.isRepack = true,
+                    _ => {
+                        eprintln!("repack can only --list the objects it would pack so far");
+                        process::exit(1)
+                    }
+                };
+                break;
+            }
+
+            "merge-base" => {
+                let rest: Vec<String> = args.by_ref().map(|a| a.to_owned()).collect();
+                if rest.iter().any(|a| is_help(a)) {
+                    usage_exit("merge-base")
+                }
+                if rest.len() > 0 && rest[0] == "--is-ancestor" {
+                    if rest.len() != 3 {
+                        eprintln!("merge-base --is-ancestor takes exactly two commits");
+                        process::exit(1)
+                    }
+                } else if rest.len() < 2 {
+                    eprintln!("merge-base requires at least two commits");
+                    process::exit(1)
+                }
+                c.isMergeBase = true;
+                c.args = rest;
+                break;
+            }
+
+            "object-type" => {
+                let name = match args.next() {
+                    Some(h) if is_help(h) => usage_exit("object-type"),
+                    Some(s) => s.to_owned(),
+                    None => {
+                        eprintln!("object-type requires the name of an object");
+                        process::exit(1)
+                    }
+                };
+                c.isObjectType = true;
+                c.args.push(name);
+                break;
+            }
+
+            "verify-commit" => {
+                let commit = match args.next() {
+                    Some(h) if is_help(h) => usage_exit("verify-commit"),
+                    Some(s) => s.to_owned(),
+                    None => {
+                        eprintln!("verify-commit requires the commit to check");
+                        process::exit(1)
+                    }
+                };
+                c.isVerifyCommit = true;
+                c.args.push(commit);
+                break;
+            }
+
+            "commit" => {
+                let mut isAmend = false;
+                let mut allowEmpty = false;
+                let mut isDryRun = false;
+                let mut noVerify = false;
+                let mut message: Option<String> = None;
+                while let Some(subarg) = args.next() {
+                    match subarg.as_ref() {
+                        "-h" | "--help" => usage_exit("commit"),
+                        "--amend" => isAmend = true,
+                        "--allow-empty" => allowEmpty = true,
+                        "--dry-run" => isDryRun = true,
+                        "-n" | "--no-verify" => noVerify = true,
+                        "-m" => match args.next() {
+                            Some(m) => message = Some(m.to_owned()),
+                            None => {
+                                eprintln!("commit -m requires a message");
+                                process::exit(1)
+                            }
+                        },
+                        rest => {
+                            eprintln!("commit does not understand {}", rest);
+                            process::exit(1)
+                        }
+                    }
+                }
+                let message = match message {
+                    Some(m) => m,
+                    None => {
+                        eprintln!("commit requires a message, given with -m");
+                        process::exit(1)
+                    }
+                };
+                if isAmend && isDryRun {
+                    eprintln!("commit --dry-run cannot be combined with --amend yet");
+                    process::exit(1)
+                }
+                c.isCommit = true;
+                c.args.push(message);
+                c.args.push(isAmend.to_string());
+                c.args.push(allowEmpty.to_string());
+                c.args.push(isDryRun.to_string());
+                c.args.push(noVerify.to_string());
+                break;
+            }
+
+            "config" => {
+                let mode = match args.next() {
+                    Some(h) if is_help(h) => usage_exit("config"),
+                    Some(m) if m == "--get" || m == "--get-all" => m.to_owned(),
+                    _ => {
+                        eprintln!("config can only read values so far, with --get <key> or --get-all <key>");
+                        process::exit(1)
+                    }
+                };
+                let key = match args.next() {
+                    Some(h) if is_help(h) => usage_exit("config"),
+                    Some(k) => k.to_owned(),
+                    None => {
+                        eprintln!("config {} requires a key, like remote.origin.fetch", mode);
+                        process::exit(1)
+                    }
+                };
+                c.isConfig = true;
+                c.args = vec![mode, key];
+                break;
+            }
+
+            "debug-index" => {
+                if args.any(|a| is_help(a)) {
+                    usage_exit("debug-index")
+                }
+                c.isDebugIndex = true;
+                break;
+            }
+
+            "diff" => {
+                let mut isPatch = false;
+                let mut exitCode = false;
+                let mut isQuiet = false;
+                let mut isCached = false;
+                let mut context = lib::DIFF_CONTEXT_DEFAULT.to_string();
+                let mut positional: Vec<String> = Vec::new();
+                while let Some(subarg) = args.next() {
+                    match subarg.as_ref() {
+                        "-h" | "--help" => usage_exit("diff"),
+                        "-p" | "--patch" => isPatch = true,
+                        "--exit-code" => exitCode = true,
+                        "--quiet" => isQuiet = true,
+                        "--cached" | "--staged" => isCached = true,
+                        x if x.starts_with("-U") => {
+                            let n = &x["-U".len()..];
+                            if n.parse::<usize>().is_err() {
+                                eprintln!("-U expects a number of context lines, received {}", n);
+                                process::exit(1)
+                            }
+                            isPatch = true;
+                            context = n.to_owned();
+                        }
+                        rest => positional.push(rest.to_owned()),
+                    }
+                }
+                if isCached {
+                    if positional.len() != 0 {
+                        eprintln!("diff --cached compares HEAD with the index, and takes no trees");
+                        process::exit(1)
+                    }
+                    positional = vec!["--cached".to_owned(), String::default()];
+                } else if positional.len() != 2 {
+                    eprintln!("diff takes the two trees, commits or tags to compare");
+                    process::exit(1)
+                }
+                c.isDiff = true;
+                let new = positional.pop().unwrap();
+                let old = positional.pop().unwrap();
+                c.args = vec![
+                    old,
+                    new,
+                    isPatch.to_string(),
+                    context,
+                    exitCode.to_string(),
+                    isQuiet.to_string(),
+                ];
+                break;
             }
 
-            "add" | "commit" | "merge" | "rebase" | "rm" => nyi(arg),
+            "add" | "merge" | "rebase" | "rm" => nyi(arg),
 
             "init" => {
                 c.isInit = true;
                 match args.next() {
+                    Some(h) if is_help(h) => usage_exit("init"),
                     Some(s) => c.path = s.to_string(),
                     None => c.path = ".".to_string(),
                 };
@@ -325,6 +1097,20 @@ fn parse_args(args: Vec<String>, c: &mut Config) {
     }
 }
 
+/// Whether `arg` asks for a command's usage rather than being one of its arguments.
+fn is_help(arg: &str) -> bool {
+    arg == "-h" || arg == "--help"
+}
+
+/// Prints `command`'s usage and exits, for a `-h` or `--help` met while parsing its arguments.
+fn usage_exit(command: &str) -> ! {
+    match command_usage(command) {
+        Some(usage) => println!("{}", usage),
+        None => print_help_short(),
+    }
+    process::exit(0)
+}
+
 fn nyi(s: &str) {
     println!("Function {} is not yet implemnented", s);
     process::exit(1)
@@ -335,23 +1121,130 @@ fn print_help_big() {
     let s = "
 Supported commands are:
     add             adds a file to staging
-    cat-file        ?
-    checkout        checkout a commit into our worktree
-    commit          adds all staged files to a new HEAD
-    hash-object     produces the SHA1 of the specified object
+    branch          lists branches, renames one (-m, or -M to overwrite an existing one), or sets the upstream it tracks (-u, --set-upstream-to=<upstream>)
+    cat-file        provides content, type (-t) or size (-s) of repository objects (--filters --path=<path> smudges a blob, --disk-size adds the compressed size, --batch-check describes objects named on stdin)
+    checkout        checkout a commit into our worktree (--set-times dates every file with the commit's timestamp, --force replaces whatever is in the way, <commit> -- <path> restores single files, --orphan <name> starts a new unborn branch)
+    commit          adds all staged files to a new HEAD (wyag can't write the index yet, so only --amend or --allow-empty, with -m <message>; --dry-run reports without writing, --no-verify skips the hooks)
+    config          prints the value of a config key (--get), or every value of a multi-valued one (--get-all)
+    debug-index     prints every index entry with all of its fields
+    diff            lists the changes between two trees, commits or tags, or with --cached those staged in the index (-p, or -U<n> for <n> lines of context, prints them as a unified diff; --exit-code exits 1 if there are any, --quiet too but prints nothing)
+    for-each-ref    lists refs with their SHA and type, optionally only those matching a pattern like refs/tags/*
+    fsck            checks the object store for loose objects named for the wrong hash algorithm
+    hash-object     produces the SHA1 of the specified object (-r hashes a directory)
     init            initializes an empty git repository
-    log             shows recent commits
-    ls-tree         ?
+    log             shows recent commits (--first-parent follows only the mainline, --topo-order lists children before parents, --labels names each node with its subject)
+    ls-tree         lists a tree (-r recurses, stopping at submodules; --recurse-submodules is not supported yet)
     merge           merges a commit into the working branch
+    merge-base      prints the best common ancestor of two or more commits (--is-ancestor <a> <b> exits 0 only if a is an ancestor of b)
+    object-type     prints the type of the object a name resolves to
     rebase          collapses commits together
-    rev-parse       ?
+    repack          lists the loose objects a repack would pack (--list)
+    restore         discards worktree changes to paths, restoring them from the index (--staged is not supported, as wyag can't write the index yet)
+    rev-parse       resolves a revision to a SHA (--abbrev-ref gives the branch name, --short an abbreviated SHA, --verify fails unless exactly one object matches, --show-toplevel and --git-dir print the repository's paths)
     rm              removes a file from staging
-    show-ref        ?
-    tag             ?
+    show-ref        lists refs (--verify checks a single ref, --exclude-existing filters ref names read from stdin)
+    status          prints the branch and its upstream, then the staged changes and untracked files (-sb, -z ends records in NUL; changes to tracked files in the worktree are not listed yet)
+    tag             lists tags (-l <pattern> filters them), or deletes one (-d)
+    verify-commit   reports whether a commit is signed
 ";
     println!("{}", s);
 }
 
+/// The synopsis and options of `command`, as `<command> -h` prints them. `None` for commands wyag doesn't have.
+fn command_usage(command: &str) -> Option<&'static str> {
+    let usage = match command {
+        "branch" => "usage: wyag branch
+   or: wyag branch (-m | -M) [<old>] <new>
+   or: wyag branch (-u <upstream> | --set-upstream-to=<upstream>) [<branch>]
+
+    -m          rename a branch, refusing to overwrite an existing one
+    -M          rename a branch even if the new name exists
+    -u          set the upstream the branch tracks",
+        "cat-file" => "usage: wyag cat-file <type> <object>
+   or: wyag cat-file (-t | -s) [--allow-unknown-type] <object>
+   or: wyag cat-file --filters --path=<path> <object>
+   or: wyag cat-file --disk-size <object>
+   or: wyag cat-file --batch-check
+
+    <type>                  one of blob, commit, tag or tree
+    -t                      show the object's type
+    -s                      show the object's size
+    --allow-unknown-type    with -t or -s, accept types other than the four known ones
+    --filters               show a blob as the smudge filter for <path> would check it out
+    --disk-size             show the object's size and the size of its compressed file
+    --batch-check           describe each object named on stdin",
+        "checkout" => "usage: wyag checkout [--set-times] [-f | --force] <commit-or-tree> <path>
+   or: wyag checkout [<commit>] -- <path>...
+   or: wyag checkout --orphan <branch>
+
+    --set-times     give every file the commit's timestamp
+    -f, --force     check out into a non-empty directory, replacing what is in the way
+    --orphan        switch to a new branch with no commits",
+        "commit" => "usage: wyag commit [--amend] [--allow-empty] [--dry-run] [-n | --no-verify] -m <message>
+
+    -m              the commit message
+    --amend         replace the commit at HEAD
+    --allow-empty   record a commit even if its tree is the same as its parent's
+    --dry-run       show what would be committed, without writing anything
+    -n, --no-verify skip the pre-commit and commit-msg hooks",
+        "config" => "usage: wyag config (--get | --get-all) <key>
+
+    --get           show the value of <key>
+    --get-all       show every value of a multi-valued <key>",
+        "debug-index" => "usage: wyag debug-index",
+        "diff" => "usage: wyag diff [-p | --patch] [-U<n>] [--exit-code | --quiet] <old> <new>
+   or: wyag diff [-p | --patch] [-U<n>] [--exit-code | --quiet] --cached
+
+    --cached        compare HEAD with the index (also --staged)
+    -p, --patch     show a unified diff of each change
+    -U<n>           show <n> lines of context (default 3)
+    --exit-code     exit 1 if there are differences
+    --quiet         like --exit-code, but print nothing",
+        "for-each-ref" => "usage: wyag for-each-ref [<pattern>]",
+        "fsck" => "usage: wyag fsck",
+        "hash-object" => "usage: wyag hash-object [-w] [-r] [-t <type>] <path>
+
+    -w              write the object into the object store
+    -r              hash every file under a directory, one blob per line
+    -t              the type of object to hash as (default blob)",
+        "init" => "usage: wyag init [<directory>]",
+        "log" => "usage: wyag log [--first-parent] [--topo-order] [--labels] [<commit>]
+
+    --first-parent  follow only the first parent of merges
+    --topo-order    list no parent before all of its children
+    --labels        label each commit with its subject",
+        "ls-tree" => "usage: wyag ls-tree [-r] [--recurse-submodules] <tree-ish>
+
+    -r              recurse into subtrees",
+        "merge-base" => "usage: wyag merge-base <commit> <commit>...
+   or: wyag merge-base --is-ancestor <commit> <commit>",
+        "object-type" => "usage: wyag object-type <object>",
+        "repack" => "usage: wyag repack --list",
+        "restore" => "usage: wyag restore [-W | --worktree] [-S | --staged] <path>...
+
+    -W, --worktree  restore the worktree from the index (the default)
+    -S, --staged    reset the index entries to HEAD's (not supported yet)",
+        "rev-parse" => "usage: wyag rev-parse [--wyag-type <type>] [--abbrev-ref | --short[=<n>] | --verify] <name>
+   or: wyag rev-parse (--show-toplevel | --git-dir)
+
+    --abbrev-ref    show the branch name instead of the SHA
+    --short         show an abbreviated SHA
+    --verify        fail unless the name resolves to exactly one object",
+        "show-ref" => "usage: wyag show-ref
+   or: wyag show-ref --verify <ref>
+   or: wyag show-ref --exclude-existing",
+        "status" => "usage: wyag status -sb [-z]
+
+    -z, --null      end records with NUL instead of newline",
+        "tag" => "usage: wyag tag [-l <pattern>]
+   or: wyag tag -d <tagname>
+   or: wyag tag [-a] <tagname> [<object>]",
+        "verify-commit" => "usage: wyag verify-commit <commit>",
+        _ => return None,
+    };
+    Some(usage)
+}
+
 fn print_help_short() {
     let s = "
 usage:  wyat [--version] [--help
@@ -360,3 +1253,26 @@ usage:  wyat [--version] [--help
 
     println!("{}", s);
 }
+
+#[cfg(test)]
+mod usage_tests {
+    use super::*;
+
+    #[test]
+    fn checkout_usage_gives_its_synopsis() {
+        let usage = command_usage("checkout").unwrap();
+        assert!(usage.starts_with("usage: wyag checkout "));
+        assert!(usage.contains("--orphan <branch>"));
+        assert!(usage.contains("[<commit>] -- <path>..."));
+        assert!(command_usage("no-such-command").is_none());
+    }
+
+    #[test]
+    fn restore_usage_lists_every_flag_it_parses() {
+        let usage = command_usage("restore").unwrap();
+        assert!(usage.contains("-W, --worktree"));
+        assert!(usage.contains("-S, --staged"));
+        assert!(is_help("-h") && is_help("--help"));
+        assert!(!is_help("-hb"));
+    }
+}